@@ -0,0 +1,127 @@
+-- SQLite schema v10 as a released bridge-orchestrator left it on disk,
+-- copied from sqlite_master. Frozen: never regenerate it from the
+-- migrations, which are tested against it.
+
+CREATE TABLE work_items (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                flow TEXT NOT NULL,
+                task_type TEXT NOT NULL,
+                item_id TEXT NOT NULL,
+                idempotency_key TEXT NOT NULL UNIQUE,
+                payload_json TEXT NOT NULL,
+                state TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            , max_attempts INTEGER NOT NULL DEFAULT 8, next_retry_at INTEGER, last_attempt_at INTEGER, error_class TEXT, step TEXT NOT NULL DEFAULT 'new', cl_link_hash TEXT, cl_rave_hash TEXT, br_spend_hash TEXT, br_rave_hash TEXT, fee_wei TEXT, eth_sender TEXT GENERATED ALWAYS AS (
+            CASE WHEN json_valid(payload_json)
+            THEN lower(json_extract(payload_json, '$.sender')) END
+        ) VIRTUAL, holochain_agent TEXT GENERATED ALWAYS AS (
+            CASE WHEN json_valid(payload_json)
+            THEN coalesce(json_extract(payload_json, '$.holochain_agent'),
+                          json_extract(payload_json, '$.spender')) END
+        ) VIRTUAL, eth_tx_hash TEXT GENERATED ALWAYS AS (
+            CASE WHEN json_valid(payload_json)
+            THEN lower(json_extract(payload_json, '$.tx_hash')) END
+        ) VIRTUAL, lock_id INTEGER GENERATED ALWAYS AS (
+            CASE WHEN flow = 'lock' AND json_valid(payload_json)
+                  AND length(json_extract(payload_json, '$.lock_id')) BETWEEN 1 AND 18
+                  AND json_extract(payload_json, '$.lock_id') NOT GLOB '*[^0-9]*'
+            THEN CAST(json_extract(payload_json, '$.lock_id') AS INTEGER) END
+        ) VIRTUAL, eth_recipient TEXT GENERATED ALWAYS AS (
+            CASE WHEN flow = 'withdraw' AND json_valid(payload_json)
+            THEN lower(json_extract(payload_json, '$.recipient')) END
+        ) VIRTUAL, recent_errors TEXT);
+
+CREATE TABLE checkpoints (
+                checkpoint_key TEXT PRIMARY KEY,
+                checkpoint_value TEXT NOT NULL,
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+
+CREATE TABLE schema_meta (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                version INTEGER NOT NULL
+            );
+
+CREATE TABLE withdrawal_rejections (
+            link_id TEXT PRIMARY KEY,
+            reason TEXT NOT NULL,
+            detail TEXT NOT NULL,
+            payload_json TEXT NOT NULL,
+            seen_count INTEGER NOT NULL DEFAULT 1,
+            first_seen_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+            last_seen_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+
+CREATE INDEX idx_work_items_flow_br_spend ON work_items(flow, br_spend_hash);
+
+CREATE TABLE work_item_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            work_item_id INTEGER NOT NULL,
+            item_id TEXT NOT NULL,
+            from_state TEXT,
+            to_state TEXT NOT NULL,
+            from_step TEXT,
+            to_step TEXT NOT NULL,
+            reason TEXT,
+            attempt INTEGER NOT NULL,
+            action_hash TEXT,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        , actor TEXT NOT NULL DEFAULT 'orchestrator');
+
+CREATE INDEX idx_work_item_events_item ON work_item_events(item_id, id);
+
+CREATE INDEX idx_work_item_events_work_item ON work_item_events(work_item_id);
+
+CREATE TRIGGER work_item_events_append_only
+        BEFORE UPDATE ON work_item_events
+        BEGIN
+            SELECT RAISE(ABORT, 'work_item_events is append-only');
+        END;
+
+CREATE TABLE writer_lease (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            pid INTEGER NOT NULL,
+            host TEXT NOT NULL,
+            acquired_at INTEGER NOT NULL,
+            heartbeat_at INTEGER NOT NULL
+        );
+
+CREATE INDEX idx_work_items_eth_sender ON work_items(eth_sender);
+
+CREATE INDEX idx_work_items_holochain_agent ON work_items(holochain_agent);
+
+CREATE INDEX idx_work_items_eth_tx_hash ON work_items(eth_tx_hash);
+
+CREATE INDEX idx_work_items_lock_id ON work_items(lock_id);
+
+CREATE INDEX idx_work_items_step ON work_items(step, state);
+
+CREATE INDEX idx_work_items_error_class ON work_items(error_class);
+
+CREATE INDEX idx_work_items_created ON work_items(created_at, id);
+
+CREATE INDEX idx_work_items_updated ON work_items(updated_at, id);
+
+CREATE INDEX idx_work_items_eth_recipient ON work_items(eth_recipient);
+
+CREATE TABLE lock_refunds (
+        work_item_id BIGINT PRIMARY KEY,
+        item_id TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'refundable',
+        approved_by TEXT,
+        approved_at BIGINT,
+        coupon TEXT,
+        coupon_signed_at BIGINT,
+        claim_tx_hash TEXT,
+        reported_at BIGINT,
+        last_error TEXT,
+        created_at BIGINT NOT NULL,
+        updated_at BIGINT NOT NULL
+    );
+
+CREATE INDEX idx_lock_refunds_status ON lock_refunds (status, updated_at);
+
+INSERT INTO schema_meta (id, version) VALUES (1, 10);
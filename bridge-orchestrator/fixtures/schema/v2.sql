@@ -0,0 +1,30 @@
+-- SQLite schema v2 as a released bridge-orchestrator left it on disk,
+-- copied from sqlite_master. Frozen: never regenerate it from the
+-- migrations, which are tested against it.
+
+CREATE TABLE work_items (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                flow TEXT NOT NULL,
+                task_type TEXT NOT NULL,
+                item_id TEXT NOT NULL,
+                idempotency_key TEXT NOT NULL UNIQUE,
+                payload_json TEXT NOT NULL,
+                state TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            , max_attempts INTEGER NOT NULL DEFAULT 8, next_retry_at INTEGER, last_attempt_at INTEGER, error_class TEXT, step TEXT NOT NULL DEFAULT 'new', cl_link_hash TEXT, cl_rave_hash TEXT, br_spend_hash TEXT, br_rave_hash TEXT);
+
+CREATE TABLE checkpoints (
+                checkpoint_key TEXT PRIMARY KEY,
+                checkpoint_value TEXT NOT NULL,
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+
+CREATE TABLE schema_meta (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                version INTEGER NOT NULL
+            );
+
+INSERT INTO schema_meta (id, version) VALUES (1, 2);
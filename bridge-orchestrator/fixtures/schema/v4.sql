@@ -0,0 +1,66 @@
+-- SQLite schema v4 as a released bridge-orchestrator left it on disk,
+-- copied from sqlite_master. Frozen: never regenerate it from the
+-- migrations, which are tested against it.
+
+CREATE TABLE work_items (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                flow TEXT NOT NULL,
+                task_type TEXT NOT NULL,
+                item_id TEXT NOT NULL,
+                idempotency_key TEXT NOT NULL UNIQUE,
+                payload_json TEXT NOT NULL,
+                state TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT,
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            , max_attempts INTEGER NOT NULL DEFAULT 8, next_retry_at INTEGER, last_attempt_at INTEGER, error_class TEXT, step TEXT NOT NULL DEFAULT 'new', cl_link_hash TEXT, cl_rave_hash TEXT, br_spend_hash TEXT, br_rave_hash TEXT, fee_wei TEXT);
+
+CREATE TABLE checkpoints (
+                checkpoint_key TEXT PRIMARY KEY,
+                checkpoint_value TEXT NOT NULL,
+                updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+
+CREATE TABLE schema_meta (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                version INTEGER NOT NULL
+            );
+
+CREATE TABLE withdrawal_rejections (
+            link_id TEXT PRIMARY KEY,
+            reason TEXT NOT NULL,
+            detail TEXT NOT NULL,
+            payload_json TEXT NOT NULL,
+            seen_count INTEGER NOT NULL DEFAULT 1,
+            first_seen_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+            last_seen_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+
+CREATE INDEX idx_work_items_flow_br_spend ON work_items(flow, br_spend_hash);
+
+CREATE TABLE work_item_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            work_item_id INTEGER NOT NULL,
+            item_id TEXT NOT NULL,
+            from_state TEXT,
+            to_state TEXT NOT NULL,
+            from_step TEXT,
+            to_step TEXT NOT NULL,
+            reason TEXT,
+            attempt INTEGER NOT NULL,
+            action_hash TEXT,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+
+CREATE INDEX idx_work_item_events_item ON work_item_events(item_id, id);
+
+CREATE INDEX idx_work_item_events_work_item ON work_item_events(work_item_id);
+
+CREATE TRIGGER work_item_events_append_only
+        BEFORE UPDATE ON work_item_events
+        BEGIN
+            SELECT RAISE(ABORT, 'work_item_events is append-only');
+        END;
+
+INSERT INTO schema_meta (id, version) VALUES (1, 4);
@@ -0,0 +1,438 @@
+//! Online backups of the state database, and restoring from one.
+//!
+//! `db backup` and the periodic task copy the database with SQLite's online
+//! backup API through a read-only connection of their own, a few pages per
+//! step. They never take the writer mutex, and a running daemon's writes go
+//! through between steps. The copy is written to `<dest>.partial` and only
+//! renamed into place once complete, so a snapshot on disk is always whole.
+//!
+//! `db restore` holds the writer lock for the whole swap, so the daemon must
+//! be stopped. It refuses a backup that fails `integrity_check` or was
+//! written by a newer binary. It also refuses to move the lock watcher's
+//! checkpoint backwards unless told to: a lock detected and bridged after
+//! the snapshot was taken would be detected again and bridged a second
+//! time. The database being replaced is moved aside, never deleted.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::config::BackupConfig;
+use crate::lease;
+use crate::lock_flow::LOCK_CHECKPOINT_KEY;
+use crate::migrations;
+
+/// Pages copied per backup step; the source is only read-locked while a
+/// step runs.
+const PAGES_PER_STEP: i32 = 256;
+/// Pause between steps, which lets the daemon's writes through.
+const STEP_PAUSE: Duration = Duration::from_millis(10);
+
+/// A snapshot written by [`backup`], as printed by `db backup`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupReport {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub schema_version: i64,
+    pub work_items: i64,
+    /// The `checkpoints` table as it stood in the snapshot.
+    pub checkpoints: BTreeMap<String, String>,
+}
+
+/// Outcome of [`restore`], as printed by `db restore`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreReport {
+    pub restored_from: PathBuf,
+    pub db_path: PathBuf,
+    pub schema_version: i64,
+    pub work_items: i64,
+    pub checkpoints: BTreeMap<String, String>,
+    /// Where the replaced database was moved. `None` when there was none.
+    pub previous_moved_to: Option<PathBuf>,
+    /// The lock checkpoint went backwards (only with `allow_rewind`).
+    pub rewound: bool,
+}
+
+/// What a database file holds, read through a read-only connection.
+struct Snapshot {
+    schema_version: i64,
+    work_items: i64,
+    checkpoints: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    fn lock_checkpoint(&self) -> Option<u64> {
+        self.checkpoints
+            .get(LOCK_CHECKPOINT_KEY)
+            .and_then(|v| v.parse().ok())
+    }
+}
+
+/// Copy the database at `db_path` to `dest`, which must not exist yet.
+/// Safe to run while the daemon is writing.
+pub fn backup(db_path: &Path, dest: &Path) -> Result<BackupReport> {
+    if dest.exists() {
+        bail!("{} already exists", dest.display());
+    }
+    let src = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("open {} read-only", db_path.display()))?;
+
+    let partial = with_suffix(dest, ".partial");
+    // Left behind by a backup that was interrupted.
+    let _ = fs::remove_file(&partial);
+    let written = copy_database(&src, &partial)
+        .with_context(|| format!("back up {} to {}", db_path.display(), dest.display()))
+        .and_then(|()| inspect(&partial));
+    let snapshot = match written {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    fs::rename(&partial, dest).with_context(|| format!("move {} into place", partial.display()))?;
+
+    Ok(BackupReport {
+        bytes: fs::metadata(dest)?.len(),
+        path: dest.to_path_buf(),
+        schema_version: snapshot.schema_version,
+        work_items: snapshot.work_items,
+        checkpoints: snapshot.checkpoints,
+    })
+}
+
+/// Replace the database at `db_path` with the backup at `backup_path`.
+/// Fails while another process holds the writer lock. The replaced file
+/// is kept as `<db>.pre-restore.<unix-seconds>.bak`. The restored database
+/// is migrated by whichever command opens it next.
+pub fn restore(backup_path: &Path, db_path: &str, allow_rewind: bool) -> Result<RestoreReport> {
+    let snapshot = inspect(backup_path)?;
+    migrations::check_not_newer(snapshot.schema_version, migrations::latest_version())
+        .with_context(|| format!("refusing to restore {}", backup_path.display()))?;
+
+    let _lock = lease::lock_database(db_path)?;
+    let db = Path::new(db_path);
+
+    let mut rewound = false;
+    if db.exists() {
+        // A database that no longer opens has no checkpoint to protect.
+        let current = inspect(db).ok().and_then(|s| s.lock_checkpoint());
+        let restored = snapshot.lock_checkpoint();
+        if let Some(current) = current {
+            rewound = restored.is_none_or(|restored| restored < current);
+        }
+        if rewound && !allow_rewind {
+            bail!(
+                "restoring {} would move the lock checkpoint back from block {} to {}; \
+                 locks bridged since the backup was taken would be bridged again. \
+                 Pass --allow-rewind if that is intended",
+                backup_path.display(),
+                current.unwrap_or_default(),
+                restored.map_or_else(|| "none".to_string(), |b| b.to_string())
+            );
+        }
+    }
+
+    let previous_moved_to = if db.exists() {
+        let aside = with_suffix(
+            db,
+            &format!(".pre-restore.{}.bak", chrono::Utc::now().timestamp()),
+        );
+        move_aside(db, &aside)?;
+        Some(aside)
+    } else {
+        None
+    };
+
+    let src = Connection::open_with_flags(backup_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("open {} read-only", backup_path.display()))?;
+    copy_database(&src, db).with_context(|| match &previous_moved_to {
+        Some(aside) => format!(
+            "restore into {} failed; the previous database is at {}",
+            db.display(),
+            aside.display()
+        ),
+        None => format!("restore into {} failed", db.display()),
+    })?;
+    tracing::info!(
+        event = "state.restored",
+        from = %backup_path.display(),
+        rewound,
+        "restored {} from {}",
+        db.display(),
+        backup_path.display()
+    );
+
+    Ok(RestoreReport {
+        restored_from: backup_path.to_path_buf(),
+        db_path: db.to_path_buf(),
+        schema_version: snapshot.schema_version,
+        work_items: snapshot.work_items,
+        checkpoints: snapshot.checkpoints,
+        previous_moved_to,
+        rewound,
+    })
+}
+
+/// Spawn the periodic backup loop. Returns the `JoinHandle` so callers can
+/// `drop(...)` it, like [`crate::retention::spawn`]. The first snapshot is
+/// taken straight away; a failed one is logged and retried next tick.
+pub fn spawn(cfg: BackupConfig, db_path: String) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        tracing::info!(
+            event = "bridge_orchestrator.backup.started",
+            dir = %cfg.dir.display(),
+            interval_ms = cfg.interval_ms,
+            keep = cfg.keep,
+            "periodic backup task started"
+        );
+
+        let mut tick = interval(Duration::from_millis(cfg.interval_ms));
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tick.tick().await;
+            let cfg = cfg.clone();
+            let db_path = db_path.clone();
+            match tokio::task::spawn_blocking(move || scheduled_backup(&cfg, Path::new(&db_path)))
+                .await
+            {
+                Ok(Ok((report, pruned))) => tracing::info!(
+                    event = "bridge_orchestrator.backup.written",
+                    path = %report.path.display(),
+                    bytes = report.bytes,
+                    pruned,
+                    "wrote database backup"
+                ),
+                Ok(Err(e)) => tracing::warn!(
+                    event = "bridge_orchestrator.backup.failed",
+                    error = %e,
+                    "database backup failed; will retry next tick"
+                ),
+                Err(join_err) => tracing::warn!(
+                    event = "bridge_orchestrator.backup.join_error",
+                    error = %join_err,
+                    "backup spawn_blocking join failed; will retry next tick"
+                ),
+            }
+        }
+    })
+}
+
+/// Write `<dir>/<db-stem>-<UTC timestamp>.db`, then delete all but the
+/// newest `keep` of them. Returns the report and how many were deleted.
+fn scheduled_backup(cfg: &BackupConfig, db_path: &Path) -> Result<(BackupReport, usize)> {
+    fs::create_dir_all(&cfg.dir)
+        .with_context(|| format!("create backup directory {}", cfg.dir.display()))?;
+    let prefix = snapshot_prefix(db_path);
+    let name = format!(
+        "{}{}.db",
+        prefix,
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+    );
+    let report = backup(db_path, &cfg.dir.join(name))?;
+    let pruned = rotate(&cfg.dir, &prefix, cfg.keep)?;
+    Ok((report, pruned))
+}
+
+fn snapshot_prefix(db_path: &Path) -> String {
+    let stem = db_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "bridge_orchestrator".to_string());
+    format!("{}-", stem)
+}
+
+/// Delete the oldest `<prefix>*.db` files in `dir` beyond the newest
+/// `keep`. The timestamp in the name sorts chronologically; nothing else
+/// in the directory is touched.
+fn rotate(dir: &Path, prefix: &str, keep: usize) -> Result<usize> {
+    let mut snapshots: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("list {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(prefix) && n.ends_with(".db"))
+        })
+        .collect();
+    snapshots.sort();
+    let excess = snapshots.len().saturating_sub(keep);
+    for old in &snapshots[..excess] {
+        fs::remove_file(old).with_context(|| format!("delete old backup {}", old.display()))?;
+    }
+    Ok(excess)
+}
+
+fn copy_database(src: &Connection, dest: &Path) -> Result<()> {
+    let mut dst = Connection::open(dest)?;
+    Backup::new(src, &mut dst)?.run_to_completion(PAGES_PER_STEP, STEP_PAUSE, None)?;
+    Ok(())
+}
+
+/// Check the file at `path` is a sound orchestrator database and read what
+/// the reports need from it.
+fn inspect(path: &Path) -> Result<Snapshot> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("open {} read-only", path.display()))?;
+    let verdict: String = conn
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .with_context(|| format!("{} is not a readable SQLite database", path.display()))?;
+    if verdict != "ok" {
+        bail!("{} fails integrity_check: {}", path.display(), verdict);
+    }
+
+    let work_items = conn
+        .query_row("SELECT COUNT(*) FROM work_items", [], |row| row.get(0))
+        .with_context(|| format!("{} is not a bridge-orchestrator database", path.display()))?;
+    let mut stmt = conn.prepare("SELECT checkpoint_key, checkpoint_value FROM checkpoints")?;
+    let checkpoints = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(Snapshot {
+        schema_version: migrations::current_version(&conn)?,
+        work_items,
+        checkpoints,
+    })
+}
+
+/// Rename `db` to `aside`, with any journal files SQLite keeps next to it,
+/// so an interrupted transaction can still be recovered from the copy.
+fn move_aside(db: &Path, aside: &Path) -> Result<()> {
+    fs::rename(db, aside).with_context(|| format!("move {} aside", db.display()))?;
+    for sidecar in ["-journal", "-wal", "-shm"] {
+        let from = with_suffix(db, sidecar);
+        if from.exists() {
+            fs::rename(&from, with_suffix(aside, sidecar))
+                .with_context(|| format!("move {} aside", from.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::SqliteStore;
+    use crate::store::StateStore;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn test_path(name: &str) -> PathBuf {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        PathBuf::from(format!("/tmp/bridge-orchestrator-backup-{}-{}", name, ts))
+    }
+
+    fn db_with_checkpoint(name: &str, block: u64) -> (String, SqliteStore) {
+        let path = format!("{}.db", test_path(name).display());
+        let db = SqliteStore::open(&path).unwrap();
+        db.set_checkpoint_u64(LOCK_CHECKPOINT_KEY, block).unwrap();
+        (path, db)
+    }
+
+    #[test]
+    fn backup_snapshots_a_database_that_is_still_open_for_writing() {
+        let (path, db) = db_with_checkpoint("online", 42);
+        let dest = with_suffix(&test_path("online-copy"), ".db");
+
+        let report = backup(Path::new(&path), &dest).unwrap();
+        assert_eq!(report.schema_version, migrations::latest_version());
+        assert_eq!(report.checkpoints[LOCK_CHECKPOINT_KEY], "42");
+        assert!(report.bytes > 0);
+        assert!(!with_suffix(&dest, ".partial").exists());
+
+        // The source stays writable, and the copy doesn't follow it.
+        db.set_checkpoint_u64(LOCK_CHECKPOINT_KEY, 43).unwrap();
+        assert_eq!(inspect(&dest).unwrap().lock_checkpoint(), Some(42));
+        assert!(backup(Path::new(&path), &dest).is_err(), "never overwrites");
+    }
+
+    #[test]
+    fn restore_refuses_to_rewind_the_lock_checkpoint_unless_allowed() {
+        let (path, db) = db_with_checkpoint("rewind", 100);
+        let dest = with_suffix(&test_path("rewind-copy"), ".db");
+        backup(Path::new(&path), &dest).unwrap();
+        db.set_checkpoint_u64(LOCK_CHECKPOINT_KEY, 200).unwrap();
+        drop(db);
+
+        let err = restore(&dest, &path, false).unwrap_err().to_string();
+        assert!(err.contains("back from block 200 to 100"), "{}", err);
+
+        let report = restore(&dest, &path, true).unwrap();
+        assert!(report.rewound);
+        let aside = report.previous_moved_to.expect("the old database is kept");
+        assert_eq!(inspect(&aside).unwrap().lock_checkpoint(), Some(200));
+
+        let db = SqliteStore::open(&path).unwrap();
+        assert_eq!(
+            db.get_checkpoint_u64(LOCK_CHECKPOINT_KEY).unwrap(),
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn restore_refuses_a_backup_from_a_newer_binary() {
+        let (path, db) = db_with_checkpoint("newer", 7);
+        drop(db);
+        let dest = with_suffix(&test_path("newer-copy"), ".db");
+        backup(Path::new(&path), &dest).unwrap();
+        Connection::open(&dest)
+            .unwrap()
+            .execute(
+                "UPDATE schema_meta SET version = ?1",
+                [migrations::latest_version() + 1],
+            )
+            .unwrap();
+
+        let err = format!("{:#}", restore(&dest, &path, false).unwrap_err());
+        assert!(err.contains("newer than binary"), "{}", err);
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_snapshots_and_nothing_else() {
+        let dir = test_path("rotate");
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "bridge-20261001T000000Z.db",
+            "bridge-20261002T000000Z.db",
+            "bridge-20261003T000000Z.db",
+            "bridge-20261004T000000Z.db.partial",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        assert_eq!(rotate(&dir, "bridge-", 2).unwrap(), 1);
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "bridge-20261002T000000Z.db",
+                "bridge-20261003T000000Z.db",
+                "bridge-20261004T000000Z.db.partial",
+                "notes.txt",
+            ]
+        );
+    }
+}
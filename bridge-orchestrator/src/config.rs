@@ -1,10 +1,14 @@
-use alloy::primitives::Address;
+use alloy::primitives::{Address, U256};
 use anyhow::{Context, Result};
 use clap::ValueEnum;
 use holo_hash::{ActionHashB64, AgentPubKeyB64};
+use std::collections::BTreeMap;
 use std::env;
+use std::path::PathBuf;
 use std::str::FromStr;
 
+use crate::state::ErrorClass;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum Network {
     Mainnet,
@@ -23,6 +27,78 @@ impl FromStr for Network {
     }
 }
 
+/// How S4 orders the pooled deposit and withdrawal links before
+/// `RAVE_MAX_LINKS` truncates the batch. Only matters when the cap is
+/// hit: whatever sorts last is what gets deferred to the next cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum S4OrderingPolicy {
+    /// Deposits, then withdrawals (the historical order). Deposits have
+    /// already settled on the HOT side in S3, but sustained deposit load
+    /// can defer withdrawals indefinitely.
+    DepositsFirst,
+    /// Withdrawals, then deposits.
+    WithdrawalsFirst,
+    /// Every link by its parked-spend action timestamp, oldest first.
+    OldestFirst,
+    /// Alternate deposit / withdrawal, each oldest first. A link parked
+    /// longer than `S4_MAX_DEFERRAL_S` jumps the rotation.
+    RoundRobin,
+}
+
+impl FromStr for S4OrderingPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().replace('-', "_").as_str() {
+            "deposits_first" => Ok(S4OrderingPolicy::DepositsFirst),
+            "withdrawals_first" => Ok(S4OrderingPolicy::WithdrawalsFirst),
+            "oldest_first" => Ok(S4OrderingPolicy::OldestFirst),
+            "round_robin" => Ok(S4OrderingPolicy::RoundRobin),
+            _ => Err(anyhow::anyhow!("Unknown S4 ordering policy: {}", s)),
+        }
+    }
+}
+
+/// How the bridge charges a withdrawal for the Ethereum gas its coupon
+/// claim costs. The fee is deducted from the parked-spend amount when
+/// the coupon is built; amounts are in HOT base units (18 decimals).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawalFeeModel {
+    /// Coupons carry the full parked-spend amount (the historical
+    /// behaviour).
+    None,
+    /// A fixed fee per withdrawal.
+    Flat { fee_wei: U256 },
+    /// A share of the withdrawal, in basis points.
+    Percentage { bps: u32 },
+    /// `gas_units` at the RPC's current gas price, converted to HOT at
+    /// `hot_per_eth_wei` (HOT base units per whole ETH).
+    GasIndexed {
+        gas_units: u64,
+        hot_per_eth_wei: U256,
+    },
+}
+
+impl WithdrawalFeeModel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WithdrawalFeeModel::None => "none",
+            WithdrawalFeeModel::Flat { .. } => "flat",
+            WithdrawalFeeModel::Percentage { .. } => "percentage",
+            WithdrawalFeeModel::GasIndexed { .. } => "gas_indexed",
+        }
+    }
+}
+
+/// Withdrawal fee settings, read from the `WITHDRAWAL_FEE_*` env vars.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithdrawalFeeConfig {
+    pub model: WithdrawalFeeModel,
+    /// Optional ceiling on any single fee, whatever the model computes.
+    /// Mostly a guard for `gas_indexed` during a gas spike.
+    pub max_fee_wei: Option<U256>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub network: Network,
@@ -89,6 +165,16 @@ pub struct Config {
     /// hangs correlate with large batch sizes; the existing withdrawal
     /// `coupons_target_bytes` cap remains in force on top.
     pub rave_max_links: Option<usize>,
+    /// Order of the pooled S4 links ahead of the `rave_max_links` cap.
+    /// Driven by `S4_ORDERING_POLICY`; defaults to deposits-first.
+    pub s4_ordering: S4OrderingPolicy,
+    /// Under [`S4OrderingPolicy::RoundRobin`], a link parked longer than
+    /// this many seconds is promoted ahead of the rotation so no user's
+    /// link is deferred past it while the cap has room. Driven by
+    /// `S4_MAX_DEFERRAL_S`.
+    pub s4_max_deferral_s: u64,
+    /// Fee deducted from each withdrawal coupon. Defaults to no fee.
+    pub withdrawal_fee: WithdrawalFeeConfig,
     /// Optional watchtower reporter configuration. When `None`, the
     /// reporter task is not spawned and the orchestrator runs exactly as
     /// before. All fields must be supplied together for reporting to be
@@ -99,16 +185,155 @@ pub struct Config {
     /// with compact defaults; set `BRIDGE_RETENTION_DISABLED=true` to
     /// skip spawning the retention task entirely.
     pub retention: RetentionConfig,
+    /// Active/standby leader election. `None` (the default) runs the
+    /// bridge cycle unconditionally, as a single instance.
+    pub leader_election: Option<LeaderElectionConfig>,
+    /// Where the work queue lives: the SQLite file at `DB_PATH` or, in
+    /// builds with the `postgres` feature, a PostgreSQL database.
+    pub storage: StorageBackend,
+    /// Connection tuning and health checks for `DB_PATH`.
+    pub sqlite: SqliteConfig,
+    /// Periodic online backups of `DB_PATH`. `None` (the default) unless
+    /// `BRIDGE_BACKUP_DIR` is set.
+    pub backup: Option<BackupConfig>,
+    /// How long a row waits after a failed cycle before it is retried, and
+    /// how many attempts it gets. Driven by the `RETRY_*` variables.
+    pub retry: RetryPolicy,
+}
+
+/// Leader election between orchestrators sharing one `DB_PATH`. Enabled by
+/// `LEADER_ELECTION_ENABLED=true`; see [`crate::leader`].
+#[derive(Debug, Clone)]
+pub struct LeaderElectionConfig {
+    /// SQLite file holding the lease, shared by every candidate. Driven by
+    /// `LEADER_ELECTION_LEASE_PATH`; defaults to `<DB_PATH>.leader`.
+    pub lease_path: String,
+    /// This instance's name in the lease. Driven by `LEADER_ELECTION_ID`;
+    /// defaults to `<host>:<pid>`, and must differ between candidates.
+    pub candidate_id: String,
+    /// How long a renewal holds the lease. A standby takes over at most
+    /// `ttl_ms + renew_interval_ms` after the leader's last renewal.
+    /// Driven by `LEADER_ELECTION_TTL_MS`.
+    pub ttl_ms: u64,
+    /// How often every candidate renews or campaigns. At most half of
+    /// `ttl_ms`, so one missed renewal never costs the lease. Driven by
+    /// `LEADER_ELECTION_RENEW_MS`; defaults to a third of `ttl_ms`.
+    pub renew_interval_ms: u64,
+}
+
+/// The backend behind [`crate::store::StateStore`]. Driven by `DB_BACKEND`
+/// (`sqlite` or `postgres`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// The SQLite file at `DB_PATH`, the default.
+    Sqlite,
+    /// The PostgreSQL database at `DATABASE_URL`.
+    #[cfg(feature = "postgres")]
+    Postgres { url: String },
+}
+
+/// `PRAGMA synchronous` for the state database. The database always runs
+/// in WAL mode, where `Normal` cannot corrupt it but may lose the last
+/// transactions before a power failure; `Full` syncs every commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqliteSynchronous {
+    Normal,
+    Full,
+}
+
+impl SqliteSynchronous {
+    pub fn as_pragma(self) -> &'static str {
+        match self {
+            SqliteSynchronous::Normal => "NORMAL",
+            SqliteSynchronous::Full => "FULL",
+        }
+    }
+}
+
+impl FromStr for SqliteSynchronous {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "normal" => Ok(SqliteSynchronous::Normal),
+            "full" => Ok(SqliteSynchronous::Full),
+            _ => Err(anyhow::anyhow!("Unknown synchronous mode: {}", s)),
+        }
+    }
+}
+
+/// Which integrity check `run` performs before it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    Off,
+    /// `PRAGMA quick_check`: everything but index contents, O(N).
+    Quick,
+    /// `PRAGMA integrity_check`: also cross-checks every index.
+    Full,
+}
+
+impl FromStr for IntegrityCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(IntegrityCheck::Off),
+            "quick" => Ok(IntegrityCheck::Quick),
+            "full" => Ok(IntegrityCheck::Full),
+            _ => Err(anyhow::anyhow!("Unknown integrity check: {}", s)),
+        }
+    }
+}
+
+/// Connection tuning for the state database, applied by
+/// [`crate::state::SqliteStore`] to every connection it opens.
+#[derive(Debug, Clone)]
+pub struct SqliteConfig {
+    /// Driven by `DB_SYNCHRONOUS` (`normal` or `full`).
+    pub synchronous: SqliteSynchronous,
+    /// How long a connection waits on a lock held by another before
+    /// failing with `SQLITE_BUSY`. Driven by `DB_BUSY_TIMEOUT_MS`.
+    pub busy_timeout_ms: u64,
+    /// How often `run` checkpoints and truncates the WAL. `None` leaves
+    /// it to SQLite's automatic checkpoints. Driven by
+    /// `DB_CHECKPOINT_INTERVAL_MS` (`0` turns it off).
+    pub checkpoint_interval_ms: Option<u64>,
+    /// Driven by `DB_STARTUP_CHECK` (`off`, `quick` or `full`).
+    pub startup_check: IntegrityCheck,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        Self {
+            synchronous: SqliteSynchronous::Full,
+            busy_timeout_ms: 5_000,
+            checkpoint_interval_ms: Some(300_000),
+            startup_check: IntegrityCheck::Quick,
+        }
+    }
+}
+
+/// Periodic backups of the state database, written by [`crate::backup`].
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    /// Directory the snapshots go in. Driven by `BRIDGE_BACKUP_DIR`.
+    pub dir: PathBuf,
+    /// How often a snapshot is taken. Driven by `BRIDGE_BACKUP_INTERVAL_MS`.
+    pub interval_ms: u64,
+    /// How many snapshots to keep; older ones are deleted after each new
+    /// one. Driven by `BRIDGE_BACKUP_KEEP`.
+    pub keep: usize,
 }
 
 /// Configuration for the in-process retention task that prunes
 /// long-lived terminal `work_items` rows. Enabled by default with
 /// compact windows; operators tune via `BRIDGE_RETENTION_*` env vars.
 ///
-/// The task runs a single DELETE per eligible state per tick through
-/// the writer mutex — brief enough at an hourly cadence not to
-/// measurably impact the bridge cycle, and the existing
-/// `idx_work_items_state_created` index keeps each DELETE cheap.
+/// The task deletes in batches of `batch_size` rows, one transaction
+/// each, releasing the writer mutex between them, and stops a tick at
+/// its row or time budget. A first prune after months of accumulation
+/// is therefore spread over several ticks instead of stalling the
+/// bridge cycle.
 #[derive(Debug, Clone)]
 pub struct RetentionConfig {
     /// When `false` the retention task is never spawned. Driven by
@@ -121,12 +346,87 @@ pub struct RetentionConfig {
     /// they're eligible for deletion. Driven by
     /// `BRIDGE_RETENTION_SUCCEEDED_MAX_AGE_S`.
     pub succeeded_max_age_s: u64,
-    /// Maximum age (seconds) for `state = 'failed'` rows before
-    /// they're eligible for deletion. Typically larger than
+    /// Maximum age (seconds) for `state = 'failed'` withdrawal rows
+    /// before they're eligible for deletion; failed locks are dead
+    /// letters and never pruned. Typically larger than
     /// `succeeded_max_age_s` because failures are operationally
     /// useful for postmortems. Driven by
     /// `BRIDGE_RETENTION_FAILED_MAX_AGE_S`.
     pub failed_max_age_s: u64,
+    /// Rows deleted per transaction. Driven by
+    /// `BRIDGE_RETENTION_BATCH_SIZE`.
+    pub batch_size: usize,
+    /// Rows one tick may delete before leaving the rest to the next
+    /// tick. `None` means no cap. Driven by
+    /// `BRIDGE_RETENTION_MAX_ROWS_PER_TICK` (`0` disables the cap).
+    pub max_rows_per_tick: Option<u64>,
+    /// Wall time after which a tick stops starting new batches. `None`
+    /// means no cap. Driven by `BRIDGE_RETENTION_MAX_TICK_MS` (`0`
+    /// disables the cap).
+    pub max_tick_ms: Option<u64>,
+    /// Where pruned rows are written before they are deleted. `None`
+    /// (the default) deletes them outright. Driven by
+    /// `BRIDGE_RETENTION_ARCHIVE` / `BRIDGE_RETENTION_ARCHIVE_PATH`.
+    pub archive: Option<RetentionArchiveConfig>,
+}
+
+/// Format of the retention archive; see [`crate::retention`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// One gzip-compressed JSON line per row, in a file per UTC day.
+    JsonlGz,
+    /// Rows upserted into a separate SQLite database.
+    Sqlite,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().replace('-', "_").as_str() {
+            "jsonl_gz" => Ok(ArchiveFormat::JsonlGz),
+            "sqlite" => Ok(ArchiveFormat::Sqlite),
+            _ => Err(anyhow::anyhow!("Unknown retention archive format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionArchiveConfig {
+    pub format: ArchiveFormat,
+    /// A directory for `jsonl_gz`, the archive database file for `sqlite`.
+    pub path: PathBuf,
+}
+
+/// Backoff applied to every row a failed cycle returns to the queue: the
+/// wait doubles with each failed attempt from `base_delay_s` up to
+/// `max_delay_s`, less up to `jitter` of it, and the row fails for good
+/// once it has used `max_attempts`. Overrides replace any of the three
+/// numbers for one [`ErrorClass`]. With the default `base_delay_s` of 0
+/// there is no wait, and a row is retried on the next cycle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Driven by `RETRY_MAX_ATTEMPTS`.
+    pub max_attempts: i64,
+    /// Wait after the first failed attempt. Driven by `RETRY_BASE_DELAY_S`.
+    pub base_delay_s: u64,
+    /// Ceiling on the doubled wait. Driven by `RETRY_MAX_DELAY_S`.
+    pub max_delay_s: u64,
+    /// Fraction of each wait, `0.0..=1.0`, taken off at random so rows
+    /// that failed together are not all retried together. Driven by
+    /// `RETRY_JITTER`.
+    pub jitter: f64,
+    /// Driven by `RETRY_CLASS_OVERRIDES`, e.g.
+    /// `source_chain_pressure:base_s=120,attempts=20;unclassified:attempts=3`.
+    pub overrides: BTreeMap<ErrorClass, RetryOverride>,
+}
+
+/// The settings one class of failure replaces; `None` keeps the default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetryOverride {
+    pub max_attempts: Option<i64>,
+    pub base_delay_s: Option<u64>,
+    pub max_delay_s: Option<u64>,
 }
 
 /// Configuration for the optional watchtower reporter task.
@@ -290,8 +590,29 @@ impl Config {
             Err(_) => None,
         };
 
+        let s4_ordering = env::var("S4_ORDERING_POLICY")
+            .unwrap_or_else(|_| "deposits_first".into())
+            .parse()
+            .context("Invalid S4_ORDERING_POLICY")?;
+        let s4_max_deferral_s = env::var("S4_MAX_DEFERRAL_S")
+            .unwrap_or_else(|_| "3600".into())
+            .parse()
+            .context("Invalid S4_MAX_DEFERRAL_S")?;
+
+        let withdrawal_fee = WithdrawalFeeConfig::from_env()?;
         let watchtower = WatchtowerReporterConfig::from_env();
         let retention = RetentionConfig::from_env()?;
+        let leader_election =
+            LeaderElectionConfig::from_lookup(|key| env::var(key).ok(), &db_path)?;
+        let storage = StorageBackend::from_lookup(|key| env::var(key).ok())?;
+        let sqlite = SqliteConfig::from_lookup(|key| env::var(key).ok())?;
+        let backup = BackupConfig::from_lookup(|key| env::var(key).ok())?;
+        let retry = RetryPolicy::from_lookup(|key| env::var(key).ok())?;
+        if backup.is_some() && storage != StorageBackend::Sqlite {
+            anyhow::bail!(
+                "BRIDGE_BACKUP_DIR backs up the SQLite file at DB_PATH; back a Postgres database up with its own tooling"
+            );
+        }
 
         Ok(Self {
             network,
@@ -320,12 +641,375 @@ impl Config {
             ham_pressure_cooldown_max_ms,
             slow_call_threshold_ms,
             rave_max_links,
+            s4_ordering,
+            s4_max_deferral_s,
+            withdrawal_fee,
             watchtower,
             retention,
+            leader_election,
+            storage,
+            sqlite,
+            backup,
+            retry,
+        })
+    }
+}
+
+impl LeaderElectionConfig {
+    pub const DEFAULT_TTL_MS: u64 = 30_000;
+
+    /// `None` unless `LEADER_ELECTION_ENABLED` is truthy.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>, db_path: &str) -> Result<Option<Self>> {
+        let enabled = lookup("LEADER_ELECTION_ENABLED")
+            .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+
+        let ttl_ms = lookup("LEADER_ELECTION_TTL_MS")
+            .map(|v| {
+                v.trim()
+                    .parse::<u64>()
+                    .context("Invalid LEADER_ELECTION_TTL_MS")
+            })
+            .transpose()?
+            .unwrap_or(Self::DEFAULT_TTL_MS);
+        let renew_interval_ms = lookup("LEADER_ELECTION_RENEW_MS")
+            .map(|v| {
+                v.trim()
+                    .parse::<u64>()
+                    .context("Invalid LEADER_ELECTION_RENEW_MS")
+            })
+            .transpose()?
+            .unwrap_or(ttl_ms / 3);
+        if renew_interval_ms == 0 || renew_interval_ms * 2 > ttl_ms {
+            anyhow::bail!(
+                "LEADER_ELECTION_RENEW_MS must be between 1 and half of LEADER_ELECTION_TTL_MS ({}), got {}",
+                ttl_ms,
+                renew_interval_ms
+            );
+        }
+
+        let lease_path =
+            lookup("LEADER_ELECTION_LEASE_PATH").unwrap_or_else(|| format!("{}.leader", db_path));
+        if lease_path == db_path {
+            anyhow::bail!("LEADER_ELECTION_LEASE_PATH must not be DB_PATH itself");
+        }
+        let candidate_id = lookup("LEADER_ELECTION_ID")
+            .unwrap_or_else(|| format!("{}:{}", crate::lease::hostname(), std::process::id()));
+
+        Ok(Some(Self {
+            lease_path,
+            candidate_id,
+            ttl_ms,
+            renew_interval_ms,
+        }))
+    }
+}
+
+impl StorageBackend {
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let backend = lookup("DB_BACKEND").unwrap_or_else(|| "sqlite".into());
+        match backend.trim().to_lowercase().as_str() {
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            #[cfg(feature = "postgres")]
+            "postgres" => {
+                let url = lookup("DATABASE_URL")
+                    .filter(|url| !url.trim().is_empty())
+                    .context("DATABASE_URL is required with DB_BACKEND=postgres")?;
+                Ok(StorageBackend::Postgres { url })
+            }
+            #[cfg(not(feature = "postgres"))]
+            "postgres" => {
+                anyhow::bail!("DB_BACKEND=postgres needs a build with the `postgres` feature")
+            }
+            _ => anyhow::bail!("Unknown DB_BACKEND: {}", backend),
+        }
+    }
+}
+
+impl SqliteConfig {
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let defaults = Self::default();
+        let synchronous = lookup("DB_SYNCHRONOUS")
+            .map(|v| v.parse().context("Invalid DB_SYNCHRONOUS"))
+            .transpose()?
+            .unwrap_or(defaults.synchronous);
+        let busy_timeout_ms = lookup("DB_BUSY_TIMEOUT_MS")
+            .map(|v| {
+                v.trim()
+                    .parse::<u64>()
+                    .context("Invalid DB_BUSY_TIMEOUT_MS")
+            })
+            .transpose()?
+            .unwrap_or(defaults.busy_timeout_ms);
+        let checkpoint_interval_ms = match lookup("DB_CHECKPOINT_INTERVAL_MS") {
+            Some(v) => {
+                let ms = v
+                    .trim()
+                    .parse::<u64>()
+                    .context("Invalid DB_CHECKPOINT_INTERVAL_MS")?;
+                (ms > 0).then_some(ms)
+            }
+            None => defaults.checkpoint_interval_ms,
+        };
+        let startup_check = lookup("DB_STARTUP_CHECK")
+            .map(|v| v.parse().context("Invalid DB_STARTUP_CHECK"))
+            .transpose()?
+            .unwrap_or(defaults.startup_check);
+
+        Ok(Self {
+            synchronous,
+            busy_timeout_ms,
+            checkpoint_interval_ms,
+            startup_check,
+        })
+    }
+}
+
+impl BackupConfig {
+    /// Every six hours.
+    pub const DEFAULT_INTERVAL_MS: u64 = 6 * 60 * 60 * 1000;
+    /// A week of snapshots at the default interval.
+    pub const DEFAULT_KEEP: usize = 28;
+
+    /// `None` unless `BRIDGE_BACKUP_DIR` is set.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Option<Self>> {
+        let Some(dir) = lookup("BRIDGE_BACKUP_DIR").filter(|d| !d.trim().is_empty()) else {
+            return Ok(None);
+        };
+        let interval_ms = lookup("BRIDGE_BACKUP_INTERVAL_MS")
+            .map(|v| {
+                v.trim()
+                    .parse::<u64>()
+                    .context("Invalid BRIDGE_BACKUP_INTERVAL_MS")
+            })
+            .transpose()?
+            .unwrap_or(Self::DEFAULT_INTERVAL_MS);
+        if interval_ms == 0 {
+            anyhow::bail!("BRIDGE_BACKUP_INTERVAL_MS must be at least 1");
+        }
+        let keep = lookup("BRIDGE_BACKUP_KEEP")
+            .map(|v| {
+                v.trim()
+                    .parse::<usize>()
+                    .context("Invalid BRIDGE_BACKUP_KEEP")
+            })
+            .transpose()?
+            .unwrap_or(Self::DEFAULT_KEEP);
+        if keep == 0 {
+            anyhow::bail!("BRIDGE_BACKUP_KEEP must be at least 1");
+        }
+
+        Ok(Some(Self {
+            dir: PathBuf::from(dir.trim()),
+            interval_ms,
+            keep,
+        }))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Self::DEFAULT_MAX_ATTEMPTS,
+            base_delay_s: Self::DEFAULT_BASE_DELAY_S,
+            max_delay_s: Self::DEFAULT_MAX_DELAY_S,
+            jitter: Self::DEFAULT_JITTER,
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// What `work_items.max_attempts` has always defaulted to.
+    pub const DEFAULT_MAX_ATTEMPTS: i64 = 8;
+    /// No backoff unless asked for: a failed row is retried on the next
+    /// cycle, as it always was.
+    pub const DEFAULT_BASE_DELAY_S: u64 = 0;
+    pub const DEFAULT_MAX_DELAY_S: u64 = 6 * 60 * 60;
+    pub const DEFAULT_JITTER: f64 = 0.2;
+
+    /// The attempt budget of a row whose last failure was of `class`.
+    pub fn max_attempts(&self, class: ErrorClass) -> i64 {
+        self.overrides
+            .get(&class)
+            .and_then(|o| o.max_attempts)
+            .unwrap_or(self.max_attempts)
+    }
+
+    /// When a row that has now failed `attempts` times with `class` should
+    /// next be tried, or `None` once its budget is spent. The jitter is
+    /// drawn from the row's `id` and `attempts`, so it is reproducible and
+    /// still differs between the rows of one batch.
+    pub fn next_retry_at(
+        &self,
+        class: ErrorClass,
+        id: i64,
+        attempts: i64,
+        now: i64,
+    ) -> Option<i64> {
+        if attempts >= self.max_attempts(class) {
+            return None;
+        }
+        let o = self.overrides.get(&class);
+        let base = o.and_then(|o| o.base_delay_s).unwrap_or(self.base_delay_s);
+        let ceiling = o.and_then(|o| o.max_delay_s).unwrap_or(self.max_delay_s);
+        let doublings = attempts.saturating_sub(1).clamp(0, 32) as u32;
+        let delay = base.saturating_mul(1u64 << doublings).min(ceiling);
+        let unit = (splitmix64((id as u64) ^ ((attempts as u64) << 32)) >> 11) as f64
+            / (1u64 << 53) as f64;
+        let shaved = (delay as f64 * self.jitter * unit) as u64;
+        Some(now.saturating_add((delay - shaved.min(delay)) as i64))
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let defaults = Self::default();
+        let number = |key: &str| {
+            lookup(key)
+                .map(|v| {
+                    v.trim()
+                        .parse::<u64>()
+                        .with_context(|| format!("Invalid {}", key))
+                })
+                .transpose()
+        };
+        let max_attempts = number("RETRY_MAX_ATTEMPTS")?
+            .map(|n| n as i64)
+            .unwrap_or(defaults.max_attempts);
+        if max_attempts == 0 {
+            anyhow::bail!("RETRY_MAX_ATTEMPTS must be at least 1");
+        }
+        let base_delay_s = number("RETRY_BASE_DELAY_S")?.unwrap_or(defaults.base_delay_s);
+        let max_delay_s = number("RETRY_MAX_DELAY_S")?.unwrap_or(defaults.max_delay_s);
+        let jitter = lookup("RETRY_JITTER")
+            .map(|v| v.trim().parse::<f64>().context("Invalid RETRY_JITTER"))
+            .transpose()?
+            .unwrap_or(defaults.jitter);
+        if !(0.0..=1.0).contains(&jitter) {
+            anyhow::bail!("RETRY_JITTER must be between 0 and 1, got {}", jitter);
+        }
+        let overrides = match lookup("RETRY_CLASS_OVERRIDES") {
+            Some(raw) => parse_retry_overrides(&raw).context("Invalid RETRY_CLASS_OVERRIDES")?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(Self {
+            max_attempts,
+            base_delay_s,
+            max_delay_s,
+            jitter,
+            overrides,
         })
     }
 }
 
+/// `class:key=value,...;class:...` with keys `attempts`, `base_s` and
+/// `max_s`.
+fn parse_retry_overrides(raw: &str) -> Result<BTreeMap<ErrorClass, RetryOverride>> {
+    let mut overrides = BTreeMap::new();
+    for entry in raw.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (class, settings) = entry
+            .split_once(':')
+            .with_context(|| format!("expected `class:key=value`, got `{}`", entry))?;
+        let class = class.trim().to_lowercase();
+        let Ok(class) = class.parse::<ErrorClass>() else {
+            let known: Vec<_> = ErrorClass::value_variants()
+                .iter()
+                .map(ErrorClass::as_str)
+                .collect();
+            anyhow::bail!(
+                "unknown class `{}`, expected one of {}",
+                class,
+                known.join(", ")
+            );
+        };
+        let mut o = RetryOverride::default();
+        for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .with_context(|| format!("expected `key=value`, got `{}`", setting))?;
+            let value: u64 = value
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid number in `{}`", setting))?;
+            match key.trim() {
+                "attempts" if value > 0 => o.max_attempts = Some(value as i64),
+                "attempts" => anyhow::bail!("`{}` needs at least one attempt", class),
+                "base_s" => o.base_delay_s = Some(value),
+                "max_s" => o.max_delay_s = Some(value),
+                other => anyhow::bail!(
+                    "unknown setting `{}`, expected attempts, base_s or max_s",
+                    other
+                ),
+            }
+        }
+        overrides.insert(class, o);
+    }
+    Ok(overrides)
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl WithdrawalFeeConfig {
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    /// Parse the fee settings through `lookup`, so tests can supply
+    /// variables without touching the process environment. Only the
+    /// variables the selected model needs are required; HOT amounts are
+    /// decimal strings (`"0.5"`).
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let required = |key: &str| {
+            lookup(key)
+                .ok_or_else(|| anyhow::anyhow!("{} is required by WITHDRAWAL_FEE_MODEL", key))
+        };
+        let hot = |key: &str, raw: String| {
+            crate::signer::parse_amount(raw.trim()).with_context(|| format!("Invalid {}", key))
+        };
+
+        let raw_model = lookup("WITHDRAWAL_FEE_MODEL").unwrap_or_else(|| "none".into());
+        let model = match raw_model.trim().to_lowercase().replace('-', "_").as_str() {
+            "none" => WithdrawalFeeModel::None,
+            "flat" => WithdrawalFeeModel::Flat {
+                fee_wei: hot("WITHDRAWAL_FEE_FLAT", required("WITHDRAWAL_FEE_FLAT")?)?,
+            },
+            "percentage" => {
+                let bps: u32 = required("WITHDRAWAL_FEE_BPS")?
+                    .trim()
+                    .parse()
+                    .context("Invalid WITHDRAWAL_FEE_BPS")?;
+                if bps > 10_000 {
+                    anyhow::bail!("WITHDRAWAL_FEE_BPS must be at most 10000, got {}", bps);
+                }
+                WithdrawalFeeModel::Percentage { bps }
+            }
+            "gas_indexed" => WithdrawalFeeModel::GasIndexed {
+                gas_units: required("WITHDRAWAL_FEE_GAS_UNITS")?
+                    .trim()
+                    .parse()
+                    .context("Invalid WITHDRAWAL_FEE_GAS_UNITS")?,
+                hot_per_eth_wei: hot(
+                    "WITHDRAWAL_FEE_HOT_PER_ETH",
+                    required("WITHDRAWAL_FEE_HOT_PER_ETH")?,
+                )?,
+            },
+            _ => anyhow::bail!("Unknown WITHDRAWAL_FEE_MODEL: {}", raw_model),
+        };
+        let max_fee_wei = lookup("WITHDRAWAL_FEE_MAX")
+            .map(|raw| hot("WITHDRAWAL_FEE_MAX", raw))
+            .transpose()?;
+        Ok(Self { model, max_fee_wei })
+    }
+}
+
 impl RetentionConfig {
     /// How often the retention task wakes up. Hourly is plenty —
     /// rows only accumulate at the pace the bridge cycle terminates
@@ -340,23 +1024,29 @@ impl RetentionConfig {
     /// forensic — you want them around long enough to correlate with
     /// downstream incident reviews.
     pub const DEFAULT_FAILED_MAX_AGE_S: u64 = 30 * 24 * 60 * 60;
+    /// Small enough that one batch holds the writer mutex for a few
+    /// milliseconds.
+    pub const DEFAULT_BATCH_SIZE: usize = 500;
+    pub const DEFAULT_MAX_ROWS_PER_TICK: u64 = 50_000;
+    pub const DEFAULT_MAX_TICK_MS: u64 = 10_000;
 
     /// Read retention config from env. Infallible modulo malformed
     /// numbers; unset variables fall back to compact defaults above.
     pub fn from_env() -> Result<Self> {
-        let enabled = !env::var("BRIDGE_RETENTION_DISABLED")
-            .ok()
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let enabled = !lookup("BRIDGE_RETENTION_DISABLED")
             .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
             .unwrap_or(false);
 
-        let tick_interval_ms = env::var("BRIDGE_RETENTION_TICK_MS")
-            .ok()
+        let tick_interval_ms = lookup("BRIDGE_RETENTION_TICK_MS")
             .map(|v| v.parse::<u64>().context("Invalid BRIDGE_RETENTION_TICK_MS"))
             .transpose()?
             .unwrap_or(Self::DEFAULT_TICK_INTERVAL_MS);
 
-        let succeeded_max_age_s = env::var("BRIDGE_RETENTION_SUCCEEDED_MAX_AGE_S")
-            .ok()
+        let succeeded_max_age_s = lookup("BRIDGE_RETENTION_SUCCEEDED_MAX_AGE_S")
             .map(|v| {
                 v.parse::<u64>()
                     .context("Invalid BRIDGE_RETENTION_SUCCEEDED_MAX_AGE_S")
@@ -364,8 +1054,7 @@ impl RetentionConfig {
             .transpose()?
             .unwrap_or(Self::DEFAULT_SUCCEEDED_MAX_AGE_S);
 
-        let failed_max_age_s = env::var("BRIDGE_RETENTION_FAILED_MAX_AGE_S")
-            .ok()
+        let failed_max_age_s = lookup("BRIDGE_RETENTION_FAILED_MAX_AGE_S")
             .map(|v| {
                 v.parse::<u64>()
                     .context("Invalid BRIDGE_RETENTION_FAILED_MAX_AGE_S")
@@ -373,11 +1062,54 @@ impl RetentionConfig {
             .transpose()?
             .unwrap_or(Self::DEFAULT_FAILED_MAX_AGE_S);
 
+        let batch_size = lookup("BRIDGE_RETENTION_BATCH_SIZE")
+            .map(|v| {
+                v.parse::<usize>()
+                    .context("Invalid BRIDGE_RETENTION_BATCH_SIZE")
+            })
+            .transpose()?
+            .unwrap_or(Self::DEFAULT_BATCH_SIZE);
+        if batch_size == 0 {
+            anyhow::bail!("BRIDGE_RETENTION_BATCH_SIZE must be at least 1");
+        }
+        // `0` turns a budget off, like `RAVE_MAX_LINKS`.
+        let budget = |key: &str, default: u64| -> Result<Option<u64>> {
+            let value = lookup(key)
+                .map(|v| v.parse::<u64>().with_context(|| format!("Invalid {}", key)))
+                .transpose()?
+                .unwrap_or(default);
+            Ok((value > 0).then_some(value))
+        };
+        let max_rows_per_tick = budget(
+            "BRIDGE_RETENTION_MAX_ROWS_PER_TICK",
+            Self::DEFAULT_MAX_ROWS_PER_TICK,
+        )?;
+        let max_tick_ms = budget("BRIDGE_RETENTION_MAX_TICK_MS", Self::DEFAULT_MAX_TICK_MS)?;
+
+        let archive = match lookup("BRIDGE_RETENTION_ARCHIVE")
+            .filter(|v| !matches!(v.trim().to_ascii_lowercase().as_str(), "" | "none"))
+        {
+            Some(format) => Some(RetentionArchiveConfig {
+                format: format.parse().context("Invalid BRIDGE_RETENTION_ARCHIVE")?,
+                path: lookup("BRIDGE_RETENTION_ARCHIVE_PATH")
+                    .filter(|p| !p.trim().is_empty())
+                    .map(|p| PathBuf::from(p.trim()))
+                    .context(
+                        "BRIDGE_RETENTION_ARCHIVE_PATH is required by BRIDGE_RETENTION_ARCHIVE",
+                    )?,
+            }),
+            None => None,
+        };
+
         Ok(Self {
             enabled,
             tick_interval_ms,
             succeeded_max_age_s,
             failed_max_age_s,
+            batch_size,
+            max_rows_per_tick,
+            max_tick_ms,
+            archive,
         })
     }
 }
@@ -505,6 +1237,358 @@ mod tests {
         );
     }
 
+    fn leader_config(vars: &[(&str, &str)]) -> Result<Option<LeaderElectionConfig>> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        LeaderElectionConfig::from_lookup(|key| vars.get(key).cloned(), "/data/bridge.db")
+    }
+
+    #[test]
+    fn leader_election_is_off_unless_enabled_and_defaults_from_the_ttl() {
+        assert!(leader_config(&[]).unwrap().is_none());
+        assert!(leader_config(&[("LEADER_ELECTION_ENABLED", "false")])
+            .unwrap()
+            .is_none());
+
+        let cfg = leader_config(&[
+            ("LEADER_ELECTION_ENABLED", "true"),
+            ("LEADER_ELECTION_TTL_MS", "9000"),
+            ("LEADER_ELECTION_ID", "bridge-a"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(cfg.lease_path, "/data/bridge.db.leader");
+        assert_eq!(cfg.candidate_id, "bridge-a");
+        assert_eq!((cfg.ttl_ms, cfg.renew_interval_ms), (9000, 3000));
+    }
+
+    #[test]
+    fn leader_election_rejects_renewals_too_slow_for_the_ttl() {
+        for renew in ["0", "6000"] {
+            assert!(leader_config(&[
+                ("LEADER_ELECTION_ENABLED", "1"),
+                ("LEADER_ELECTION_TTL_MS", "10000"),
+                ("LEADER_ELECTION_RENEW_MS", renew),
+            ])
+            .is_err());
+        }
+        assert!(leader_config(&[
+            ("LEADER_ELECTION_ENABLED", "1"),
+            ("LEADER_ELECTION_LEASE_PATH", "/data/bridge.db"),
+        ])
+        .is_err());
+    }
+
+    fn retention_config(vars: &[(&str, &str)]) -> Result<RetentionConfig> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        RetentionConfig::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn retention_budgets_default_on_and_zero_turns_them_off() {
+        let cfg = retention_config(&[]).unwrap();
+        assert_eq!(cfg.batch_size, RetentionConfig::DEFAULT_BATCH_SIZE);
+        assert_eq!(
+            cfg.max_rows_per_tick,
+            Some(RetentionConfig::DEFAULT_MAX_ROWS_PER_TICK)
+        );
+        assert_eq!(cfg.max_tick_ms, Some(RetentionConfig::DEFAULT_MAX_TICK_MS));
+
+        let cfg = retention_config(&[
+            ("BRIDGE_RETENTION_MAX_ROWS_PER_TICK", "0"),
+            ("BRIDGE_RETENTION_MAX_TICK_MS", "0"),
+        ])
+        .unwrap();
+        assert_eq!(cfg.max_rows_per_tick, None);
+        assert_eq!(cfg.max_tick_ms, None);
+
+        assert!(retention_config(&[("BRIDGE_RETENTION_BATCH_SIZE", "0")]).is_err());
+    }
+
+    #[test]
+    fn retention_archive_is_off_by_default_and_needs_a_path() {
+        assert!(retention_config(&[]).unwrap().archive.is_none());
+        assert!(retention_config(&[("BRIDGE_RETENTION_ARCHIVE", "none")])
+            .unwrap()
+            .archive
+            .is_none());
+
+        let archive = retention_config(&[
+            ("BRIDGE_RETENTION_ARCHIVE", "jsonl-gz"),
+            ("BRIDGE_RETENTION_ARCHIVE_PATH", "/var/lib/bridge/archive"),
+        ])
+        .unwrap()
+        .archive
+        .unwrap();
+        assert_eq!(archive.format, ArchiveFormat::JsonlGz);
+        assert_eq!(archive.path, PathBuf::from("/var/lib/bridge/archive"));
+
+        assert!(retention_config(&[("BRIDGE_RETENTION_ARCHIVE", "sqlite")]).is_err());
+        assert!(retention_config(&[
+            ("BRIDGE_RETENTION_ARCHIVE", "parquet"),
+            ("BRIDGE_RETENTION_ARCHIVE_PATH", "/tmp/a"),
+        ])
+        .is_err());
+    }
+
+    fn sqlite_config(vars: &[(&str, &str)]) -> Result<SqliteConfig> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        SqliteConfig::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn sqlite_tuning_defaults_to_full_sync_and_a_quick_startup_check() {
+        let cfg = sqlite_config(&[]).unwrap();
+        assert_eq!(cfg.synchronous, SqliteSynchronous::Full);
+        assert_eq!(cfg.busy_timeout_ms, 5_000);
+        assert_eq!(cfg.checkpoint_interval_ms, Some(300_000));
+        assert_eq!(cfg.startup_check, IntegrityCheck::Quick);
+
+        let cfg = sqlite_config(&[
+            ("DB_SYNCHRONOUS", "NORMAL"),
+            ("DB_CHECKPOINT_INTERVAL_MS", "0"),
+            ("DB_STARTUP_CHECK", "full"),
+        ])
+        .unwrap();
+        assert_eq!(cfg.synchronous, SqliteSynchronous::Normal);
+        assert_eq!(cfg.checkpoint_interval_ms, None);
+        assert_eq!(cfg.startup_check, IntegrityCheck::Full);
+
+        assert!(sqlite_config(&[("DB_SYNCHRONOUS", "off")]).is_err());
+    }
+
+    fn storage_backend(vars: &[(&str, &str)]) -> Result<StorageBackend> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        StorageBackend::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn storage_defaults_to_sqlite_and_postgres_needs_the_feature_and_a_url() {
+        assert_eq!(storage_backend(&[]).unwrap(), StorageBackend::Sqlite);
+        assert_eq!(
+            storage_backend(&[("DB_BACKEND", "SQLite")]).unwrap(),
+            StorageBackend::Sqlite
+        );
+        assert!(storage_backend(&[("DB_BACKEND", "mysql")]).is_err());
+        assert!(storage_backend(&[("DB_BACKEND", "postgres")]).is_err());
+
+        #[cfg(feature = "postgres")]
+        assert_eq!(
+            storage_backend(&[
+                ("DB_BACKEND", "postgres"),
+                ("DATABASE_URL", "postgres://bridge@db/bridge"),
+            ])
+            .unwrap(),
+            StorageBackend::Postgres {
+                url: "postgres://bridge@db/bridge".to_string()
+            }
+        );
+    }
+
+    fn backup_config(vars: &[(&str, &str)]) -> Result<Option<BackupConfig>> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        BackupConfig::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn backups_are_off_without_a_directory_and_reject_keeping_none() {
+        assert!(backup_config(&[]).unwrap().is_none());
+
+        let cfg = backup_config(&[("BRIDGE_BACKUP_DIR", "/var/backups/bridge")])
+            .unwrap()
+            .unwrap();
+        assert_eq!(cfg.dir, PathBuf::from("/var/backups/bridge"));
+        assert_eq!(cfg.interval_ms, BackupConfig::DEFAULT_INTERVAL_MS);
+        assert_eq!(cfg.keep, BackupConfig::DEFAULT_KEEP);
+
+        assert!(backup_config(&[
+            ("BRIDGE_BACKUP_DIR", "/var/backups/bridge"),
+            ("BRIDGE_BACKUP_KEEP", "0"),
+        ])
+        .is_err());
+    }
+
+    fn retry_policy(vars: &[(&str, &str)]) -> Result<RetryPolicy> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        RetryPolicy::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn retry_backoff_doubles_to_the_ceiling_and_stops_at_max_attempts() {
+        let policy = retry_policy(&[
+            ("RETRY_BASE_DELAY_S", "60"),
+            ("RETRY_MAX_DELAY_S", "300"),
+            ("RETRY_JITTER", "0"),
+            ("RETRY_MAX_ATTEMPTS", "5"),
+        ])
+        .unwrap();
+        let waits: Vec<_> = (1..=5)
+            .map(|n| policy.next_retry_at(ErrorClass::Unclassified, 7, n, 1_000))
+            .collect();
+        assert_eq!(
+            waits,
+            vec![Some(1_060), Some(1_120), Some(1_240), Some(1_300), None]
+        );
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        let at: Vec<_> = (1..=20)
+            .map(|id| jittered.next_retry_at(ErrorClass::Rpc, id, 3, 0).unwrap())
+            .collect();
+        assert!(at.iter().all(|t| (120..=240).contains(t)), "{:?}", at);
+        assert!(at.iter().any(|t| *t != at[0]), "rows spread out: {:?}", at);
+        assert_eq!(
+            jittered.next_retry_at(ErrorClass::Rpc, 3, 3, 0),
+            jittered.next_retry_at(ErrorClass::Rpc, 3, 3, 0)
+        );
+    }
+
+    #[test]
+    fn retry_overrides_replace_the_defaults_for_one_class() {
+        let policy = retry_policy(&[
+            ("RETRY_JITTER", "0"),
+            (
+                "RETRY_CLASS_OVERRIDES",
+                "source_chain_pressure:base_s=600,max_s=3600,attempts=20; unclassified:attempts=2",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(policy.max_attempts(ErrorClass::SourceChainPressure), 20);
+        assert_eq!(policy.max_attempts(ErrorClass::Unclassified), 2);
+        assert_eq!(
+            policy.max_attempts(ErrorClass::Rpc),
+            RetryPolicy::DEFAULT_MAX_ATTEMPTS
+        );
+        assert_eq!(
+            policy.next_retry_at(ErrorClass::SourceChainPressure, 1, 1, 0),
+            Some(600)
+        );
+        assert_eq!(
+            policy.next_retry_at(ErrorClass::Unclassified, 1, 2, 0),
+            None
+        );
+        assert_eq!(
+            policy.next_retry_at(ErrorClass::Rpc, 1, 1, 0),
+            Some(RetryPolicy::DEFAULT_BASE_DELAY_S as i64)
+        );
+
+        assert_eq!(retry_policy(&[]).unwrap(), RetryPolicy::default());
+        // Backoff is opt-in: by default a failed row is due again straight
+        // away, and the next cycle picks it up.
+        assert_eq!(
+            RetryPolicy::default().next_retry_at(ErrorClass::Rpc, 1, 3, 500),
+            Some(500)
+        );
+        assert!(retry_policy(&[("RETRY_CLASS_OVERRIDES", "connection:attempts=3")]).is_err());
+        assert!(retry_policy(&[("RETRY_CLASS_OVERRIDES", "rpc:tries=3")]).is_err());
+        assert!(retry_policy(&[("RETRY_JITTER", "1.5")]).is_err());
+        assert!(retry_policy(&[("RETRY_MAX_ATTEMPTS", "0")]).is_err());
+    }
+
+    fn fee_config(vars: &[(&str, &str)]) -> Result<WithdrawalFeeConfig> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        WithdrawalFeeConfig::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn withdrawal_fee_defaults_to_none_and_parses_each_model() {
+        let wei = |n: u64| U256::from(n) * U256::from(10).pow(U256::from(15));
+
+        assert_eq!(fee_config(&[]).unwrap().model, WithdrawalFeeModel::None);
+        assert_eq!(
+            fee_config(&[
+                ("WITHDRAWAL_FEE_MODEL", "flat"),
+                ("WITHDRAWAL_FEE_FLAT", "0.5")
+            ])
+            .unwrap()
+            .model,
+            WithdrawalFeeModel::Flat { fee_wei: wei(500) }
+        );
+        assert_eq!(
+            fee_config(&[
+                ("WITHDRAWAL_FEE_MODEL", "percentage"),
+                ("WITHDRAWAL_FEE_BPS", "25")
+            ])
+            .unwrap()
+            .model,
+            WithdrawalFeeModel::Percentage { bps: 25 }
+        );
+        let gas = fee_config(&[
+            ("WITHDRAWAL_FEE_MODEL", "gas-indexed"),
+            ("WITHDRAWAL_FEE_GAS_UNITS", "120000"),
+            ("WITHDRAWAL_FEE_HOT_PER_ETH", "2"),
+            ("WITHDRAWAL_FEE_MAX", "0.25"),
+        ])
+        .unwrap();
+        assert_eq!(
+            gas.model,
+            WithdrawalFeeModel::GasIndexed {
+                gas_units: 120_000,
+                hot_per_eth_wei: wei(2_000)
+            }
+        );
+        assert_eq!(gas.max_fee_wei, Some(wei(250)));
+    }
+
+    #[test]
+    fn withdrawal_fee_rejects_incomplete_or_out_of_range_settings() {
+        let missing = fee_config(&[("WITHDRAWAL_FEE_MODEL", "flat")]).unwrap_err();
+        assert!(
+            missing.to_string().contains("WITHDRAWAL_FEE_FLAT"),
+            "{}",
+            missing
+        );
+        assert!(fee_config(&[
+            ("WITHDRAWAL_FEE_MODEL", "percentage"),
+            ("WITHDRAWAL_FEE_BPS", "10001")
+        ])
+        .is_err());
+        assert!(fee_config(&[("WITHDRAWAL_FEE_MODEL", "tiered")]).is_err());
+    }
+
+    #[test]
+    fn s4_ordering_policy_parses_snake_and_kebab_case() {
+        assert_eq!(
+            "deposits_first".parse::<S4OrderingPolicy>().unwrap(),
+            S4OrderingPolicy::DepositsFirst
+        );
+        assert_eq!(
+            "Withdrawals-First".parse::<S4OrderingPolicy>().unwrap(),
+            S4OrderingPolicy::WithdrawalsFirst
+        );
+        assert_eq!(
+            " oldest_first ".parse::<S4OrderingPolicy>().unwrap(),
+            S4OrderingPolicy::OldestFirst
+        );
+        assert_eq!(
+            "round-robin".parse::<S4OrderingPolicy>().unwrap(),
+            S4OrderingPolicy::RoundRobin
+        );
+        assert!("fifo".parse::<S4OrderingPolicy>().is_err());
+    }
+
     #[test]
     fn normalize_dna_b64_strips_leading_u() {
         assert_eq!(
@@ -0,0 +1,238 @@
+//! Health of the state database: the integrity check `run` performs
+//! before it starts, periodic WAL checkpoints, and `db doctor`.
+//!
+//! The database runs in WAL mode (see [`crate::state`]). SQLite
+//! checkpoints the WAL on its own once it passes 1000 pages, but a
+//! checkpoint cannot finish while a reader is inside the WAL, so with the
+//! reporter and the CLI reading alongside the writer it can keep growing.
+//! `run` therefore also checkpoints on a timer with `TRUNCATE`, which
+//! waits for readers and resets the file to zero bytes.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::config::{IntegrityCheck, SqliteConfig};
+use crate::migrations;
+use crate::state::{integrity_problems, SqliteStore};
+
+/// Problems quoted in the startup error; `db doctor` lists them all.
+const PROBLEMS_QUOTED: usize = 5;
+
+/// Refuse to start on a database that fails `mode`'s check. A bridge
+/// that keeps writing to a corrupt database can lose track of what it
+/// has already bridged.
+pub fn startup_check(store: &SqliteStore, mode: IntegrityCheck) -> Result<()> {
+    let full = match mode {
+        IntegrityCheck::Off => return Ok(()),
+        IntegrityCheck::Quick => false,
+        IntegrityCheck::Full => true,
+    };
+    let started = Instant::now();
+    let problems = store.integrity_problems(full)?;
+    if !problems.is_empty() {
+        bail!(
+            "state database failed {} ({} problem(s), first: {}); run `db doctor` for the full list and `db restore` to recover from a backup",
+            check_name(full),
+            problems.len(),
+            problems[..problems.len().min(PROBLEMS_QUOTED)].join("; ")
+        );
+    }
+    tracing::info!(
+        event = "state.integrity_ok",
+        check = check_name(full),
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "state database passed {}",
+        check_name(full)
+    );
+    Ok(())
+}
+
+/// Spawn the periodic WAL checkpoint. Returns the `JoinHandle` so callers
+/// can `drop(...)` it, like [`crate::retention::spawn`]. A failed
+/// checkpoint is logged and retried next tick.
+pub fn spawn_checkpoints(interval_ms: u64, db: SqliteStore) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tick = interval(Duration::from_millis(interval_ms));
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            let db = db.clone();
+            match tokio::task::spawn_blocking(move || db.checkpoint_wal()).await {
+                Ok(Ok(cp)) if cp.busy => tracing::info!(
+                    event = "state.wal_checkpoint_busy",
+                    wal_pages = cp.wal_pages,
+                    checkpointed_pages = cp.checkpointed_pages,
+                    "WAL checkpoint held up by a reader; will retry next tick"
+                ),
+                Ok(Ok(cp)) => tracing::debug!(
+                    event = "state.wal_checkpoint",
+                    wal_pages = cp.wal_pages,
+                    "checkpointed and truncated the WAL"
+                ),
+                Ok(Err(e)) => tracing::warn!(
+                    event = "state.wal_checkpoint_failed",
+                    error = %e,
+                    "WAL checkpoint failed; will retry next tick"
+                ),
+                Err(e) => tracing::warn!(
+                    event = "state.wal_checkpoint_join_error",
+                    error = %e,
+                    "WAL checkpoint join failed; will retry next tick"
+                ),
+            }
+        }
+    })
+}
+
+/// What `db doctor` prints.
+#[derive(Debug, Clone, Serialize)]
+pub struct DoctorReport {
+    pub path: PathBuf,
+    pub schema_version: i64,
+    pub journal_mode: String,
+    pub page_size: i64,
+    pub page_count: i64,
+    pub freelist_count: i64,
+    /// Share of pages on the freelist, in percent: the space `VACUUM`
+    /// would give back.
+    pub fragmentation_pct: f64,
+    pub db_bytes: u64,
+    /// Size of `<db>-wal`; 0 when there is none.
+    pub wal_bytes: u64,
+    /// `quick_check` or `integrity_check`.
+    pub check: &'static str,
+    /// Empty when the database passed.
+    pub problems: Vec<String>,
+}
+
+/// Inspect the database at `db_path` through a read-only connection of
+/// its own, so it is safe to run beside the daemon.
+pub fn doctor(db_path: &Path, tuning: &SqliteConfig, full: bool) -> Result<DoctorReport> {
+    if !db_path.exists() {
+        bail!("{} does not exist", db_path.display());
+    }
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("open {} read-only", db_path.display()))?;
+    conn.busy_timeout(Duration::from_millis(tuning.busy_timeout_ms))?;
+
+    let pragma = |name: &str| -> Result<i64> {
+        Ok(conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))?)
+    };
+    let page_count = pragma("page_count")?;
+    let freelist_count = pragma("freelist_count")?;
+    let fragmentation_pct = if page_count > 0 {
+        (freelist_count as f64 * 1000.0 / page_count as f64).round() / 10.0
+    } else {
+        0.0
+    };
+    let mut wal = db_path.as_os_str().to_owned();
+    wal.push("-wal");
+
+    Ok(DoctorReport {
+        path: db_path.to_path_buf(),
+        schema_version: migrations::current_version(&conn)?,
+        journal_mode: conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?,
+        page_size: pragma("page_size")?,
+        page_count,
+        freelist_count,
+        fragmentation_pct,
+        db_bytes: std::fs::metadata(db_path)?.len(),
+        wal_bytes: std::fs::metadata(PathBuf::from(wal))
+            .map(|m| m.len())
+            .unwrap_or(0),
+        check: check_name(full),
+        problems: integrity_problems(&conn, full)?,
+    })
+}
+
+fn check_name(full: bool) -> &'static str {
+    if full {
+        "integrity_check"
+    } else {
+        "quick_check"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StateStore;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn test_db_path(name: &str) -> String {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        format!("/tmp/bridge-orchestrator-doctor-{}-{}.db", name, ts)
+    }
+
+    #[test]
+    fn doctor_reports_a_wal_database_beside_a_live_writer() {
+        let path = test_db_path("report");
+        let db = SqliteStore::open(&path).unwrap();
+        db.set_checkpoint_u64("lock.last_processed_block", 1)
+            .unwrap();
+        startup_check(&db, IntegrityCheck::Full).unwrap();
+
+        let report = doctor(Path::new(&path), &SqliteConfig::default(), true).unwrap();
+        assert_eq!(report.journal_mode, "wal");
+        assert_eq!(report.schema_version, migrations::latest_version());
+        assert!(report.page_count > 0);
+        assert!(report.wal_bytes > 0, "the write is still in the WAL");
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        // A reader on an older snapshot holds the checkpoint up; it gives
+        // up at once rather than stall the writer behind it.
+        let reader = Connection::open(&path).unwrap();
+        reader
+            .execute_batch("BEGIN; SELECT COUNT(*) FROM work_items;")
+            .unwrap();
+        db.set_checkpoint_u64("lock.last_processed_block", 2)
+            .unwrap();
+        assert!(db.checkpoint_wal().unwrap().busy);
+        reader.execute_batch("COMMIT").unwrap();
+
+        let cp = db.checkpoint_wal().unwrap();
+        assert!(!cp.busy);
+        let report = doctor(Path::new(&path), &SqliteConfig::default(), false).unwrap();
+        assert_eq!(report.wal_bytes, 0, "TRUNCATE empties the WAL");
+    }
+
+    #[test]
+    fn startup_check_refuses_a_corrupt_database() {
+        let path = test_db_path("corrupt");
+        let db = SqliteStore::open(&path).unwrap();
+        db.checkpoint_wal().unwrap();
+        let (page_size, root): (i64, i64) = Connection::open(&path)
+            .unwrap()
+            .query_row(
+                "SELECT (SELECT page_size FROM pragma_page_size), rootpage
+                 FROM sqlite_master WHERE name = 'idx_work_item_events_work_item'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        drop(db);
+
+        // Scribble over an index page that opening the store never reads.
+        let mut bytes = std::fs::read(&path).unwrap();
+        let start = (root as usize - 1) * page_size as usize;
+        bytes[start..start + page_size as usize].fill(0xA5);
+        std::fs::write(&path, bytes).unwrap();
+
+        let db = SqliteStore::open(&path).unwrap();
+        startup_check(&db, IntegrityCheck::Off).unwrap();
+        let err = startup_check(&db, IntegrityCheck::Quick).unwrap_err();
+        assert!(err.to_string().contains("failed quick_check"), "{:#}", err);
+
+        let report = doctor(Path::new(&path), &SqliteConfig::default(), false).unwrap();
+        assert!(!report.problems.is_empty());
+    }
+}
@@ -0,0 +1,388 @@
+//! Optional leader election for an active/standby pair of orchestrators.
+//!
+//! Both instances run against the same `DB_PATH`, but only the leader
+//! writes to it: recording locks, the bridge cycle and retention all wait
+//! for leadership. The standby only reads, and keeps watching the vault for
+//! locks without recording them. Leadership is a
+//! time-limited lease in a [`LeaseBackend`]: the leader renews it every
+//! `renew_interval_ms`, and once it lapses for `ttl_ms` the standby takes
+//! it on its next renewal tick. Takeover is therefore bounded by
+//! `ttl_ms + renew_interval_ms`.
+//!
+//! The lease decides *who should* lead; the single-writer
+//! [`crate::lease::WriterLease`] still decides who *may* write. A new
+//! leader takes the writer lease before its first bridge cycle. A leader
+//! that stopped renewing but is still alive (wedged, or paused by the
+//! kernel) keeps that lease until its heartbeat has been stale for
+//! [`crate::lease::HEARTBEAT_EXPIRY`]; only then does its successor take
+//! it over.
+//!
+//! Each instance only trusts its lease until `ttl_ms` after the renewal
+//! that granted it, by its own clock, so a leader that can no longer reach
+//! the backend steps down by itself no later than a standby could take
+//! over.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use rusqlite::{params, Connection, TransactionBehavior};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::config::LeaderElectionConfig;
+
+/// The lease as a backend last recorded it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaseRecord {
+    pub holder: String,
+    /// Bumped every time the lease changes hands, so log lines from two
+    /// leaders can be told apart.
+    pub term: i64,
+    pub expires_at_ms: i64,
+}
+
+/// Where the leader lease lives. An implementation must make
+/// [`LeaseBackend::try_acquire`] atomic across every candidate sharing it.
+pub trait LeaseBackend: Send + Sync {
+    /// Take the lease for `candidate` until `now_ms + ttl_ms` if it is free,
+    /// expired, or already `candidate`'s. Returns the lease afterwards,
+    /// whoever holds it.
+    fn try_acquire(&self, candidate: &str, now_ms: i64, ttl_ms: i64) -> Result<LeaseRecord>;
+
+    /// Give the lease up early if `candidate` holds it, so the standby
+    /// does not have to wait out the TTL.
+    fn release(&self, candidate: &str) -> Result<()>;
+}
+
+/// Lease in a SQLite file shared by both instances on one host.
+pub struct SqliteLeaseBackend {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteLeaseBackend {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS leader_lease (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                holder TEXT NOT NULL,
+                term INTEGER NOT NULL,
+                expires_at_ms INTEGER NOT NULL
+            );",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl LeaseBackend for SqliteLeaseBackend {
+    fn try_acquire(&self, candidate: &str, now_ms: i64, ttl_ms: i64) -> Result<LeaseRecord> {
+        let mut conn = self.conn.lock().expect("lease mutex poisoned");
+        // IMMEDIATE takes the write lock up front, so two candidates can
+        // never both read "expired" and both claim it.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
+            "INSERT INTO leader_lease (id, holder, term, expires_at_ms)
+             VALUES (1, ?1, 1, ?2 + ?3)
+             ON CONFLICT(id) DO UPDATE SET
+                 term = CASE WHEN holder = excluded.holder THEN term ELSE term + 1 END,
+                 holder = excluded.holder,
+                 expires_at_ms = excluded.expires_at_ms
+             WHERE holder = excluded.holder OR expires_at_ms <= ?2",
+            params![candidate, now_ms, ttl_ms],
+        )?;
+        let record = tx.query_row(
+            "SELECT holder, term, expires_at_ms FROM leader_lease WHERE id = 1",
+            [],
+            |row| {
+                Ok(LeaseRecord {
+                    holder: row.get(0)?,
+                    term: row.get(1)?,
+                    expires_at_ms: row.get(2)?,
+                })
+            },
+        )?;
+        tx.commit()?;
+        Ok(record)
+    }
+
+    fn release(&self, candidate: &str) -> Result<()> {
+        let conn = self.conn.lock().expect("lease mutex poisoned");
+        conn.execute(
+            "UPDATE leader_lease SET expires_at_ms = 0 WHERE id = 1 AND holder = ?1",
+            [candidate],
+        )?;
+        Ok(())
+    }
+}
+
+/// In-process backend: candidates in one process share it. The reference
+/// behaviour a new backend should match, and the tests' stand-in.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryLeaseBackend {
+    record: Mutex<Option<LeaseRecord>>,
+}
+
+#[cfg(test)]
+impl LeaseBackend for MemoryLeaseBackend {
+    fn try_acquire(&self, candidate: &str, now_ms: i64, ttl_ms: i64) -> Result<LeaseRecord> {
+        let mut record = self.record.lock().expect("lease mutex poisoned");
+        let next = match record.take() {
+            Some(r) if r.holder == candidate => LeaseRecord {
+                expires_at_ms: now_ms + ttl_ms,
+                ..r
+            },
+            Some(r) if r.expires_at_ms > now_ms => r,
+            other => LeaseRecord {
+                holder: candidate.to_string(),
+                term: other.map_or(1, |r| r.term + 1),
+                expires_at_ms: now_ms + ttl_ms,
+            },
+        };
+        *record = Some(next.clone());
+        Ok(next)
+    }
+
+    fn release(&self, candidate: &str) -> Result<()> {
+        let mut record = self.record.lock().expect("lease mutex poisoned");
+        if let Some(r) = record.as_mut().filter(|r| r.holder == candidate) {
+            r.expires_at_ms = 0;
+        }
+        Ok(())
+    }
+}
+
+/// This instance's view of the election, shared between the renewal task
+/// and the cycle loop.
+struct Leadership {
+    candidate: String,
+    /// Local time (ms) until which this instance may act as leader; 0 when
+    /// it is standby.
+    valid_until_ms: AtomicI64,
+    term: AtomicI64,
+}
+
+impl Leadership {
+    fn new(candidate: String) -> Self {
+        Self {
+            candidate,
+            valid_until_ms: AtomicI64::new(0),
+            term: AtomicI64::new(0),
+        }
+    }
+
+    fn is_leader(&self) -> bool {
+        now_ms() < self.valid_until_ms.load(Ordering::SeqCst)
+    }
+
+    fn candidate(&self) -> &str {
+        &self.candidate
+    }
+
+    fn term(&self) -> i64 {
+        self.term.load(Ordering::SeqCst)
+    }
+
+    /// One renewal attempt started at `started_ms`. Returns whether this
+    /// instance holds the lease afterwards.
+    fn renew(&self, backend: &dyn LeaseBackend, started_ms: i64, ttl_ms: i64) -> Result<bool> {
+        let record = backend.try_acquire(&self.candidate, started_ms, ttl_ms)?;
+        if record.holder == self.candidate {
+            // Count from before the call, never from the backend's
+            // answer: a slow round-trip only shortens our tenure.
+            self.valid_until_ms
+                .store(started_ms + ttl_ms, Ordering::SeqCst);
+            self.term.store(record.term, Ordering::SeqCst);
+            Ok(true)
+        } else {
+            self.valid_until_ms.store(0, Ordering::SeqCst);
+            Ok(false)
+        }
+    }
+}
+
+/// A running campaign for the lease. Dropping it stops renewing and hands
+/// the lease back, so on a clean shutdown the standby takes over on its
+/// next tick instead of after the TTL.
+pub struct Candidacy {
+    leadership: Arc<Leadership>,
+    backend: Arc<dyn LeaseBackend>,
+    task: JoinHandle<()>,
+}
+
+impl Candidacy {
+    /// Start campaigning. Begins as standby and turns leader once a
+    /// renewal wins the lease.
+    pub fn spawn(cfg: LeaderElectionConfig, backend: Arc<dyn LeaseBackend>) -> Self {
+        let leadership = Arc::new(Leadership::new(cfg.candidate_id.clone()));
+        let task = tokio::spawn(campaign(cfg, backend.clone(), leadership.clone()));
+        Self {
+            leadership,
+            backend,
+            task,
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leadership.is_leader()
+    }
+
+    pub fn term(&self) -> i64 {
+        self.leadership.term()
+    }
+}
+
+impl Drop for Candidacy {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.leadership.valid_until_ms.store(0, Ordering::SeqCst);
+        if let Err(e) = self.backend.release(self.leadership.candidate()) {
+            tracing::warn!(
+                event = "bridge_orchestrator.leader.release_failed",
+                error = %e,
+                "failed to release the leader lease; the standby will wait out the TTL"
+            );
+        }
+    }
+}
+
+async fn campaign(
+    cfg: LeaderElectionConfig,
+    backend: Arc<dyn LeaseBackend>,
+    leadership: Arc<Leadership>,
+) {
+    let ttl_ms = cfg.ttl_ms as i64;
+    let mut tick = interval(Duration::from_millis(cfg.renew_interval_ms));
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut was_leader = false;
+    loop {
+        tick.tick().await;
+        let started_ms = now_ms();
+        let backend = backend.clone();
+        let attempt = leadership.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            attempt.renew(backend.as_ref(), started_ms, ttl_ms)
+        })
+        .await;
+        match result {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => tracing::warn!(
+                event = "bridge_orchestrator.leader.renew_failed",
+                error = %e,
+                "leader lease renewal failed; will retry next tick"
+            ),
+            Err(e) => tracing::warn!(
+                event = "bridge_orchestrator.leader.join_error",
+                error = %e,
+                "leader lease renewal join failed; will retry next tick"
+            ),
+        }
+        // Judged by the local deadline rather than the attempt, so a
+        // leader cut off from the backend reports stepping down when its
+        // tenure actually runs out.
+        let is_leader = leadership.is_leader();
+        if is_leader != was_leader {
+            if is_leader {
+                tracing::info!(
+                    event = "bridge_orchestrator.leader.acquired",
+                    candidate = %leadership.candidate(),
+                    term = leadership.term(),
+                    "leader lease acquired; taking over the bridge cycle"
+                );
+            } else {
+                tracing::warn!(
+                    event = "bridge_orchestrator.leader.lost",
+                    candidate = %leadership.candidate(),
+                    term = leadership.term(),
+                    "leader lease lost; standing by"
+                );
+            }
+            was_leader = is_leader;
+        }
+    }
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn test_lease_path(name: &str) -> String {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        format!("/tmp/bridge-orchestrator-leader-{}-{}.db", name, ts)
+    }
+
+    /// The election rules every backend must follow, driven on a fake clock.
+    fn exercise(backend: &dyn LeaseBackend) {
+        let a = backend.try_acquire("a", 1_000, 500).unwrap();
+        assert_eq!((a.holder.as_str(), a.term), ("a", 1));
+
+        // Held and live: the standby is refused, the leader renews.
+        assert_eq!(backend.try_acquire("b", 1_200, 500).unwrap().holder, "a");
+        let renewed = backend.try_acquire("a", 1_400, 500).unwrap();
+        assert_eq!((renewed.term, renewed.expires_at_ms), (1, 1_900));
+
+        // Lapsed: the standby takes over in a new term, and the old
+        // leader cannot take it back.
+        let b = backend.try_acquire("b", 1_900, 500).unwrap();
+        assert_eq!((b.holder.as_str(), b.term), ("b", 2));
+        assert_eq!(backend.try_acquire("a", 2_000, 500).unwrap().holder, "b");
+
+        // Released: free straight away.
+        backend.release("a").unwrap();
+        assert_eq!(backend.try_acquire("a", 2_100, 500).unwrap().holder, "b");
+        backend.release("b").unwrap();
+        let back = backend.try_acquire("a", 2_100, 500).unwrap();
+        assert_eq!((back.holder.as_str(), back.term), ("a", 3));
+    }
+
+    #[test]
+    fn sqlite_backend_hands_over_only_after_expiry_or_release() {
+        let path = test_lease_path("sqlite");
+        exercise(&SqliteLeaseBackend::open(&path).unwrap());
+    }
+
+    #[test]
+    fn sqlite_backend_is_shared_by_separate_connections() {
+        let path = test_lease_path("shared");
+        let a = SqliteLeaseBackend::open(&path).unwrap();
+        let b = SqliteLeaseBackend::open(&path).unwrap();
+        a.try_acquire("a", 1_000, 500).unwrap();
+        assert_eq!(b.try_acquire("b", 1_100, 500).unwrap().holder, "a");
+        assert_eq!(b.try_acquire("b", 1_500, 500).unwrap().holder, "b");
+    }
+
+    #[test]
+    fn memory_backend_follows_the_same_rules() {
+        exercise(&MemoryLeaseBackend::default());
+    }
+
+    #[test]
+    fn leadership_lapses_on_its_own_clock_when_renewals_stop() {
+        let backend = MemoryLeaseBackend::default();
+        let leadership = Leadership::new("a".into());
+        let now = now_ms();
+        assert!(leadership.renew(&backend, now, 60_000).unwrap());
+        assert!(leadership.is_leader());
+        assert_eq!(leadership.term(), 1);
+
+        let standby = Leadership::new("b".into());
+        assert!(!standby.renew(&backend, now, 60_000).unwrap());
+        assert!(!standby.is_leader());
+
+        // A grant that has already run out by the local clock.
+        assert!(leadership.renew(&backend, now - 120_000, 60_000).unwrap());
+        assert!(!leadership.is_leader());
+    }
+}
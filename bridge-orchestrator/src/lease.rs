@@ -0,0 +1,449 @@
+//! Single-writer lease on the state database.
+//!
+//! Two `run` processes against one `DB_PATH` would both claim and submit
+//! the same work items. Whoever writes to the pipeline first takes an
+//! exclusive OS lock on `<DB_PATH>.lock`; the kernel drops it however the
+//! process exits, so a crash never leaves a stale lock behind. The lock is
+//! taken before the database is opened, so migrations never run under a
+//! live daemon. Under leader election the lease follows leadership, and a
+//! standby makes no writes until it holds it.
+//!
+//! The lock file says nothing about who holds it, so the holder also
+//! records itself in the `writer_lease` row and refreshes its heartbeat
+//! every [`HEARTBEAT_INTERVAL`]. A process that is refused reads that row
+//! to name the holder's pid and host in its error.
+//!
+//! A process that is alive but hung keeps its lock, so a leader-elected
+//! standby goes by the heartbeat instead: once it is older than
+//! [`HEARTBEAT_EXPIRY`] the standby takes the lease over (see
+//! [`WriterLease::open_or_take_over`]). The row then names the new holder,
+//! and the old one stops heartbeating as soon as it notices.
+//!
+//! On Postgres there is no file to lock: the writer's own session takes an
+//! advisory lock instead (see [`crate::postgres`]), and the lease only
+//! keeps the row up to date.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::config::{Config, SqliteConfig, StorageBackend};
+use crate::migrations::MigrationReport;
+use crate::state::{read_writer_lease, SqliteStore, WriterLeaseRow};
+use crate::store::SharedStore;
+
+/// How often the holder refreshes `writer_lease.heartbeat_at`.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How stale the heartbeat must be, three missed refreshes, before a
+/// leader-elected standby takes the lease from a holder that still has it.
+pub const HEARTBEAT_EXPIRY: Duration = Duration::from_secs(30);
+
+/// The single-writer lease, held until dropped. Owns the store opened
+/// under it.
+pub struct WriterLease {
+    // Holding the handle is what holds the lock. `None` on Postgres, where
+    // the store's session holds it, and after a take-over until the old
+    // holder lets go of it.
+    lock_file: Arc<Mutex<Option<File>>>,
+    // The SQLite database, so the heartbeat can take the lock file back
+    // after a take-over.
+    db_path: Option<String>,
+    store: SharedStore,
+    migration: MigrationReport,
+    pid: u32,
+    taken_over: Arc<AtomicBool>,
+}
+
+impl WriterLease {
+    /// Take the lease on the configured backend and open it. Fails
+    /// straight away, naming the holder, if another process has it.
+    pub fn open(cfg: &Config) -> Result<Self> {
+        match &cfg.storage {
+            StorageBackend::Sqlite => Self::acquire(&cfg.db_path, &cfg.sqlite),
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres { url } => {
+                let (store, migration) = crate::postgres::PostgresStore::connect_writer(url)?;
+                Self::hold(None, None, Arc::new(store), migration)
+            }
+        }
+    }
+
+    /// [`Self::open`] for a leader-elected instance: if the holder's
+    /// heartbeat is older than `stale_after`, take the lease from it even
+    /// though it still holds the lock. On SQLite the lock file is taken
+    /// back once the old holder exits; on Postgres the old holder's session
+    /// is terminated, which needs the same role or `pg_signal_backend`.
+    pub fn open_or_take_over(cfg: &Config, stale_after: Duration) -> Result<Self> {
+        match &cfg.storage {
+            StorageBackend::Sqlite => {
+                Self::acquire_or_take_over(&cfg.db_path, &cfg.sqlite, stale_after)
+            }
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres { url } => {
+                let (store, migration) =
+                    crate::postgres::PostgresStore::take_over_writer(url, stale_after)?;
+                Self::hold(None, None, Arc::new(store), migration)
+            }
+        }
+    }
+
+    /// Take the lease on the configured backend if nobody holds it, or
+    /// `None` if somebody does. For the operator commands that may also run
+    /// next to a live daemon.
+    pub fn try_open(cfg: &Config) -> Result<Option<Self>> {
+        match &cfg.storage {
+            StorageBackend::Sqlite => match try_lock_database(&cfg.db_path)? {
+                Some(lock_file) => {
+                    Self::open_locked(lock_file, &cfg.db_path, &cfg.sqlite).map(Some)
+                }
+                None => Ok(None),
+            },
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres { url } => {
+                match crate::postgres::PostgresStore::try_connect_writer(url)? {
+                    Some((store, migration)) => {
+                        Self::hold(None, None, Arc::new(store), migration).map(Some)
+                    }
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Take the lease on the SQLite database at `db_path` and open it.
+    pub fn acquire(db_path: &str, tuning: &SqliteConfig) -> Result<Self> {
+        Self::open_locked(lock_database(db_path)?, db_path, tuning)
+    }
+
+    /// [`Self::acquire`], or take the lease over from a holder whose
+    /// heartbeat is older than `stale_after`.
+    pub fn acquire_or_take_over(
+        db_path: &str,
+        tuning: &SqliteConfig,
+        stale_after: Duration,
+    ) -> Result<Self> {
+        if let Some(lock_file) = try_lock_database(db_path)? {
+            return Self::open_locked(lock_file, db_path, tuning);
+        }
+        let holder = read_holder(Path::new(db_path));
+        if !heartbeat_expired(holder.as_ref(), stale_after) {
+            bail!(
+                "{} is held by another bridge-orchestrator ({}); stop it first",
+                db_path,
+                describe_lease_row(holder)
+            );
+        }
+        tracing::warn!(
+            event = "bridge_orchestrator.lease.taken_over",
+            holder = %describe_lease_row(holder),
+            "taking the writer lease from a holder whose heartbeat expired"
+        );
+        let (store, migration) = SqliteStore::open_with_report(db_path, tuning)?;
+        Self::hold(None, Some(db_path), Arc::new(store), migration)
+    }
+
+    fn open_locked(lock_file: File, db_path: &str, tuning: &SqliteConfig) -> Result<Self> {
+        let (store, migration) = SqliteStore::open_with_report(db_path, tuning)?;
+        Self::hold(Some(lock_file), Some(db_path), Arc::new(store), migration)
+    }
+
+    fn hold(
+        lock_file: Option<File>,
+        db_path: Option<&str>,
+        store: SharedStore,
+        migration: MigrationReport,
+    ) -> Result<Self> {
+        let pid = std::process::id();
+        store.record_writer_lease(pid, &hostname())?;
+        Ok(Self {
+            lock_file: Arc::new(Mutex::new(lock_file)),
+            db_path: db_path.map(str::to_string),
+            store,
+            migration,
+            pid,
+            taken_over: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn store(&self) -> &SharedStore {
+        &self.store
+    }
+
+    /// What opening the database under the lease migrated.
+    pub fn migration_report(&self) -> &MigrationReport {
+        &self.migration
+    }
+
+    /// Whether another process has taken the lease over since this one's
+    /// heartbeat expired. Once it has, this lease must not be written under.
+    pub fn taken_over(&self) -> bool {
+        self.taken_over.load(Ordering::SeqCst)
+    }
+
+    /// Keep the `writer_lease` heartbeat fresh for as long as the lease is
+    /// held, and take the lock file back once a holder this lease took
+    /// over from lets go of it. Runs detached like the retention task; a
+    /// failed tick is logged and retried on the next one. Stops for good if
+    /// the row names another holder.
+    pub fn spawn_heartbeat(&self) -> JoinHandle<()> {
+        let store = Arc::clone(&self.store);
+        let lock_file = Arc::clone(&self.lock_file);
+        let db_path = self.db_path.clone();
+        let taken_over = Arc::clone(&self.taken_over);
+        let pid = self.pid;
+        let host = hostname();
+        tokio::spawn(async move {
+            let mut tick = interval(HEARTBEAT_INTERVAL);
+            tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tick.tick().await;
+                let store = Arc::clone(&store);
+                let host = host.clone();
+                match tokio::task::spawn_blocking(move || store.refresh_writer_lease(pid, &host))
+                    .await
+                {
+                    Ok(Ok(true)) => {}
+                    Ok(Ok(false)) => {
+                        taken_over.store(true, Ordering::SeqCst);
+                        tracing::error!(
+                            event = "bridge_orchestrator.lease.lost",
+                            "another bridge-orchestrator took the writer lease over; stopping the heartbeat"
+                        );
+                        return;
+                    }
+                    Ok(Err(e)) => tracing::warn!(
+                        event = "bridge_orchestrator.lease.heartbeat_failed",
+                        error = %e,
+                        "writer lease heartbeat failed; will retry next tick"
+                    ),
+                    Err(e) => tracing::warn!(
+                        event = "bridge_orchestrator.lease.join_error",
+                        error = %e,
+                        "writer lease heartbeat join failed; will retry next tick"
+                    ),
+                }
+                if let Some(db_path) = &db_path {
+                    if let Err(e) = reclaim_lock_file(&lock_file, db_path) {
+                        tracing::warn!(
+                            event = "bridge_orchestrator.lease.relock_failed",
+                            error = %e,
+                            "could not take the lease lock file back; will retry next tick"
+                        );
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl Drop for WriterLease {
+    fn drop(&mut self) {
+        // The lock goes with the handle (or the session); clearing the row
+        // keeps it from naming a process that has exited.
+        if let Err(e) = self.store.release_writer_lease(self.pid) {
+            tracing::warn!(
+                event = "bridge_orchestrator.lease.release_failed",
+                error = %e,
+                "failed to clear the writer lease row"
+            );
+        }
+    }
+}
+
+/// Take only the OS lock on `<db_path>.lock`, without opening the
+/// database. For `db restore`, which must work on a file that no longer
+/// opens. The lock is held until the returned handle is dropped.
+pub fn lock_database(db_path: &str) -> Result<File> {
+    match try_lock_database(db_path)? {
+        Some(lock_file) => Ok(lock_file),
+        None => bail!(
+            "{} is held by another bridge-orchestrator ({}); stop it first",
+            db_path,
+            describe_holder(db_path)
+        ),
+    }
+}
+
+/// [`lock_database`], or `None` while another process holds the lock.
+fn try_lock_database(db_path: &str) -> Result<Option<File>> {
+    let lock_path = lock_path(db_path);
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("open lease lock file {}", lock_path.display()))?;
+    match lock_file.try_lock() {
+        Ok(()) => Ok(Some(lock_file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("lock {}", lock_path.display()))
+        }
+    }
+}
+
+/// Take the lock file if this lease does not hold it yet, as after a
+/// take-over. Returns whether it is held now.
+fn reclaim_lock_file(lock_file: &Mutex<Option<File>>, db_path: &str) -> Result<bool> {
+    let mut held = lock_file.lock().expect("lease mutex poisoned");
+    if held.is_none() {
+        *held = try_lock_database(db_path)?;
+    }
+    Ok(held.is_some())
+}
+
+/// Whether the holder's heartbeat is older than `stale_after`. A holder
+/// that has not recorded itself yet has only just taken the lock.
+pub(crate) fn heartbeat_expired(row: Option<&WriterLeaseRow>, stale_after: Duration) -> bool {
+    row.is_some_and(|row| {
+        let age = chrono::Utc::now().timestamp() - row.heartbeat_at;
+        age > stale_after.as_secs() as i64
+    })
+}
+
+fn lock_path(db_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.lock", db_path))
+}
+
+fn describe_holder(db_path: &str) -> String {
+    describe_lease_row(read_holder(Path::new(db_path)))
+}
+
+/// "pid 123 on host, heartbeat 4s ago", or as much of it as is known.
+pub fn describe_lease_row(row: Option<WriterLeaseRow>) -> String {
+    match row {
+        Some(row) => {
+            let now = chrono::Utc::now().timestamp();
+            format!(
+                "pid {} on {}, heartbeat {}s ago",
+                row.pid,
+                row.host,
+                (now - row.heartbeat_at).max(0)
+            )
+        }
+        None => "holder not recorded yet".to_string(),
+    }
+}
+
+fn read_holder(db_path: &Path) -> Option<WriterLeaseRow> {
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY).ok()?;
+    // A database from before the lease existed has no `writer_lease` table.
+    read_writer_lease(&conn).ok().flatten()
+}
+
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown host".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StateStore;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn test_db_path(name: &str) -> String {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        format!("/tmp/bridge-orchestrator-lease-{}-{}.db", name, ts)
+    }
+
+    fn sqlite(lease: &WriterLease) -> &SqliteStore {
+        lease.store().as_sqlite().unwrap()
+    }
+
+    #[test]
+    fn a_second_writer_is_refused_with_the_holders_pid_and_host() {
+        let path = test_db_path("exclusive");
+        let lease = WriterLease::acquire(&path, &SqliteConfig::default()).unwrap();
+        let holder = sqlite(&lease).writer_lease().unwrap().unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        assert_eq!(holder.host, hostname());
+
+        let err = WriterLease::acquire(&path, &SqliteConfig::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains(&format!("pid {} on {}", holder.pid, holder.host)),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn dropping_the_lease_releases_it() {
+        let path = test_db_path("release");
+        let lease = WriterLease::acquire(&path, &SqliteConfig::default()).unwrap();
+        let store = sqlite(&lease).clone();
+        drop(lease);
+        assert!(store.writer_lease().unwrap().is_none());
+
+        let again = WriterLease::acquire(&path, &SqliteConfig::default()).unwrap();
+        assert!(sqlite(&again).writer_lease().unwrap().is_some());
+    }
+
+    #[test]
+    fn a_holder_that_stops_heartbeating_is_taken_over_while_still_alive() {
+        let path = test_db_path("take-over");
+        SqliteStore::open(&path)
+            .unwrap()
+            .record_writer_lease(4242, "node-a")
+            .unwrap();
+        // The old leader: hung, still holding the lock, no longer heartbeating.
+        let hung = lock_database(&path).unwrap();
+        let tuning = SqliteConfig::default();
+
+        let err = WriterLease::acquire_or_take_over(&path, &tuning, HEARTBEAT_EXPIRY)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("pid 4242 on node-a"), "{}", err);
+
+        Connection::open(&path)
+            .unwrap()
+            .execute(
+                "UPDATE writer_lease SET heartbeat_at = heartbeat_at - 60",
+                [],
+            )
+            .unwrap();
+        let lease = WriterLease::acquire_or_take_over(&path, &tuning, HEARTBEAT_EXPIRY).unwrap();
+        let holder = sqlite(&lease).writer_lease().unwrap().unwrap();
+        assert_eq!((holder.pid, holder.host), (std::process::id(), hostname()));
+        assert!(WriterLease::acquire(&path, &tuning).is_err());
+
+        // When the old leader wakes, its heartbeat finds the row taken.
+        assert!(!lease.store().refresh_writer_lease(4242, "node-a").unwrap());
+        assert!(lease
+            .store()
+            .refresh_writer_lease(lease.pid, &hostname())
+            .unwrap());
+
+        assert!(!reclaim_lock_file(&lease.lock_file, &path).unwrap());
+        drop(hung);
+        assert!(reclaim_lock_file(&lease.lock_file, &path).unwrap());
+    }
+
+    #[test]
+    fn try_lock_yields_to_a_live_holder() {
+        let path = test_db_path("try-lock");
+        let lease = WriterLease::acquire(&path, &SqliteConfig::default()).unwrap();
+        assert!(try_lock_database(&path).unwrap().is_none());
+        drop(lease);
+        assert!(try_lock_database(&path).unwrap().is_some());
+    }
+}
@@ -1,14 +1,14 @@
 use crate::config::Config;
-use crate::state::StateStore;
-use alloy::primitives::U256;
+use crate::payload::{format_wei_as_hot, lock_load_failure_class, LockPayload, WorkPayload};
+use crate::store::SharedStore;
 use alloy::providers::{Provider, ProviderBuilder, RootProvider};
 use alloy::rpc::types::{BlockTransactionsKind, Filter, Log};
 use alloy::sol;
 use alloy::sol_types::SolEvent;
 use alloy::transports::http::{Client, Http};
 use anyhow::{Context, Result};
-use serde_json::json;
-use tracing::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{debug, error, info};
 
 sol! {
     #[derive(Debug)]
@@ -21,16 +21,33 @@ sol! {
 }
 
 const MAX_BLOCK_RANGE: u64 = 10;
-const LOCK_CHECKPOINT_KEY: &str = "lock.last_processed_block";
+pub const LOCK_CHECKPOINT_KEY: &str = "lock.last_processed_block";
 
 pub struct LockFlow {
     cfg: Config,
-    db: StateStore,
+    db: SharedStore,
+    /// Last block [`LockFlow::watch`] scanned, so a standby does not rescan
+    /// what it already saw while the leader's checkpoint catches up.
+    watched: AtomicU64,
+}
+
+/// Current gas price (wei) from the Ethereum RPC, for the `gas_indexed`
+/// withdrawal fee model. Read once per bridge cycle.
+pub async fn current_gas_price_wei(rpc_url: &str) -> Result<u128> {
+    let provider = ProviderBuilder::new().on_http(rpc_url.parse()?);
+    provider
+        .get_gas_price()
+        .await
+        .context("eth_gasPrice failed")
 }
 
 impl LockFlow {
-    pub fn new(cfg: Config, db: StateStore) -> Self {
-        Self { cfg, db }
+    pub fn new(cfg: Config, db: SharedStore) -> Self {
+        Self {
+            cfg,
+            db,
+            watched: AtomicU64::new(0),
+        }
     }
 
     pub async fn run_cycle(&self) -> Result<()> {
@@ -45,12 +62,7 @@ impl LockFlow {
         let mut cursor = from_block.take().unwrap_or(current_block) + 1;
         while cursor <= current_block {
             let end = (cursor + MAX_BLOCK_RANGE - 1).min(current_block);
-            let filter = Filter::new()
-                .address(self.cfg.lock_vault_address)
-                .event_signature(Lock::SIGNATURE_HASH)
-                .from_block(cursor)
-                .to_block(end);
-            let logs = provider.get_logs(&filter).await?;
+            let logs = provider.get_logs(&self.lock_filter(cursor, end)).await?;
             for log in logs {
                 self.process_lock_log(&provider, log).await?;
             }
@@ -62,6 +74,42 @@ impl LockFlow {
         Ok(())
     }
 
+    /// A standby's cycle: scan the blocks past the leader's checkpoint like
+    /// [`Self::run_cycle`], but write nothing. The leader records the locks;
+    /// this keeps the standby's RPC connection and view of the vault warm,
+    /// and an error here is an early sign it could not take over.
+    pub async fn watch(&self) -> Result<()> {
+        let provider = self.provider()?;
+        let current_block = provider.get_block_number().await?;
+        let Some(checkpoint) = self.db.get_checkpoint_u64(LOCK_CHECKPOINT_KEY)? else {
+            return Ok(());
+        };
+        let mut cursor = checkpoint.max(self.watched.load(Ordering::Relaxed)) + 1;
+        let mut seen = 0;
+        while cursor <= current_block {
+            let end = (cursor + MAX_BLOCK_RANGE - 1).min(current_block);
+            seen += provider
+                .get_logs(&self.lock_filter(cursor, end))
+                .await?
+                .len();
+            self.watched.store(end, Ordering::Relaxed);
+            cursor = end + 1;
+        }
+        debug!(
+            "[lock-flow] standby: watched to block {} (leader checkpoint {}), {} lock(s) seen",
+            current_block, checkpoint, seen
+        );
+        Ok(())
+    }
+
+    fn lock_filter(&self, from_block: u64, to_block: u64) -> Filter {
+        Filter::new()
+            .address(self.cfg.lock_vault_address)
+            .event_signature(Lock::SIGNATURE_HASH)
+            .from_block(from_block)
+            .to_block(to_block)
+    }
+
     fn provider(&self) -> Result<RootProvider<Http<Client>>> {
         Ok(ProviderBuilder::new().on_http(self.cfg.rpc_url.parse()?))
     }
@@ -83,39 +131,24 @@ impl LockFlow {
             .await?
             .context("Block not found")?;
         let data = decoded.inner.data;
-        let amount_wei = data.amount.to_string();
-        let amount_hot = format_amount(&amount_wei);
-        let sender = format!("{:?}", data.sender);
-        let holochain_agent = format!("0x{}", hex::encode(data.holochainAgent));
-        let tx_hash_hex = format!("0x{}", hex::encode(tx_hash));
         let item_id = format!("lock:{}", data.lockId);
         let idempotency_key = format!("lock:{}:create_parked_link", data.lockId);
-        let payload = json!({
-            "lock_id": data.lockId.to_string(),
-            "sender": sender,
-            "amount": amount_wei,
-            "amount_raw_wei": amount_wei,
-            "amount_hot": amount_hot,
-            "holochain_agent": holochain_agent,
-            "tx_hash": tx_hash_hex,
-            "block_number": block_number,
-            "timestamp": block.header.timestamp,
-            "required_confirmations": self.cfg.confirmations,
-        });
-        self.db.enqueue_detected(
-            "lock",
-            "create_parked_link",
-            &item_id,
-            &idempotency_key,
-            &payload,
-        )?;
+        let payload = LockPayload {
+            lock_id: data.lockId.to_string(),
+            sender: format!("{:?}", data.sender),
+            amount_raw_wei: Some(data.amount.to_string()),
+            amount_hot: format_wei_as_hot(data.amount),
+            holochain_agent: format!("0x{}", hex::encode(data.holochainAgent)),
+            tx_hash: format!("0x{}", hex::encode(tx_hash)),
+            block_number,
+            timestamp: block.header.timestamp,
+            required_confirmations: self.cfg.confirmations,
+        };
+        self.db
+            .enqueue_detected(&item_id, &idempotency_key, &payload.clone().into())?;
         info!(
             "[lock-flow] lock detected id={} amount={} agent={} tx={} block={}",
-            item_id,
-            payload["amount_hot"].as_str().unwrap_or("0"),
-            payload["holochain_agent"].as_str().unwrap_or("unknown"),
-            payload["tx_hash"].as_str().unwrap_or("unknown"),
-            block_number
+            item_id, payload.amount_hot, payload.holochain_agent, payload.tx_hash, block_number
         );
         Ok(())
     }
@@ -125,55 +158,33 @@ impl LockFlow {
             self.db
                 .list_work_items("lock", crate::state::WorkState::Detected, 5000)?;
         for item in candidates {
-            let payload = item.payload_json;
-            let block_number = payload
-                .get("block_number")
-                .and_then(|v| v.as_u64())
-                .unwrap_or_default();
-            let confirmations = current_block.saturating_sub(block_number);
-            if confirmations >= self.cfg.confirmations {
-                let idempotency_key = format!(
-                    "lock:{}:create_parked_link",
-                    payload
-                        .get("lock_id")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("unknown")
-                );
-                if self.db.move_detected_to_queued(&idempotency_key)? {
-                    let amount = payload
-                        .get("amount_hot")
-                        .and_then(|v| v.as_str())
-                        .map(str::to_string)
-                        .or_else(|| {
-                            payload
-                                .get("amount_raw_wei")
-                                .and_then(|v| v.as_str())
-                                .map(format_amount)
-                        })
-                        .unwrap_or_else(|| "0".to_string());
-                    let agent = payload
-                        .get("holochain_agent")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("unknown");
-                    info!(
-                        "[lock-flow] lock queued id={} confirmations={} amount={} agent={}",
-                        item.item_id, confirmations, amount, agent
+            // Rows are validated on insert; only one an older binary wrote
+            // can fail here, and waiting will not fix it.
+            let payload = match item.payload().and_then(WorkPayload::into_lock) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!(
+                        "[lock-flow] lock {} has an unreadable payload, abandoning it: {:#}",
+                        item.item_id, e
                     );
+                    self.db.mark_failed_permanent(
+                        item.id,
+                        lock_load_failure_class(&item.task_type, &item.payload_json),
+                        &format!("invalid payload: {e:#}"),
+                    )?;
+                    continue;
                 }
+            };
+            let confirmations = current_block.saturating_sub(payload.block_number);
+            if confirmations >= self.cfg.confirmations
+                && self.db.move_detected_to_queued(&item.idempotency_key)?
+            {
+                info!(
+                    "[lock-flow] lock queued id={} confirmations={} amount={} agent={}",
+                    item.item_id, confirmations, payload.amount_hot, payload.holochain_agent
+                );
             }
         }
         Ok(())
     }
 }
-
-pub fn format_amount(amount: &str) -> String {
-    let amount: U256 = amount.parse().unwrap_or_default();
-    let decimals = U256::from(10).pow(U256::from(18));
-    let whole = amount / decimals;
-    let frac = (amount % decimals) / U256::from(10).pow(U256::from(12));
-    if frac.is_zero() {
-        whole.to_string()
-    } else {
-        format!("{}.{:06}", whole, frac)
-    }
-}
@@ -1,16 +1,34 @@
+mod backup;
 mod config;
+mod doctor;
+mod leader;
+mod lease;
 mod lock_flow;
+mod migrations;
 mod orchestrator;
+mod output;
+mod payload;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod refund;
+mod report;
 mod retention;
 mod signer;
 mod state;
+mod store;
 mod watchtower_reporter;
+mod withdrawal;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use config::Config;
+use config::{Config, StorageBackend};
+use lease::WriterLease;
 use orchestrator::BridgeOrchestrator;
-use state::{StateFilter, WorkState};
+use output::{OutputFormat, Printer};
+use state::{
+    parse_timestamp, ErrorClass, Lookup, RefundStatus, RequeueOptions, StateFilter, StatusCursor,
+    StatusSort, WithdrawStep, WorkState, WorkStep,
+};
 use tracing::info;
 use tracing_subscriber::EnvFilter;
 
@@ -19,24 +37,19 @@ use tracing_subscriber::EnvFilter;
 struct Args {
     #[command(subcommand)]
     command: Command,
+    /// How subcommands print their results: `json` lines for scripts, an
+    /// aligned `table` for people, or `csv` for spreadsheets.
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Json)]
+    output: OutputFormat,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
     /// Run lock detection and periodic bridge cycle with single-writer execution.
     Run,
-    /// Inspect orchestrator state from SQLite.
-    Status {
-        #[arg(long)]
-        flow: Option<String>,
-        #[arg(long)]
-        state: Option<WorkState>,
-        #[arg(long)]
-        item_id: Option<String>,
-        #[arg(long, default_value_t = 50)]
-        limit: usize,
-    },
-    /// Clear orchestrator work items from SQLite.
+    /// Inspect orchestrator state.
+    Status(Box<StatusArgs>),
+    /// Clear orchestrator work items.
     Clear {
         /// Delete only rows that are not in progress (succeeded, failed).
         #[arg(long, conflicts_with = "all", required_unless_present = "all")]
@@ -55,6 +68,263 @@ enum Command {
         #[arg(long, conflicts_with = "all")]
         older_than_s: Option<u64>,
     },
+    /// List quarantined withdrawal parked spends from the rejection ledger.
+    Rejections {
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Show the recorded state/step transitions of one work item, oldest
+    /// first.
+    History {
+        /// `item_id` as shown by `status` (e.g. `lock:42`).
+        item_id: String,
+    },
+    /// Find every lock and withdrawal for a wallet, agent or transaction,
+    /// newest first.
+    Lookup {
+        /// A 0x Ethereum address, a `uhCAk…` agent key, or a 0x lock
+        /// transaction hash / agent key in hex.
+        #[arg(value_parser = Lookup::parse)]
+        identifier: Lookup,
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// The aggregate counters watchtower receives, including p50/p90/p99
+    /// durations of each lock pipeline stage over the last hour and 24h.
+    Stats,
+    /// Daily bridged volume, counts, fees and latencies for accounting,
+    /// from the state database and the retention archive.
+    Report {
+        /// First UTC day to report (a date, RFC 3339 time or unix seconds).
+        #[arg(long, value_parser = parse_timestamp)]
+        from: i64,
+        /// Last UTC day to report, inclusive. Defaults to today.
+        #[arg(long, value_parser = parse_timestamp)]
+        to: Option<i64>,
+    },
+    /// Move a failed work item back to `queued` so the next cycle retries
+    /// it.
+    Requeue {
+        /// `item_id` as shown by `status` (e.g. `lock:42`).
+        item_id: String,
+        /// Give the item a fresh retry budget.
+        #[arg(long)]
+        reset_attempts: bool,
+        /// Lock items only: resume at this step instead of the one the item
+        /// stopped at.
+        #[arg(long)]
+        step: Option<WorkStep>,
+    },
+    /// Terminally fail a work item so the daemon stops retrying it.
+    Fail {
+        /// `item_id` as shown by `status` (e.g. `lock:42`).
+        item_id: String,
+        /// Why, recorded as the item's `last_error` and in its history.
+        #[arg(long)]
+        reason: String,
+    },
+    /// Add a note to a work item's history without changing it.
+    Annotate {
+        /// `item_id` as shown by `status` (e.g. `lock:42`).
+        item_id: String,
+        #[arg(long)]
+        note: String,
+    },
+    /// Failed locks, which retention keeps until they are replayed.
+    Dlq {
+        #[command(subcommand)]
+        command: DlqCommand,
+    },
+    /// Refunds of locks that can never be bridged: an oversize proof or
+    /// an agent key that does not decode.
+    Refund {
+        #[command(subcommand)]
+        command: RefundCommand,
+    },
+    /// Database maintenance.
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DlqCommand {
+    /// List failed locks, most recently failed first, with where a replay
+    /// would re-enter each.
+    List {
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Re-validate a failed lock's payload and re-enter it at the step it
+    /// reached, with a fresh retry budget. The daemon must be stopped.
+    Replay {
+        /// `item_id` as shown by `dlq list` (e.g. `lock:42`).
+        item_id: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RefundCommand {
+    /// List refunds, most recently updated first, with the sender and
+    /// amount each pays and its coupon once signed.
+    List {
+        #[arg(long, value_enum)]
+        status: Option<RefundStatus>,
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Approve a refund and sign its coupon to the lock's sender. Run it
+    /// again to retry a coupon that failed to sign. The daemon must be
+    /// stopped.
+    Approve {
+        /// `item_id` as shown by `refund list` (e.g. `lock:42`).
+        item_id: String,
+    },
+    /// Record the claim transaction an operator reports for the refund
+    /// coupon, as `operator_reported`; it is not checked on-chain. The
+    /// daemon must be stopped.
+    Complete {
+        /// `item_id` as shown by `refund list` (e.g. `lock:42`).
+        item_id: String,
+        /// Ethereum transaction that claimed the coupon.
+        #[arg(long)]
+        tx: String,
+    },
+}
+
+/// Times are unix seconds, RFC 3339 (`2026-01-31T12:00:00Z`) or a UTC date
+/// (`2026-01-31`).
+#[derive(clap::Args, Debug)]
+struct StatusArgs {
+    #[arg(long)]
+    flow: Option<String>,
+    #[arg(long)]
+    state: Option<WorkState>,
+    #[arg(long)]
+    item_id: Option<String>,
+    /// Pipeline step, lock (`new` .. `br_rave_executed`) or withdrawal
+    /// (`seen` .. `claimed`).
+    #[arg(long, value_parser = parse_step)]
+    step: Option<String>,
+    /// Created at or after this time.
+    #[arg(long, value_parser = parse_timestamp)]
+    created_after: Option<i64>,
+    /// Created before this time.
+    #[arg(long, value_parser = parse_timestamp)]
+    created_before: Option<i64>,
+    /// Last updated at or after this time.
+    #[arg(long, value_parser = parse_timestamp)]
+    updated_after: Option<i64>,
+    /// Last updated before this time.
+    #[arg(long, value_parser = parse_timestamp)]
+    updated_before: Option<i64>,
+    /// Ethereum address that locked the tokens.
+    #[arg(long)]
+    sender: Option<String>,
+    /// Holochain agent a lock credits, or that parked a withdrawal.
+    #[arg(long)]
+    agent: Option<String>,
+    /// Ethereum transaction of a lock.
+    #[arg(long)]
+    tx_hash: Option<String>,
+    /// Locks whose numeric id is at least this.
+    #[arg(long)]
+    lock_id_min: Option<i64>,
+    /// Locks whose numeric id is at most this.
+    #[arg(long)]
+    lock_id_max: Option<i64>,
+    /// Rows whose latest error is of this class.
+    #[arg(long, value_enum)]
+    error_class: Option<ErrorClass>,
+    #[arg(long, value_enum, default_value_t = StatusSort::Created)]
+    sort: StatusSort,
+    /// Oldest first instead of newest first.
+    #[arg(long)]
+    asc: bool,
+    /// Continue after the last row of a previous page: the `next_cursor`
+    /// it printed to stderr. Use the same filters and sort.
+    #[arg(long, conflicts_with = "summary")]
+    cursor: Option<StatusCursor>,
+    #[arg(long, default_value_t = 50)]
+    limit: usize,
+    /// Print counts and HOT totals by flow, state and step instead of rows.
+    #[arg(long)]
+    summary: bool,
+}
+
+impl StatusArgs {
+    fn filter(self) -> StateFilter {
+        StateFilter {
+            flow: self.flow,
+            state: self.state,
+            item_id: self.item_id,
+            step: self.step,
+            created_after: self.created_after,
+            created_before: self.created_before,
+            updated_after: self.updated_after,
+            updated_before: self.updated_before,
+            sender: self.sender,
+            agent: self.agent.map(|agent| {
+                // Lock agents are stored as lowercase hex.
+                if agent.starts_with("0x") {
+                    agent.to_lowercase()
+                } else {
+                    agent
+                }
+            }),
+            tx_hash: self.tx_hash,
+            lock_id_min: self.lock_id_min,
+            lock_id_max: self.lock_id_max,
+            error_class: self.error_class,
+            sort: self.sort,
+            ascending: self.asc,
+            cursor: self.cursor,
+            limit: self.limit,
+        }
+    }
+}
+
+fn parse_step(s: &str) -> Result<String, String> {
+    if s.parse::<WorkStep>().is_ok() || s.parse::<WithdrawStep>().is_ok() {
+        Ok(s.to_string())
+    } else {
+        Err(format!("unknown step {:?}", s))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Apply pending schema migrations. `run` and the other commands that
+    /// take the writer lease also apply them; read-only commands refuse a
+    /// schema that is behind.
+    Migrate {
+        /// Print the pending migrations without applying them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Copy the database to a new file. Safe while the daemon is running.
+    Backup {
+        /// Where to write the copy; must not exist yet.
+        path: std::path::PathBuf,
+    },
+    /// Replace the database with a backup. The daemon must be stopped.
+    Restore {
+        /// Backup written by `db backup` or the periodic backup task.
+        path: std::path::PathBuf,
+        /// Restore even if the backup's lock checkpoint is older than the
+        /// current database's.
+        #[arg(long)]
+        allow_rewind: bool,
+    },
+    /// Report on the database's health: journal mode, size, WAL size,
+    /// fragmentation and integrity. Safe while the daemon is running.
+    /// Exits non-zero if the integrity check finds problems.
+    Doctor {
+        /// Run the full `integrity_check` instead of `quick_check`.
+        #[arg(long)]
+        full: bool,
+    },
 }
 
 #[tokio::main]
@@ -64,27 +334,31 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
     let config = Config::from_env()?;
+    let out = Printer::new(args.output);
 
     match args.command {
         Command::Run => {
             info!("bridge-orchestrator starting");
             BridgeOrchestrator::new(config)?.run().await?;
         }
-        Command::Status {
-            flow,
-            state,
-            item_id,
-            limit,
-        } => {
-            let db = state::StateStore::open(&config.db_path)?;
-            let rows = db.status(StateFilter {
-                flow,
-                state,
-                item_id,
-                limit,
-            })?;
-            for row in rows {
-                println!("{}", serde_json::to_string(&row)?);
+        Command::Status(args) => {
+            let summary = args.summary;
+            let filter = args.filter();
+            let db = store::open_shared(&config)?;
+            if summary {
+                let summary = db.status_summary(&filter)?;
+                out.nested(&summary, &output::summary_lines(&summary))?;
+            } else {
+                let (sort, limit) = (filter.sort, filter.limit);
+                let rows = db.status(filter)?;
+                out.rows(&rows)?;
+                // A full page may have more after it.
+                if let Some(last) = rows.last().filter(|_| rows.len() == limit) {
+                    eprintln!(
+                        "{}",
+                        serde_json::json!({ "next_cursor": sort.cursor_after(last).to_string() })
+                    );
+                }
             }
         }
         Command::Clear {
@@ -92,7 +366,8 @@ async fn main() -> Result<()> {
             all,
             older_than_s,
         } => {
-            let db = state::StateStore::open(&config.db_path)?;
+            let lease = WriterLease::open(&config)?;
+            let db = lease.store().as_ref();
             let output = if all {
                 let deleted = db.clear_all()?;
                 serde_json::json!({
@@ -105,7 +380,13 @@ async fn main() -> Result<()> {
                     // this flag is a single knob; callers who want
                     // different succeeded/failed windows should rely
                     // on the in-process retention task instead.
-                    let stats = db.prune_terminal_older_than(age_s, age_s)?;
+                    let stats = retention::prune(
+                        db,
+                        config.retention.archive.as_ref(),
+                        age_s,
+                        age_s,
+                        retention::PruneBudget::until_done(&config.retention),
+                    )?;
                     serde_json::json!({
                         "mode": "non_in_progress_older_than",
                         "older_than_s": age_s,
@@ -123,13 +404,174 @@ async fn main() -> Result<()> {
             } else {
                 unreachable!("clap enforces one clear mode flag")
             };
-            println!("{}", serde_json::to_string(&output)?);
+            out.report(&output)?;
+        }
+        Command::Rejections { limit } => {
+            let db = store::open_shared(&config)?;
+            out.rows(&db.list_withdrawal_rejections(limit)?)?;
+        }
+        Command::History { item_id } => {
+            let db = store::open_shared(&config)?;
+            out.rows(&db.history(&item_id)?)?;
+        }
+        Command::Lookup { identifier, limit } => {
+            let db = store::open_shared(&config)?;
+            out.rows(&db.lookup(&identifier, limit)?)?;
+        }
+        Command::Stats => {
+            let stats = store::open_shared(&config)?.aggregate_stats()?;
+            out.nested(&stats, &output::stage_lines(&stats))?;
+        }
+        Command::Report { from, to } => {
+            let day = |ts: i64| ts.div_euclid(86_400);
+            let (from_day, to_day) = (
+                day(from),
+                day(to.unwrap_or_else(|| chrono::Utc::now().timestamp())),
+            );
+            if from_day > to_day {
+                anyhow::bail!("--from is after --to");
+            }
+            let db = store::open_shared(&config)?;
+            let days = report::daily_report(
+                db.as_ref(),
+                config.retention.archive.as_ref(),
+                from_day,
+                to_day,
+            )?;
+            out.rows(&days)?;
+        }
+        Command::Requeue {
+            item_id,
+            reset_attempts,
+            step,
+        } => {
+            let (lease, db) = lease_if_free(&config)?;
+            let opts = RequeueOptions {
+                reset_attempts,
+                step,
+            };
+            let event = db.requeue_item(&item_id, &opts, lease.is_some(), &operator_actor())?;
+            out.one(&event)?;
+        }
+        Command::Fail { item_id, reason } => {
+            let (lease, db) = lease_if_free(&config)?;
+            let event =
+                db.force_fail_item(&item_id, &reason, lease.is_some(), &operator_actor())?;
+            out.one(&event)?;
+        }
+        Command::Annotate { item_id, note } => {
+            let db = store::open_shared(&config)?;
+            let event = db.annotate_item(&item_id, &note, &operator_actor())?;
+            out.one(&event)?;
+        }
+        Command::Dlq {
+            command: DlqCommand::List { limit },
+        } => {
+            out.rows(&store::open_shared(&config)?.dead_letters(limit)?)?;
+        }
+        Command::Dlq {
+            command: DlqCommand::Replay { item_id },
+        } => {
+            let lease = WriterLease::open(&config)?;
+            let event = lease
+                .store()
+                .replay_dead_letter(&item_id, &operator_actor())?;
+            out.one(&event)?;
+        }
+        Command::Refund {
+            command: RefundCommand::List { status, limit },
+        } => {
+            let db = store::open_shared(&config)?;
+            out.rows(&db.refunds(status, limit)?)?;
+        }
+        Command::Refund {
+            command: RefundCommand::Approve { item_id },
+        } => {
+            let lease = WriterLease::open(&config)?;
+            out.one(&refund::approve(lease.store().as_ref(), &item_id, &operator_actor()).await?)?;
+        }
+        Command::Refund {
+            command: RefundCommand::Complete { item_id, tx },
+        } => {
+            let lease = WriterLease::open(&config)?;
+            let refund = lease
+                .store()
+                .complete_refund(&item_id, &tx, &operator_actor())?;
+            out.one(&refund)?;
+        }
+        Command::Db {
+            command: DbCommand::Migrate { dry_run },
+        } => {
+            let report = if dry_run {
+                store::schema_plan(&config)?
+            } else {
+                WriterLease::open(&config)?.migration_report().clone()
+            };
+            let mut output = serde_json::to_value(&report)?;
+            output["dry_run"] = serde_json::Value::Bool(dry_run);
+            out.report(&output)?;
+        }
+        Command::Db {
+            command: DbCommand::Backup { path },
+        } => {
+            let db_path = sqlite_path(&config, "db backup")?;
+            let report = backup::backup(std::path::Path::new(db_path), &path)?;
+            out.report(&report)?;
+        }
+        Command::Db {
+            command: DbCommand::Restore { path, allow_rewind },
+        } => {
+            let db_path = sqlite_path(&config, "db restore")?;
+            let report = backup::restore(&path, db_path, allow_rewind)?;
+            out.report(&report)?;
+        }
+        Command::Db {
+            command: DbCommand::Doctor { full },
+        } => {
+            let db_path = sqlite_path(&config, "db doctor")?;
+            let report = doctor::doctor(std::path::Path::new(db_path), &config.sqlite, full)?;
+            out.report(&report)?;
+            if !report.problems.is_empty() {
+                anyhow::bail!(
+                    "{} found {} problem(s) in {}",
+                    report.check,
+                    report.problems.len(),
+                    config.db_path
+                );
+            }
         }
     }
 
     Ok(())
 }
 
+/// `DB_PATH`, for the commands that work on the SQLite file itself.
+fn sqlite_path<'a>(config: &'a Config, what: &str) -> Result<&'a str> {
+    if config.storage != StorageBackend::Sqlite {
+        anyhow::bail!("{} is only available with DB_BACKEND=sqlite", what);
+    }
+    Ok(&config.db_path)
+}
+
+/// The writer lease and its store when no daemon holds the lease, else a
+/// shared store next to the daemon. `requeue` and `fail` run either way;
+/// without the lease they leave alone the rows the daemon may be working on.
+fn lease_if_free(config: &Config) -> Result<(Option<WriterLease>, store::SharedStore)> {
+    match WriterLease::try_open(config)? {
+        Some(lease) => {
+            let db = std::sync::Arc::clone(lease.store());
+            Ok((Some(lease), db))
+        }
+        None => Ok((None, store::open_shared(config)?)),
+    }
+}
+
+/// Actor recorded on the audit events of the operator commands.
+fn operator_actor() -> String {
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    format!("operator:{}", user)
+}
+
 fn init_logging() {
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     tracing_subscriber::fmt()
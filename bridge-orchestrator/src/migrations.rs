@@ -0,0 +1,941 @@
+//! Versioned schema migrations for the orchestrator's SQLite database.
+//!
+//! `schema_meta.version` records the last migration applied. On open,
+//! [`migrate`] applies every later entry of [`MIGRATIONS`] in order, each in
+//! its own transaction together with the version bump, so a failure leaves
+//! the database at the last migration that fully applied. Before touching an
+//! existing database it is copied aside with `VACUUM INTO`.
+//!
+//! Migrations are append-only: never edit or renumber one that has shipped.
+//! Add a new entry with the next version instead.
+
+use crate::payload::lock_load_failure_class;
+use crate::state::ErrorClass;
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Transaction};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    up: fn(&Transaction<'_>) -> Result<()>,
+}
+
+/// Every schema change, oldest first. Versions are contiguous from 1.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        up: initial_schema,
+    },
+    Migration {
+        version: 2,
+        name: "work_item_pipeline_columns",
+        up: work_item_pipeline_columns,
+    },
+    Migration {
+        version: 3,
+        name: "withdrawal_tracking",
+        up: withdrawal_tracking,
+    },
+    Migration {
+        version: 4,
+        name: "work_item_events",
+        up: work_item_events,
+    },
+    Migration {
+        version: 5,
+        name: "work_item_event_actor",
+        up: work_item_event_actor,
+    },
+    Migration {
+        version: 6,
+        name: "writer_lease",
+        up: writer_lease,
+    },
+    Migration {
+        version: 7,
+        name: "work_item_query_columns",
+        up: work_item_query_columns,
+    },
+    Migration {
+        version: 8,
+        name: "work_item_recipient_column",
+        up: work_item_recipient_column,
+    },
+    Migration {
+        version: 9,
+        name: "work_item_error_taxonomy",
+        up: work_item_error_taxonomy,
+    },
+    Migration {
+        version: 10,
+        name: "lock_refunds",
+        up: lock_refunds,
+    },
+];
+
+/// Schema version this binary writes: the last migration's.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// A migration that is pending or was applied, as reported by
+/// `db migrate`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationStep {
+    pub version: i64,
+    pub name: &'static str,
+}
+
+/// Outcome of [`migrate`], or what it would do for [`plan`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationReport {
+    pub from_version: i64,
+    pub to_version: i64,
+    pub steps: Vec<MigrationStep>,
+    /// Copy taken before the first step. `None` when nothing was pending
+    /// or the database was new.
+    pub backup_path: Option<PathBuf>,
+}
+
+/// Bring the database at `path` (already open as `conn`) up to
+/// [`latest_version`].
+pub fn migrate(conn: &mut Connection, path: &Path) -> Result<MigrationReport> {
+    apply(conn, path, MIGRATIONS)
+}
+
+/// Report what [`migrate`] would apply to the database at `path`, without
+/// writing anything. A missing file reports every migration as pending
+/// and is not created.
+pub fn plan(path: &Path) -> Result<MigrationReport> {
+    if !path.exists() {
+        return Ok(report(0, MIGRATIONS, None));
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .context("open sqlite database read-only")?;
+    let current = current_version(&conn)?;
+    check_not_newer(current, latest_version())?;
+    Ok(report(current, MIGRATIONS, None))
+}
+
+fn apply(conn: &mut Connection, path: &Path, migrations: &[Migration]) -> Result<MigrationReport> {
+    ensure_schema_meta(conn)?;
+    let current = current_version(conn)?;
+    let target = migrations.last().map(|m| m.version).unwrap_or(0);
+    check_not_newer(current, target)?;
+
+    let mut report = report(current, migrations, None);
+    if report.steps.is_empty() {
+        return Ok(report);
+    }
+    if has_user_tables(conn)? {
+        report.backup_path = Some(backup(conn, path, current)?);
+    }
+
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        (migration.up)(&tx).with_context(|| {
+            format!(
+                "schema migration {} ({}) failed",
+                migration.version, migration.name
+            )
+        })?;
+        tx.execute(
+            "INSERT INTO schema_meta (id, version) VALUES (1, ?1)
+             ON CONFLICT(id) DO UPDATE SET version = excluded.version",
+            [migration.version],
+        )?;
+        tx.commit()?;
+        tracing::info!(
+            event = "state.migration_applied",
+            version = migration.version,
+            name = migration.name,
+            "applied schema migration {} ({})",
+            migration.version,
+            migration.name
+        );
+    }
+    Ok(report)
+}
+
+fn report(current: i64, migrations: &[Migration], backup_path: Option<PathBuf>) -> MigrationReport {
+    let steps: Vec<MigrationStep> = migrations
+        .iter()
+        .filter(|m| m.version > current)
+        .map(|m| MigrationStep {
+            version: m.version,
+            name: m.name,
+        })
+        .collect();
+    MigrationReport {
+        from_version: current,
+        to_version: steps.last().map(|s| s.version).unwrap_or(current),
+        steps,
+        backup_path,
+    }
+}
+
+/// Refuse a database that `plan` says is behind this binary. For the
+/// connections opened without the writer lease, which never migrate: a
+/// daemon may be running against the schema as it is.
+pub fn ensure_current(plan: &MigrationReport, database: &str) -> Result<()> {
+    if plan.steps.is_empty() {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "{} is at schema version {} but this binary needs {}; run `bridge-orchestrator db migrate` (or `run`) to apply {} pending migration(s) under the writer lease",
+        database,
+        plan.from_version,
+        plan.to_version,
+        plan.steps.len()
+    )
+}
+
+pub fn check_not_newer(current: i64, latest: i64) -> Result<()> {
+    if current > latest {
+        anyhow::bail!(
+            "database schema version {} is newer than binary version {}",
+            current,
+            latest
+        );
+    }
+    Ok(())
+}
+
+fn ensure_schema_meta(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_meta (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            version INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// `schema_meta.version`, or 0 for a new database or one written before
+/// versioning existed.
+pub fn current_version(conn: &Connection) -> Result<i64> {
+    let has_meta: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type='table' AND name='schema_meta')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_meta {
+        return Ok(0);
+    }
+    Ok(conn
+        .query_row("SELECT version FROM schema_meta WHERE id = 1", [], |row| {
+            row.get(0)
+        })
+        .optional()?
+        .unwrap_or(0))
+}
+
+fn has_user_tables(conn: &Connection) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master
+                        WHERE type='table' AND name NOT IN ('schema_meta', 'sqlite_sequence'))",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+/// Copy the database next to itself before migrating it:
+/// `<db>.pre-v<from>.<unix-seconds>.bak`. `VACUUM INTO` writes a consistent
+/// snapshot through the open connection.
+fn backup(conn: &Connection, path: &Path, from_version: i64) -> Result<PathBuf> {
+    let ts = chrono::Utc::now().timestamp();
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".pre-v{}.{}.bak", from_version, ts));
+    let backup_path = PathBuf::from(name);
+    conn.execute("VACUUM INTO ?1", [backup_path.to_string_lossy().as_ref()])
+        .with_context(|| format!("pre-migration backup to {}", backup_path.display()))?;
+    tracing::info!(
+        event = "state.migration_backup",
+        path = %backup_path.display(),
+        "backed up database before migrating from schema version {}",
+        from_version
+    );
+    Ok(backup_path)
+}
+
+fn add_column_if_missing(
+    tx: &Transaction<'_>,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<()> {
+    let mut stmt = tx.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|c| c == column);
+    if !exists {
+        tx.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// v1: the tables every versioned database starts from. `IF NOT EXISTS`
+/// throughout, since a database written before `schema_meta` existed
+/// arrives here at version 0 with some of them already present.
+fn initial_schema(tx: &Transaction<'_>) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS work_items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            flow TEXT NOT NULL,
+            task_type TEXT NOT NULL,
+            item_id TEXT NOT NULL,
+            idempotency_key TEXT NOT NULL UNIQUE,
+            payload_json TEXT NOT NULL,
+            state TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            max_attempts INTEGER NOT NULL DEFAULT 8,
+            next_retry_at INTEGER,
+            last_attempt_at INTEGER,
+            error_class TEXT,
+            last_error TEXT,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+            updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+        CREATE INDEX IF NOT EXISTS idx_work_items_state_created ON work_items(state, created_at);
+        CREATE INDEX IF NOT EXISTS idx_work_items_flow_state ON work_items(flow, state);
+        CREATE TABLE IF NOT EXISTS checkpoints (
+            checkpoint_key TEXT PRIMARY KEY,
+            checkpoint_value TEXT NOT NULL,
+            updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );",
+    )?;
+    Ok(())
+}
+
+/// v2: columns that version-1 binaries added on open as they were
+/// introduced, so a v1 database may have any subset of them.
+fn work_item_pipeline_columns(tx: &Transaction<'_>) -> Result<()> {
+    for (column, decl) in [
+        ("max_attempts", "INTEGER NOT NULL DEFAULT 8"),
+        ("next_retry_at", "INTEGER"),
+        ("last_attempt_at", "INTEGER"),
+        ("error_class", "TEXT"),
+        ("step", "TEXT NOT NULL DEFAULT 'new'"),
+        ("cl_link_hash", "TEXT"),
+        ("cl_rave_hash", "TEXT"),
+        ("br_spend_hash", "TEXT"),
+        ("br_rave_hash", "TEXT"),
+    ] {
+        add_column_if_missing(tx, "work_items", column, decl)?;
+    }
+    Ok(())
+}
+
+/// v3: withdrawal rejection ledger, withdraw-flow lookup index, and the
+/// per-withdrawal fee.
+fn withdrawal_tracking(tx: &Transaction<'_>) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS withdrawal_rejections (
+            link_id TEXT PRIMARY KEY,
+            reason TEXT NOT NULL,
+            detail TEXT NOT NULL,
+            payload_json TEXT NOT NULL,
+            seen_count INTEGER NOT NULL DEFAULT 1,
+            first_seen_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+            last_seen_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+        CREATE INDEX IF NOT EXISTS idx_work_items_flow_br_spend ON work_items(flow, br_spend_hash);",
+    )?;
+    add_column_if_missing(tx, "work_items", "fee_wei", "TEXT")?;
+    Ok(())
+}
+
+/// v4: append-only transition history. Rows are only ever inserted, or
+/// deleted together with their work item; the trigger refuses rewrites.
+fn work_item_events(tx: &Transaction<'_>) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE work_item_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            work_item_id INTEGER NOT NULL,
+            item_id TEXT NOT NULL,
+            from_state TEXT,
+            to_state TEXT NOT NULL,
+            from_step TEXT,
+            to_step TEXT NOT NULL,
+            reason TEXT,
+            attempt INTEGER NOT NULL,
+            action_hash TEXT,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+        CREATE INDEX idx_work_item_events_item ON work_item_events(item_id, id);
+        CREATE INDEX idx_work_item_events_work_item ON work_item_events(work_item_id);
+        CREATE TRIGGER work_item_events_append_only
+        BEFORE UPDATE ON work_item_events
+        BEGIN
+            SELECT RAISE(ABORT, 'work_item_events is append-only');
+        END;",
+    )?;
+    Ok(())
+}
+
+/// v5: who made a transition. Everything the daemon records is
+/// `orchestrator`; operator commands record `operator:<user>`.
+fn work_item_event_actor(tx: &Transaction<'_>) -> Result<()> {
+    add_column_if_missing(
+        tx,
+        "work_item_events",
+        "actor",
+        "TEXT NOT NULL DEFAULT 'orchestrator'",
+    )
+}
+
+/// v6: who holds the single-writer lease. The lock itself is an OS file
+/// lock; this row only names the holder and carries its heartbeat.
+fn writer_lease(tx: &Transaction<'_>) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS writer_lease (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            pid INTEGER NOT NULL,
+            host TEXT NOT NULL,
+            acquired_at INTEGER NOT NULL,
+            heartbeat_at INTEGER NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+/// v7: what `status` filters on, indexed. The payload fields it looks up
+/// are virtual generated columns, so they can never disagree with
+/// `payload_json`: the lock's sender and transaction (lowercased), the
+/// agent a lock credits or a withdrawal came from, and a numeric lock id
+/// (NULL when it is not one). Plus indexes for the step, error class and
+/// timestamp filters and sorts.
+fn work_item_query_columns(tx: &Transaction<'_>) -> Result<()> {
+    // `json_valid` first: json_extract raises on malformed JSON, and an
+    // indexed column is computed on every write.
+    tx.execute_batch(
+        "ALTER TABLE work_items ADD COLUMN eth_sender TEXT GENERATED ALWAYS AS (
+            CASE WHEN json_valid(payload_json)
+            THEN lower(json_extract(payload_json, '$.sender')) END
+        ) VIRTUAL;
+        ALTER TABLE work_items ADD COLUMN holochain_agent TEXT GENERATED ALWAYS AS (
+            CASE WHEN json_valid(payload_json)
+            THEN coalesce(json_extract(payload_json, '$.holochain_agent'),
+                          json_extract(payload_json, '$.spender')) END
+        ) VIRTUAL;
+        ALTER TABLE work_items ADD COLUMN eth_tx_hash TEXT GENERATED ALWAYS AS (
+            CASE WHEN json_valid(payload_json)
+            THEN lower(json_extract(payload_json, '$.tx_hash')) END
+        ) VIRTUAL;
+        ALTER TABLE work_items ADD COLUMN lock_id INTEGER GENERATED ALWAYS AS (
+            CASE WHEN flow = 'lock' AND json_valid(payload_json)
+                  AND length(json_extract(payload_json, '$.lock_id')) BETWEEN 1 AND 18
+                  AND json_extract(payload_json, '$.lock_id') NOT GLOB '*[^0-9]*'
+            THEN CAST(json_extract(payload_json, '$.lock_id') AS INTEGER) END
+        ) VIRTUAL;
+        CREATE INDEX idx_work_items_eth_sender ON work_items(eth_sender);
+        CREATE INDEX idx_work_items_holochain_agent ON work_items(holochain_agent);
+        CREATE INDEX idx_work_items_eth_tx_hash ON work_items(eth_tx_hash);
+        CREATE INDEX idx_work_items_lock_id ON work_items(lock_id);
+        CREATE INDEX idx_work_items_step ON work_items(step, state);
+        CREATE INDEX idx_work_items_error_class ON work_items(error_class);
+        CREATE INDEX idx_work_items_created ON work_items(created_at, id);
+        CREATE INDEX idx_work_items_updated ON work_items(updated_at, id);",
+    )?;
+    Ok(())
+}
+
+/// v8: a withdrawal's recipient (lowercased), indexed like the v7 columns
+/// so `lookup` can find what an Ethereum address was paid.
+fn work_item_recipient_column(tx: &Transaction<'_>) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE work_items ADD COLUMN eth_recipient TEXT GENERATED ALWAYS AS (
+            CASE WHEN flow = 'withdraw' AND json_valid(payload_json)
+            THEN lower(json_extract(payload_json, '$.recipient')) END
+        ) VIRTUAL;
+        CREATE INDEX idx_work_items_eth_recipient ON work_items(eth_recipient);",
+    )?;
+    Ok(())
+}
+
+/// v9: `recent_errors`, and the `error_class` values written before
+/// [`crate::state::ErrorClass`] renamed to its classes. A `permanent` row
+/// is placed by the message the cycle gave it; the rest of `permanent`
+/// and all of `transient` were never told apart, so are `unclassified`.
+fn work_item_error_taxonomy(tx: &Transaction<'_>) -> Result<()> {
+    tx.execute_batch(WORK_ITEM_ERROR_TAXONOMY)?;
+    Ok(())
+}
+
+/// Shared with the Postgres schema's v4, which runs it as written.
+pub(crate) const WORK_ITEM_ERROR_TAXONOMY: &str = "
+    ALTER TABLE work_items ADD COLUMN recent_errors TEXT;
+    UPDATE work_items SET error_class = CASE
+        WHEN error_class IN ('signer_config', 'coupon_signing') THEN 'signer'
+        WHEN error_class = 'fee_quote' THEN 'rpc'
+        WHEN error_class = 'fee_exceeds_amount' THEN 'policy_rejected'
+        WHEN error_class = 'permanent'
+             AND last_error LIKE 'proof exceeds the link tag ceiling%' THEN 'tag_oversize'
+        WHEN error_class = 'permanent'
+             AND (last_error LIKE 'proof extraction failed%'
+                  OR last_error LIKE 'invalid payload%'
+                  OR last_error LIKE '%tag size estimation failed%') THEN 'invalid_payload'
+        WHEN error_class = 'permanent' AND last_error LIKE 'operator:%' THEN 'operator'
+        ELSE 'unclassified'
+    END
+    WHERE error_class IS NOT NULL;
+";
+
+/// v10: the refund ledger of locks that can never be bridged, seeded
+/// with the ones already failed. A lock whose agent key is not a usable
+/// Ed25519 key was `invalid_payload` until `invalid_agent` was split out
+/// of it; such locks are found by re-running that check on their payload,
+/// not by their error text.
+fn lock_refunds(tx: &Transaction<'_>) -> Result<()> {
+    tx.execute_batch(LOCK_REFUNDS)?;
+    let rows = tx
+        .prepare(INVALID_PAYLOAD_LOCKS)?
+        .query_map([], |r| {
+            Ok((
+                r.get::<_, i64>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (id, task_type, payload) in rows {
+        let payload = serde_json::from_str(&payload).unwrap_or(Value::Null);
+        if is_agent_failure(&task_type, &payload) {
+            tx.execute(
+                "UPDATE work_items SET error_class = 'invalid_agent' WHERE id = ?1",
+                [id],
+            )?;
+        }
+    }
+    tx.execute_batch(SEED_LOCK_REFUNDS)?;
+    Ok(())
+}
+
+/// Whether a lock failed as `invalid_payload` failed only on its agent key
+/// and can be refunded, by the check a lock load applies today.
+pub(crate) fn is_agent_failure(task_type: &str, payload: &Value) -> bool {
+    lock_load_failure_class(task_type, payload) == ErrorClass::InvalidAgent
+}
+
+/// This and the two queries below are shared with the Postgres schema's
+/// v5, which runs them in the same order. Rows carry their timestamps
+/// rather than defaulting them, so the table reads the same on both
+/// backends.
+pub(crate) const LOCK_REFUNDS: &str = "
+    CREATE TABLE IF NOT EXISTS lock_refunds (
+        work_item_id BIGINT PRIMARY KEY,
+        item_id TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'refundable',
+        approved_by TEXT,
+        approved_at BIGINT,
+        coupon TEXT,
+        coupon_signed_at BIGINT,
+        claim_tx_hash TEXT,
+        reported_at BIGINT,
+        last_error TEXT,
+        created_at BIGINT NOT NULL,
+        updated_at BIGINT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_lock_refunds_status ON lock_refunds (status, updated_at);
+";
+
+/// The locks v10 re-checks for a bad agent key.
+pub(crate) const INVALID_PAYLOAD_LOCKS: &str = "
+    SELECT id, task_type, payload_json FROM work_items
+    WHERE flow = 'lock' AND error_class = 'invalid_payload'
+";
+
+/// Seeds v10's ledger once the bad-agent locks are reclassified. A lock
+/// whose CL RAVE already ran is left out: its value moved on Holochain.
+pub(crate) const SEED_LOCK_REFUNDS: &str = "
+    INSERT INTO lock_refunds (work_item_id, item_id, created_at, updated_at)
+    SELECT id, item_id, updated_at, updated_at FROM work_items
+    WHERE flow = 'lock' AND state = 'failed'
+      AND error_class IN ('tag_oversize', 'invalid_agent')
+      AND step IN ('new', 'cl_link_created') AND cl_rave_hash IS NULL
+    ON CONFLICT (work_item_id) DO NOTHING;
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{LockPayload, PAYLOAD_VERSION};
+    use alloy::primitives::Address;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn test_db_path(name: &str) -> PathBuf {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        PathBuf::from(format!(
+            "/tmp/bridge-orchestrator-migrate-{}-{}.db",
+            name, ts
+        ))
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(&format!("PRAGMA table_info({})", table))
+            .unwrap();
+        stmt.query_map([], |row| row.get::<_, String>(1))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    /// The schema of every released version from v2 on, frozen as it was
+    /// left on disk. A new migration needs the schema it upgrades from
+    /// frozen here too.
+    const SNAPSHOTS: &[&str] = &[
+        include_str!("../fixtures/schema/v2.sql"),
+        include_str!("../fixtures/schema/v3.sql"),
+        include_str!("../fixtures/schema/v4.sql"),
+        include_str!("../fixtures/schema/v5.sql"),
+        include_str!("../fixtures/schema/v6.sql"),
+        include_str!("../fixtures/schema/v7.sql"),
+        include_str!("../fixtures/schema/v8.sql"),
+        include_str!("../fixtures/schema/v9.sql"),
+        include_str!("../fixtures/schema/v10.sql"),
+    ];
+
+    /// Each schema a released binary could have left on disk, with one
+    /// lock row: hand-written for the two before the first migration, and
+    /// the frozen [`SNAPSHOTS`] after, never built by the migrations under
+    /// test.
+    fn fixture(path: &Path, version: i64) {
+        let conn = Connection::open(path).unwrap();
+        match version {
+            // Pre-`schema_meta`, and the oldest v1 binaries: only the
+            // original columns.
+            0 | 1 => conn
+                .execute_batch(
+                    "CREATE TABLE work_items (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        flow TEXT NOT NULL,
+                        task_type TEXT NOT NULL,
+                        item_id TEXT NOT NULL,
+                        idempotency_key TEXT NOT NULL UNIQUE,
+                        payload_json TEXT NOT NULL,
+                        state TEXT NOT NULL,
+                        attempts INTEGER NOT NULL DEFAULT 0,
+                        last_error TEXT,
+                        created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+                        updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+                    );
+                    CREATE TABLE checkpoints (
+                        checkpoint_key TEXT PRIMARY KEY,
+                        checkpoint_value TEXT NOT NULL,
+                        updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+                    );",
+                )
+                .unwrap(),
+            _ => {
+                let snapshot = SNAPSHOTS
+                    .get(version as usize - 2)
+                    .unwrap_or_else(|| panic!("no frozen schema for v{}", version));
+                conn.execute_batch(snapshot).unwrap();
+            }
+        }
+        if version == 1 {
+            conn.execute_batch(
+                "CREATE TABLE schema_meta (
+                    id INTEGER PRIMARY KEY CHECK (id = 1),
+                    version INTEGER NOT NULL
+                );
+                INSERT INTO schema_meta (id, version) VALUES (1, 1);",
+            )
+            .unwrap();
+        }
+        conn.execute_batch(
+            "INSERT INTO work_items (flow, task_type, item_id, idempotency_key, payload_json, state)
+             VALUES ('lock', 'create_parked_link', 'lock:fixture', 'lock:fixture:create_parked_link',
+                     '{\"lock_id\":\"fixture\"}', 'queued');
+             INSERT INTO checkpoints (checkpoint_key, checkpoint_value)
+             VALUES ('lock.last_processed_block', '42');",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn migration_versions_are_contiguous_from_one() {
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version, i as i64 + 1, "{} is out of sequence", m.name);
+        }
+    }
+
+    #[test]
+    fn every_historical_version_migrates_to_latest_without_losing_rows() {
+        for version in 0..=latest_version() {
+            let path = test_db_path(&format!("v{}", version));
+            fixture(&path, version);
+
+            let mut conn = Connection::open(&path).unwrap();
+            let report = migrate(&mut conn, &path).unwrap();
+            assert_eq!(report.from_version, version);
+            assert_eq!(report.to_version, latest_version());
+            assert_eq!(
+                report.steps.len() as i64,
+                latest_version() - version,
+                "v{}",
+                version
+            );
+            assert_eq!(current_version(&conn).unwrap(), latest_version());
+
+            let cols = columns(&conn, "work_items");
+            for column in ["step", "br_spend_hash", "fee_wei", "max_attempts"] {
+                assert!(
+                    cols.iter().any(|c| c == column),
+                    "v{} is missing {}",
+                    version,
+                    column
+                );
+            }
+            let (item_id, step): (String, String) = conn
+                .query_row("SELECT item_id, step FROM work_items", [], |r| {
+                    Ok((r.get(0)?, r.get(1)?))
+                })
+                .unwrap();
+            assert_eq!((item_id.as_str(), step.as_str()), ("lock:fixture", "new"));
+            let checkpoint: String = conn
+                .query_row("SELECT checkpoint_value FROM checkpoints", [], |r| r.get(0))
+                .unwrap();
+            assert_eq!(checkpoint, "42");
+
+            // A second open is a no-op.
+            let again = migrate(&mut conn, &path).unwrap();
+            assert!(again.steps.is_empty());
+            assert!(again.backup_path.is_none());
+        }
+    }
+
+    #[test]
+    fn legacy_error_classes_are_renamed_by_what_failed() {
+        let path = test_db_path("error-taxonomy");
+        fixture(&path, 8);
+        let mut conn = Connection::open(&path).unwrap();
+        for (n, (class, error)) in [
+            ("permanent", "proof exceeds the link tag ceiling (size=950)"),
+            ("permanent", "proof extraction failed: bad hex"),
+            ("permanent", "operator: stuck"),
+            ("permanent", "Exceeded max attempts in-cycle"),
+            ("transient", "deadline has elapsed"),
+            ("coupon_signing", "no key"),
+            ("fee_exceeds_amount", "fee 2 >= amount 1"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            conn.execute(
+                "INSERT INTO work_items
+                     (flow, task_type, item_id, idempotency_key, payload_json, state, error_class, last_error)
+                 VALUES ('lock', 'create_parked_link', ?1, ?1, '{}', 'failed', ?2, ?3)",
+                rusqlite::params![format!("lock:legacy{}", n), class, error],
+            )
+            .unwrap();
+        }
+        migrate(&mut conn, &path).unwrap();
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT error_class FROM work_items WHERE item_id LIKE 'lock:legacy%' ORDER BY id",
+            )
+            .unwrap();
+        let classes = stmt
+            .query_map([], |r| r.get::<_, String>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            classes,
+            [
+                "tag_oversize",
+                "invalid_payload",
+                "operator",
+                "unclassified",
+                "unclassified",
+                "signer",
+                "policy_rejected"
+            ]
+        );
+        assert!(columns(&conn, "work_items")
+            .iter()
+            .any(|c| c == "recent_errors"));
+    }
+
+    #[test]
+    fn locks_that_can_never_be_bridged_are_seeded_as_refundable() {
+        let lock = |sender: Address, agent: &str| {
+            let mut lock = LockPayload::for_test("1");
+            lock.sender = sender.to_string();
+            lock.holochain_agent = agent.to_string();
+            let mut stored = serde_json::to_value(&lock).unwrap();
+            stored["v"] = PAYLOAD_VERSION.into();
+            stored.to_string()
+        };
+        let not_a_key = format!("0x{}", "ab".repeat(32));
+        let path = test_db_path("lock-refunds");
+        fixture(&path, 9);
+        let mut conn = Connection::open(&path).unwrap();
+        for (n, (state, class, error, payload)) in [
+            (
+                "failed",
+                "tag_oversize",
+                "proof exceeds the link tag ceiling",
+                "{}".to_string(),
+            ),
+            (
+                "failed",
+                "invalid_payload",
+                "proof extraction failed: not an agent",
+                lock(Address::repeat_byte(0x22), &not_a_key),
+            ),
+            // The error names the agent key, but there is no sender to
+            // refund, so the payload is at fault.
+            (
+                "failed",
+                "invalid_payload",
+                "proof extraction failed: expected 32 byte agent key, got 20",
+                lock(Address::ZERO, &not_a_key),
+            ),
+            (
+                "failed",
+                "invalid_payload",
+                "invalid payload: malformed lock payload",
+                "{}".to_string(),
+            ),
+            ("queued", "rpc", "connection refused", "{}".to_string()),
+        ]
+        .iter()
+        .enumerate()
+        {
+            conn.execute(
+                "INSERT INTO work_items
+                     (flow, task_type, item_id, idempotency_key, payload_json, state, error_class, last_error)
+                 VALUES ('lock', 'create_parked_link', ?1, ?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![format!("lock:r{}", n), payload, state, class, error],
+            )
+            .unwrap();
+        }
+        migrate(&mut conn, &path).unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT item_id, status FROM lock_refunds ORDER BY work_item_id")
+            .unwrap();
+        let refunds = stmt
+            .query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            refunds,
+            [
+                ("lock:r0".to_string(), "refundable".to_string()),
+                ("lock:r1".to_string(), "refundable".to_string())
+            ]
+        );
+        let mut stmt = conn
+            .prepare("SELECT error_class FROM work_items WHERE item_id IN ('lock:r1', 'lock:r2') ORDER BY id")
+            .unwrap();
+        let classes = stmt
+            .query_map([], |r| r.get::<_, String>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(classes, ["invalid_agent", "invalid_payload"]);
+    }
+
+    #[test]
+    fn existing_databases_are_backed_up_and_new_ones_are_not() {
+        let path = test_db_path("backup");
+        fixture(&path, 1);
+        let mut conn = Connection::open(&path).unwrap();
+        let report = migrate(&mut conn, &path).unwrap();
+        let backup = report.backup_path.expect("a v1 database is backed up");
+        let copy = Connection::open(&backup).unwrap();
+        assert_eq!(current_version(&copy).unwrap(), 1);
+        assert!(!columns(&copy, "work_items").iter().any(|c| c == "step"));
+
+        let fresh = test_db_path("fresh");
+        let mut conn = Connection::open(&fresh).unwrap();
+        assert!(migrate(&mut conn, &fresh).unwrap().backup_path.is_none());
+    }
+
+    #[test]
+    fn a_failing_migration_rolls_back_and_keeps_the_last_good_version() {
+        fn broken(tx: &Transaction<'_>) -> Result<()> {
+            tx.execute("CREATE TABLE half_done (id INTEGER)", [])?;
+            anyhow::bail!("boom")
+        }
+        let path = test_db_path("rollback");
+        fixture(&path, 1);
+        let mut conn = Connection::open(&path).unwrap();
+        let migrations = [
+            Migration {
+                version: 1,
+                name: "initial_schema",
+                up: initial_schema,
+            },
+            Migration {
+                version: 2,
+                name: "work_item_pipeline_columns",
+                up: work_item_pipeline_columns,
+            },
+            Migration {
+                version: 3,
+                name: "broken",
+                up: broken,
+            },
+        ];
+        let err = apply(&mut conn, &path, &migrations).unwrap_err();
+        assert!(format!("{:#}", err).contains("schema migration 3 (broken)"));
+        assert_eq!(current_version(&conn).unwrap(), 2);
+        let half_done: bool = conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name='half_done')",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert!(!half_done, "the failed step's DDL must roll back");
+    }
+
+    #[test]
+    fn plan_reports_pending_steps_without_writing() {
+        let missing = test_db_path("plan-missing");
+        let report = plan(&missing).unwrap();
+        assert_eq!(report.from_version, 0);
+        assert_eq!(report.steps.len() as i64, latest_version());
+        assert!(!missing.exists(), "a dry run must not create the file");
+
+        let path = test_db_path("plan-v1");
+        fixture(&path, 1);
+        let report = plan(&path).unwrap();
+        assert_eq!(report.from_version, 1);
+        assert_eq!(report.steps[0].name, "work_item_pipeline_columns");
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 1);
+    }
+
+    #[test]
+    fn a_database_from_a_newer_binary_is_refused() {
+        let path = test_db_path("newer");
+        fixture(&path, 1);
+        let mut conn = Connection::open(&path).unwrap();
+        conn.execute("UPDATE schema_meta SET version = 999", [])
+            .unwrap();
+        let err = migrate(&mut conn, &path).unwrap_err();
+        assert!(err.to_string().contains("newer than binary"), "{}", err);
+        assert!(plan(&path).is_err());
+    }
+}
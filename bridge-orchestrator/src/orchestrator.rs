@@ -1,8 +1,18 @@
-use crate::config::{Config, LINK_TAG_BYTES_CEILING};
-use crate::lock_flow::{format_amount, LockFlow};
-use crate::signer::{generate_coupon, signer_context_from_env};
-use crate::state::{StateStore, WorkItem, WorkStep};
+use crate::config::{
+    Config, S4OrderingPolicy, StorageBackend, WithdrawalFeeModel, LINK_TAG_BYTES_CEILING,
+};
+use crate::leader::{Candidacy, LeaseBackend, SqliteLeaseBackend};
+use crate::lease::{WriterLease, HEARTBEAT_EXPIRY};
+use crate::lock_flow::{current_gas_price_wei, LockFlow};
+use crate::payload::lock_load_failure_class;
+use crate::signer::signer_context_from_env;
+use crate::state::{ErrorClass, WithdrawStep, WorkItem, WorkStep};
+use crate::store::SharedStore;
 use crate::watchtower_reporter::{self, CycleClass, ReporterState};
+use crate::withdrawal::{
+    classify_bridging_link, sign_withdrawal_coupon, BridgingLink, FeeQuote, WithdrawalFee,
+    WithdrawalRejection, WithdrawalRequest,
+};
 use anyhow::{Context, Result};
 use ham::{
     connect_with_backoff, install_shutdown_handler, is_connection_error, is_request_timeout,
@@ -15,10 +25,11 @@ use rave_engine::types::{
     ParkedData, ParkedLinkType, ParkedSpendData, RAVEExecuteInputs, Transaction,
     TransactionDetails, UnitFee, UnitMap, RAVE,
 };
-use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::task::JoinHandle;
 use tracing::{debug, error, info, warn};
 use zfuel::fuel::ZFuel;
 
@@ -27,8 +38,12 @@ const ORACLE_ROLE: &str = "oracle";
 
 pub struct BridgeOrchestrator {
     cfg: Config,
-    db: StateStore,
+    db: SharedStore,
     reporter: ReporterState,
+    /// Held for the orchestrator's lifetime, and `db` opened under it. `None`
+    /// under leader election, where the writer lease follows leadership
+    /// instead (see [`BridgeOrchestrator::follow_leadership`]).
+    lease: Option<WriterLease>,
 }
 
 /// Severity bucket for a source-chain-pressure event. Mapped to a
@@ -76,6 +91,28 @@ fn classify_cycle_failure(e: &anyhow::Error) -> CycleFailureAction {
     }
 }
 
+/// The [`ErrorClass`] a failed cycle requeues its in-flight rows under,
+/// checked in the same order as [`classify_cycle_failure`].
+fn retry_class(e: &anyhow::Error) -> ErrorClass {
+    if is_connection_error(e) {
+        ErrorClass::Rpc
+    } else if is_source_chain_pressure(e) {
+        ErrorClass::SourceChainPressure
+    } else if is_request_timeout(e) {
+        ErrorClass::ConductorTimeout
+    } else {
+        ErrorClass::Unclassified
+    }
+}
+
+/// Drop the rows still waiting out a retry backoff. Only a stage's input
+/// goes through this: a backed-off row whose link a RAVE consumed must
+/// still advance.
+fn due(mut rows: Vec<WorkItem>, now: i64) -> Vec<WorkItem> {
+    rows.retain(|row| row.next_retry_at.is_none_or(|at| at <= now));
+    rows
+}
+
 /// Wait `duration_ms`, returning early if shutdown is signalled, so the loop
 /// never sits out a full cooldown or poll interval after a Ctrl-C. Only wakes
 /// the wait — the loop's own `shutdown.borrow()` checks are what exit it.
@@ -87,10 +124,31 @@ async fn sleep_or_shutdown(duration_ms: u64, shutdown: &mut ShutdownRx) {
 }
 
 impl BridgeOrchestrator {
+    /// Takes the single-writer lease on the configured store; fails if
+    /// another orchestrator holds it. Under leader election the store is
+    /// shared with the other candidate, and the lease waits for leadership;
+    /// a schema that is behind is first migrated under the lease, which
+    /// fails, naming the holder, if the other candidate is running.
     pub fn new(cfg: Config) -> Result<Self> {
-        let db = StateStore::open(&cfg.db_path)?;
+        let (db, lease) = if cfg.leader_election.is_some() {
+            if !crate::store::schema_plan(&cfg)?.steps.is_empty() {
+                drop(WriterLease::open(&cfg)?);
+            }
+            (crate::store::open_shared(&cfg)?, None)
+        } else {
+            let lease = WriterLease::open(&cfg)?;
+            (Arc::clone(lease.store()), Some(lease))
+        };
+        if let Some(sqlite) = db.as_sqlite() {
+            crate::doctor::startup_check(sqlite, cfg.sqlite.startup_check)?;
+        }
         let reporter = ReporterState::new();
-        Ok(Self { cfg, db, reporter })
+        Ok(Self {
+            cfg,
+            db,
+            reporter,
+            lease,
+        })
     }
 
     /// Timestamp in milliseconds. Wrapped so we can keep every
@@ -220,14 +278,62 @@ impl BridgeOrchestrator {
             );
         }
 
+        // Keep the writer lease's heartbeat row fresh so a refused
+        // process (or an operator) can see this one is alive.
+        if let Some(lease) = &self.lease {
+            drop(lease.spawn_heartbeat());
+        }
+
+        // Under leader election, campaign for the lease; dropping the
+        // candidacy on the way out hands it straight to the standby.
+        let candidacy = match self.cfg.leader_election.clone() {
+            Some(le) => {
+                let backend: Arc<dyn LeaseBackend> = match &self.cfg.storage {
+                    StorageBackend::Sqlite => Arc::new(SqliteLeaseBackend::open(&le.lease_path)?),
+                    #[cfg(feature = "postgres")]
+                    StorageBackend::Postgres { url } => {
+                        Arc::new(crate::postgres::PostgresLeaseBackend::connect(url)?)
+                    }
+                };
+                info!(
+                    candidate = %le.candidate_id,
+                    lease_path = %le.lease_path,
+                    ttl_ms = le.ttl_ms,
+                    "[bridge] leader election enabled; starting as standby"
+                );
+                Some(Candidacy::spawn(le, backend))
+            }
+            None => None,
+        };
+        let mut leader_lease: Option<(WriterLease, [JoinHandle<()>; 2])> = None;
+
         // Spawn the retention task. Like the reporter, it runs
         // detached and swallows its own errors — the bridge cycle is
         // never affected. Disabled via `BRIDGE_RETENTION_DISABLED=true`
-        // in which case the task exits immediately.
-        drop(crate::retention::spawn(
-            self.cfg.retention.clone(),
-            self.db.clone(),
-        ));
+        // in which case the task exits immediately. Under leader election
+        // it only runs while this instance leads (see `follow_leadership`).
+        if candidacy.is_none() {
+            drop(crate::retention::spawn(
+                self.cfg.retention.clone(),
+                self.db.clone(),
+            ));
+        }
+        // Periodic snapshots read through their own connection, so they
+        // never take the writer mutex. Off unless `BRIDGE_BACKUP_DIR` is set.
+        if let Some(backup) = &self.cfg.backup {
+            drop(crate::backup::spawn(
+                backup.clone(),
+                self.cfg.db_path.clone(),
+            ));
+        }
+        if let (Some(interval_ms), Some(sqlite)) =
+            (self.cfg.sqlite.checkpoint_interval_ms, self.db.as_sqlite())
+        {
+            drop(crate::doctor::spawn_checkpoints(
+                interval_ms,
+                sqlite.clone(),
+            ));
+        }
 
         let mut shutdown = install_shutdown_handler();
         let backoff = backoff_config(&self.cfg);
@@ -266,8 +372,20 @@ impl BridgeOrchestrator {
                 return Ok(());
             }
 
-            if let Err(e) = lock_flow.run_cycle().await {
-                error!("[lock-flow] cycle failed: {}", e);
+            // A standby writes nothing: it keeps watching for locks, but
+            // recording them and the checkpoint waits for the writer lease
+            // like the bridge cycle.
+            let leading = match &candidacy {
+                Some(candidacy) => self.follow_leadership(candidacy, &mut leader_lease),
+                None => true,
+            };
+
+            if leading {
+                if let Err(e) = lock_flow.run_cycle().await {
+                    error!("[lock-flow] cycle failed: {}", e);
+                }
+            } else if let Err(e) = lock_flow.watch().await {
+                warn!("[lock-flow] standby watch failed: {}", e);
             }
 
             if *shutdown.borrow() {
@@ -275,8 +393,9 @@ impl BridgeOrchestrator {
                 return Ok(());
             }
 
-            if last_bridge_cycle.elapsed()
-                >= Duration::from_millis(self.cfg.bridge_cycle_interval_ms)
+            if leading
+                && last_bridge_cycle.elapsed()
+                    >= Duration::from_millis(self.cfg.bridge_cycle_interval_ms)
             {
                 // Pre-cycle health probe: surfaces dead sockets before we
                 // start a multi-step write sequence. If the probe fails for
@@ -335,13 +454,19 @@ impl BridgeOrchestrator {
                                     h.last_error = Some(err_str.clone());
                                     h.last_error_at_ms = Some(Self::now_ms());
                                 });
-                                if let Err(reset_err) =
-                                    self.db.reset_in_flight_to_queued("lock", &err_str)
-                                {
-                                    error!(
-                                        "[bridge] failed to reset in_flight locks: {}",
-                                        reset_err
-                                    );
+                                let class = retry_class(&e);
+                                for flow in ["lock", "withdraw"] {
+                                    if let Err(reset_err) = self.db.reset_in_flight_to_queued(
+                                        flow,
+                                        class,
+                                        &err_str,
+                                        &self.cfg.retry,
+                                    ) {
+                                        error!(
+                                            "[bridge] failed to reset in_flight {} rows: {}",
+                                            flow, reset_err
+                                        );
+                                    }
                                 }
                                 match classify_cycle_failure(&e) {
                                     CycleFailureAction::Reconnect => {
@@ -526,6 +651,64 @@ impl BridgeOrchestrator {
         }
     }
 
+    /// Under leader election, hold the writer lease — and run its heartbeat
+    /// and the retention task — exactly while this instance leads, and
+    /// return whether it may run the lock watcher and the bridge cycle.
+    /// Taking the lease opens the database as its writer, which returns the
+    /// previous leader's `in_flight` rows to the queue. If the previous
+    /// leader's process still holds it — alive but no longer renewing —
+    /// this instance waits until its heartbeat is [`HEARTBEAT_EXPIRY`] old
+    /// and then takes the lease over from it.
+    fn follow_leadership(
+        &self,
+        candidacy: &Candidacy,
+        held: &mut Option<(WriterLease, [JoinHandle<()>; 2])>,
+    ) -> bool {
+        let leading = candidacy.is_leader();
+        self.reporter.update(|h| h.standby = !leading);
+        if held.as_ref().is_some_and(|(lease, _)| lease.taken_over()) {
+            if let Some((_, tasks)) = held.take() {
+                tasks.iter().for_each(JoinHandle::abort);
+            }
+            warn!("[bridge] writer lease taken over by another instance, standing by");
+            self.reporter.update(|h| h.standby = true);
+            return false;
+        }
+        match (leading, held.is_some()) {
+            (true, true) => true,
+            (true, false) => match WriterLease::open_or_take_over(&self.cfg, HEARTBEAT_EXPIRY) {
+                Ok(lease) => {
+                    info!(
+                        term = candidacy.term(),
+                        "[bridge] leader: writer lease taken, running the bridge cycle"
+                    );
+                    let tasks = [
+                        lease.spawn_heartbeat(),
+                        crate::retention::spawn(self.cfg.retention.clone(), self.db.clone()),
+                    ];
+                    *held = Some((lease, tasks));
+                    true
+                }
+                Err(e) => {
+                    warn!(
+                        term = candidacy.term(),
+                        "[bridge] elected leader but the writer lease is still held: {:#}", e
+                    );
+                    false
+                }
+            },
+            (false, true) => {
+                if let Some((lease, tasks)) = held.take() {
+                    tasks.iter().for_each(JoinHandle::abort);
+                    drop(lease);
+                }
+                warn!("[bridge] no longer leader: writer lease released, standing by");
+                false
+            }
+            (false, false) => false,
+        }
+    }
+
     /// Single unified bridge cycle built as a four-stage pipeline.
     ///
     /// Each lock row's `step` column (see [`WorkStep`]) tracks which zome calls
@@ -618,10 +801,19 @@ impl BridgeOrchestrator {
         // giving operators a single-line picture of the whole cycle.
         let reconcile = self.reconcile_pipeline(&cl_parked_initial, &br_parked_initial)?;
 
-        let s1_rows = self.db.list_pending_by_step("lock", WorkStep::New, 5000)?;
-        let s3_rows_initial =
+        // Rows a failed cycle backed off sit out S1 and S3 until their
+        // `next_retry_at`, so one that keeps failing is not retried every
+        // cycle.
+        let now = Self::now_ms() / 1000;
+        let s1_rows = due(
+            self.db.list_pending_by_step("lock", WorkStep::New, 5000)?,
+            now,
+        );
+        let s3_rows_initial = due(
             self.db
-                .list_pending_by_step("lock", WorkStep::ClRaveExecuted, 5000)?;
+                .list_pending_by_step("lock", WorkStep::ClRaveExecuted, 5000)?,
+            now,
+        );
         let br_spend_pending_initial =
             self.db
                 .list_pending_by_step("lock", WorkStep::BrSpendCreated, 5000)?;
@@ -796,9 +988,11 @@ impl BridgeOrchestrator {
         // Re-list `cl_rave_executed` rows here (not reusing the initial
         // snapshot) because S2 may have just promoted additional rows
         // into this step.
-        let s3_rows = self
-            .db
-            .list_pending_by_step("lock", WorkStep::ClRaveExecuted, 5000)?;
+        let s3_rows = due(
+            self.db
+                .list_pending_by_step("lock", WorkStep::ClRaveExecuted, 5000)?,
+            now,
+        );
         let s3_batch = if s3_rows.is_empty() {
             ProofBatch::default()
         } else {
@@ -890,89 +1084,140 @@ impl BridgeOrchestrator {
             .await?;
 
         let mut coupons_map = serde_json::Map::new();
-        let mut selected_withdrawal_links: Vec<Transaction> = Vec::new();
+        let mut signed_withdrawal_links: Vec<Transaction> = Vec::new();
+        let mut signed_coupons: HashMap<String, SignedCoupon> = HashMap::new();
         let mut deposit_rave_links: Vec<Transaction> = Vec::new();
-        let mut coupon_cumulative_bytes: usize = 0;
         let mut total_withdrawals_found: usize = 0;
-        let mut withdrawal_capped = false;
+        let mut rejected_withdrawals: usize = 0;
+        let mut coupon_failures: usize = 0;
+        let mut withdrawals_held: usize = 0;
+        // Read once per cycle and kept as a `Result`: a bad signer config
+        // fails each withdrawal in `sign_withdrawal_coupon`, not the cycle.
+        let signer_ctx = signer_context_from_env().map_err(|e| format!("{:#}", e));
+        // Same for the fee quote: one gas price per cycle, and an RPC
+        // failure defers withdrawals rather than failing deposits.
+        let fee_quote = self.quote_withdrawal_fee().await;
+        let mut withdrawal_fees: HashMap<String, WithdrawalFee> = HashMap::new();
 
         for tx in &bridging_links {
-            if let TransactionDetails::ParkedSpend {
-                attached_payload, ..
-            } = &tx.details
-            {
-                if attached_payload.get("proof_of_deposit").is_some() {
+            let request = match classify_bridging_link(tx) {
+                BridgingLink::Deposit => {
                     deposit_rave_links.push(tx.clone());
                     continue;
                 }
+                BridgingLink::Unrelated => continue,
+                BridgingLink::Rejected(rejection) => {
+                    rejected_withdrawals += 1;
+                    self.quarantine_withdrawal(&rejection)?;
+                    continue;
+                }
+                BridgingLink::Withdrawal(request) => request,
+            };
+            total_withdrawals_found += 1;
+            if self.db.record_withdrawal_seen(&request.payload())? {
+                info!(
+                    "[bridge/withdrawals] tracking withdrawal {} recipient={} amount={}",
+                    request.link_id, request.recipient, request.amount
+                );
+            }
 
-                if let Some(withdraw_to) = attached_payload
-                    .get("withdraw_to_address")
-                    .and_then(|v| v.as_str())
-                {
-                    total_withdrawals_found += 1;
-
-                    if withdrawal_capped {
-                        continue;
-                    }
+            // A withdrawal backing off after a failure sits out S4 until
+            // its `next_retry_at`, like S1 and S3's rows; one that failed
+            // for good is left alone while its spend stays parked.
+            if !self.db.withdrawal_due(&request.link_id, now)? {
+                withdrawals_held += 1;
+                continue;
+            }
 
-                    let amount = tx
-                        .amount
-                        .get("1")
-                        .map(|v| v.to_string())
-                        .unwrap_or_default();
-
-                    let signer_ctx = signer_context_from_env()?;
-                    let coupon = generate_coupon(&amount, withdraw_to, &signer_ctx).await?;
-                    let key = tx.id.to_string();
-
-                    let entry_bytes = serde_json::to_vec(&json!({ &key: &coupon }))
-                        .map(|v| v.len())
-                        .unwrap_or(0);
-
-                    if coupon_cumulative_bytes + entry_bytes > coupons_budget
-                        && !selected_withdrawal_links.is_empty()
-                    {
-                        withdrawal_capped = true;
-                        info!(
-                            "[bridge/withdrawals] batch: cap reached at {} coupons, coupon_bytes={}",
-                            selected_withdrawal_links.len(),
-                            coupon_cumulative_bytes
+            let (coupon, fee) =
+                match sign_withdrawal_coupon(&signer_ctx, &fee_quote, &request).await {
+                    Ok(signed) => signed,
+                    Err((class, detail)) => {
+                        coupon_failures += 1;
+                        self.db.mark_withdrawal_coupon_failed(
+                            &request.link_id,
+                            class.error_class(),
+                            &format!("{}: {}", class, detail),
+                            &self.cfg.retry,
+                        )?;
+                        warn!(
+                            event = "bridge.s4.coupon_failed",
+                            link_id = %request.link_id,
+                            error_class = %class,
+                            error = %detail,
+                            "[bridge/withdrawals] coupon failed for {} ({}): {}; backing off",
+                            request.link_id,
+                            class,
+                            detail
                         );
                         continue;
                     }
-
-                    coupon_cumulative_bytes += entry_bytes;
-                    coupons_map.insert(key, Value::String(coupon));
-                    selected_withdrawal_links.push(tx.clone());
-
-                    info!(
-                        "[bridge/withdrawals] generating coupon tx_id={:?} recipient={} amount={}",
-                        tx.id, withdraw_to, amount
-                    );
-                }
-            }
+                };
+            let bytes = serde_json::to_vec(&json!({ &request.link_id: &coupon }))
+                .map(|v| v.len())
+                .unwrap_or(0);
+            signed_coupons.insert(
+                request.link_id.clone(),
+                SignedCoupon {
+                    request,
+                    coupon,
+                    fee,
+                    bytes,
+                },
+            );
+            signed_withdrawal_links.push(tx.clone());
         }
 
-        // Build the pooled RAVE link Vec (deposits first, then selected
-        // withdrawals) before applying the optional per-cycle cap. The
-        // deposits-first ordering makes `apply_rave_link_cap` preferentially
-        // defer withdrawals, which is operationally preferable: deposits
-        // already have their HOT-side settled in S3, so finishing their
-        // CL→BR flow unlocks user-facing progress faster than re-batching
+        // Build the pooled RAVE link Vec in the configured order before
+        // applying the coupon byte budget and the optional per-cycle cap, so
+        // the policy decides which links both of them defer. Deposits-first
+        // (the default) favours deposits, whose HOT side already settled in
+        // S3; the other policies keep sustained deposit load from starving
         // withdrawals.
         let pre_cap_deposit_count = deposit_rave_links.len();
-        let pre_cap_withdrawal_count = selected_withdrawal_links.len();
         let deposit_rave_ids: HashSet<String> = deposit_rave_links
             .iter()
             .map(|t| t.id.to_string())
             .collect();
 
-        let mut rave_links: Vec<Transaction> = Vec::new();
-        rave_links.extend(deposit_rave_links.iter().cloned());
-        rave_links.extend(selected_withdrawal_links);
+        let rave_links = order_s4_links(
+            self.cfg.s4_ordering,
+            deposit_rave_links,
+            signed_withdrawal_links,
+            chrono::Utc::now().timestamp_micros(),
+            self.cfg.s4_max_deferral_s,
+        );
+
+        let (rave_links, coupon_cumulative_bytes, deferred_by_budget) =
+            apply_coupon_budget(rave_links, coupons_budget, |t| {
+                signed_coupons.get(&t.id.to_string()).map(|c| c.bytes)
+            });
+        if deferred_by_budget > 0 {
+            info!(
+                "[bridge/withdrawals] batch: cap reached at {} coupons, coupon_bytes={}",
+                rave_links.len() - pre_cap_deposit_count,
+                coupon_cumulative_bytes
+            );
+        }
+        let pre_cap_withdrawal_count = rave_links.len() - pre_cap_deposit_count;
+        for t in &rave_links {
+            let Some(signed) = signed_coupons.remove(&t.id.to_string()) else {
+                continue;
+            };
+            info!(
+                "[bridge/withdrawals] generating coupon tx_id={:?} recipient={} amount={} fee={} ({}) net={}",
+                t.id,
+                signed.request.recipient,
+                signed.request.amount,
+                signed.fee.fee_amount(),
+                signed.fee.model,
+                signed.fee.net_amount()
+            );
+            coupons_map.insert(signed.request.link_id.clone(), Value::String(signed.coupon));
+            withdrawal_fees.insert(signed.request.link_id, signed.fee);
+        }
 
-        let (rave_links, deferred_br_rave) =
+        let (mut rave_links, deferred_br_rave) =
             apply_rave_link_cap(rave_links, self.cfg.rave_max_links);
 
         // Derive the post-cap retained subsets. Every bookkeeping step
@@ -984,7 +1229,7 @@ impl BridgeOrchestrator {
             .filter(|t| deposit_rave_ids.contains(&t.id.to_string()))
             .map(|t| t.id.to_string())
             .collect();
-        let retained_withdrawal_ids: HashSet<String> = rave_links
+        let mut retained_withdrawal_ids: HashSet<String> = rave_links
             .iter()
             .filter(|t| !deposit_rave_ids.contains(&t.id.to_string()))
             .map(|t| t.id.to_string())
@@ -997,7 +1242,8 @@ impl BridgeOrchestrator {
 
         let retained_deposit_count = retained_deposit_ids.len();
         let withdrawal_count = retained_withdrawal_ids.len();
-        let deferred_withdrawals = total_withdrawals_found - withdrawal_count;
+        let deferred_withdrawals =
+            total_withdrawals_found - withdrawals_held - coupon_failures - withdrawal_count;
         let deferred_deposits_by_rave_cap = pre_cap_deposit_count - retained_deposit_count;
         let deferred_withdrawals_by_rave_cap = pre_cap_withdrawal_count - withdrawal_count;
 
@@ -1015,15 +1261,44 @@ impl BridgeOrchestrator {
         }
 
         info!(
-            "[bridge/withdrawals] scan: found={} selected={}/{} coupon_bytes={} deferred={}",
+            "[bridge/withdrawals] scan: found={} selected={}/{} coupon_bytes={} deferred={} held={} rejected={} coupon_failed={}",
             total_withdrawals_found,
             withdrawal_count,
             total_withdrawals_found,
             coupon_cumulative_bytes,
-            deferred_withdrawals
+            deferred_withdrawals,
+            withdrawals_held,
+            rejected_withdrawals,
+            coupon_failures
         );
+        if coupon_failures > 0 {
+            self.reporter.update(|h| {
+                h.coupon_failures_total = h
+                    .coupon_failures_total
+                    .saturating_add(coupon_failures as u32);
+            });
+        }
 
         let consumed_deposit_spend_ids: HashSet<String> = retained_deposit_ids;
+        let mut refused_withdrawal_ids: HashSet<String> = HashSet::new();
+        for link_id in &retained_withdrawal_ids {
+            if let Some(fee) = withdrawal_fees.get(link_id) {
+                if !self.db.mark_withdrawal_coupon_signed(link_id, fee)? {
+                    warn!(
+                        "[bridge/withdrawals] withdrawal {} is no longer pending; leaving it out of the RAVE",
+                        link_id
+                    );
+                    refused_withdrawal_ids.insert(link_id.clone());
+                }
+            }
+        }
+        // A row failed or finished since it was seen (e.g. by `fail`) must
+        // not have its coupon delivered.
+        if !refused_withdrawal_ids.is_empty() {
+            retained_withdrawal_ids.retain(|id| !refused_withdrawal_ids.contains(id));
+            coupons_map.retain(|k, _| !refused_withdrawal_ids.contains(k));
+            rave_links.retain(|t| !refused_withdrawal_ids.contains(&t.id.to_string()));
+        }
 
         let mut succeeded_locks = 0usize;
         if !rave_links.is_empty() {
@@ -1066,6 +1341,10 @@ impl BridgeOrchestrator {
                     }
                 }
             }
+            for link_id in &retained_withdrawal_ids {
+                self.db
+                    .advance_withdrawal_to_rave_executed(link_id, Some(&br_rave_hash))?;
+            }
             // Same rationale as S2: always log after the bridging
             // RAVE ran. A zero here means the RAVE consumed nothing
             // stored by us, which is an orphaned-spend warning sign.
@@ -1079,12 +1358,13 @@ impl BridgeOrchestrator {
 
         let duration_ms = started.elapsed().as_millis() as u64;
         info!(
-            "[bridge/cycle] completed duration={}ms reconcile=(s1={} s2={} s3={} s4={}) s1_written={} s2_advanced={} s3_written={} s4_succeeded={} withdrawals={} capped_cl={} capped_spend={} deferred_cl={} deferred_br_rave={}",
+            "[bridge/cycle] completed duration={}ms reconcile=(s1={} s2={} s3={} s4={} withdrawals={}) s1_written={} s2_advanced={} s3_written={} s4_succeeded={} withdrawals={} capped_cl={} capped_spend={} deferred_cl={} deferred_br_rave={}",
             duration_ms,
             reconcile.s1_advanced,
             reconcile.s2_advanced,
             reconcile.s3_advanced,
             reconcile.s4_advanced,
+            reconcile.withdrawals_advanced,
             s1_batch.ids.len(),
             cl_rave_advanced,
             s3_written,
@@ -1099,6 +1379,49 @@ impl BridgeOrchestrator {
         Ok(())
     }
 
+    /// Resolve this cycle's withdrawal fee quote. Only `gas_indexed` needs
+    /// the network; every other model is a constant.
+    async fn quote_withdrawal_fee(&self) -> Result<FeeQuote, String> {
+        let gas_price_wei = match self.cfg.withdrawal_fee.model {
+            WithdrawalFeeModel::GasIndexed { .. } => current_gas_price_wei(&self.cfg.rpc_url)
+                .await
+                .map_err(|e| format!("{:#}", e))?,
+            _ => 0,
+        };
+        Ok(FeeQuote::new(&self.cfg.withdrawal_fee, gas_price_wei))
+    }
+
+    /// Record a withdrawal that failed validation in the rejection ledger
+    /// so it stays out of every RAVE until an operator deals with it. Warns
+    /// on the first sighting only; the spend stays parked on-chain and is
+    /// re-classified every cycle, so later sightings just bump the ledger.
+    fn quarantine_withdrawal(&self, rejection: &WithdrawalRejection) -> Result<()> {
+        let first_seen = self.db.record_withdrawal_rejection(
+            &rejection.link_id,
+            rejection.reason.as_str(),
+            &rejection.detail,
+            &rejection.payload,
+        )?;
+        if first_seen {
+            warn!(
+                event = "bridge.s4.withdrawal_rejected",
+                link_id = %rejection.link_id,
+                reason = %rejection.reason,
+                detail = %rejection.detail,
+                "[bridge/withdrawals] quarantined parked spend {}: {} ({})",
+                rejection.link_id,
+                rejection.reason,
+                rejection.detail
+            );
+        } else {
+            debug!(
+                "[bridge/withdrawals] skipping quarantined parked spend {} ({})",
+                rejection.link_id, rejection.reason
+            );
+        }
+        Ok(())
+    }
+
     /// Reconcile each lock against live chain truth before running the
     /// pipeline's write stages. Every advancement here is driven by
     /// observing the expected side-effect on `get_parked_links_by_ea` — we
@@ -1121,6 +1444,13 @@ impl BridgeOrchestrator {
     /// * `step='br_spend_created'` and `br_spend_hash` is NOT in the live
     ///   bridging link set → the bridging RAVE consumed the spend. Advance
     ///   to `br_rave_executed` (simultaneously `state='succeeded'`).
+    /// * A `withdraw` row at `coupon_signed` whose parked spend is NOT in
+    ///   the live bridging link set → the bridging RAVE its coupon was
+    ///   handed to consumed it. Advance to `rave_executed` (simultaneously
+    ///   `state='succeeded'`).
+    /// * A `withdraw` row still at `seen` whose parked spend is NOT live →
+    ///   no RAVE ever carried a coupon for it, so the spend was cancelled.
+    ///   Fail it as `spend_cancelled`.
     fn reconcile_pipeline(
         &self,
         cl_parked: &[Transaction],
@@ -1197,6 +1527,30 @@ impl BridgeOrchestrator {
             }
         }
 
+        for (link_id, step) in self.db.list_pending_withdrawal_links(5000)? {
+            if br_live_ids.contains(&link_id) {
+                continue;
+            }
+            if step == WithdrawStep::CouponSigned {
+                debug!(
+                    "[bridge/reconcile] withdrawal={} coupon_signed → rave_executed (parked spend no longer live)",
+                    link_id
+                );
+                self.db
+                    .advance_withdrawal_to_rave_executed(&link_id, None)?;
+                counts.withdrawals_advanced += 1;
+            } else {
+                warn!(
+                    event = "bridge.reconcile.withdrawal_cancelled",
+                    link_id = %link_id,
+                    "[bridge/reconcile] withdrawal={} failed: parked spend gone before a RAVE delivered a coupon for it",
+                    link_id
+                );
+                self.db.mark_withdrawal_cancelled(&link_id)?;
+                counts.withdrawals_cancelled += 1;
+            }
+        }
+
         // One structured summary line per cycle. In production the
         // per-row `info!`s above can be noisy; this single record is
         // the canonical signal for "the reconciler did work this
@@ -1207,6 +1561,8 @@ impl BridgeOrchestrator {
             s2 = counts.s2_advanced,
             s3 = counts.s3_advanced,
             s4 = counts.s4_advanced,
+            withdrawals = counts.withdrawals_advanced,
+            withdrawals_cancelled = counts.withdrawals_cancelled,
             "[bridge/reconcile] cycle summary"
         );
 
@@ -1256,10 +1612,11 @@ impl BridgeOrchestrator {
                         "[bridge/s1] proof extraction failed id={} error={}, abandoning the deposit",
                         item.item_id, e
                     );
-                    if let Err(db_err) = self
-                        .db
-                        .mark_failed_permanent(item.id, &format!("proof extraction failed: {e}"))
-                    {
+                    if let Err(db_err) = self.db.mark_failed_permanent(
+                        item.id,
+                        lock_load_failure_class(&item.task_type, &item.payload_json),
+                        &format!("proof extraction failed: {e}"),
+                    ) {
                         error!(
                             "[bridge] failed to mark lock {} failed: {}",
                             item.id, db_err
@@ -1285,6 +1642,7 @@ impl BridgeOrchestrator {
                     );
                     if let Err(db_err) = self.db.mark_failed_permanent(
                         item.id,
+                        ErrorClass::InvalidPayload,
                         &format!("cl tag size estimation failed: {e}"),
                     ) {
                         error!(
@@ -1351,10 +1709,11 @@ impl BridgeOrchestrator {
                         "[bridge/s3] proof extraction failed id={} error={}, abandoning the deposit",
                         item.item_id, e
                     );
-                    if let Err(db_err) = self
-                        .db
-                        .mark_failed_permanent(item.id, &format!("proof extraction failed: {e}"))
-                    {
+                    if let Err(db_err) = self.db.mark_failed_permanent(
+                        item.id,
+                        lock_load_failure_class(&item.task_type, &item.payload_json),
+                        &format!("proof extraction failed: {e}"),
+                    ) {
                         error!(
                             "[bridge] failed to mark lock {} failed: {}",
                             item.id, db_err
@@ -1380,6 +1739,7 @@ impl BridgeOrchestrator {
                     );
                     if let Err(db_err) = self.db.mark_failed_permanent(
                         item.id,
+                        ErrorClass::InvalidPayload,
                         &format!("spend tag size estimation failed: {e}"),
                     ) {
                         error!(
@@ -1456,6 +1816,7 @@ impl BridgeOrchestrator {
         );
         if let Err(db_err) = self.db.mark_failed_permanent(
             item.id,
+            ErrorClass::TagOversize,
             &format!(
                 "proof exceeds the link tag ceiling (size={alone}, ceiling={LINK_TAG_BYTES_CEILING}, cap={tag_cap})"
             ),
@@ -1469,11 +1830,11 @@ impl BridgeOrchestrator {
     }
 
     fn extract_lock_proof(&self, item: &WorkItem) -> Result<(Value, UnitMap)> {
-        let payload = LockPayload::deserialize(item.payload_json.clone())?;
+        let payload = item.payload()?.into_lock()?;
         let contract_hex = format!("{:x}", self.cfg.lock_vault_address);
-        let depositor = decode_holochain_agent_as_pubkey_string(&payload.holochain_agent)?;
-        let normalized = payload.normalized_amounts()?;
-        let amount = normalized.amount_hot.clone();
+        let depositor =
+            holo_hash::AgentPubKey::from_raw_32(payload.agent_key()?.to_vec()).to_string();
+        let amount = payload.amount_hot.clone();
 
         // Normalize tx_hash to lowercase at the proof boundary so the
         // reconciler's string-keyed lookup is robust to any upstream caller
@@ -1583,77 +1944,8 @@ struct ReconcileCounts {
     s2_advanced: usize,
     s3_advanced: usize,
     s4_advanced: usize,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct LockPayload {
-    lock_id: String,
-    sender: String,
-    #[serde(default)]
-    amount: Option<String>,
-    #[serde(default)]
-    amount_raw_wei: Option<String>,
-    #[serde(default)]
-    amount_hot: Option<String>,
-    holochain_agent: String,
-    tx_hash: String,
-    block_number: u64,
-    timestamp: u64,
-    required_confirmations: u64,
-}
-
-struct NormalizedLockAmount {
-    amount_hot: String,
-}
-
-impl LockPayload {
-    fn normalized_amounts(&self) -> Result<NormalizedLockAmount> {
-        let amount_hot = self
-            .amount_hot
-            .clone()
-            .or_else(|| amount_from_legacy_field(self.amount.clone()))
-            .or_else(|| {
-                let raw = self.amount_raw_wei.as_ref().or(self.amount.as_ref())?;
-                Some(format_amount(raw))
-            })
-            .context("cannot determine amount_hot: no amount_hot, amount, or amount_raw_wei")?;
-        validate_hot_amount(&amount_hot)?;
-        Ok(NormalizedLockAmount { amount_hot })
-    }
-}
-
-fn amount_from_legacy_field(amount: Option<String>) -> Option<String> {
-    let amount = amount?;
-    if amount.contains('.') {
-        return Some(amount);
-    }
-    if !amount.chars().all(|c| c.is_ascii_digit()) {
-        return None;
-    }
-    if amount.len() >= 13 {
-        Some(format_amount(&amount))
-    } else {
-        Some(amount)
-    }
-}
-
-fn validate_hot_amount(amount_hot: &str) -> Result<()> {
-    if amount_hot.is_empty() {
-        anyhow::bail!("amount_hot cannot be empty");
-    }
-    if amount_hot.chars().all(|c| c.is_ascii_digit() || c == '.') {
-        return Ok(());
-    }
-    anyhow::bail!("amount_hot is not a valid numeric string: {}", amount_hot);
-}
-
-fn decode_holochain_agent_as_pubkey_string(agent_hex: &str) -> Result<String> {
-    let bytes = hex::decode(agent_hex.trim_start_matches("0x"))
-        .context("holochain agent key should be a hex string")?;
-    let core_bytes: [u8; 32] = bytes
-        .try_into()
-        .map_err(|v: Vec<u8>| anyhow::anyhow!("expected 32 byte agent key, got {}", v.len()))?;
-    Ok(holo_hash::AgentPubKey::from_raw_32(core_bytes.to_vec()).to_string())
+    withdrawals_advanced: usize,
+    withdrawals_cancelled: usize,
 }
 
 struct DepositContext {
@@ -1685,6 +1977,115 @@ fn apply_rave_link_cap(
     }
 }
 
+/// A withdrawal's coupon, signed in S4 before the byte budget picks which
+/// coupons ride this cycle's RAVE.
+struct SignedCoupon {
+    request: WithdrawalRequest,
+    coupon: String,
+    fee: WithdrawalFee,
+    /// Size of its entry in the `coupons` map.
+    bytes: usize,
+}
+
+/// Keep the ordered S4 links while the withdrawals' coupons fit in
+/// `budget` bytes. The first withdrawal that does not fit, and every one
+/// after it, waits for the next cycle; the first is always kept so an
+/// oversized coupon cannot stall the queue. Deposits, for which
+/// `coupon_bytes` is `None`, carry no coupon and are always kept. Returns
+/// the kept links, the coupon bytes they use, and how many were deferred.
+fn apply_coupon_budget(
+    links: Vec<Transaction>,
+    budget: usize,
+    coupon_bytes: impl Fn(&Transaction) -> Option<usize>,
+) -> (Vec<Transaction>, usize, usize) {
+    let mut used = 0;
+    let mut admitted = 0;
+    let mut capped = false;
+    let mut deferred = 0;
+    let kept = links
+        .into_iter()
+        .filter(|t| {
+            let Some(bytes) = coupon_bytes(t) else {
+                return true;
+            };
+            capped = capped || (used + bytes > budget && admitted > 0);
+            if capped {
+                deferred += 1;
+                return false;
+            }
+            used += bytes;
+            admitted += 1;
+            true
+        })
+        .collect();
+    (kept, used, deferred)
+}
+
+/// Order the pooled S4 links per `policy`, ahead of `apply_coupon_budget`
+/// and `apply_rave_link_cap`.
+/// The result is always a permutation of both inputs — the policy only
+/// decides which links sit past the budget or the cap and wait for the
+/// next cycle.
+/// Sorting is stable, so links with equal timestamps keep the order
+/// `get_parked_links_by_ea` returned them in.
+fn order_s4_links(
+    policy: S4OrderingPolicy,
+    mut deposits: Vec<Transaction>,
+    mut withdrawals: Vec<Transaction>,
+    now_micros: i64,
+    max_deferral_s: u64,
+) -> Vec<Transaction> {
+    let age = |t: &Transaction| t.timestamp.as_micros();
+    match policy {
+        S4OrderingPolicy::DepositsFirst => {
+            deposits.extend(withdrawals);
+            deposits
+        }
+        S4OrderingPolicy::WithdrawalsFirst => {
+            withdrawals.extend(deposits);
+            withdrawals
+        }
+        S4OrderingPolicy::OldestFirst => {
+            deposits.extend(withdrawals);
+            deposits.sort_by_key(age);
+            deposits
+        }
+        S4OrderingPolicy::RoundRobin => {
+            let cutoff =
+                now_micros.saturating_sub((max_deferral_s as i64).saturating_mul(1_000_000));
+            let (mut overdue, mut deposits): (Vec<_>, Vec<_>) =
+                deposits.into_iter().partition(|t| age(t) < cutoff);
+            let (overdue_w, mut withdrawals): (Vec<_>, Vec<_>) =
+                withdrawals.into_iter().partition(|t| age(t) < cutoff);
+            overdue.extend(overdue_w);
+            overdue.sort_by_key(age);
+            deposits.sort_by_key(age);
+            withdrawals.sort_by_key(age);
+
+            // Start the rotation with whichever kind has waited longer.
+            let withdrawal_leads = match (deposits.first(), withdrawals.first()) {
+                (Some(d), Some(w)) => age(w) < age(d),
+                _ => false,
+            };
+            let (first, second) = if withdrawal_leads {
+                (withdrawals, deposits)
+            } else {
+                (deposits, withdrawals)
+            };
+            let mut ordered = overdue;
+            let mut first = first.into_iter();
+            let mut second = second.into_iter();
+            loop {
+                match (first.next(), second.next()) {
+                    (None, None) => break,
+                    (a, b) => ordered.extend(a.into_iter().chain(b)),
+                }
+            }
+            ordered
+        }
+    }
+}
+
 /// One connect path, shared by startup and reconnect.
 async fn connect_ham(cfg: &Config) -> Result<Ham> {
     Ham::connect(
@@ -1887,15 +2288,16 @@ mod tests {
     // Reconciler tests
     //
     // These exercise `BridgeOrchestrator::reconcile_pipeline` end-to-end
-    // against a real SQLite StateStore and synthetic `Transaction`
+    // against a real SQLite SqliteStore and synthetic `Transaction`
     // fixtures standing in for `get_parked_links_by_ea` results. The
     // reconciler is the recovery gate for every crashed/half-retried
     // cycle, so each step transition gets its own positive and (where
     // the transition is step-gated) negative test.
     // -----------------------------------------------------------------
 
-    use crate::config::{Network, RetentionConfig};
-    use alloy::primitives::Address;
+    use crate::config::{Network, RetentionConfig, RetryPolicy, SqliteConfig, WithdrawalFeeConfig};
+    use crate::payload::{LockPayload, WithdrawPayload};
+    use alloy::primitives::{Address, U256};
     use holo_hash::{ActionHash, AgentPubKey, AgentPubKeyB64};
     use holochain_zome_types::timestamp::Timestamp;
     use rave_engine::types::TransactionType;
@@ -1940,23 +2342,39 @@ mod tests {
             ham_pressure_cooldown_max_ms: 90000,
             slow_call_threshold_ms: 35000,
             rave_max_links: None,
+            s4_ordering: S4OrderingPolicy::DepositsFirst,
+            s4_max_deferral_s: 3600,
+            withdrawal_fee: WithdrawalFeeConfig {
+                model: WithdrawalFeeModel::None,
+                max_fee_wei: None,
+            },
             watchtower: None,
             retention: RetentionConfig {
                 enabled: false,
                 tick_interval_ms: 3_600_000,
                 succeeded_max_age_s: 7 * 24 * 60 * 60,
                 failed_max_age_s: 30 * 24 * 60 * 60,
+                batch_size: 500,
+                max_rows_per_tick: None,
+                max_tick_ms: None,
+                archive: None,
             },
+            leader_election: None,
+            storage: StorageBackend::Sqlite,
+            sqlite: SqliteConfig::default(),
+            backup: None,
+            retry: RetryPolicy::default(),
         }
     }
 
     fn test_orchestrator(name: &str) -> BridgeOrchestrator {
         let path = test_db_path(name);
-        let db = StateStore::open(&path).unwrap();
+        let lease = WriterLease::acquire(&path, &SqliteConfig::default()).unwrap();
         BridgeOrchestrator {
             cfg: test_config(path),
-            db,
+            db: lease.store().clone(),
             reporter: ReporterState::new(),
+            lease: Some(lease),
         }
     }
 
@@ -2033,28 +2451,16 @@ mod tests {
     /// `extract_lock_proof` can parse. The `tx_hash` field is what the
     /// reconciler matches against the live-parked map.
     fn enqueue_lock(orch: &BridgeOrchestrator, item_id: &str, tx_hash: &str) -> i64 {
-        // A valid 32-byte hex agent (64 hex chars) so
-        // `decode_holochain_agent_as_pubkey_string` succeeds.
-        let agent_hex = "00".repeat(32);
-        let payload = serde_json::json!({
-            "lock_id": item_id,
-            "sender": "0x0000000000000000000000000000000000000000",
-            "amount_hot": "1.0",
-            "holochain_agent": agent_hex,
-            "tx_hash": tx_hash,
-            "block_number": 1,
-            "timestamp": 0,
-            "required_confirmations": 1,
-        });
+        let payload = LockPayload {
+            tx_hash: tx_hash.to_string(),
+            amount_hot: "1.0".to_string(),
+            ..LockPayload::for_test(item_id)
+        };
+        let key = format!("{}:key", item_id);
         orch.db
-            .enqueue_queued(
-                "lock",
-                "create_parked_link",
-                item_id,
-                &format!("{}:key", item_id),
-                &payload,
-            )
+            .enqueue_detected(item_id, &key, &payload.into())
             .unwrap();
+        assert!(orch.db.move_detected_to_queued(&key).unwrap());
         orch.db
             .list_work_items("lock", crate::state::WorkState::Queued, 1000)
             .unwrap()
@@ -2643,7 +3049,7 @@ mod tests {
             vec![id_oversized],
             "only the proof that cannot be written at all is abandoned"
         );
-        assert_eq!(failed[0].error_class.as_deref(), Some("permanent"));
+        assert_eq!(failed[0].error_class, Some(ErrorClass::TagOversize));
         let error = failed[0].last_error.clone().unwrap();
         assert!(
             error.contains(&LINK_TAG_BYTES_CEILING.to_string()),
@@ -2722,7 +3128,7 @@ mod tests {
             failed.iter().map(|r| r.id).collect::<Vec<_>>(),
             vec![id_oversized]
         );
-        assert_eq!(failed[0].error_class.as_deref(), Some("permanent"));
+        assert_eq!(failed[0].error_class, Some(ErrorClass::TagOversize));
     }
 
     #[test]
@@ -2863,22 +3269,117 @@ mod tests {
         );
     }
 
+    fn link_at(seed: u8, ts_s: i64) -> Transaction {
+        let mut tx = parked_spend_tx(seed, "0x01");
+        tx.timestamp = Timestamp(ts_s * 1_000_000);
+        tx
+    }
+
+    fn seeds(links: &[Transaction]) -> Vec<String> {
+        links.iter().map(|t| t.id.to_string()).collect()
+    }
+
     #[test]
-    fn validate_hot_amount_rejects_non_numeric() {
-        assert!(validate_hot_amount("1.230000").is_ok());
-        assert!(validate_hot_amount("12").is_ok());
-        assert!(validate_hot_amount("bad-value").is_err());
+    fn order_s4_links_applies_each_policy() {
+        let deposits = vec![link_at(10, 300), link_at(11, 400), link_at(12, 500)];
+        let withdrawals = vec![link_at(20, 100), link_at(21, 200)];
+        let now = 1_000 * 1_000_000;
+        let order = |policy| {
+            seeds(&order_s4_links(
+                policy,
+                deposits.clone(),
+                withdrawals.clone(),
+                now,
+                3600,
+            ))
+        };
+        let d = seeds(&deposits);
+        let w = seeds(&withdrawals);
+
+        assert_eq!(
+            order(S4OrderingPolicy::DepositsFirst),
+            [d.clone(), w.clone()].concat()
+        );
+        assert_eq!(
+            order(S4OrderingPolicy::WithdrawalsFirst),
+            [w.clone(), d.clone()].concat()
+        );
+        assert_eq!(
+            order(S4OrderingPolicy::OldestFirst),
+            [w.clone(), d.clone()].concat(),
+            "oldest-first sorts purely by action timestamp"
+        );
+        // Withdrawals have waited longer, so the rotation starts with them
+        // and alternates until the deposits run on alone.
+        assert_eq!(
+            order(S4OrderingPolicy::RoundRobin),
+            vec![
+                w[0].clone(),
+                d[0].clone(),
+                w[1].clone(),
+                d[1].clone(),
+                d[2].clone()
+            ]
+        );
     }
 
     #[test]
-    fn amount_from_legacy_field_converts_wei_like_values() {
+    fn round_robin_promotes_links_past_max_deferral() {
+        // A cap of 2 under plain rotation would keep d0 and w0; the overdue
+        // withdrawal w1 must jump the queue instead.
+        let deposits = vec![link_at(10, 900), link_at(11, 950)];
+        let withdrawals = vec![link_at(20, 920), link_at(21, 100)];
+        let out = order_s4_links(
+            S4OrderingPolicy::RoundRobin,
+            deposits.clone(),
+            withdrawals.clone(),
+            1_000 * 1_000_000,
+            600,
+        );
+        let (kept, deferred) = apply_rave_link_cap(out, Some(2));
+        assert_eq!(deferred, 2);
+        assert_eq!(
+            seeds(&kept),
+            vec![withdrawals[1].id.to_string(), deposits[0].id.to_string()]
+        );
+    }
+
+    #[test]
+    fn the_coupon_budget_is_spent_in_s4_order() {
+        // Parked order lists the oldest withdrawal last, so a budget for two
+        // coupons spent in that order would leave it out under oldest-first.
+        let deposits = vec![link_at(10, 300)];
+        let withdrawals = vec![link_at(20, 500), link_at(21, 400), link_at(22, 100)];
+        let deposit_ids = seeds(&deposits);
+        let coupon_bytes =
+            |t: &Transaction| (!deposit_ids.contains(&t.id.to_string())).then_some(100);
+        let ordered = order_s4_links(
+            S4OrderingPolicy::OldestFirst,
+            deposits.clone(),
+            withdrawals.clone(),
+            1_000 * 1_000_000,
+            3600,
+        );
+
+        let (kept, bytes, deferred) = apply_coupon_budget(ordered, 200, coupon_bytes);
+        assert_eq!((bytes, deferred), (200, 1));
+        let w = seeds(&withdrawals);
+        assert_eq!(
+            seeds(&kept),
+            vec![w[2].clone(), deposits[0].id.to_string(), w[1].clone()],
+            "the newest withdrawal is the one that waits"
+        );
+        let (capped, _) = apply_rave_link_cap(kept, Some(2));
         assert_eq!(
-            amount_from_legacy_field(Some("1000000000000000000".to_string())),
-            Some("1".to_string())
+            seeds(&capped),
+            vec![w[2].clone(), deposits[0].id.to_string()]
         );
+
+        // A coupon bigger than the whole budget still goes out on its own.
+        let (kept, bytes, deferred) = apply_coupon_budget(withdrawals, 50, coupon_bytes);
         assert_eq!(
-            amount_from_legacy_field(Some("2.500000".to_string())),
-            Some("2.500000".to_string())
+            (seeds(&kept), bytes, deferred),
+            (vec![w[0].clone()], 100, 2)
         );
     }
 
@@ -3234,6 +3735,8 @@ mod tests {
                 s2_advanced: 1,
                 s3_advanced: 1,
                 s4_advanced: 1,
+                withdrawals_advanced: 0,
+                withdrawals_cancelled: 0,
             }
         );
 
@@ -3265,6 +3768,125 @@ mod tests {
             .any(|r| r.id == id_s4));
     }
 
+    #[test]
+    fn reconcile_advances_a_withdrawal_only_once_its_spend_is_consumed() {
+        // A withdrawal stays at `coupon_signed` only while the RAVE carrying
+        // its coupon is in flight or has returned; a failed or interrupted
+        // one puts it back at `seen`. So its spend disappearing from the
+        // bridging EA is the chain truth that the RAVE consumed it.
+        let orch = test_orchestrator("reconcile-withdraw");
+        let consumed = parked_spend_tx(0x91, "0xw1");
+        let live = parked_spend_tx(0x92, "0xw2");
+        for tx in [&consumed, &live] {
+            orch.db
+                .record_withdrawal_seen(&WithdrawPayload::for_test(&tx.id.to_string(), "1"))
+                .unwrap();
+            orch.db
+                .mark_withdrawal_coupon_signed(
+                    &tx.id.to_string(),
+                    &WithdrawalFee {
+                        model: "none",
+                        fee_wei: U256::ZERO,
+                        net_wei: U256::from(1u8),
+                    },
+                )
+                .unwrap();
+        }
+
+        let counts = orch
+            .reconcile_pipeline(&[], std::slice::from_ref(&live))
+            .unwrap();
+        assert_eq!(counts.withdrawals_advanced, 1);
+        assert_eq!(
+            orch.db.list_pending_withdrawal_links(10).unwrap(),
+            vec![(live.id.to_string(), WithdrawStep::CouponSigned)]
+        );
+
+        let again = orch.reconcile_pipeline(&[], &[live]).unwrap();
+        assert_eq!(again.withdrawals_advanced, 0);
+    }
+
+    #[test]
+    fn reconcile_fails_a_withdrawal_whose_spend_went_before_its_coupon_was_signed() {
+        // No RAVE ever carried a coupon for a row still at `seen`, so its
+        // spend vanishing means it was cancelled, not bridged.
+        let orch = test_orchestrator("reconcile-withdraw-cancelled");
+        let cancelled = parked_spend_tx(0x93, "0xw3");
+        orch.db
+            .record_withdrawal_seen(&WithdrawPayload::for_test(&cancelled.id.to_string(), "1"))
+            .unwrap();
+
+        let counts = orch.reconcile_pipeline(&[], &[]).unwrap();
+        assert_eq!(
+            (counts.withdrawals_advanced, counts.withdrawals_cancelled),
+            (0, 1)
+        );
+        assert!(orch
+            .db
+            .list_pending_withdrawal_links(10)
+            .unwrap()
+            .is_empty());
+        let failed = orch
+            .db
+            .list_work_items("withdraw", crate::state::WorkState::Failed, 10)
+            .unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].error_class, Some(ErrorClass::SpendCancelled));
+    }
+
+    #[test]
+    fn a_withdrawal_cancelled_after_its_rave_failed_is_not_counted_as_delivered() {
+        // The coupon was signed into a RAVE that then failed. The user
+        // cancelling the spend before the next cycle must read as a
+        // cancel, with no fee collected, not as the RAVE having run.
+        let orch = test_orchestrator("reconcile-withdraw-failed-rave");
+        let spend = parked_spend_tx(0x94, "0xw4");
+        let link_id = spend.id.to_string();
+        orch.db
+            .record_withdrawal_seen(&WithdrawPayload::for_test(&link_id, "1"))
+            .unwrap();
+        orch.db
+            .mark_withdrawal_coupon_signed(
+                &link_id,
+                &WithdrawalFee {
+                    model: "flat",
+                    fee_wei: U256::from(1u8),
+                    net_wei: U256::ZERO,
+                },
+            )
+            .unwrap();
+        orch.db
+            .reset_in_flight_to_queued(
+                "withdraw",
+                ErrorClass::Rpc,
+                "execute_rave failed",
+                &orch.cfg.retry,
+            )
+            .unwrap();
+        assert_eq!(
+            orch.db.list_pending_withdrawal_links(10).unwrap(),
+            vec![(link_id.clone(), WithdrawStep::Seen)]
+        );
+
+        let counts = orch.reconcile_pipeline(&[], &[]).unwrap();
+        assert_eq!(
+            (counts.withdrawals_advanced, counts.withdrawals_cancelled),
+            (0, 1)
+        );
+        let failed = orch
+            .db
+            .list_work_items("withdraw", crate::state::WorkState::Failed, 10)
+            .unwrap();
+        assert_eq!(failed[0].error_class, Some(ErrorClass::SpendCancelled));
+        assert_eq!(
+            orch.db
+                .aggregate_stats()
+                .unwrap()
+                .withdrawals_fee_charged_24h,
+            0
+        );
+    }
+
     #[test]
     fn reconcile_pipeline_returns_zeroed_counts_when_no_rows_advance() {
         // Negative case: an empty DB plus empty live sets must yield
@@ -3568,6 +4190,41 @@ mod tests {
         }
     }
 
+    #[test]
+    fn retry_class_sorts_cycle_failures_into_error_classes() {
+        for (msg, class) in [
+            (
+                "Failed to call zome: Websocket error: Websocket closed: No connection",
+                ErrorClass::Rpc,
+            ),
+            (
+                "Failed to call zome: Source chain error: deadline has elapsed",
+                ErrorClass::SourceChainPressure,
+            ),
+            (
+                "Failed to call zome: Websocket error: Timeout",
+                ErrorClass::ConductorTimeout,
+            ),
+            ("some entirely unexpected failure", ErrorClass::Unclassified),
+        ] {
+            assert_eq!(retry_class(&anyhow::anyhow!("{msg}")), class);
+        }
+    }
+
+    #[test]
+    fn backed_off_rows_sit_out_a_stage_until_they_are_due() {
+        let orch = test_orchestrator("due-rows");
+        let id_due = enqueue_lock(&orch, "lock:due:a", "0xf1");
+        let id_later = enqueue_lock(&orch, "lock:due:b", "0xf2");
+        let mut rows = pending_rows(&orch);
+        rows[0].next_retry_at = Some(100);
+        rows[1].next_retry_at = Some(101);
+
+        let ids = |rows: Vec<WorkItem>| rows.into_iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids(due(rows.clone(), 100)), vec![id_due]);
+        assert_eq!(ids(due(rows, 101)), vec![id_due, id_later]);
+    }
+
     // -----------------------------------------------------------------
     // Shared cooldown-helper tests
     //
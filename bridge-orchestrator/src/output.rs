@@ -0,0 +1,892 @@
+//! How the CLI prints what it found, chosen with `--output`.
+//!
+//! - `json` (the default): one JSON object per line, exactly as before, for
+//!   scripts.
+//! - `table`: aligned columns for people. Amounts are in HOT, times are
+//!   relative ("3h ago"), steps show how far through their flow they are,
+//!   and hashes and long errors are shortened.
+//! - `csv`: RFC 4180, with a header row, every value in full and times in
+//!   RFC 3339 UTC, for spreadsheets.
+//!
+//! Anything serializable prints through [`Record`]'s defaults, flattening
+//! nested objects into dotted columns. The listings people read most
+//! (`status`, `history`, `rejections`) override them with views of their
+//! own.
+
+use std::io::Write;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::report::DailyReport;
+use crate::state::{
+    BridgeAggregateStats, DeadLetter, LockRefund, LookupRow, StatusRow, StatusSummary,
+    WithdrawStep, WithdrawalRejectionRow, WorkItemEvent, WorkStep,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Table,
+    Csv,
+}
+
+/// Named cells, in column order.
+pub type Cells = Vec<(String, String)>;
+
+/// Something the CLI prints. JSON is always the serde form.
+pub trait Record: Serialize {
+    /// Cells for CSV: full values. By default the serde form, flattened.
+    fn csv_cells(&self) -> Cells {
+        let mut cells = Vec::new();
+        flatten(
+            "",
+            &serde_json::to_value(self).unwrap_or(Value::Null),
+            &mut cells,
+        );
+        cells
+    }
+
+    /// Cells for the table, at `now` (unix seconds). By default the CSV
+    /// cells.
+    fn table_cells(&self, _now: i64) -> Cells {
+        self.csv_cells()
+    }
+}
+
+impl Record for Value {}
+
+pub struct Printer {
+    format: OutputFormat,
+    now: i64,
+}
+
+impl Printer {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            now: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// A listing: a JSON line per record, or one table / CSV.
+    pub fn rows<T: Record>(&self, rows: &[T]) -> Result<()> {
+        let mut out = std::io::stdout().lock();
+        match self.format {
+            OutputFormat::Json => {
+                for row in rows {
+                    writeln!(out, "{}", serde_json::to_string(row)?)?;
+                }
+            }
+            OutputFormat::Table => {
+                let cells: Vec<Cells> = rows.iter().map(|r| r.table_cells(self.now)).collect();
+                out.write_all(render_table(&cells).as_bytes())?;
+            }
+            OutputFormat::Csv => {
+                let cells: Vec<Cells> = rows.iter().map(Record::csv_cells).collect();
+                out.write_all(render_csv(&cells).as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A single result, such as a report. The table lists it one field per
+    /// line; CSV is a header and one row.
+    pub fn one<T: Record>(&self, record: &T) -> Result<()> {
+        if self.format != OutputFormat::Table {
+            return self.rows(std::slice::from_ref(record));
+        }
+        let fields: Vec<Cells> = record
+            .table_cells(self.now)
+            .into_iter()
+            .map(|(field, value)| vec![("field".to_string(), field), ("value".to_string(), value)])
+            .collect();
+        std::io::stdout().write_all(render_table(&fields).as_bytes())?;
+        Ok(())
+    }
+
+    /// A report with no view of its own: as itself in JSON, otherwise as
+    /// [`Printer::one`] prints its flattened fields.
+    pub fn report<T: Serialize>(&self, report: &T) -> Result<()> {
+        match self.format {
+            OutputFormat::Json => {
+                println!("{}", serde_json::to_string(report)?);
+                Ok(())
+            }
+            _ => self.one(&serde_json::to_value(report)?),
+        }
+    }
+
+    /// `record` as JSON, or `rows` as a table / CSV: for a record whose
+    /// JSON nests what reads better as rows.
+    pub fn nested<T: Serialize, R: Record>(&self, record: &T, rows: &[R]) -> Result<()> {
+        match self.format {
+            OutputFormat::Json => {
+                println!("{}", serde_json::to_string(record)?);
+                Ok(())
+            }
+            _ => self.rows(rows),
+        }
+    }
+}
+
+/// Aligned columns under a header, two spaces apart. Columns come from the
+/// first row.
+fn render_table(rows: &[Cells]) -> String {
+    let Some(first) = rows.first() else {
+        return "(no rows)\n".to_string();
+    };
+    let header: Vec<String> = first.iter().map(|(name, _)| name.to_uppercase()).collect();
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, (_, cell)) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        format!("{}\n", padded.join("  ").trim_end())
+    };
+    let mut out = line(header.iter().map(String::as_str).collect());
+    for row in rows {
+        out.push_str(&line(row.iter().map(|(_, cell)| cell.as_str()).collect()));
+    }
+    out
+}
+
+/// A header row from the first row's names, then every row, CRLF-terminated
+/// as RFC 4180 has it. Nothing at all for no rows.
+fn render_csv(rows: &[Cells]) -> String {
+    let Some(first) = rows.first() else {
+        return String::new();
+    };
+    let line = |cells: Vec<&str>| {
+        let quoted: Vec<String> = cells.into_iter().map(csv_field).collect();
+        format!("{}\r\n", quoted.join(","))
+    };
+    let mut out = line(first.iter().map(|(name, _)| name.as_str()).collect());
+    for row in rows {
+        out.push_str(&line(row.iter().map(|(_, cell)| cell.as_str()).collect()));
+    }
+    out
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Nested objects become `parent.child` columns, in key order; arrays stay
+/// JSON; null is empty.
+fn flatten(prefix: &str, value: &Value, cells: &mut Cells) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&name, value, cells);
+            }
+        }
+        Value::Null => cells.push((prefix.to_string(), String::new())),
+        Value::String(s) => cells.push((prefix.to_string(), s.clone())),
+        other => cells.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+fn cell(name: &str, value: impl ToString) -> (String, String) {
+    (name.to_string(), value.to_string())
+}
+
+fn opt(value: &Option<String>) -> String {
+    value.clone().unwrap_or_default()
+}
+
+/// `ts` as RFC 3339 UTC.
+fn iso(ts: i64) -> String {
+    chrono::DateTime::from_timestamp(ts, 0)
+        .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .unwrap_or_else(|| ts.to_string())
+}
+
+fn iso_opt(ts: Option<i64>) -> String {
+    ts.map(iso).unwrap_or_default()
+}
+
+/// `secs` in its largest whole unit: `45s`, `3h`.
+fn span(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3_599 => format!("{}m", secs / 60),
+        3_600..=86_399 => format!("{}h", secs / 3_600),
+        _ => format!("{}d", secs / 86_400),
+    }
+}
+
+/// How long before or after `now` `ts` is: `45s ago`, `3h ago`, `in 5m`.
+fn relative(ts: i64, now: i64) -> String {
+    let delta = now - ts;
+    let amount = span(delta.unsigned_abs());
+    if delta >= 0 {
+        format!("{} ago", amount)
+    } else {
+        format!("in {}", amount)
+    }
+}
+
+fn relative_opt(ts: Option<i64>, now: i64) -> String {
+    ts.map(|ts| relative(ts, now)).unwrap_or_default()
+}
+
+/// The first and last few characters of a hash, address or agent key.
+fn short_hash(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= 16 {
+        return value.to_string();
+    }
+    let head: String = chars[..8].iter().collect();
+    let tail: String = chars[chars.len() - 6..].iter().collect();
+    format!("{}…{}", head, tail)
+}
+
+/// At most `max` characters of free text, on one line.
+fn short_text(value: &str, max: usize) -> String {
+    let flat = value.replace(['\r', '\n'], " ");
+    if flat.chars().count() <= max {
+        return flat;
+    }
+    let kept: String = flat.chars().take(max - 1).collect();
+    format!("{}…", kept)
+}
+
+/// `step` with how far through its flow it is: `cl_rave_executed 3/5`.
+fn step_progress(flow: &str, step: &str) -> String {
+    const LOCK: [WorkStep; 5] = [
+        WorkStep::New,
+        WorkStep::ClLinkCreated,
+        WorkStep::ClRaveExecuted,
+        WorkStep::BrSpendCreated,
+        WorkStep::BrRaveExecuted,
+    ];
+    // `claimed` is reserved, so a delivered withdrawal is complete.
+    const WITHDRAW: [WithdrawStep; 3] = [
+        WithdrawStep::Seen,
+        WithdrawStep::CouponSigned,
+        WithdrawStep::RaveExecuted,
+    ];
+    let position = match flow {
+        "lock" => step
+            .parse::<WorkStep>()
+            .ok()
+            .and_then(|s| LOCK.iter().position(|l| *l == s))
+            .map(|i| (i, LOCK.len())),
+        "withdraw" => step
+            .parse::<WithdrawStep>()
+            .ok()
+            .and_then(|s| WITHDRAW.iter().position(|w| *w == s))
+            .map(|i| (i, WITHDRAW.len())),
+        _ => None,
+    };
+    match position {
+        Some((i, len)) => format!("{} {}/{}", step, i + 1, len),
+        None => step.to_string(),
+    }
+}
+
+impl Record for StatusRow {
+    fn csv_cells(&self) -> Cells {
+        vec![
+            cell("id", self.id),
+            cell("item_id", &self.item_id),
+            cell("flow", &self.flow),
+            cell("task_type", &self.task_type),
+            cell("direction", opt(&self.direction)),
+            cell("transfer_type", opt(&self.transfer_type)),
+            cell("amount_hot", opt(&self.amount_raw)),
+            cell("fee_hot", opt(&self.fee)),
+            cell("beneficiary", opt(&self.beneficiary)),
+            cell("counterparty", opt(&self.counterparty)),
+            cell("status", self.status.to_string()),
+            cell("step", &self.step),
+            cell("attempts", self.attempts),
+            cell("max_attempts", self.max_attempts),
+            cell(
+                "error_class",
+                self.error_class.map(|c| c.to_string()).unwrap_or_default(),
+            ),
+            cell("last_error", opt(&self.last_error)),
+            cell("created_at", iso(self.created_at)),
+            cell("updated_at", iso(self.updated_at)),
+            cell("last_attempt_at", iso_opt(self.last_attempt_at)),
+            cell("next_retry_at", iso_opt(self.next_retry_at)),
+            cell(
+                "recent_errors",
+                if self.recent_errors.is_empty() {
+                    String::new()
+                } else {
+                    serde_json::to_string(&self.recent_errors).unwrap_or_default()
+                },
+            ),
+        ]
+    }
+
+    fn table_cells(&self, now: i64) -> Cells {
+        vec![
+            cell("item", short_hash(&self.item_id)),
+            cell("status", self.status.to_string()),
+            cell("step", step_progress(&self.flow, &self.step)),
+            cell("hot", opt(&self.amount_raw)),
+            cell("fee", opt(&self.fee)),
+            cell(
+                "counterparty",
+                self.counterparty
+                    .as_deref()
+                    .map(short_hash)
+                    .unwrap_or_default(),
+            ),
+            cell("tries", format!("{}/{}", self.attempts, self.max_attempts)),
+            cell("created", relative(self.created_at, now)),
+            cell("updated", relative(self.updated_at, now)),
+            cell("retry", relative_opt(self.next_retry_at, now)),
+            cell(
+                "error",
+                match (&self.error_class, &self.last_error) {
+                    (Some(class), Some(error)) => short_text(&format!("{}: {}", class, error), 48),
+                    (None, Some(error)) => short_text(error, 48),
+                    (Some(class), None) => class.to_string(),
+                    (None, None) => String::new(),
+                },
+            ),
+        ]
+    }
+}
+
+impl Record for LookupRow {
+    fn csv_cells(&self) -> Cells {
+        let mut cells = self.row.csv_cells();
+        cells.extend([
+            cell("cl_link_hash", opt(&self.cl_link_hash)),
+            cell("cl_rave_hash", opt(&self.cl_rave_hash)),
+            cell("br_spend_hash", opt(&self.br_spend_hash)),
+            cell("br_rave_hash", opt(&self.br_rave_hash)),
+            cell(
+                "coupon",
+                self.coupon.map(|c| c.to_string()).unwrap_or_default(),
+            ),
+        ]);
+        cells
+    }
+
+    /// The latest action the item recorded stands in for all four.
+    fn table_cells(&self, now: i64) -> Cells {
+        let row = &self.row;
+        let latest_action = [
+            &self.br_rave_hash,
+            &self.br_spend_hash,
+            &self.cl_rave_hash,
+            &self.cl_link_hash,
+        ]
+        .into_iter()
+        .find_map(|hash| hash.as_deref());
+        vec![
+            cell("item", short_hash(&row.item_id)),
+            cell("status", row.status.to_string()),
+            cell("step", step_progress(&row.flow, &row.step)),
+            cell("hot", opt(&row.amount_raw)),
+            cell(
+                "counterparty",
+                row.counterparty
+                    .as_deref()
+                    .map(short_hash)
+                    .unwrap_or_default(),
+            ),
+            cell(
+                "beneficiary",
+                row.beneficiary
+                    .as_deref()
+                    .map(short_hash)
+                    .unwrap_or_default(),
+            ),
+            cell("action", latest_action.map(short_hash).unwrap_or_default()),
+            cell(
+                "coupon",
+                self.coupon.map(|c| c.to_string()).unwrap_or_default(),
+            ),
+            cell("created", relative(row.created_at, now)),
+        ]
+    }
+}
+
+impl Record for DeadLetter {
+    fn csv_cells(&self) -> Cells {
+        let mut cells = self.row.csv_cells();
+        cells.extend([
+            cell("replay_state", self.replay_state.to_string()),
+            cell("payload_error", opt(&self.payload_error)),
+            cell(
+                "refund",
+                self.refund.map(|r| r.to_string()).unwrap_or_default(),
+            ),
+        ]);
+        cells
+    }
+
+    fn table_cells(&self, now: i64) -> Cells {
+        let row = &self.row;
+        vec![
+            cell("item", short_hash(&row.item_id)),
+            cell("step", step_progress(&row.flow, &row.step)),
+            cell("hot", opt(&row.amount_raw)),
+            cell("tries", format!("{}/{}", row.attempts, row.max_attempts)),
+            cell("failed", relative(row.updated_at, now)),
+            cell(
+                "class",
+                row.error_class.map(|c| c.to_string()).unwrap_or_default(),
+            ),
+            cell(
+                "refund",
+                self.refund.map(|r| r.to_string()).unwrap_or_default(),
+            ),
+            cell(
+                "replay",
+                match &self.payload_error {
+                    Some(error) => short_text(&format!("refused: {}", error), 48),
+                    None => self.replay_state.to_string(),
+                },
+            ),
+        ]
+    }
+}
+
+impl Record for LockRefund {
+    fn csv_cells(&self) -> Cells {
+        vec![
+            cell("item_id", &self.item_id),
+            cell("status", self.status),
+            cell(
+                "error_class",
+                self.error_class.map(|c| c.to_string()).unwrap_or_default(),
+            ),
+            cell("recipient", opt(&self.recipient)),
+            cell("amount", opt(&self.amount)),
+            cell("terms_error", opt(&self.terms_error)),
+            cell("approved_by", opt(&self.approved_by)),
+            cell("approved_at", iso_opt(self.approved_at)),
+            cell("coupon", opt(&self.coupon)),
+            cell("coupon_signed_at", iso_opt(self.coupon_signed_at)),
+            cell("claim_tx_hash", opt(&self.claim_tx_hash)),
+            cell("reported_at", iso_opt(self.reported_at)),
+            cell("last_error", opt(&self.last_error)),
+            cell("created_at", iso(self.created_at)),
+            cell("updated_at", iso(self.updated_at)),
+            cell(
+                "step",
+                self.step
+                    .as_ref()
+                    .map(|s| s.to_string())
+                    .unwrap_or_default(),
+            ),
+            cell("cl_rave_hash", opt(&self.cl_rave_hash)),
+        ]
+    }
+
+    fn table_cells(&self, now: i64) -> Cells {
+        vec![
+            cell("item", &self.item_id),
+            cell("status", self.status),
+            cell(
+                "class",
+                self.error_class.map(|c| c.to_string()).unwrap_or_default(),
+            ),
+            cell("hot", opt(&self.amount)),
+            cell(
+                "to",
+                self.recipient
+                    .as_deref()
+                    .map(short_hash)
+                    .unwrap_or_default(),
+            ),
+            cell("updated", relative(self.updated_at, now)),
+            cell(
+                "note",
+                match (&self.terms_error, &self.last_error, &self.claim_tx_hash) {
+                    (Some(error), _, _) => short_text(&format!("refused: {}", error), 48),
+                    (None, _, Some(tx)) => format!("claimed in {}", short_hash(tx)),
+                    (None, Some(error), None) => short_text(error, 48),
+                    (None, None, None) => String::new(),
+                },
+            ),
+        ]
+    }
+}
+
+impl Record for WorkItemEvent {
+    fn csv_cells(&self) -> Cells {
+        vec![
+            cell("id", self.id),
+            cell("item_id", &self.item_id),
+            cell("from_state", opt(&self.from_state)),
+            cell("to_state", &self.to_state),
+            cell("from_step", opt(&self.from_step)),
+            cell("to_step", &self.to_step),
+            cell("reason", opt(&self.reason)),
+            cell("attempt", self.attempt),
+            cell("action_hash", opt(&self.action_hash)),
+            cell("actor", &self.actor),
+            cell("created_at", iso(self.created_at)),
+        ]
+    }
+
+    fn table_cells(&self, now: i64) -> Cells {
+        let change = |from: &Option<String>, to: &str| match from {
+            Some(from) if from != to => format!("{} → {}", from, to),
+            Some(_) => to.to_string(),
+            None => format!("→ {}", to),
+        };
+        vec![
+            cell("when", relative(self.created_at, now)),
+            cell("state", change(&self.from_state, &self.to_state)),
+            cell("step", change(&self.from_step, &self.to_step)),
+            cell("attempt", self.attempt),
+            cell(
+                "action",
+                self.action_hash
+                    .as_deref()
+                    .map(short_hash)
+                    .unwrap_or_default(),
+            ),
+            cell("actor", &self.actor),
+            cell(
+                "reason",
+                self.reason
+                    .as_deref()
+                    .map(|r| short_text(r, 60))
+                    .unwrap_or_default(),
+            ),
+        ]
+    }
+}
+
+impl Record for WithdrawalRejectionRow {
+    fn csv_cells(&self) -> Cells {
+        vec![
+            cell("link_id", &self.link_id),
+            cell("reason", &self.reason),
+            cell("detail", &self.detail),
+            cell("seen_count", self.seen_count),
+            cell("first_seen_at", iso(self.first_seen_at)),
+            cell("last_seen_at", iso(self.last_seen_at)),
+            cell("payload_json", self.payload_json.to_string()),
+        ]
+    }
+
+    fn table_cells(&self, now: i64) -> Cells {
+        vec![
+            cell("link", short_hash(&self.link_id)),
+            cell("reason", &self.reason),
+            cell("seen", self.seen_count),
+            cell("first_seen", relative(self.first_seen_at, now)),
+            cell("last_seen", relative(self.last_seen_at, now)),
+            cell("detail", short_text(&self.detail, 60)),
+        ]
+    }
+}
+
+impl Record for DailyReport {
+    fn csv_cells(&self) -> Cells {
+        let secs = |s: Option<i64>| s.map(|s| s.to_string()).unwrap_or_default();
+        vec![
+            cell("date", &self.date),
+            cell("locks_detected", self.locks_detected),
+            cell("hot_locked", &self.hot_locked),
+            cell("locks_bridged", self.locks_bridged),
+            cell("hot_bridged", &self.hot_bridged),
+            cell("locks_failed", self.locks_failed),
+            cell("withdrawals_seen", self.withdrawals_seen),
+            cell("coupons_issued", self.coupons_issued),
+            cell("hot_withdrawn", &self.hot_withdrawn),
+            cell("fees", &self.fees),
+            cell("withdrawals_failed", self.withdrawals_failed),
+            cell("lock_latency_p50_s", secs(self.lock_latency_p50_s)),
+            cell("lock_latency_p90_s", secs(self.lock_latency_p90_s)),
+            cell("lock_latency_p99_s", secs(self.lock_latency_p99_s)),
+            cell("withdraw_latency_p50_s", secs(self.withdraw_latency_p50_s)),
+            cell("withdraw_latency_p90_s", secs(self.withdraw_latency_p90_s)),
+            cell("withdraw_latency_p99_s", secs(self.withdraw_latency_p99_s)),
+        ]
+    }
+
+    /// Counts as detected/done/failed, latencies as p50/p99.
+    fn table_cells(&self, _now: i64) -> Cells {
+        let latency = |p50: Option<i64>, p99: Option<i64>| match (p50, p99) {
+            (Some(p50), Some(p99)) => {
+                format!("{}/{}", span(p50.unsigned_abs()), span(p99.unsigned_abs()))
+            }
+            _ => String::new(),
+        };
+        vec![
+            cell("date", &self.date),
+            cell(
+                "locks",
+                format!(
+                    "{}/{}/{}",
+                    self.locks_detected, self.locks_bridged, self.locks_failed
+                ),
+            ),
+            cell("hot_locked", &self.hot_locked),
+            cell("hot_bridged", &self.hot_bridged),
+            cell(
+                "withdrawals",
+                format!(
+                    "{}/{}/{}",
+                    self.withdrawals_seen, self.coupons_issued, self.withdrawals_failed
+                ),
+            ),
+            cell("hot_withdrawn", &self.hot_withdrawn),
+            cell("fees", &self.fees),
+            cell(
+                "lock_latency",
+                latency(self.lock_latency_p50_s, self.lock_latency_p99_s),
+            ),
+            cell(
+                "withdraw_latency",
+                latency(self.withdraw_latency_p50_s, self.withdraw_latency_p99_s),
+            ),
+        ]
+    }
+}
+
+/// One stage's latencies in one window of [`BridgeAggregateStats`], as a
+/// row.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StageLine {
+    pub window: &'static str,
+    pub stage: &'static str,
+    pub count: i64,
+    pub p50_s: Option<i64>,
+    pub p90_s: Option<i64>,
+    pub p99_s: Option<i64>,
+}
+
+impl Record for StageLine {
+    fn csv_cells(&self) -> Cells {
+        let secs = |s: Option<i64>| s.map(|s| s.to_string()).unwrap_or_default();
+        vec![
+            cell("window", self.window),
+            cell("stage", self.stage),
+            cell("count", self.count),
+            cell("p50_s", secs(self.p50_s)),
+            cell("p90_s", secs(self.p90_s)),
+            cell("p99_s", secs(self.p99_s)),
+        ]
+    }
+
+    fn table_cells(&self, _now: i64) -> Cells {
+        let secs = |s: Option<i64>| s.map(|s| span(s.unsigned_abs())).unwrap_or_default();
+        vec![
+            cell("window", self.window),
+            cell("stage", self.stage),
+            cell("count", self.count),
+            cell("p50", secs(self.p50_s)),
+            cell("p90", secs(self.p90_s)),
+            cell("p99", secs(self.p99_s)),
+        ]
+    }
+}
+
+/// The stage latencies of `stats` as rows: the last hour's stages, then
+/// the last 24h's.
+pub fn stage_lines(stats: &BridgeAggregateStats) -> Vec<StageLine> {
+    [
+        ("1h", &stats.stage_latencies_1h),
+        ("24h", &stats.stage_latencies_24h),
+    ]
+    .into_iter()
+    .flat_map(|(window, latencies)| {
+        latencies.stages().map(|(stage, latency)| StageLine {
+            window,
+            stage,
+            count: latency.count,
+            p50_s: latency.p50_s,
+            p90_s: latency.p90_s,
+            p99_s: latency.p99_s,
+        })
+    })
+    .collect()
+}
+
+/// One group of a [`StatusSummary`], as a row.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SummaryLine {
+    pub group: &'static str,
+    pub key: String,
+    pub count: i64,
+    pub hot_total: String,
+}
+
+impl Record for SummaryLine {}
+
+/// `summary` as rows: every flow, then every state, then every step, with
+/// the overall row count first.
+pub fn summary_lines(summary: &StatusSummary) -> Vec<SummaryLine> {
+    let mut lines = vec![SummaryLine {
+        group: "all",
+        key: "rows".to_string(),
+        count: summary.rows,
+        hot_total: String::new(),
+    }];
+    for (group, buckets) in [
+        ("flow", &summary.by_flow),
+        ("state", &summary.by_state),
+        ("step", &summary.by_step),
+    ] {
+        lines.extend(buckets.iter().map(|(key, bucket)| SummaryLine {
+            group,
+            key: key.clone(),
+            count: bucket.count,
+            hot_total: bucket.hot_total.clone(),
+        }));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{ErrorClass, RecentError, SummaryBucket, WorkState};
+
+    fn status_row() -> StatusRow {
+        StatusRow {
+            id: 7,
+            flow: "lock".to_string(),
+            task_type: "create_parked_link".to_string(),
+            item_id: "lock:7".to_string(),
+            direction: Some("transfer_in".to_string()),
+            transfer_type: Some("lock".to_string()),
+            amount_raw: Some("2.500000".to_string()),
+            beneficiary: Some(format!("0x{}", "ab".repeat(32))),
+            counterparty: Some("0x1111111111111111111111111111111111111111".to_string()),
+            fee: None,
+            step: "cl_rave_executed".to_string(),
+            status: WorkState::Queued,
+            attempts: 1,
+            max_attempts: 8,
+            next_retry_at: Some(1_000_300),
+            last_attempt_at: None,
+            error_class: Some(ErrorClass::ConductorTimeout),
+            last_error: Some("timed out,\nretrying".to_string()),
+            recent_errors: vec![RecentError {
+                at: 1_000_000 - 330,
+                class: ErrorClass::ConductorTimeout,
+                error: "timed out,\nretrying".to_string(),
+            }],
+            created_at: 1_000_000 - 7_200,
+            updated_at: 1_000_000 - 30,
+        }
+    }
+
+    #[test]
+    fn the_status_table_is_for_reading() {
+        let cells = status_row().table_cells(1_000_000);
+        let get = |name: &str| cells.iter().find(|(n, _)| n == name).unwrap().1.as_str();
+        assert_eq!(get("step"), "cl_rave_executed 3/5");
+        assert_eq!(get("hot"), "2.500000");
+        assert_eq!(get("counterparty"), "0x111111…111111");
+        assert_eq!(get("tries"), "1/8");
+        assert_eq!(get("created"), "2h ago");
+        assert_eq!(get("updated"), "30s ago");
+        assert_eq!(get("retry"), "in 5m");
+        assert_eq!(get("error"), "conductor_timeout: timed out, retrying");
+    }
+
+    #[test]
+    fn status_csv_keeps_full_values_and_quotes_them() {
+        let csv = render_csv(&[status_row().csv_cells()]);
+        let mut lines = csv.split("\r\n");
+        assert!(lines.next().unwrap().starts_with("id,item_id,flow,"));
+        let row = lines.next().unwrap();
+        assert!(row.contains(",0x1111111111111111111111111111111111111111,"));
+        assert!(row.contains(",\"timed out,\nretrying\","));
+        assert!(row.contains(",1970-01-12T11:46:40Z,"), "{}", row);
+        assert!(
+            row.ends_with(r#","[{""at"":999670,""class"":""conductor_timeout"",""error"":""timed out,\nretrying""}]""#),
+            "{}",
+            row
+        );
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(render_csv(&[]), "");
+    }
+
+    #[test]
+    fn tables_align_columns_under_the_header() {
+        let rows = vec![
+            vec![cell("a", "x"), cell("bb", "1")],
+            vec![cell("a", "longer"), cell("bb", "22")],
+        ];
+        assert_eq!(render_table(&rows), "A       BB\nx       1\nlonger  22\n");
+        assert_eq!(render_table(&[]), "(no rows)\n");
+    }
+
+    #[test]
+    fn reports_flatten_into_dotted_columns() {
+        let report = serde_json::json!({
+            "mode": "all",
+            "deleted": {"succeeded": 2, "failed": null},
+            "steps": [1, 2],
+        });
+        assert_eq!(
+            report.csv_cells(),
+            vec![
+                cell("deleted.failed", ""),
+                cell("deleted.succeeded", "2"),
+                cell("mode", "all"),
+                cell("steps", "[1,2]"),
+            ]
+        );
+    }
+
+    #[test]
+    fn summaries_become_one_row_per_group() {
+        let mut summary = StatusSummary {
+            rows: 3,
+            ..Default::default()
+        };
+        summary.by_flow.insert(
+            "lock".to_string(),
+            SummaryBucket {
+                count: 3,
+                hot_total: "4.5".to_string(),
+            },
+        );
+        let lines = summary_lines(&summary);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            (lines[1].group, lines[1].key.as_str(), lines[1].count),
+            ("flow", "lock", 3)
+        );
+    }
+
+    #[test]
+    fn relative_times_and_progress() {
+        assert_eq!(relative(100, 100), "0s ago");
+        assert_eq!(relative(0, 86_400 * 3), "3d ago");
+        assert_eq!(
+            step_progress("withdraw", "rave_executed"),
+            "rave_executed 3/3"
+        );
+        assert_eq!(step_progress("withdraw", "claimed"), "claimed");
+        assert_eq!(step_progress("lock", "new"), "new 1/5");
+    }
+}
@@ -0,0 +1,587 @@
+//! Typed payloads for `work_items.payload_json`.
+//!
+//! Each kind of work item has one payload type: a lock's
+//! `create_parked_link` row carries a [`LockPayload`], a withdrawal's
+//! `execute_rave` row a [`WithdrawPayload`]. Payloads are validated before
+//! they are written, so a malformed one fails the enqueue that built it
+//! rather than a bridge cycle that reads it back later.
+//!
+//! Every payload written is stamped with its version under `"v"`. Rows
+//! written before versioning carry none and read as version 0, which
+//! [`WorkPayload::load`] upgrades as it reads them. A change to a payload's
+//! shape bumps [`PAYLOAD_VERSION`] and adds an upgrade from the version
+//! before it; stored rows are never rewritten.
+
+use crate::state::ErrorClass;
+use crate::withdrawal::format_token_amount;
+use alloy::primitives::{Address, U256};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Version stamped on every payload this binary writes.
+pub const PAYLOAD_VERSION: u64 = 1;
+
+const VERSION_KEY: &str = "v";
+
+/// A work item's payload, by kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkPayload {
+    Lock(LockPayload),
+    Withdraw(WithdrawPayload),
+}
+
+impl WorkPayload {
+    /// The `flow` column of a row carrying this payload.
+    pub fn flow(&self) -> &'static str {
+        match self {
+            WorkPayload::Lock(_) => "lock",
+            WorkPayload::Withdraw(_) => "withdraw",
+        }
+    }
+
+    /// The `task_type` column of a row carrying this payload.
+    pub fn task_type(&self) -> &'static str {
+        match self {
+            WorkPayload::Lock(_) => "create_parked_link",
+            WorkPayload::Withdraw(_) => "execute_rave",
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            WorkPayload::Lock(p) => p.validate(),
+            WorkPayload::Withdraw(p) => p.validate(),
+        }
+    }
+
+    /// Validate, then serialise for `payload_json` at [`PAYLOAD_VERSION`].
+    pub fn to_json(&self) -> Result<Value> {
+        self.validate()?;
+        let mut value = match self {
+            WorkPayload::Lock(p) => serde_json::to_value(p)?,
+            WorkPayload::Withdraw(p) => serde_json::to_value(p)?,
+        };
+        value[VERSION_KEY] = PAYLOAD_VERSION.into();
+        Ok(value)
+    }
+
+    /// Read the stored payload of a `flow` / `task_type` row, upgrading it
+    /// from the version it was written at, and validate it.
+    pub fn load(flow: &str, task_type: &str, value: &Value) -> Result<Self> {
+        let payload = Self::read(flow, task_type, value)?;
+        payload.validate()?;
+        Ok(payload)
+    }
+
+    /// [`Self::load`] without the validation.
+    fn read(flow: &str, task_type: &str, value: &Value) -> Result<Self> {
+        let version = match value.get(VERSION_KEY) {
+            None => 0,
+            Some(v) => v
+                .as_u64()
+                .with_context(|| format!("payload version {} is not an integer", v))?,
+        };
+        if version > PAYLOAD_VERSION {
+            bail!(
+                "payload version {} was written by a newer binary (this one reads up to {})",
+                version,
+                PAYLOAD_VERSION
+            );
+        }
+        let payload = match (flow, task_type) {
+            ("lock", "create_parked_link") => WorkPayload::Lock(match version {
+                0 => LegacyLockPayload::deserialize(value)
+                    .context("malformed legacy lock payload")?
+                    .upgrade()?,
+                _ => LockPayload::deserialize(value).context("malformed lock payload")?,
+            }),
+            // Withdrawals were tracked after the lock payload settled, so
+            // version 0 already has the version 1 shape.
+            ("withdraw", "execute_rave") => WorkPayload::Withdraw(
+                WithdrawPayload::deserialize(value).context("malformed withdraw payload")?,
+            ),
+            _ => bail!("no payload type for flow={} task_type={}", flow, task_type),
+        };
+        Ok(payload)
+    }
+
+    pub fn into_lock(self) -> Result<LockPayload> {
+        match self {
+            WorkPayload::Lock(p) => Ok(p),
+            other => bail!("expected a lock payload, found a {} payload", other.flow()),
+        }
+    }
+}
+
+impl From<LockPayload> for WorkPayload {
+    fn from(p: LockPayload) -> Self {
+        WorkPayload::Lock(p)
+    }
+}
+
+impl From<WithdrawPayload> for WorkPayload {
+    fn from(p: WithdrawPayload) -> Self {
+        WorkPayload::Withdraw(p)
+    }
+}
+
+/// A `Lock` event on the vault, as the lock watcher recorded it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockPayload {
+    /// `lockId` from the event.
+    pub lock_id: String,
+    /// Address that locked the tokens.
+    pub sender: String,
+    /// Locked amount in wei, as emitted. `None` only on rows upgraded from
+    /// a legacy payload that recorded the HOT amount alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_raw_wei: Option<String>,
+    /// Amount to bridge, in HOT: [`format_wei_as_hot`] of the raw amount.
+    pub amount_hot: String,
+    /// 32-byte Holochain agent key the deposit is credited to, `0x` hex.
+    pub holochain_agent: String,
+    pub tx_hash: String,
+    pub block_number: u64,
+    /// Block timestamp, Unix seconds.
+    pub timestamp: u64,
+    pub required_confirmations: u64,
+}
+
+impl LockPayload {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.lock_id.is_empty(),
+            "lock payload has an empty lock_id"
+        );
+        self.sender
+            .parse::<Address>()
+            .with_context(|| format!("lock sender {:?} is not an address", self.sender))?;
+        if let Some(wei) = &self.amount_raw_wei {
+            parse_wei(wei)?;
+        }
+        validate_decimal("amount_hot", &self.amount_hot)?;
+        self.agent_key()?;
+        let tx = self
+            .tx_hash
+            .strip_prefix("0x")
+            .with_context(|| format!("tx_hash {:?} is not 0x-prefixed", self.tx_hash))?;
+        ensure!(
+            !tx.is_empty() && tx.chars().all(|c| c.is_ascii_hexdigit()),
+            "tx_hash {:?} is not hex",
+            self.tx_hash
+        );
+        Ok(())
+    }
+
+    /// The 32 bytes of the agent key the deposit proof credits. The contract
+    /// takes any bytes32, so this is where a key that no agent can hold is
+    /// caught: it must decode to an Ed25519 point outside the small-order
+    /// subgroup, or the credit would sit on an account nobody can sign for.
+    pub fn agent_key(&self) -> Result<[u8; 32]> {
+        let agent = hex::decode(self.holochain_agent.trim_start_matches("0x"))
+            .with_context(|| format!("holochain_agent {:?} is not hex", self.holochain_agent))?;
+        let key: [u8; 32] = agent
+            .try_into()
+            .map_err(|v: Vec<u8>| anyhow!("holochain_agent should be 32 bytes, got {}", v.len()))?;
+        let point = VerifyingKey::from_bytes(&key).map_err(|_| {
+            anyhow!(
+                "holochain_agent {:?} is not an Ed25519 public key",
+                self.holochain_agent
+            )
+        })?;
+        ensure!(
+            !point.is_weak(),
+            "holochain_agent {:?} is a small-order Ed25519 point",
+            self.holochain_agent
+        );
+        Ok(key)
+    }
+}
+
+/// Who a lock's refund pays and how much: the address that locked the
+/// tokens, for exactly the amount it locked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefundTerms {
+    pub recipient: Address,
+    /// Decimal amount in the bridged unit, as `generate_coupon` expects it.
+    pub amount: String,
+}
+
+impl RefundTerms {
+    /// Read from a lock's stored payload. A lock is refunded because part
+    /// of it cannot be bridged, so only the sender and amount are checked.
+    pub fn of_lock(task_type: &str, value: &Value) -> Result<Self> {
+        let lock = WorkPayload::read("lock", task_type, value)?.into_lock()?;
+        let recipient = lock
+            .sender
+            .parse::<Address>()
+            .with_context(|| format!("lock sender {:?} is not an address", lock.sender))?;
+        ensure!(!recipient.is_zero(), "lock sender is the zero address");
+        let amount = match &lock.amount_raw_wei {
+            Some(wei) => {
+                let wei = parse_wei(wei)?;
+                ensure!(!wei.is_zero(), "lock amount is zero");
+                format_token_amount(wei)
+            }
+            None => {
+                validate_decimal("amount_hot", &lock.amount_hot)?;
+                lock.amount_hot
+            }
+        };
+        Ok(Self { recipient, amount })
+    }
+}
+
+/// The class a lock whose stored payload will not load is failed under:
+/// [`ErrorClass::InvalidAgent`] when the agent key is what is wrong and the
+/// lock can still be refunded, else [`ErrorClass::InvalidPayload`].
+pub fn lock_load_failure_class(task_type: &str, value: &Value) -> ErrorClass {
+    match WorkPayload::read("lock", task_type, value).and_then(WorkPayload::into_lock) {
+        Ok(lock) if lock.agent_key().is_err() && RefundTerms::of_lock(task_type, value).is_ok() => {
+            ErrorClass::InvalidAgent
+        }
+        _ => ErrorClass::InvalidPayload,
+    }
+}
+
+/// A lock payload as written before versioning. Depending on the release
+/// that wrote it, the amount is in `amount_hot`, in `amount_raw_wei`, or
+/// in `amount`, which held either wei or HOT.
+#[derive(Debug, Deserialize)]
+struct LegacyLockPayload {
+    lock_id: String,
+    sender: String,
+    #[serde(default)]
+    amount: Option<String>,
+    #[serde(default)]
+    amount_raw_wei: Option<String>,
+    #[serde(default)]
+    amount_hot: Option<String>,
+    holochain_agent: String,
+    tx_hash: String,
+    block_number: u64,
+    timestamp: u64,
+    required_confirmations: u64,
+}
+
+impl LegacyLockPayload {
+    fn upgrade(self) -> Result<LockPayload> {
+        let amount_hot = match (&self.amount_hot, &self.amount, &self.amount_raw_wei) {
+            (Some(hot), _, _) => hot.clone(),
+            (None, Some(amount), _) => legacy_amount_as_hot(amount)?,
+            (None, None, Some(wei)) => format_wei_as_hot(parse_wei(wei)?),
+            (None, None, None) => {
+                bail!("legacy lock payload has no amount_hot, amount or amount_raw_wei")
+            }
+        };
+        let amount_raw_wei = self
+            .amount_raw_wei
+            .or_else(|| self.amount.filter(|a| is_legacy_wei(a)));
+        Ok(LockPayload {
+            lock_id: self.lock_id,
+            sender: self.sender,
+            amount_raw_wei,
+            amount_hot,
+            holochain_agent: self.holochain_agent,
+            tx_hash: self.tx_hash,
+            block_number: self.block_number,
+            timestamp: self.timestamp,
+            required_confirmations: self.required_confirmations,
+        })
+    }
+}
+
+/// Legacy `amount` values: a decimal is HOT, a long integer string is wei,
+/// and a short one is whole HOT.
+fn legacy_amount_as_hot(amount: &str) -> Result<String> {
+    if is_legacy_wei(amount) {
+        return Ok(format_wei_as_hot(parse_wei(amount)?));
+    }
+    validate_decimal("amount", amount)?;
+    Ok(amount.to_string())
+}
+
+fn is_legacy_wei(amount: &str) -> bool {
+    amount.len() >= 13 && amount.chars().all(|c| c.is_ascii_digit())
+}
+
+/// A withdrawal's parked spend, once it passed
+/// [`crate::withdrawal`] validation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WithdrawPayload {
+    /// The parked spend's ActionHash.
+    pub link_id: String,
+    /// Address the coupon pays.
+    pub recipient: String,
+    /// Decimal amount in the bridged unit, before the fee.
+    pub amount: String,
+    /// Holochain agent that parked the spend.
+    pub spender: String,
+    /// Fee model, fee and net amount the coupon was signed with; written by
+    /// [`crate::store::StateStore::mark_withdrawal_coupon_signed`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub net_amount: Option<String>,
+}
+
+impl WithdrawPayload {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.link_id.is_empty(),
+            "withdraw payload has an empty link_id"
+        );
+        self.recipient
+            .parse::<Address>()
+            .with_context(|| format!("recipient {:?} is not an address", self.recipient))?;
+        validate_decimal("amount", &self.amount)?;
+        ensure!(
+            !self.spender.is_empty(),
+            "withdraw payload has an empty spender"
+        );
+        Ok(())
+    }
+}
+
+/// A non-negative decimal: digits, optionally followed by `.` and more
+/// digits.
+fn validate_decimal(field: &str, value: &str) -> Result<()> {
+    let (whole, frac) = value.split_once('.').unwrap_or((value, "0"));
+    let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    ensure!(
+        digits(whole) && digits(frac),
+        "{} is not a valid numeric string: {:?}",
+        field,
+        value
+    );
+    Ok(())
+}
+
+fn parse_wei(wei: &str) -> Result<U256> {
+    ensure!(
+        !wei.is_empty() && wei.chars().all(|c| c.is_ascii_digit()),
+        "amount_raw_wei is not an integer: {:?}",
+        wei
+    );
+    wei.parse()
+        .with_context(|| format!("amount_raw_wei {:?} is out of range", wei))
+}
+
+/// 18-decimal base units as HOT, truncated to 6 decimals.
+pub fn format_wei_as_hot(wei: U256) -> String {
+    let decimals = U256::from(10).pow(U256::from(18));
+    let whole = wei / decimals;
+    let frac = (wei % decimals) / U256::from(10).pow(U256::from(12));
+    if frac.is_zero() {
+        whole.to_string()
+    } else {
+        format!("{}.{:06}", whole, frac)
+    }
+}
+
+#[cfg(test)]
+impl LockPayload {
+    /// A valid one-HOT lock.
+    pub fn for_test(lock_id: &str) -> Self {
+        LockPayload {
+            lock_id: lock_id.to_string(),
+            sender: Address::ZERO.to_string(),
+            amount_raw_wei: Some("1000000000000000000".to_string()),
+            amount_hot: "1".to_string(),
+            holochain_agent: format!("0x{}", "01".repeat(32)),
+            tx_hash: "0x01".to_string(),
+            block_number: 1,
+            timestamp: 0,
+            required_confirmations: 1,
+        }
+    }
+}
+
+#[cfg(test)]
+impl WithdrawPayload {
+    /// A valid withdrawal of `amount`, before its coupon is signed.
+    pub fn for_test(link_id: &str, amount: &str) -> Self {
+        WithdrawPayload {
+            link_id: link_id.to_string(),
+            recipient: Address::repeat_byte(0x11).to_string(),
+            amount: amount.to_string(),
+            spender: "uhCAk1".to_string(),
+            fee_model: None,
+            fee: None,
+            net_amount: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn legacy_lock(amounts: Value) -> Value {
+        let mut payload = json!({
+            "lock_id": "7",
+            "sender": Address::ZERO.to_string(),
+            "holochain_agent": format!("0x{}", "01".repeat(32)),
+            "tx_hash": "0xfeed",
+            "block_number": 10,
+            "timestamp": 1_700_000_000,
+            "required_confirmations": 5,
+        });
+        for (k, v) in amounts.as_object().unwrap() {
+            payload[k] = v.clone();
+        }
+        payload
+    }
+
+    #[test]
+    fn a_payload_round_trips_through_its_stored_form() {
+        for payload in [
+            WorkPayload::from(LockPayload::for_test("1")),
+            WorkPayload::from(WithdrawPayload::for_test("uhCkkW1", "2.5")),
+        ] {
+            let stored = payload.to_json().unwrap();
+            assert_eq!(stored["v"], json!(PAYLOAD_VERSION));
+            let loaded = WorkPayload::load(payload.flow(), payload.task_type(), &stored).unwrap();
+            assert_eq!(loaded, payload);
+        }
+    }
+
+    #[test]
+    fn legacy_lock_amounts_upgrade_to_amount_hot() {
+        let cases = [
+            // What the lock watcher wrote until payloads were versioned.
+            (
+                json!({"amount": "2500000000000000000", "amount_raw_wei": "2500000000000000000", "amount_hot": "2.500000"}),
+                "2.500000",
+                Some("2500000000000000000"),
+            ),
+            (
+                json!({"amount_raw_wei": "1000000000000000000"}),
+                "1",
+                Some("1000000000000000000"),
+            ),
+            (
+                json!({"amount": "1000000000000000000"}),
+                "1",
+                Some("1000000000000000000"),
+            ),
+            (json!({"amount": "2.500000"}), "2.500000", None),
+            (json!({"amount": "12"}), "12", None),
+        ];
+        for (amounts, hot, wei) in cases {
+            let lock =
+                WorkPayload::load("lock", "create_parked_link", &legacy_lock(amounts.clone()))
+                    .unwrap()
+                    .into_lock()
+                    .unwrap();
+            assert_eq!(lock.amount_hot, hot, "{}", amounts);
+            assert_eq!(lock.amount_raw_wei.as_deref(), wei, "{}", amounts);
+            assert_eq!(lock.block_number, 10);
+        }
+    }
+
+    #[test]
+    fn malformed_payloads_are_refused_not_defaulted() {
+        for (amounts, expect) in [
+            (json!({}), "no amount_hot"),
+            (json!({"amount": "bad-value"}), "not a valid numeric string"),
+            (json!({"amount_raw_wei": "1e18"}), "not an integer"),
+        ] {
+            let err =
+                WorkPayload::load("lock", "create_parked_link", &legacy_lock(amounts)).unwrap_err();
+            assert!(format!("{:#}", err).contains(expect), "{:#}", err);
+        }
+
+        let missing_block = json!({"lock_id": "7", "amount_hot": "1"});
+        assert!(WorkPayload::load("lock", "create_parked_link", &missing_block).is_err());
+
+        let mut bad_agent = LockPayload::for_test("1");
+        bad_agent.holochain_agent = "uhCAkAgent".to_string();
+        assert!(WorkPayload::from(bad_agent).to_json().is_err());
+
+        let mut bad_recipient = WithdrawPayload::for_test("uhCkkW1", "1");
+        bad_recipient.recipient = "0xabc".to_string();
+        assert!(WorkPayload::from(bad_recipient).to_json().is_err());
+
+        let newer = json!({"v": PAYLOAD_VERSION + 1});
+        let err = WorkPayload::load("lock", "create_parked_link", &newer).unwrap_err();
+        assert!(err.to_string().contains("newer binary"), "{:#}", err);
+        assert!(WorkPayload::load("lock", "initiate_deposit", &json!({})).is_err());
+    }
+
+    #[test]
+    fn a_lock_with_a_bad_agent_is_refunded_its_exact_amount_to_its_sender() {
+        let mut lock = LockPayload::for_test("1");
+        lock.sender = Address::repeat_byte(0x22).to_string();
+        lock.amount_raw_wei = Some("1234567890123456789".to_string());
+        lock.amount_hot = "1.234567".to_string();
+        // 32 bytes of hex, as the contract's bytes32 guarantees, but no
+        // Ed25519 point.
+        lock.holochain_agent = format!("0x{}", "ab".repeat(32));
+        let mut stored = serde_json::to_value(&lock).unwrap();
+        stored["v"] = json!(PAYLOAD_VERSION);
+
+        let terms = RefundTerms::of_lock("create_parked_link", &stored).unwrap();
+        assert_eq!(terms.recipient, Address::repeat_byte(0x22));
+        assert_eq!(terms.amount, "1.234567890123456789");
+        assert_eq!(
+            lock_load_failure_class("create_parked_link", &stored),
+            ErrorClass::InvalidAgent
+        );
+
+        // Nothing to refund to: the payload is at fault, not just its agent.
+        stored["sender"] = json!(Address::ZERO.to_string());
+        assert!(RefundTerms::of_lock("create_parked_link", &stored).is_err());
+        assert_eq!(
+            lock_load_failure_class("create_parked_link", &stored),
+            ErrorClass::InvalidPayload
+        );
+        assert_eq!(
+            lock_load_failure_class("create_parked_link", &json!({"v": 1})),
+            ErrorClass::InvalidPayload
+        );
+    }
+
+    #[test]
+    fn an_agent_key_must_be_a_full_order_ed25519_point() {
+        let mut lock = LockPayload::for_test("1");
+        assert!(lock.agent_key().is_ok());
+        for (agent, expect) in [
+            ("0xabcd".to_string(), "32 bytes"),
+            (
+                format!("0x{}", "ab".repeat(32)),
+                "not an Ed25519 public key",
+            ),
+            (format!("0x{}", "00".repeat(32)), "small-order"),
+        ] {
+            lock.holochain_agent = agent;
+            let err = lock.agent_key().unwrap_err();
+            assert!(err.to_string().contains(expect), "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn validate_decimal_rejects_non_numeric() {
+        assert!(validate_decimal("amount_hot", "1.230000").is_ok());
+        assert!(validate_decimal("amount_hot", "12").is_ok());
+        assert!(validate_decimal("amount_hot", "bad-value").is_err());
+        assert!(validate_decimal("amount_hot", "").is_err());
+        assert!(validate_decimal("amount_hot", "1.2.3").is_err());
+        assert!(validate_decimal("amount_hot", ".5").is_err());
+    }
+
+    #[test]
+    fn legacy_amount_converts_wei_like_values() {
+        assert_eq!(legacy_amount_as_hot("1000000000000000000").unwrap(), "1");
+        assert_eq!(legacy_amount_as_hot("2.500000").unwrap(), "2.500000");
+        assert_eq!(
+            format_wei_as_hot(U256::from(1_234_567_890_123_456_789u64)),
+            "1.234567"
+        );
+    }
+}
@@ -0,0 +1,2866 @@
+//! PostgreSQL backend for [`StateStore`], built with the `postgres` feature
+//! and selected with `DB_BACKEND=postgres` and `DATABASE_URL`.
+//!
+//! The tables mirror the SQLite schema, timestamps included (unix seconds
+//! in `BIGINT`), so `status`, the reporter and the retention archive see
+//! the same values on either backend. `payload_json` is `JSONB`, so
+//! dashboards can query into it. The schema is versioned in `schema_meta`
+//! like [`crate::migrations`], with its own append-only list, [`SCHEMA`];
+//! pending entries are applied in one transaction under an advisory lock,
+//! so two processes starting together never both apply them.
+//!
+//! The single-writer lease is a session-level advisory lock taken on the
+//! writer's own connection, so the lock lives exactly as long as the
+//! connection that writes under it. A writer therefore never reconnects:
+//! once its connection drops, another instance may already have taken
+//! over, so every later call fails and the process has to be restarted.
+//! Every other connection reconnects on its next call. Advisory locks are
+//! keyed on the current schema, so bridges sharing a database in separate
+//! schemas (`?options=-c search_path=...`) do not lock each other out.
+//!
+//! The client is the blocking `postgres` crate, which drives a runtime of
+//! its own. Calls made from a tokio worker go through
+//! [`tokio::task::block_in_place`], so the daemon's multi-threaded runtime
+//! is not blocked and the nested runtime is allowed.
+//!
+//! Connections are made without TLS: point `DATABASE_URL` at a local
+//! socket, a private network, or a TLS-terminating proxy.
+//!
+//! The tests run against the server at `BRIDGE_TEST_POSTGRES_URL`, each in
+//! a schema of its own, and are skipped when it is unset.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use postgres::types::ToSql;
+use postgres::{Client, NoTls, Row, Transaction};
+use serde_json::Value;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use crate::config::RetryPolicy;
+use crate::leader::{LeaseBackend, LeaseRecord};
+use crate::lease::{describe_lease_row, heartbeat_expired};
+use crate::migrations::{check_not_newer, ensure_current, MigrationReport, MigrationStep};
+use crate::payload::{RefundTerms, WithdrawPayload, WorkPayload};
+use crate::report::{report_query, ReportItem};
+use crate::state::{
+    approval_reason, cleared_hashes, ensure_claimable, ensure_settled, flow_counts_query,
+    lookup_columns, parse_claim_tx_hash, reload_payload, replay_state, requeue_reason,
+    stage_events_query, status_row, tally_error_classes, tally_flow_counts, tally_withdrawal_fees,
+    ArchiveFn, ArchivedWorkItem, BridgeAggregateStats, DeadLetter, ErrorClass, LockRefund, Lookup,
+    LookupRow, OperatorTarget, PruneStats, QueryParam, RecentError, RefundStatus, RequeueOptions,
+    StageTally, StateFilter, StatusQuery, StatusRow, StatusSummary, SummaryTally, Transition,
+    WithdrawStep, WithdrawalRejectionRow, WorkItem, WorkItemEvent, WorkState, WorkStep,
+    WriterLeaseRow, ERROR_CLASS_COUNTS, FAILED_FROM_STATE, OUTSTANDING_REFUNDS, SELECT_REFUNDS,
+    STATUS_COLUMNS, UNSENT_COUPON, WITHDRAWAL_CANCELLED,
+};
+use crate::store::StateStore;
+use crate::withdrawal::WithdrawalFee;
+
+/// A version of the Postgres schema.
+pub struct SchemaVersion {
+    pub version: i64,
+    pub name: &'static str,
+    sql: &'static str,
+    /// Run after `sql`, in the same transaction, for what SQL cannot decide.
+    then: Option<fn(&mut Transaction<'_>) -> Result<()>>,
+}
+
+/// Every schema change, oldest first. Never edit or renumber an entry that
+/// has shipped; add the next version instead.
+pub const SCHEMA: &[SchemaVersion] = &[
+    SchemaVersion {
+        version: 1,
+        name: "initial_schema",
+        sql: INITIAL_SCHEMA,
+        then: None,
+    },
+    SchemaVersion {
+        version: 2,
+        name: "work_item_query_columns",
+        sql: WORK_ITEM_QUERY_COLUMNS,
+        then: None,
+    },
+    SchemaVersion {
+        version: 3,
+        name: "work_item_recipient_column",
+        sql: WORK_ITEM_RECIPIENT_COLUMN,
+        then: None,
+    },
+    SchemaVersion {
+        version: 4,
+        name: "work_item_error_taxonomy",
+        sql: crate::migrations::WORK_ITEM_ERROR_TAXONOMY,
+        then: None,
+    },
+    SchemaVersion {
+        version: 5,
+        name: "lock_refunds",
+        sql: crate::migrations::LOCK_REFUNDS,
+        then: Some(seed_lock_refunds),
+    },
+];
+
+/// v5's backfill, as SQLite's v10 does it: reclassify the
+/// `invalid_payload` locks whose payload fails only on its agent key, then
+/// seed the ledger.
+fn seed_lock_refunds(tx: &mut Transaction<'_>) -> Result<()> {
+    for row in tx.query(crate::migrations::INVALID_PAYLOAD_LOCKS, &[])? {
+        let (id, task_type, payload): (i64, String, Value) = (row.get(0), row.get(1), row.get(2));
+        if crate::migrations::is_agent_failure(&task_type, &payload) {
+            tx.execute(
+                "UPDATE work_items SET error_class = 'invalid_agent' WHERE id = $1",
+                &[&id],
+            )?;
+        }
+    }
+    tx.batch_execute(crate::migrations::SEED_LOCK_REFUNDS)?;
+    Ok(())
+}
+
+/// v1: the SQLite schema as of its v6, in one step.
+const INITIAL_SCHEMA: &str = "
+    CREATE TABLE work_items (
+        id BIGSERIAL PRIMARY KEY,
+        flow TEXT NOT NULL,
+        task_type TEXT NOT NULL,
+        item_id TEXT NOT NULL,
+        idempotency_key TEXT NOT NULL UNIQUE,
+        payload_json JSONB NOT NULL,
+        state TEXT NOT NULL,
+        attempts BIGINT NOT NULL DEFAULT 0,
+        max_attempts BIGINT NOT NULL DEFAULT 8,
+        next_retry_at BIGINT,
+        last_attempt_at BIGINT,
+        error_class TEXT,
+        last_error TEXT,
+        created_at BIGINT NOT NULL DEFAULT (extract(epoch FROM now())::bigint),
+        updated_at BIGINT NOT NULL DEFAULT (extract(epoch FROM now())::bigint),
+        step TEXT NOT NULL DEFAULT 'new',
+        cl_link_hash TEXT,
+        cl_rave_hash TEXT,
+        br_spend_hash TEXT,
+        br_rave_hash TEXT,
+        fee_wei TEXT
+    );
+    CREATE INDEX idx_work_items_state_created ON work_items (state, created_at);
+    CREATE INDEX idx_work_items_flow_state ON work_items (flow, state);
+    CREATE INDEX idx_work_items_flow_br_spend ON work_items (flow, br_spend_hash);
+    CREATE TABLE checkpoints (
+        checkpoint_key TEXT PRIMARY KEY,
+        checkpoint_value TEXT NOT NULL,
+        updated_at BIGINT NOT NULL DEFAULT (extract(epoch FROM now())::bigint)
+    );
+    CREATE TABLE withdrawal_rejections (
+        link_id TEXT PRIMARY KEY,
+        reason TEXT NOT NULL,
+        detail TEXT NOT NULL,
+        payload_json JSONB NOT NULL,
+        seen_count BIGINT NOT NULL DEFAULT 1,
+        first_seen_at BIGINT NOT NULL DEFAULT (extract(epoch FROM now())::bigint),
+        last_seen_at BIGINT NOT NULL DEFAULT (extract(epoch FROM now())::bigint)
+    );
+    CREATE TABLE work_item_events (
+        id BIGSERIAL PRIMARY KEY,
+        work_item_id BIGINT NOT NULL,
+        item_id TEXT NOT NULL,
+        from_state TEXT,
+        to_state TEXT NOT NULL,
+        from_step TEXT,
+        to_step TEXT NOT NULL,
+        reason TEXT,
+        attempt BIGINT NOT NULL,
+        action_hash TEXT,
+        actor TEXT NOT NULL DEFAULT 'orchestrator',
+        created_at BIGINT NOT NULL DEFAULT (extract(epoch FROM now())::bigint)
+    );
+    CREATE INDEX idx_work_item_events_item ON work_item_events (item_id, id);
+    CREATE INDEX idx_work_item_events_work_item ON work_item_events (work_item_id);
+    CREATE FUNCTION work_item_events_append_only() RETURNS trigger
+    LANGUAGE plpgsql AS $$
+    BEGIN
+        RAISE EXCEPTION 'work_item_events is append-only';
+    END
+    $$;
+    CREATE TRIGGER work_item_events_append_only
+    BEFORE UPDATE ON work_item_events
+    FOR EACH ROW EXECUTE FUNCTION work_item_events_append_only();
+    CREATE TABLE writer_lease (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        pid BIGINT NOT NULL,
+        host TEXT NOT NULL,
+        acquired_at BIGINT NOT NULL,
+        heartbeat_at BIGINT NOT NULL
+    );
+";
+
+/// v2: SQLite's v7, the indexed `status` filter columns, stored rather
+/// than virtual.
+const WORK_ITEM_QUERY_COLUMNS: &str = "
+    ALTER TABLE work_items
+        ADD COLUMN eth_sender TEXT
+            GENERATED ALWAYS AS (lower(payload_json->>'sender')) STORED,
+        ADD COLUMN holochain_agent TEXT
+            GENERATED ALWAYS AS (coalesce(payload_json->>'holochain_agent',
+                                          payload_json->>'spender')) STORED,
+        ADD COLUMN eth_tx_hash TEXT
+            GENERATED ALWAYS AS (lower(payload_json->>'tx_hash')) STORED,
+        ADD COLUMN lock_id BIGINT GENERATED ALWAYS AS (
+            CASE WHEN flow = 'lock' AND payload_json->>'lock_id' ~ '^[0-9]{1,18}$'
+            THEN (payload_json->>'lock_id')::bigint END
+        ) STORED;
+    CREATE INDEX idx_work_items_eth_sender ON work_items (eth_sender);
+    CREATE INDEX idx_work_items_holochain_agent ON work_items (holochain_agent);
+    CREATE INDEX idx_work_items_eth_tx_hash ON work_items (eth_tx_hash);
+    CREATE INDEX idx_work_items_lock_id ON work_items (lock_id);
+    CREATE INDEX idx_work_items_step ON work_items (step, state);
+    CREATE INDEX idx_work_items_error_class ON work_items (error_class);
+    CREATE INDEX idx_work_items_created ON work_items (created_at, id);
+    CREATE INDEX idx_work_items_updated ON work_items (updated_at, id);
+";
+
+/// v3: SQLite's v8, the withdrawal recipient `lookup` matches, stored.
+const WORK_ITEM_RECIPIENT_COLUMN: &str = "
+    ALTER TABLE work_items
+        ADD COLUMN eth_recipient TEXT GENERATED ALWAYS AS (
+            CASE WHEN flow = 'withdraw' THEN lower(payload_json->>'recipient') END
+        ) STORED;
+    CREATE INDEX idx_work_items_eth_recipient ON work_items (eth_recipient);
+";
+
+/// The withdrawal with parked spend `$1`, while S4 may still sign its
+/// coupon.
+const PENDING_WITHDRAWAL: &str = "flow = 'withdraw' AND br_spend_hash = $1
+     AND step IN ('seen', 'coupon_signed') AND state IN ('queued', 'in_flight')";
+
+/// Advisory lock names, hashed together with the current schema.
+const SCHEMA_LOCK: &str = "bridge-orchestrator.schema";
+const WRITER_LOCK: &str = "bridge-orchestrator.writer";
+
+const WORK_ITEM_COLUMNS: &str = "id, flow, task_type, item_id, idempotency_key, payload_json, state, attempts, max_attempts, next_retry_at, last_attempt_at, error_class, last_error, created_at, updated_at, step, cl_link_hash, cl_rave_hash, br_spend_hash, br_rave_hash";
+
+const SELECT_EVENTS: &str =
+    "SELECT id, work_item_id, item_id, from_state, to_state, from_step, to_step,
+            reason, attempt, action_hash, actor, created_at
+     FROM work_item_events";
+
+/// Run `f`, which blocks on the `postgres` client's own runtime, from
+/// wherever we are: straight away on a plain or blocking-pool thread, and
+/// through `block_in_place` on a tokio worker.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// One connection, shared behind a mutex.
+struct Session {
+    client: Mutex<Option<Client>>,
+    config: postgres::Config,
+    /// Reconnect on the next call once the connection has dropped. Off
+    /// for the writer, whose advisory lock went with it.
+    reconnect: bool,
+}
+
+impl Session {
+    fn connect(config: postgres::Config, reconnect: bool) -> Result<Self> {
+        let client = blocking(|| config.connect(NoTls)).context("connect to postgres")?;
+        Ok(Self {
+            client: Mutex::new(Some(client)),
+            config,
+            reconnect,
+        })
+    }
+
+    fn with_client<T>(&self, f: impl FnOnce(&mut Client) -> Result<T>) -> Result<T> {
+        blocking(|| {
+            let mut guard = self.client.lock().expect("db mutex poisoned");
+            let closed = guard.as_ref().is_none_or(Client::is_closed);
+            if closed {
+                if !self.reconnect {
+                    bail!("lost the postgres connection holding the writer lease; restart to take it again");
+                }
+                *guard = Some(
+                    self.config
+                        .connect(NoTls)
+                        .context("reconnect to postgres")?,
+                );
+            }
+            f(guard.as_mut().expect("connected above"))
+        })
+    }
+
+    fn transaction<T>(&self, f: impl FnOnce(&mut Transaction<'_>) -> Result<T>) -> Result<T> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let out = f(&mut tx)?;
+            tx.commit()?;
+            Ok(out)
+        })
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Closing the client blocks on its runtime too.
+        if let Some(client) = self.client.get_mut().ok().and_then(Option::take) {
+            blocking(move || drop(client));
+        }
+    }
+}
+
+/// [`StateStore`] on a PostgreSQL database.
+pub struct PostgresStore {
+    session: Session,
+}
+
+impl PostgresStore {
+    /// Connect as the writer: take the writer lease, bring the schema up to
+    /// date, then return rows a previous writer left `claimed` /
+    /// `in_flight` to the queue. Fails straight away, naming the holder, if
+    /// another process holds the lease. For [`crate::lease::WriterLease`].
+    pub fn connect_writer(url: &str) -> Result<(Self, MigrationReport)> {
+        Self::connect_writer_with(url.parse().context("invalid DATABASE_URL")?)
+    }
+
+    /// [`Self::connect_writer`], or `None` while another process holds the
+    /// lease.
+    pub fn try_connect_writer(url: &str) -> Result<Option<(Self, MigrationReport)>> {
+        Self::try_connect_writer_with(url.parse().context("invalid DATABASE_URL")?)
+    }
+
+    /// [`Self::connect_writer`], but if the holder's heartbeat is older than
+    /// `stale_after`, terminate the session that holds its advisory lock
+    /// and take the lease. For a leader-elected standby whose predecessor
+    /// is hung rather than gone.
+    pub fn take_over_writer(url: &str, stale_after: Duration) -> Result<(Self, MigrationReport)> {
+        Self::take_over_writer_with(url.parse().context("invalid DATABASE_URL")?, stale_after)
+    }
+
+    /// Connect alongside a writer that may be mid-cycle, leaving
+    /// `in_flight` rows alone. Never migrates: a schema that is behind is
+    /// refused until [`Self::connect_writer`] migrates it under the lease.
+    pub fn connect_shared(url: &str) -> Result<Self> {
+        Self::connect_shared_with(url.parse().context("invalid DATABASE_URL")?)
+    }
+
+    /// What [`Self::connect_writer`] would migrate, without writing
+    /// anything or taking the lease.
+    pub fn schema_plan(url: &str) -> Result<MigrationReport> {
+        let session = Session::connect(url.parse().context("invalid DATABASE_URL")?, false)?;
+        session.with_client(plan)
+    }
+
+    fn connect_writer_with(config: postgres::Config) -> Result<(Self, MigrationReport)> {
+        match Self::try_connect_writer_with(config.clone())? {
+            Some(writer) => Ok(writer),
+            None => {
+                // A database from before the lease has no `writer_lease`.
+                let holder = Session::connect(config, false)?
+                    .with_client(read_writer_lease)
+                    .ok()
+                    .flatten();
+                bail!(
+                    "the postgres database is held by another bridge-orchestrator ({}); stop it first",
+                    describe_lease_row(holder)
+                );
+            }
+        }
+    }
+
+    fn try_connect_writer_with(
+        config: postgres::Config,
+    ) -> Result<Option<(Self, MigrationReport)>> {
+        let session = Session::connect(config, false)?;
+        let locked: bool = session.with_client(|client| {
+            Ok(client
+                .query_one(
+                    "SELECT pg_try_advisory_lock(hashtext($1), hashtext(current_schema()))",
+                    &[&WRITER_LOCK],
+                )?
+                .get(0))
+        })?;
+        if !locked {
+            return Ok(None);
+        }
+        let report = session.with_client(migrate)?;
+        let store = Self { session };
+        store.recover_stale_items()?;
+        Ok(Some((store, report)))
+    }
+
+    fn take_over_writer_with(
+        config: postgres::Config,
+        stale_after: Duration,
+    ) -> Result<(Self, MigrationReport)> {
+        if let Some(writer) = Self::try_connect_writer_with(config.clone())? {
+            return Ok(writer);
+        }
+        let session = Session::connect(config.clone(), false)?;
+        let holder = session.with_client(read_writer_lease).ok().flatten();
+        if !heartbeat_expired(holder.as_ref(), stale_after) {
+            bail!(
+                "the postgres database is held by another bridge-orchestrator ({}); stop it first",
+                describe_lease_row(holder)
+            );
+        }
+        tracing::warn!(
+            event = "bridge_orchestrator.lease.taken_over",
+            holder = %describe_lease_row(holder),
+            "terminating the session of a writer whose heartbeat expired"
+        );
+        // Two-key advisory locks show up in pg_locks as classid/objid (the
+        // keys as unsigned) with objsubid 2.
+        session.with_client(|client| {
+            client.query(
+                "SELECT pg_terminate_backend(pid, 5000) FROM pg_locks
+                 WHERE locktype = 'advisory' AND granted AND objsubid = 2
+                   AND database = (SELECT oid FROM pg_database WHERE datname = current_database())
+                   AND classid = (hashtext($1)::bigint & 4294967295)::oid
+                   AND objid = (hashtext(current_schema())::bigint & 4294967295)::oid",
+                &[&WRITER_LOCK],
+            )?;
+            Ok(())
+        })?;
+        match Self::try_connect_writer_with(config)? {
+            Some(writer) => Ok(writer),
+            None => bail!("the postgres writer lease is still held after terminating its holder"),
+        }
+    }
+
+    fn connect_shared_with(config: postgres::Config) -> Result<Self> {
+        let session = Session::connect(config, true)?;
+        ensure_current(&session.with_client(plan)?, "the postgres database")?;
+        Ok(Self { session })
+    }
+
+    fn recover_stale_items(&self) -> Result<()> {
+        self.session.transaction(|tx| {
+            rewind_unsent_coupons(tx, "state IN ('claimed', 'in_flight')", &[])?;
+            // Bump first, as on SQLite, so rows that just crossed
+            // max_attempts are failed below rather than retried.
+            tx.execute(
+                "UPDATE work_items
+                 SET attempts = attempts + 1,
+                     updated_at = extract(epoch FROM now())::bigint
+                 WHERE state IN ('claimed', 'in_flight')",
+                &[],
+            )?;
+            let retryable = "state IN ('claimed', 'in_flight') AND attempts < max_attempts";
+            let recovered = "Recovered from stale in-progress state on startup";
+            log_transition(
+                tx,
+                retryable,
+                &[],
+                &Transition::to_state("queued").because(recovered),
+            )?;
+            record_error(tx, retryable, &[], Some(ErrorClass::Interrupted), recovered)?;
+            tx.execute(
+                format!(
+                    "UPDATE work_items
+                     SET state = 'queued',
+                         next_retry_at = NULL,
+                         error_class = 'interrupted',
+                         last_error = $1,
+                         updated_at = extract(epoch FROM now())::bigint
+                     WHERE {}",
+                    retryable
+                )
+                .as_str(),
+                &[&recovered],
+            )?;
+            let exhausted = "state IN ('claimed', 'in_flight') AND attempts >= max_attempts";
+            let gave_up = "Exceeded max attempts during startup recovery";
+            log_transition(
+                tx,
+                exhausted,
+                &[],
+                &Transition::to_state("failed").because(gave_up),
+            )?;
+            record_error(tx, exhausted, &[], Some(ErrorClass::Interrupted), gave_up)?;
+            tx.execute(
+                format!(
+                    "UPDATE work_items
+                     SET state = 'failed',
+                         error_class = 'interrupted',
+                         next_retry_at = NULL,
+                         last_error = $1,
+                         updated_at = extract(epoch FROM now())::bigint
+                     WHERE {}",
+                    exhausted
+                )
+                .as_str(),
+                &[&gave_up],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Move a lock row to `step`, recording `hash` in `hash_column`, with a
+    /// fresh retry budget.
+    fn advance_lock(
+        &self,
+        id: i64,
+        step: WorkStep,
+        hash_column: &str,
+        hash: Option<&str>,
+        state: &str,
+    ) -> Result<()> {
+        let step = step.to_string();
+        self.session.transaction(|tx| {
+            log_transition(
+                tx,
+                "id = $1",
+                &[&id],
+                &Transition::to_step(state, &step, hash),
+            )?;
+            tx.execute(
+                format!(
+                    "UPDATE work_items
+                     SET step = $2,
+                         {} = $3,
+                         state = $4,
+                         attempts = 0,
+                         error_class = NULL,
+                         last_error = NULL,
+                         next_retry_at = NULL,
+                         updated_at = extract(epoch FROM now())::bigint
+                     WHERE id = $1",
+                    hash_column
+                )
+                .as_str(),
+                &[&id, &step, &hash, &state],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+impl StateStore for PostgresStore {
+    fn enqueue_detected(
+        &self,
+        item_id: &str,
+        idempotency_key: &str,
+        payload: &WorkPayload,
+    ) -> Result<()> {
+        let payload_json = payload
+            .to_json()
+            .with_context(|| format!("refusing to enqueue {}", item_id))?;
+        self.session.transaction(|tx| {
+            let inserted = tx.query_opt(
+                "INSERT INTO work_items (flow, task_type, item_id, idempotency_key, payload_json, state)
+                 VALUES ($1, $2, $3, $4, $5, 'detected')
+                 ON CONFLICT (idempotency_key) DO NOTHING
+                 RETURNING id",
+                &[
+                    &payload.flow(),
+                    &payload.task_type(),
+                    &item_id,
+                    &idempotency_key,
+                    &payload_json,
+                ],
+            )?;
+            if let Some(row) = inserted {
+                log_created(tx, row.get(0), "detected on-chain")?;
+            }
+            Ok(())
+        })
+    }
+
+    fn move_detected_to_queued(&self, idempotency_key: &str) -> Result<bool> {
+        self.session.transaction(|tx| {
+            let filter = "idempotency_key = $1 AND state = 'detected'";
+            log_transition(
+                tx,
+                filter,
+                &[&idempotency_key],
+                &Transition::to_state("queued").because("confirmed"),
+            )?;
+            let changed = tx.execute(
+                format!(
+                    "UPDATE work_items
+                     SET state = 'queued', updated_at = extract(epoch FROM now())::bigint
+                     WHERE {}",
+                    filter
+                )
+                .as_str(),
+                &[&idempotency_key],
+            )?;
+            Ok(changed > 0)
+        })
+    }
+
+    fn get_checkpoint_u64(&self, key: &str) -> Result<Option<u64>> {
+        let value: Option<String> = self.session.with_client(|client| {
+            Ok(client
+                .query_opt(
+                    "SELECT checkpoint_value FROM checkpoints WHERE checkpoint_key = $1",
+                    &[&key],
+                )?
+                .map(|row| row.get(0)))
+        })?;
+        match value {
+            Some(v) => Ok(Some(v.parse().context("checkpoint is not u64")?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_checkpoint_u64(&self, key: &str, value: u64) -> Result<()> {
+        self.session.with_client(|client| {
+            client.execute(
+                "INSERT INTO checkpoints (checkpoint_key, checkpoint_value, updated_at)
+                 VALUES ($1, $2, extract(epoch FROM now())::bigint)
+                 ON CONFLICT (checkpoint_key) DO UPDATE
+                   SET checkpoint_value = excluded.checkpoint_value,
+                       updated_at = excluded.updated_at",
+                &[&key, &value.to_string()],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn list_work_items(&self, flow: &str, state: WorkState, limit: usize) -> Result<Vec<WorkItem>> {
+        self.session.with_client(|client| {
+            client
+                .query(
+                    format!(
+                        "SELECT {} FROM work_items
+                         WHERE flow = $1 AND state = $2
+                         ORDER BY created_at ASC, id ASC
+                         LIMIT $3",
+                        WORK_ITEM_COLUMNS
+                    )
+                    .as_str(),
+                    &[&flow, &state.to_string(), &(limit as i64)],
+                )?
+                .iter()
+                .map(row_to_work_item)
+                .collect()
+        })
+    }
+
+    fn list_pending_by_step(
+        &self,
+        flow: &str,
+        step: WorkStep,
+        limit: usize,
+    ) -> Result<Vec<WorkItem>> {
+        self.session.with_client(|client| {
+            client
+                .query(
+                    format!(
+                        "SELECT {} FROM work_items
+                         WHERE flow = $1 AND step = $2 AND state IN ('queued', 'in_flight')
+                         ORDER BY created_at ASC, id ASC
+                         LIMIT $3",
+                        WORK_ITEM_COLUMNS
+                    )
+                    .as_str(),
+                    &[&flow, &step.to_string(), &(limit as i64)],
+                )?
+                .iter()
+                .map(row_to_work_item)
+                .collect()
+        })
+    }
+
+    fn mark_in_flight(&self, id: i64) -> Result<()> {
+        self.session.transaction(|tx| {
+            log_transition(tx, "id = $1", &[&id], &Transition::to_state("in_flight"))?;
+            tx.execute(
+                "UPDATE work_items
+                 SET state = 'in_flight', updated_at = extract(epoch FROM now())::bigint
+                 WHERE id = $1",
+                &[&id],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn advance_to_cl_link_created(&self, id: i64, cl_link_hash: &str) -> Result<()> {
+        self.advance_lock(
+            id,
+            WorkStep::ClLinkCreated,
+            "cl_link_hash",
+            Some(cl_link_hash),
+            "queued",
+        )
+    }
+
+    fn advance_to_cl_rave_executed(&self, id: i64, cl_rave_hash: Option<&str>) -> Result<()> {
+        self.advance_lock(
+            id,
+            WorkStep::ClRaveExecuted,
+            "cl_rave_hash",
+            cl_rave_hash,
+            "queued",
+        )
+    }
+
+    fn advance_to_br_spend_created(&self, id: i64, br_spend_hash: &str) -> Result<()> {
+        self.advance_lock(
+            id,
+            WorkStep::BrSpendCreated,
+            "br_spend_hash",
+            Some(br_spend_hash),
+            "queued",
+        )
+    }
+
+    fn advance_to_br_rave_executed(&self, id: i64, br_rave_hash: Option<&str>) -> Result<()> {
+        self.advance_lock(
+            id,
+            WorkStep::BrRaveExecuted,
+            "br_rave_hash",
+            br_rave_hash,
+            "succeeded",
+        )
+    }
+
+    fn record_withdrawal_seen(&self, payload: &WithdrawPayload) -> Result<bool> {
+        let link_id = &payload.link_id;
+        let payload_json = WorkPayload::from(payload.clone())
+            .to_json()
+            .with_context(|| format!("refusing to record withdrawal {}", link_id))?;
+        self.session.transaction(|tx| {
+            let inserted = tx.query_opt(
+                "INSERT INTO work_items (flow, task_type, item_id, idempotency_key, payload_json, state, step, br_spend_hash)
+                 VALUES ('withdraw', 'execute_rave', $1, $2, $3, 'queued', 'seen', $4)
+                 ON CONFLICT (idempotency_key) DO NOTHING
+                 RETURNING id",
+                &[
+                    &format!("withdraw:{}", link_id),
+                    &format!("withdraw:{}:execute_rave", link_id),
+                    &payload_json,
+                    link_id,
+                ],
+            )?;
+            if let Some(row) = &inserted {
+                log_created(tx, row.get(0), "withdrawal parked spend seen")?;
+            }
+            Ok(inserted.is_some())
+        })
+    }
+
+    fn mark_withdrawal_coupon_signed(&self, link_id: &str, fee: &WithdrawalFee) -> Result<bool> {
+        self.session.transaction(|tx| {
+            let filter = PENDING_WITHDRAWAL;
+            log_transition(
+                tx,
+                filter,
+                &[&link_id],
+                &Transition::to_step("in_flight", "coupon_signed", None),
+            )?;
+            let updated = tx.execute(
+                format!(
+                    "UPDATE work_items
+                     SET step = 'coupon_signed',
+                         state = 'in_flight',
+                         fee_wei = $2,
+                         payload_json = payload_json || jsonb_build_object(
+                             'fee_model', $3::text, 'fee', $4::text, 'net_amount', $5::text),
+                         error_class = NULL,
+                         last_error = NULL,
+                         next_retry_at = NULL,
+                         last_attempt_at = extract(epoch FROM now())::bigint,
+                         updated_at = extract(epoch FROM now())::bigint
+                     WHERE {}",
+                    filter
+                )
+                .as_str(),
+                &[
+                    &link_id,
+                    &fee.fee_wei.to_string(),
+                    &fee.model,
+                    &fee.fee_amount(),
+                    &fee.net_amount(),
+                ],
+            )?;
+            Ok(updated > 0)
+        })
+    }
+
+    fn mark_withdrawal_coupon_failed(
+        &self,
+        link_id: &str,
+        class: ErrorClass,
+        error: &str,
+        retry: &RetryPolicy,
+    ) -> Result<()> {
+        self.session.transaction(|tx| {
+            let now: i64 = tx
+                .query_one("SELECT extract(epoch FROM now())::bigint", &[])?
+                .try_get(0)?;
+            let row = tx.query_opt(
+                format!(
+                    "SELECT id, attempts FROM work_items WHERE {}",
+                    PENDING_WITHDRAWAL
+                )
+                .as_str(),
+                &[&link_id],
+            )?;
+            if let Some(row) = row {
+                back_off(
+                    tx,
+                    row.try_get(0)?,
+                    row.try_get(1)?,
+                    class,
+                    error,
+                    retry,
+                    now,
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn withdrawal_due(&self, link_id: &str, now: i64) -> Result<bool> {
+        self.session.with_client(|client| {
+            Ok(client
+                .query_opt(
+                    format!(
+                        "SELECT 1 FROM work_items
+                         WHERE {} AND (next_retry_at IS NULL OR next_retry_at <= $2)",
+                        PENDING_WITHDRAWAL
+                    )
+                    .as_str(),
+                    &[&link_id, &now],
+                )?
+                .is_some())
+        })
+    }
+
+    fn advance_withdrawal_to_rave_executed(
+        &self,
+        link_id: &str,
+        br_rave_hash: Option<&str>,
+    ) -> Result<()> {
+        self.session.transaction(|tx| {
+            let filter = "flow = 'withdraw' AND br_spend_hash = $1 AND step = 'coupon_signed'
+                          AND state IN ('queued', 'in_flight')";
+            log_transition(
+                tx,
+                filter,
+                &[&link_id],
+                &Transition::to_step("succeeded", "rave_executed", br_rave_hash),
+            )?;
+            tx.execute(
+                format!(
+                    "UPDATE work_items
+                     SET step = 'rave_executed',
+                         br_rave_hash = $2,
+                         state = 'succeeded',
+                         error_class = NULL,
+                         last_error = NULL,
+                         next_retry_at = NULL,
+                         updated_at = extract(epoch FROM now())::bigint
+                     WHERE {}",
+                    filter
+                )
+                .as_str(),
+                &[&link_id, &br_rave_hash],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn mark_withdrawal_cancelled(&self, link_id: &str) -> Result<()> {
+        self.session.transaction(|tx| {
+            let filter = "flow = 'withdraw' AND br_spend_hash = $1 AND step = 'seen'
+                          AND state IN ('queued', 'in_flight')";
+            log_transition(
+                tx,
+                filter,
+                &[&link_id],
+                &Transition::to_state("failed").because(WITHDRAWAL_CANCELLED),
+            )?;
+            record_error(
+                tx,
+                filter,
+                &[&link_id],
+                Some(ErrorClass::SpendCancelled),
+                WITHDRAWAL_CANCELLED,
+            )?;
+            tx.execute(
+                format!(
+                    "UPDATE work_items
+                     SET state = 'failed',
+                         error_class = $2,
+                         last_error = $3,
+                         next_retry_at = NULL,
+                         updated_at = extract(epoch FROM now())::bigint
+                     WHERE {}",
+                    filter
+                )
+                .as_str(),
+                &[
+                    &link_id,
+                    &ErrorClass::SpendCancelled.as_str(),
+                    &WITHDRAWAL_CANCELLED,
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn list_pending_withdrawal_links(&self, limit: usize) -> Result<Vec<(String, WithdrawStep)>> {
+        self.session.with_client(|client| {
+            client
+                .query(
+                    "SELECT br_spend_hash, step FROM work_items
+                     WHERE flow = 'withdraw'
+                       AND step IN ('seen', 'coupon_signed')
+                       AND state IN ('queued', 'in_flight')
+                       AND br_spend_hash IS NOT NULL
+                     ORDER BY created_at ASC, id ASC
+                     LIMIT $1",
+                    &[&(limit as i64)],
+                )?
+                .iter()
+                .map(|row| {
+                    let step: &str = row.try_get(1)?;
+                    Ok((row.try_get(0)?, step.parse().map_err(anyhow::Error::msg)?))
+                })
+                .collect()
+        })
+    }
+
+    fn record_withdrawal_rejection(
+        &self,
+        link_id: &str,
+        reason: &str,
+        detail: &str,
+        payload_json: &Value,
+    ) -> Result<bool> {
+        self.session.with_client(|client| {
+            let seen_count: i64 = client
+                .query_one(
+                    "INSERT INTO withdrawal_rejections (link_id, reason, detail, payload_json)
+                     VALUES ($1, $2, $3, $4)
+                     ON CONFLICT (link_id) DO UPDATE
+                       SET reason = excluded.reason,
+                           detail = excluded.detail,
+                           payload_json = excluded.payload_json,
+                           seen_count = withdrawal_rejections.seen_count + 1,
+                           last_seen_at = extract(epoch FROM now())::bigint
+                     RETURNING seen_count",
+                    &[&link_id, &reason, &detail, payload_json],
+                )?
+                .get(0);
+            Ok(seen_count == 1)
+        })
+    }
+
+    fn mark_failed_permanent(&self, id: i64, class: ErrorClass, error: &str) -> Result<()> {
+        self.session.transaction(|tx| {
+            log_transition(
+                tx,
+                "id = $1",
+                &[&id],
+                &Transition::to_state("failed").because(error),
+            )?;
+            record_error(tx, "id = $1", &[&id], Some(class), error)?;
+            tx.execute(
+                "UPDATE work_items
+                 SET state = 'failed',
+                     error_class = $2,
+                     last_error = $3,
+                     next_retry_at = NULL,
+                     updated_at = extract(epoch FROM now())::bigint
+                 WHERE id = $1",
+                &[&id, &class.as_str(), &error],
+            )?;
+            if class.is_refundable() {
+                tx.execute(
+                    "INSERT INTO lock_refunds (work_item_id, item_id, created_at, updated_at)
+                     SELECT id, item_id, updated_at, updated_at FROM work_items
+                     WHERE id = $1 AND flow = 'lock'
+                       AND step IN ('new', 'cl_link_created') AND cl_rave_hash IS NULL
+                     ON CONFLICT (work_item_id) DO NOTHING",
+                    &[&id],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn fail_exhausted_queued(&self, flow: &str) -> Result<usize> {
+        self.session.transaction(|tx| {
+            let filter = "flow = $1 AND state = 'queued' AND attempts >= max_attempts";
+            let gave_up = "Exceeded max attempts in-cycle";
+            log_transition(
+                tx,
+                filter,
+                &[&flow],
+                &Transition::to_state("failed").because(gave_up),
+            )?;
+            // The class stays the one that used up the attempts.
+            record_error(tx, filter, &[&flow], None, gave_up)?;
+            let updated = tx.execute(
+                format!(
+                    "UPDATE work_items
+                     SET state = 'failed',
+                         error_class = coalesce(error_class, 'unclassified'),
+                         last_error = $2,
+                         next_retry_at = NULL,
+                         updated_at = extract(epoch FROM now())::bigint
+                     WHERE {}",
+                    filter
+                )
+                .as_str(),
+                &[&flow, &gave_up],
+            )?;
+            Ok(updated as usize)
+        })
+    }
+
+    fn reset_in_flight_to_queued(
+        &self,
+        flow: &str,
+        class: ErrorClass,
+        error: &str,
+        retry: &RetryPolicy,
+    ) -> Result<usize> {
+        self.session.transaction(|tx| {
+            let now: i64 = tx
+                .query_one("SELECT extract(epoch FROM now())::bigint", &[])?
+                .try_get(0)?;
+            let rows = tx.query(
+                "SELECT id, attempts FROM work_items WHERE state = 'in_flight' AND flow = $1",
+                &[&flow],
+            )?;
+            rewind_unsent_coupons(tx, "state = 'in_flight' AND flow = $1", &[&flow])?;
+            for row in &rows {
+                back_off(
+                    tx,
+                    row.try_get(0)?,
+                    row.try_get(1)?,
+                    class,
+                    error,
+                    retry,
+                    now,
+                )?;
+            }
+            Ok(rows.len())
+        })
+    }
+
+    fn status(&self, filter: StateFilter) -> Result<Vec<StatusRow>> {
+        let (sql, params) =
+            StatusQuery::new(&filter, |n| format!("${}", n)).page(STATUS_COLUMNS, &filter);
+        self.session.with_client(|client| {
+            client
+                .query(sql.as_str(), &bind(&params))?
+                .iter()
+                .map(|row| {
+                    Ok(status_row(
+                        row.try_get(0)?,
+                        row.try_get(1)?,
+                        row.try_get(2)?,
+                        row.try_get(3)?,
+                        &row.try_get(4)?,
+                        row.try_get(5)?,
+                        row.try_get(6)?,
+                        row.try_get(7)?,
+                        row.try_get(8)?,
+                        row.try_get(9)?,
+                        row.try_get(10)?,
+                        row.try_get(11)?,
+                        row.try_get(12)?,
+                        row.try_get(13)?,
+                        row.try_get(14)?,
+                        row.try_get(15)?,
+                    ))
+                })
+                .collect()
+        })
+    }
+
+    fn lookup(&self, lookup: &Lookup, limit: usize) -> Result<Vec<LookupRow>> {
+        let (sql, params) = StatusQuery::lookup(lookup, |n| format!("${}", n)).page(
+            &lookup_columns(),
+            &StateFilter {
+                limit,
+                ..Default::default()
+            },
+        );
+        self.session.with_client(|client| {
+            client
+                .query(sql.as_str(), &bind(&params))?
+                .iter()
+                .map(|row| {
+                    let status = status_row(
+                        row.try_get(0)?,
+                        row.try_get(1)?,
+                        row.try_get(2)?,
+                        row.try_get(3)?,
+                        &row.try_get(4)?,
+                        row.try_get(5)?,
+                        row.try_get(6)?,
+                        row.try_get(7)?,
+                        row.try_get(8)?,
+                        row.try_get(9)?,
+                        row.try_get(10)?,
+                        row.try_get(11)?,
+                        row.try_get(12)?,
+                        row.try_get(13)?,
+                        row.try_get(14)?,
+                        row.try_get(15)?,
+                    );
+                    Ok(LookupRow::new(
+                        status,
+                        [
+                            row.try_get(16)?,
+                            row.try_get(17)?,
+                            row.try_get(18)?,
+                            row.try_get(19)?,
+                        ],
+                    ))
+                })
+                .collect()
+        })
+    }
+
+    fn report_items(&self, from: i64, to: i64) -> Result<Vec<ReportItem>> {
+        self.session.with_client(|client| {
+            client
+                .query(report_query(|n| format!("${}", n)).as_str(), &[&from, &to])?
+                .iter()
+                .map(|row| {
+                    Ok(ReportItem::new(
+                        row.try_get(0)?,
+                        row.try_get(1)?,
+                        row.try_get(2)?,
+                        row.try_get(3)?,
+                        &row.try_get(4)?,
+                        row.try_get(5)?,
+                        row.try_get(6)?,
+                        row.try_get(7)?,
+                    ))
+                })
+                .collect()
+        })
+    }
+
+    fn status_summary(&self, filter: &StateFilter) -> Result<StatusSummary> {
+        let (sql, params) = StatusQuery::new(filter, |n| format!("${}", n)).summary();
+        self.session.with_client(|client| {
+            let mut tally = SummaryTally::default();
+            for row in client.query(sql.as_str(), &bind(&params))? {
+                tally.add(
+                    row.try_get(0)?,
+                    row.try_get(1)?,
+                    row.try_get(2)?,
+                    row.try_get(3)?,
+                    &row.try_get(4)?,
+                );
+            }
+            Ok(tally.finish())
+        })
+    }
+
+    fn history(&self, item_id: &str) -> Result<Vec<WorkItemEvent>> {
+        self.session.with_client(|client| {
+            client
+                .query(
+                    format!("{} WHERE item_id = $1 ORDER BY id ASC", SELECT_EVENTS).as_str(),
+                    &[&item_id],
+                )?
+                .iter()
+                .map(row_to_event)
+                .collect()
+        })
+    }
+
+    fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>> {
+        self.session.with_client(|client| {
+            client
+                .query(
+                    format!(
+                        "SELECT {}, ({}),
+                                (SELECT status FROM lock_refunds WHERE work_item_id = work_items.id)
+                         FROM work_items
+                         WHERE flow = 'lock' AND state = 'failed'
+                         ORDER BY updated_at DESC, id DESC
+                         LIMIT $1",
+                        STATUS_COLUMNS, FAILED_FROM_STATE
+                    )
+                    .as_str(),
+                    &[&(limit as i64)],
+                )?
+                .iter()
+                .map(|row| {
+                    let task_type: String = row.try_get(2)?;
+                    let payload: Value = row.try_get(4)?;
+                    let payload_error = reload_payload(&task_type, &payload.to_string())
+                        .err()
+                        .map(|e| format!("{:#}", e));
+                    let failed_from: Option<String> = row.try_get(16)?;
+                    let refund: Option<String> = row.try_get(17)?;
+                    Ok(DeadLetter {
+                        row: status_row(
+                            row.try_get(0)?,
+                            row.try_get(1)?,
+                            task_type,
+                            row.try_get(3)?,
+                            &payload,
+                            row.try_get(5)?,
+                            row.try_get(6)?,
+                            row.try_get(7)?,
+                            row.try_get(8)?,
+                            row.try_get(9)?,
+                            row.try_get(10)?,
+                            row.try_get(11)?,
+                            row.try_get(12)?,
+                            row.try_get(13)?,
+                            row.try_get(14)?,
+                            row.try_get(15)?,
+                        ),
+                        replay_state: replay_state(failed_from.as_deref()),
+                        payload_error,
+                        refund: refund.and_then(|status| status.parse().ok()),
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn replay_dead_letter(&self, item_id: &str, actor: &str) -> Result<WorkItemEvent> {
+        self.session.transaction(|tx| {
+            let target = tx.query(
+                format!(
+                    "SELECT id, flow, state, attempts, task_type, payload_json, step, ({})
+                     FROM work_items WHERE item_id = $1",
+                    FAILED_FROM_STATE
+                )
+                .as_str(),
+                &[&item_id],
+            )?;
+            let row = match target.as_slice() {
+                [] => bail!("no work item with item_id {}", item_id),
+                [row] => row,
+                rows => bail!("item_id {} matches {} work items", item_id, rows.len()),
+            };
+            let (id, flow, state, attempts): (i64, String, String, i64) = (
+                row.try_get(0)?,
+                row.try_get(1)?,
+                row.try_get(2)?,
+                row.try_get(3)?,
+            );
+            if flow != "lock" || state != "failed" {
+                bail!(
+                    "{} is a {} {} item; only failed locks are dead letters",
+                    item_id,
+                    state,
+                    flow
+                );
+            }
+            let payload: Value = row.try_get(5)?;
+            let step: String = row.try_get(6)?;
+            let failed_from: Option<String> = row.try_get(7)?;
+            let payload = reload_payload(&row.try_get::<_, String>(4)?, &payload.to_string())
+                .with_context(|| format!("{} cannot be replayed", item_id))?;
+            drop_pending_refund(tx, id, item_id)?;
+            let state = replay_state(failed_from.as_deref()).to_string();
+            let event = log_operator_event(
+                tx,
+                id,
+                Some(&state),
+                None,
+                &format!("dlq replay at {} after {} attempt(s)", step, attempts),
+                actor,
+            )?;
+            // Written back at the current payload version.
+            tx.execute(
+                "UPDATE work_items
+                 SET state = $2,
+                     payload_json = $3,
+                     attempts = 0,
+                     next_retry_at = NULL,
+                     error_class = NULL,
+                     last_error = NULL,
+                     updated_at = extract(epoch FROM now())::bigint
+                 WHERE id = $1",
+                &[&id, &state, &payload.to_json()?],
+            )?;
+            Ok(event)
+        })
+    }
+
+    fn refunds(&self, status: Option<RefundStatus>, limit: usize) -> Result<Vec<LockRefund>> {
+        self.session.with_client(|client| {
+            client
+                .query(
+                    format!(
+                        "{} WHERE $1::text IS NULL OR r.status = $1
+                         ORDER BY r.updated_at DESC, r.work_item_id DESC
+                         LIMIT $2",
+                        SELECT_REFUNDS
+                    )
+                    .as_str(),
+                    &[&status.map(|s| s.to_string()), &(limit as i64)],
+                )?
+                .iter()
+                .map(row_to_refund)
+                .collect()
+        })
+    }
+
+    fn approve_refund(&self, item_id: &str, actor: &str) -> Result<LockRefund> {
+        self.session.transaction(|tx| {
+            let (id, refund) = refund_target(tx, item_id)?;
+            let Some(reason) = approval_reason(item_id, &refund)? else {
+                return Ok(refund);
+            };
+            log_operator_event(tx, id, None, None, &reason, actor)?;
+            tx.execute(
+                "UPDATE lock_refunds
+                 SET status = 'approved',
+                     approved_by = $2,
+                     approved_at = extract(epoch FROM now())::bigint,
+                     updated_at = extract(epoch FROM now())::bigint
+                 WHERE work_item_id = $1",
+                &[&id, &actor],
+            )?;
+            Ok(refund_target(tx, item_id)?.1)
+        })
+    }
+
+    fn record_refund_coupon(&self, item_id: &str, coupon: &str, actor: &str) -> Result<LockRefund> {
+        self.session.transaction(|tx| {
+            let (id, refund) = refund_target(tx, item_id)?;
+            if refund.status != RefundStatus::Approved {
+                bail!("{}'s refund is {}, not approved", item_id, refund.status);
+            }
+            log_operator_event(tx, id, None, None, "refund coupon signed", actor)?;
+            tx.execute(
+                "UPDATE lock_refunds
+                 SET status = 'coupon_signed',
+                     coupon = $2,
+                     coupon_signed_at = extract(epoch FROM now())::bigint,
+                     last_error = NULL,
+                     updated_at = extract(epoch FROM now())::bigint
+                 WHERE work_item_id = $1",
+                &[&id, &coupon],
+            )?;
+            Ok(refund_target(tx, item_id)?.1)
+        })
+    }
+
+    fn record_refund_error(&self, item_id: &str, error: &str, actor: &str) -> Result<()> {
+        self.session.transaction(|tx| {
+            let (id, _) = refund_target(tx, item_id)?;
+            log_operator_event(
+                tx,
+                id,
+                None,
+                None,
+                &format!("refund coupon not signed: {}", error),
+                actor,
+            )?;
+            tx.execute(
+                "UPDATE lock_refunds
+                 SET last_error = $2, updated_at = extract(epoch FROM now())::bigint
+                 WHERE work_item_id = $1",
+                &[&id, &error],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn complete_refund(
+        &self,
+        item_id: &str,
+        claim_tx_hash: &str,
+        actor: &str,
+    ) -> Result<LockRefund> {
+        let claim_tx_hash = parse_claim_tx_hash(claim_tx_hash)?;
+        self.session.transaction(|tx| {
+            let (id, refund) = refund_target(tx, item_id)?;
+            ensure_claimable(item_id, &refund)?;
+            log_operator_event(
+                tx,
+                id,
+                None,
+                None,
+                &format!("refund claim reported in {}", claim_tx_hash),
+                actor,
+            )?;
+            tx.execute(
+                "UPDATE lock_refunds
+                 SET status = 'operator_reported',
+                     claim_tx_hash = $2,
+                     reported_at = extract(epoch FROM now())::bigint,
+                     updated_at = extract(epoch FROM now())::bigint
+                 WHERE work_item_id = $1",
+                &[&id, &claim_tx_hash],
+            )?;
+            Ok(refund_target(tx, item_id)?.1)
+        })
+    }
+
+    fn list_withdrawal_rejections(&self, limit: usize) -> Result<Vec<WithdrawalRejectionRow>> {
+        self.session.with_client(|client| {
+            client
+                .query(
+                    "SELECT link_id, reason, detail, payload_json, seen_count, first_seen_at,
+                            last_seen_at
+                     FROM withdrawal_rejections
+                     ORDER BY last_seen_at DESC, link_id ASC
+                     LIMIT $1",
+                    &[&(limit as i64)],
+                )?
+                .iter()
+                .map(|row| {
+                    Ok(WithdrawalRejectionRow {
+                        link_id: row.try_get(0)?,
+                        reason: row.try_get(1)?,
+                        detail: row.try_get(2)?,
+                        payload_json: row.try_get(3)?,
+                        seen_count: row.try_get(4)?,
+                        first_seen_at: row.try_get(5)?,
+                        last_seen_at: row.try_get(6)?,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn clear_non_in_progress(&self) -> Result<usize> {
+        self.session.transaction(|tx| {
+            let ids: Vec<i64> = tx
+                .query(
+                    format!(
+                        "SELECT id FROM work_items
+                         WHERE state IN ('succeeded', 'failed') AND id NOT IN ({})
+                         FOR UPDATE",
+                        OUTSTANDING_REFUNDS
+                    )
+                    .as_str(),
+                    &[],
+                )?
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+            tx.execute(
+                "DELETE FROM work_item_events WHERE work_item_id = ANY($1)",
+                &[&ids],
+            )?;
+            tx.execute(
+                "DELETE FROM lock_refunds WHERE work_item_id = ANY($1)",
+                &[&ids],
+            )?;
+            Ok(tx.execute("DELETE FROM work_items WHERE id = ANY($1)", &[&ids])? as usize)
+        })
+    }
+
+    fn clear_all(&self) -> Result<usize> {
+        self.session.transaction(|tx| {
+            let outstanding: i64 = tx
+                .query_one(
+                    format!("SELECT count(*) FROM ({}) outstanding", OUTSTANDING_REFUNDS).as_str(),
+                    &[],
+                )?
+                .try_get(0)?;
+            if outstanding > 0 {
+                bail!(
+                    "{} refund(s) are approved or have a signed coupon; report their claims \
+                     with `refund complete` before clearing everything",
+                    outstanding
+                );
+            }
+            tx.execute("DELETE FROM work_item_events", &[])?;
+            tx.execute("DELETE FROM lock_refunds", &[])?;
+            Ok(tx.execute("DELETE FROM work_items", &[])? as usize)
+        })
+    }
+
+    fn requeue_item(
+        &self,
+        item_id: &str,
+        opts: &RequeueOptions,
+        holds_lease: bool,
+        actor: &str,
+    ) -> Result<WorkItemEvent> {
+        self.session.transaction(|tx| {
+            let target = operator_target(tx, item_id)?;
+            ensure_settled(item_id, &target, holds_lease)?;
+            let reason = requeue_reason(item_id, &target, opts)?;
+            drop_pending_refund(tx, target.id, item_id)?;
+            let step = opts.step.as_ref().map(ToString::to_string);
+            let event = log_operator_event(
+                tx,
+                target.id,
+                Some("queued"),
+                step.as_deref(),
+                &reason,
+                actor,
+            )?;
+            tx.execute(
+                format!(
+                    "UPDATE work_items
+                     SET state = 'queued',
+                         step = coalesce($2, step),
+                         {}attempts = CASE WHEN $3 THEN 0 ELSE attempts END,
+                         next_retry_at = NULL,
+                         error_class = NULL,
+                         last_error = NULL,
+                         updated_at = extract(epoch FROM now())::bigint
+                     WHERE id = $1",
+                    cleared_hashes(opts)
+                )
+                .as_str(),
+                &[&target.id, &step, &opts.reset_attempts],
+            )?;
+            Ok(event)
+        })
+    }
+
+    fn force_fail_item(
+        &self,
+        item_id: &str,
+        reason: &str,
+        holds_lease: bool,
+        actor: &str,
+    ) -> Result<WorkItemEvent> {
+        self.session.transaction(|tx| {
+            let target = operator_target(tx, item_id)?;
+            ensure_settled(item_id, &target, holds_lease)?;
+            if matches!(target.state, WorkState::Succeeded | WorkState::Failed) {
+                bail!("{} is already {}", item_id, target.state);
+            }
+            let error = format!("operator: {}", reason);
+            let event = log_operator_event(tx, target.id, Some("failed"), None, &error, actor)?;
+            record_error(
+                tx,
+                "id = $1",
+                &[&target.id],
+                Some(ErrorClass::Operator),
+                &error,
+            )?;
+            tx.execute(
+                "UPDATE work_items
+                 SET state = 'failed',
+                     error_class = 'operator',
+                     last_error = $2,
+                     next_retry_at = NULL,
+                     updated_at = extract(epoch FROM now())::bigint
+                 WHERE id = $1",
+                &[&target.id, &error],
+            )?;
+            Ok(event)
+        })
+    }
+
+    fn annotate_item(&self, item_id: &str, note: &str, actor: &str) -> Result<WorkItemEvent> {
+        self.session.transaction(|tx| {
+            let target = operator_target(tx, item_id)?;
+            log_operator_event(tx, target.id, None, None, &format!("note: {}", note), actor)
+        })
+    }
+
+    /// Reads over a connection of its own, like the SQLite store, so
+    /// reporting never waits on the writer's.
+    fn aggregate_stats(&self) -> Result<BridgeAggregateStats> {
+        blocking(|| {
+            let mut client = self
+                .session
+                .config
+                .connect(NoTls)
+                .context("connect to postgres")?;
+            compute_aggregate_stats(&mut client)
+        })
+    }
+
+    fn prune_terminal_batch(
+        &self,
+        succeeded_max_age_s: u64,
+        failed_max_age_s: u64,
+        max_rows: usize,
+        archive: Option<&ArchiveFn<'_>>,
+    ) -> Result<PruneStats> {
+        self.session.transaction(|tx| {
+            let mut deleted = [0; 2];
+            let mut doomed = Vec::new();
+            for (i, (state, max_age_s)) in [
+                ("succeeded", succeeded_max_age_s),
+                ("failed", failed_max_age_s),
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                let age = max_age_s as i64;
+                let room = max_rows.saturating_sub(deleted[0]).min(i64::MAX as usize) as i64;
+                if room == 0 {
+                    break;
+                }
+                // Locked, so every statement below sees the same rows.
+                let ids: Vec<i64> = tx
+                    .query(
+                        "SELECT id FROM work_items
+                         WHERE state = $1
+                           AND NOT (state = 'failed' AND flow = 'lock')
+                           AND updated_at < extract(epoch FROM now())::bigint - $2
+                         ORDER BY id LIMIT $3
+                         FOR UPDATE",
+                        &[&state, &age, &room],
+                    )?
+                    .iter()
+                    .map(|row| row.get(0))
+                    .collect();
+                if ids.is_empty() {
+                    continue;
+                }
+                if archive.is_some() {
+                    doomed.extend(select_archived(tx, &ids)?);
+                }
+                tx.execute(
+                    "DELETE FROM work_item_events WHERE work_item_id = ANY($1)",
+                    &[&ids],
+                )?;
+                deleted[i] =
+                    tx.execute("DELETE FROM work_items WHERE id = ANY($1)", &[&ids])? as usize;
+            }
+            if let Some(archive) = archive {
+                if !doomed.is_empty() {
+                    archive(&doomed)?;
+                }
+            }
+            Ok(PruneStats {
+                succeeded_deleted: deleted[0],
+                failed_deleted: deleted[1],
+                budget_exhausted: false,
+            })
+        })
+    }
+
+    fn record_writer_lease(&self, pid: u32, host: &str) -> Result<()> {
+        self.session.with_client(|client| {
+            client.execute(
+                "INSERT INTO writer_lease (id, pid, host, acquired_at, heartbeat_at)
+                 VALUES (1, $1, $2, extract(epoch FROM now())::bigint, extract(epoch FROM now())::bigint)
+                 ON CONFLICT (id) DO UPDATE SET
+                     acquired_at = CASE WHEN writer_lease.pid = excluded.pid
+                                         AND writer_lease.host = excluded.host
+                                        THEN writer_lease.acquired_at ELSE excluded.acquired_at END,
+                     pid = excluded.pid,
+                     host = excluded.host,
+                     heartbeat_at = excluded.heartbeat_at",
+                &[&i64::from(pid), &host],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn refresh_writer_lease(&self, pid: u32, host: &str) -> Result<bool> {
+        self.session.with_client(|client| {
+            let refreshed = client.execute(
+                "UPDATE writer_lease SET heartbeat_at = extract(epoch FROM now())::bigint
+                 WHERE id = 1 AND pid = $1 AND host = $2",
+                &[&i64::from(pid), &host],
+            )?;
+            Ok(refreshed == 1)
+        })
+    }
+
+    fn release_writer_lease(&self, pid: u32) -> Result<()> {
+        self.session.with_client(|client| {
+            client.execute(
+                "DELETE FROM writer_lease WHERE id = 1 AND pid = $1",
+                &[&i64::from(pid)],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+/// Leader-election lease in the shared Postgres database, for candidates on
+/// different hosts. Behaves like [`crate::leader::SqliteLeaseBackend`].
+pub struct PostgresLeaseBackend {
+    session: Session,
+}
+
+impl PostgresLeaseBackend {
+    pub fn connect(url: &str) -> Result<Self> {
+        Self::connect_with(url.parse().context("invalid DATABASE_URL")?)
+    }
+
+    fn connect_with(config: postgres::Config) -> Result<Self> {
+        let session = Session::connect(config, true)?;
+        session.with_client(|client| {
+            client.batch_execute(
+                "CREATE TABLE IF NOT EXISTS leader_lease (
+                    id INTEGER PRIMARY KEY CHECK (id = 1),
+                    holder TEXT NOT NULL,
+                    term BIGINT NOT NULL,
+                    expires_at_ms BIGINT NOT NULL
+                );",
+            )?;
+            Ok(())
+        })?;
+        Ok(Self { session })
+    }
+}
+
+impl LeaseBackend for PostgresLeaseBackend {
+    fn try_acquire(&self, candidate: &str, now_ms: i64, ttl_ms: i64) -> Result<LeaseRecord> {
+        self.session.transaction(|tx| {
+            // ON CONFLICT locks the row even when the WHERE refuses the
+            // update, so the read below sees what this statement decided.
+            tx.execute(
+                "INSERT INTO leader_lease (id, holder, term, expires_at_ms)
+                 VALUES (1, $1, 1, $2::bigint + $3::bigint)
+                 ON CONFLICT (id) DO UPDATE SET
+                     term = CASE WHEN leader_lease.holder = excluded.holder
+                                 THEN leader_lease.term ELSE leader_lease.term + 1 END,
+                     holder = excluded.holder,
+                     expires_at_ms = excluded.expires_at_ms
+                 WHERE leader_lease.holder = excluded.holder
+                    OR leader_lease.expires_at_ms <= $2",
+                &[&candidate, &now_ms, &ttl_ms],
+            )?;
+            let row = tx.query_one(
+                "SELECT holder, term, expires_at_ms FROM leader_lease WHERE id = 1",
+                &[],
+            )?;
+            Ok(LeaseRecord {
+                holder: row.try_get(0)?,
+                term: row.try_get(1)?,
+                expires_at_ms: row.try_get(2)?,
+            })
+        })
+    }
+
+    fn release(&self, candidate: &str) -> Result<()> {
+        self.session.with_client(|client| {
+            client.execute(
+                "UPDATE leader_lease SET expires_at_ms = 0 WHERE id = 1 AND holder = $1",
+                &[&candidate],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+/// Report what [`migrate`] would apply, without writing anything: a
+/// database with no `schema_meta` yet has every version pending.
+fn plan(client: &mut Client) -> Result<MigrationReport> {
+    let latest = SCHEMA.last().map_or(0, |s| s.version);
+    let has_meta: bool = client
+        .query_one("SELECT to_regclass('schema_meta') IS NOT NULL", &[])?
+        .get(0);
+    let from: i64 = if has_meta {
+        client
+            .query_opt("SELECT version FROM schema_meta WHERE id = 1", &[])?
+            .map_or(0, |row| row.get(0))
+    } else {
+        0
+    };
+    check_not_newer(from, latest)?;
+    Ok(MigrationReport {
+        from_version: from,
+        to_version: latest,
+        steps: SCHEMA
+            .iter()
+            .filter(|s| s.version > from)
+            .map(|s| MigrationStep {
+                version: s.version,
+                name: s.name,
+            })
+            .collect(),
+        backup_path: None,
+    })
+}
+
+/// Bring the schema up to the last entry of [`SCHEMA`], in one
+/// transaction.
+fn migrate(client: &mut Client) -> Result<MigrationReport> {
+    let latest = SCHEMA.last().map_or(0, |s| s.version);
+    let mut tx = client.transaction()?;
+    tx.execute(
+        "SELECT pg_advisory_xact_lock(hashtext($1), hashtext(current_schema()))",
+        &[&SCHEMA_LOCK],
+    )?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_meta (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            version BIGINT NOT NULL
+        );",
+    )?;
+    let from: i64 = tx
+        .query_opt("SELECT version FROM schema_meta WHERE id = 1", &[])?
+        .map_or(0, |row| row.get(0));
+    check_not_newer(from, latest)?;
+
+    let mut steps = Vec::new();
+    for schema in SCHEMA.iter().filter(|s| s.version > from) {
+        tx.batch_execute(schema.sql)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| schema.then.map_or(Ok(()), |then| then(&mut tx)))
+            .with_context(|| {
+                format!(
+                    "postgres schema v{} ({}) failed",
+                    schema.version, schema.name
+                )
+            })?;
+        steps.push(MigrationStep {
+            version: schema.version,
+            name: schema.name,
+        });
+    }
+    if !steps.is_empty() {
+        tx.execute(
+            "INSERT INTO schema_meta (id, version) VALUES (1, $1)
+             ON CONFLICT (id) DO UPDATE SET version = excluded.version",
+            &[&latest],
+        )?;
+        tracing::info!(
+            event = "state.postgres_schema_applied",
+            from_version = from,
+            to_version = latest,
+            "applied postgres schema"
+        );
+    }
+    tx.commit()?;
+    Ok(MigrationReport {
+        from_version: from,
+        to_version: latest,
+        steps,
+        backup_path: None,
+    })
+}
+
+/// Append a `work_item_events` row for every row `filter` matches whose
+/// state or step `transition` changes, like its SQLite counterpart: run
+/// it before the UPDATE it describes, with that UPDATE's WHERE clause and
+/// parameters (numbered from `$1`).
+fn log_transition(
+    tx: &mut Transaction<'_>,
+    filter: &str,
+    filter_params: &[&(dyn ToSql + Sync)],
+    transition: &Transition<'_>,
+) -> Result<u64> {
+    let n = filter_params.len();
+    let sql = format!(
+        "INSERT INTO work_item_events
+             (work_item_id, item_id, from_state, to_state, from_step, to_step, reason, attempt, action_hash)
+         SELECT id, item_id, state, coalesce(${s}::text, state), step, coalesce(${t}::text, step),
+                ${r}::text, attempts + ${a}::bigint, ${h}::text
+         FROM work_items
+         WHERE ({filter})
+           AND (state IS DISTINCT FROM coalesce(${s}::text, state)
+                OR step IS DISTINCT FROM coalesce(${t}::text, step))",
+        s = n + 1,
+        t = n + 2,
+        r = n + 3,
+        a = n + 4,
+        h = n + 5,
+        filter = filter,
+    );
+    let mut params: Vec<&(dyn ToSql + Sync)> = filter_params.to_vec();
+    params.extend([
+        &transition.state as &(dyn ToSql + Sync),
+        &transition.step,
+        &transition.reason,
+        &transition.attempt_bump,
+        &transition.action_hash,
+    ]);
+    Ok(tx.execute(sql.as_str(), &params)?)
+}
+
+/// `params` as the client binds them.
+fn bind(params: &[QueryParam]) -> Vec<&(dyn ToSql + Sync)> {
+    params
+        .iter()
+        .map(|p| match p {
+            QueryParam::Text(v) => v as &(dyn ToSql + Sync),
+            QueryParam::Int(v) => v,
+        })
+        .collect()
+}
+
+/// Append `error` to the `recent_errors` of every row `filter` matches,
+/// like its SQLite counterpart: under `class`, or the row's own class when
+/// it is `None`.
+fn record_error(
+    tx: &mut Transaction<'_>,
+    filter: &str,
+    filter_params: &[&(dyn ToSql + Sync)],
+    class: Option<ErrorClass>,
+    error: &str,
+) -> Result<()> {
+    let rows = tx.query(
+        format!(
+            "SELECT id, error_class, recent_errors FROM work_items WHERE ({})",
+            filter
+        )
+        .as_str(),
+        filter_params,
+    )?;
+    let at = chrono::Utc::now().timestamp();
+    for row in rows {
+        let id: i64 = row.try_get(0)?;
+        let entry = RecentError {
+            at,
+            class: class
+                .or(ErrorClass::from_column(row.try_get(1)?))
+                .unwrap_or(ErrorClass::Unclassified),
+            error: error.to_string(),
+        };
+        let recent: Option<String> = row.try_get(2)?;
+        tx.execute(
+            "UPDATE work_items SET recent_errors = $2 WHERE id = $1",
+            &[&id, &entry.append_to(recent.as_deref())?],
+        )?;
+    }
+    Ok(())
+}
+
+/// Return row `id` to `queued` behind its backoff, or fail it once its
+/// budget is spent, like its SQLite counterpart.
+fn back_off(
+    tx: &mut Transaction<'_>,
+    id: i64,
+    attempts: i64,
+    class: ErrorClass,
+    error: &str,
+    retry: &RetryPolicy,
+    now: i64,
+) -> Result<()> {
+    let next_retry_at = retry.next_retry_at(class, id, attempts + 1, now);
+    let exhausted = format!("Exceeded max attempts: {}", error);
+    let (state, reason) = match next_retry_at {
+        Some(_) => ("queued", error),
+        None => ("failed", exhausted.as_str()),
+    };
+    log_transition(
+        tx,
+        "id = $1",
+        &[&id],
+        &Transition::to_state(state)
+            .because(reason)
+            .bumping_attempts(),
+    )?;
+    record_error(tx, "id = $1", &[&id], Some(class), reason)?;
+    tx.execute(
+        "UPDATE work_items
+         SET state = $2,
+             attempts = attempts + 1,
+             max_attempts = $3,
+             error_class = $4,
+             last_error = $5,
+             next_retry_at = $6,
+             last_attempt_at = $7,
+             updated_at = $7
+         WHERE id = $1",
+        &[
+            &id,
+            &state,
+            &retry.max_attempts(class),
+            &class.as_str(),
+            &reason,
+            &next_retry_at,
+            &now,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Put the withdrawals `filter` matches that sit at `coupon_signed` back at
+/// `seen`, like its SQLite counterpart.
+fn rewind_unsent_coupons(
+    tx: &mut Transaction<'_>,
+    filter: &str,
+    filter_params: &[&(dyn ToSql + Sync)],
+) -> Result<()> {
+    let filter = format!(
+        "({}) AND flow = 'withdraw' AND step = 'coupon_signed'",
+        filter
+    );
+    log_transition(
+        tx,
+        &filter,
+        filter_params,
+        &Transition::to_step("in_flight", "seen", None).because(UNSENT_COUPON),
+    )?;
+    tx.execute(
+        format!("UPDATE work_items SET step = 'seen' WHERE {}", filter).as_str(),
+        filter_params,
+    )?;
+    Ok(())
+}
+
+/// Append the creation event for a freshly inserted row.
+fn log_created(tx: &mut Transaction<'_>, id: i64, reason: &str) -> Result<()> {
+    tx.execute(
+        "INSERT INTO work_item_events
+             (work_item_id, item_id, from_state, to_state, from_step, to_step, reason, attempt)
+         SELECT id, item_id, NULL, state, NULL, step, $2::text, attempts
+         FROM work_items WHERE id = $1",
+        &[&id, &reason],
+    )?;
+    Ok(())
+}
+
+/// Append an operator's audit event for row `id`, moving it to `to_state`
+/// / `to_step` (`None` keeps the current value), and return it, like its
+/// SQLite counterpart.
+fn log_operator_event(
+    tx: &mut Transaction<'_>,
+    id: i64,
+    to_state: Option<&str>,
+    to_step: Option<&str>,
+    reason: &str,
+    actor: &str,
+) -> Result<WorkItemEvent> {
+    let event = tx.query_one(
+        "INSERT INTO work_item_events
+             (work_item_id, item_id, from_state, to_state, from_step, to_step, reason, attempt, actor)
+         SELECT id, item_id, state, coalesce($2::text, state), step, coalesce($3::text, step),
+                $4::text, attempts, $5::text
+         FROM work_items WHERE id = $1
+         RETURNING id, work_item_id, item_id, from_state, to_state, from_step, to_step,
+                   reason, attempt, action_hash, actor, created_at",
+        &[&id, &to_state, &to_step, &reason, &actor],
+    )?;
+    row_to_event(&event)
+}
+
+/// Resolve `item_id` to exactly one row for an operator command.
+fn operator_target(tx: &mut Transaction<'_>, item_id: &str) -> Result<OperatorTarget> {
+    let rows = tx.query(
+        "SELECT id, flow, state, attempts, max_attempts FROM work_items WHERE item_id = $1",
+        &[&item_id],
+    )?;
+    let row = match rows.as_slice() {
+        [] => bail!("no work item with item_id {}", item_id),
+        [row] => row,
+        rows => bail!("item_id {} matches {} work items", item_id, rows.len()),
+    };
+    Ok(OperatorTarget {
+        id: row.try_get(0)?,
+        flow: row.try_get(1)?,
+        state: row
+            .try_get::<_, String>(2)?
+            .parse()
+            .unwrap_or(WorkState::Failed),
+        attempts: row.try_get(3)?,
+        max_attempts: row.try_get(4)?,
+    })
+}
+
+/// Forget a lock's refund because an operator is retrying the lock
+/// instead, like its SQLite counterpart.
+fn drop_pending_refund(tx: &mut Transaction<'_>, id: i64, item_id: &str) -> Result<()> {
+    let status: Option<String> = tx
+        .query_opt(
+            "SELECT status FROM lock_refunds WHERE work_item_id = $1",
+            &[&id],
+        )?
+        .map(|r| r.try_get(0))
+        .transpose()?;
+    match status.as_deref() {
+        None => Ok(()),
+        Some("refundable") => {
+            tx.execute("DELETE FROM lock_refunds WHERE work_item_id = $1", &[&id])?;
+            Ok(())
+        }
+        Some(status) => bail!(
+            "{}'s refund is {}; it cannot be retried as well",
+            item_id,
+            status
+        ),
+    }
+}
+
+/// Resolve `item_id` to its row and its refund for a `refund` command.
+fn refund_target(tx: &mut Transaction<'_>, item_id: &str) -> Result<(i64, LockRefund)> {
+    let ids: Vec<i64> = tx
+        .query("SELECT id FROM work_items WHERE item_id = $1", &[&item_id])?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    let id = match ids.as_slice() {
+        [] => bail!("no work item with item_id {}", item_id),
+        [id] => *id,
+        ids => bail!("item_id {} matches {} work items", item_id, ids.len()),
+    };
+    let refund = tx
+        .query_opt(
+            format!("{} WHERE r.work_item_id = $1", SELECT_REFUNDS).as_str(),
+            &[&id],
+        )?
+        .with_context(|| {
+            format!(
+                "{} has no refund; only locks that failed as tag_oversize or invalid_agent do",
+                item_id
+            )
+        })?;
+    Ok((id, row_to_refund(&refund)?))
+}
+
+/// The rows with `ids`, each with its events, oldest event first.
+fn select_archived(tx: &mut Transaction<'_>, ids: &[i64]) -> Result<Vec<ArchivedWorkItem>> {
+    let items = tx
+        .query(
+            format!(
+                "SELECT {} FROM work_items WHERE id = ANY($1) ORDER BY id ASC",
+                WORK_ITEM_COLUMNS
+            )
+            .as_str(),
+            &[&ids],
+        )?
+        .iter()
+        .map(row_to_work_item)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut events: HashMap<i64, Vec<WorkItemEvent>> = HashMap::new();
+    for row in tx.query(
+        format!(
+            "{} WHERE work_item_id = ANY($1) ORDER BY id ASC",
+            SELECT_EVENTS
+        )
+        .as_str(),
+        &[&ids],
+    )? {
+        let event = row_to_event(&row)?;
+        events.entry(event.work_item_id).or_default().push(event);
+    }
+
+    Ok(items
+        .into_iter()
+        .map(|item| ArchivedWorkItem {
+            events: events.remove(&item.id).unwrap_or_default(),
+            item,
+        })
+        .collect())
+}
+
+fn read_writer_lease(client: &mut Client) -> Result<Option<WriterLeaseRow>> {
+    let row = client.query_opt(
+        "SELECT pid, host, acquired_at, heartbeat_at FROM writer_lease WHERE id = 1",
+        &[],
+    )?;
+    row.map(|row| {
+        Ok(WriterLeaseRow {
+            pid: u32::try_from(row.try_get::<_, i64>(0)?).unwrap_or_default(),
+            host: row.try_get(1)?,
+            acquired_at: row.try_get(2)?,
+            heartbeat_at: row.try_get(3)?,
+        })
+    })
+    .transpose()
+}
+
+fn compute_aggregate_stats(client: &mut Client) -> Result<BridgeAggregateStats> {
+    let mut stats = BridgeAggregateStats::default();
+    let day_ago: i64 = client
+        .query_one("SELECT extract(epoch FROM now())::bigint - 86400", &[])?
+        .try_get(0)?;
+    let rows = client
+        .query(
+            flow_counts_query(|n| format!("${}", n)).as_str(),
+            &[&day_ago],
+        )?
+        .iter()
+        .map(|row| {
+            Ok((
+                row.try_get(0)?,
+                row.try_get(1)?,
+                row.try_get(2)?,
+                row.try_get(3)?,
+            ))
+        })
+        .collect::<Result<Vec<_>, postgres::Error>>()?;
+    tally_flow_counts(&mut stats, rows);
+
+    let row = client.query_one(
+        "SELECT
+             count(*) FILTER (WHERE state = 'succeeded' AND updated_at >= now_s - 86400),
+             count(*) FILTER (WHERE state = 'failed' AND updated_at >= now_s - 86400),
+             count(*) FILTER (WHERE state = 'succeeded' AND updated_at >= now_s - 3600),
+             count(*) FILTER (WHERE state = 'failed' AND updated_at >= now_s - 3600),
+             max(now_s - created_at) FILTER (WHERE state = 'queued'),
+             (avg(updated_at - created_at)
+                 FILTER (WHERE state = 'succeeded' AND updated_at >= now_s - 86400))::float8
+         FROM work_items, (SELECT extract(epoch FROM now())::bigint AS now_s) AS clock",
+        &[],
+    )?;
+    stats.succeeded_24h = row.try_get(0)?;
+    stats.failed_24h = row.try_get(1)?;
+    stats.succeeded_1h = row.try_get(2)?;
+    stats.failed_1h = row.try_get(3)?;
+    stats.oldest_queued_age_s = row.try_get(4)?;
+    stats.avg_time_to_succeed_s_24h = row.try_get(5)?;
+
+    stats.withdrawals_rejected_total = client
+        .query_one("SELECT count(*) FROM withdrawal_rejections", &[])?
+        .try_get(0)?;
+
+    // Summed here rather than in SQL, as on SQLite.
+    let fees = client
+        .query(
+            "SELECT fee_wei FROM work_items
+             WHERE flow = 'withdraw' AND state = 'succeeded' AND fee_wei IS NOT NULL
+               AND updated_at >= extract(epoch FROM now())::bigint - 86400",
+            &[],
+        )?
+        .iter()
+        .map(|row| row.try_get(0))
+        .collect::<Result<Vec<String>, _>>()?;
+    tally_withdrawal_fees(&mut stats, fees);
+
+    let now: i64 = client
+        .query_one("SELECT extract(epoch FROM now())::bigint", &[])?
+        .try_get(0)?;
+    let mut tally = StageTally::new(now);
+    for row in client.query(
+        stage_events_query(|n| format!("${}", n)).as_str(),
+        &[&(now - 86_400)],
+    )? {
+        tally.add(
+            row.try_get(0)?,
+            row.try_get(1)?,
+            row.try_get(2)?,
+            row.try_get(3)?,
+            row.try_get(4)?,
+            row.try_get(5)?,
+        );
+    }
+    (stats.stage_latencies_1h, stats.stage_latencies_24h) = tally.finish();
+
+    let mut by_class = Vec::new();
+    for row in client.query(ERROR_CLASS_COUNTS, &[])? {
+        by_class.push((row.try_get(0)?, row.try_get(1)?, row.try_get(2)?));
+    }
+    stats.errors_by_class = tally_error_classes(by_class);
+
+    Ok(stats)
+}
+
+fn row_to_work_item(row: &Row) -> Result<WorkItem> {
+    let state: String = row.try_get(6)?;
+    let step: String = row.try_get(15)?;
+    Ok(WorkItem {
+        id: row.try_get(0)?,
+        flow: row.try_get(1)?,
+        task_type: row.try_get(2)?,
+        item_id: row.try_get(3)?,
+        idempotency_key: row.try_get(4)?,
+        payload_json: row.try_get(5)?,
+        state: state.parse().unwrap_or(WorkState::Failed),
+        attempts: row.try_get(7)?,
+        max_attempts: row.try_get(8)?,
+        next_retry_at: row.try_get(9)?,
+        last_attempt_at: row.try_get(10)?,
+        error_class: ErrorClass::from_column(row.try_get(11)?),
+        last_error: row.try_get(12)?,
+        created_at: row.try_get(13)?,
+        updated_at: row.try_get(14)?,
+        step: step.parse().unwrap_or(WorkStep::New),
+        cl_link_hash: row.try_get(16)?,
+        cl_rave_hash: row.try_get(17)?,
+        br_spend_hash: row.try_get(18)?,
+        br_rave_hash: row.try_get(19)?,
+    })
+}
+
+fn row_to_event(row: &Row) -> Result<WorkItemEvent> {
+    Ok(WorkItemEvent {
+        id: row.try_get(0)?,
+        work_item_id: row.try_get(1)?,
+        item_id: row.try_get(2)?,
+        from_state: row.try_get(3)?,
+        to_state: row.try_get(4)?,
+        from_step: row.try_get(5)?,
+        to_step: row.try_get(6)?,
+        reason: row.try_get(7)?,
+        attempt: row.try_get(8)?,
+        action_hash: row.try_get(9)?,
+        actor: row.try_get(10)?,
+        created_at: row.try_get(11)?,
+    })
+}
+
+fn row_to_refund(row: &Row) -> Result<LockRefund> {
+    let task_type: String = row.try_get(3)?;
+    let payload: Value = row.try_get(4)?;
+    let (recipient, amount, terms_error) = match RefundTerms::of_lock(&task_type, &payload) {
+        Ok(terms) => (Some(terms.recipient.to_string()), Some(terms.amount), None),
+        Err(e) => (None, None, Some(format!("{:#}", e))),
+    };
+    Ok(LockRefund {
+        item_id: row.try_get(0)?,
+        status: row
+            .try_get::<_, String>(1)?
+            .parse()
+            .unwrap_or(RefundStatus::Refundable),
+        error_class: ErrorClass::from_column(row.try_get(2)?),
+        recipient,
+        amount,
+        terms_error,
+        approved_by: row.try_get(5)?,
+        approved_at: row.try_get(6)?,
+        coupon: row.try_get(7)?,
+        coupon_signed_at: row.try_get(8)?,
+        claim_tx_hash: row.try_get(9)?,
+        reported_at: row.try_get(10)?,
+        last_error: row.try_get(11)?,
+        created_at: row.try_get(12)?,
+        updated_at: row.try_get(13)?,
+        step: row.try_get::<_, String>(14)?.parse().ok(),
+        cl_rave_hash: row.try_get(15)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::LockPayload;
+    use crate::state::CouponStatus;
+    use crate::store::SharedStore;
+    use alloy::primitives::U256;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// A schema of its own on the `BRIDGE_TEST_POSTGRES_URL` server,
+    /// dropped with the guard. `None` when the variable is unset.
+    struct TestSchema {
+        admin: postgres::Config,
+        name: String,
+    }
+
+    impl TestSchema {
+        fn create(label: &str) -> Option<Self> {
+            let url = std::env::var("BRIDGE_TEST_POSTGRES_URL").ok()?;
+            let admin: postgres::Config = url.parse().expect("BRIDGE_TEST_POSTGRES_URL");
+            let ts = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+            let name = format!("bridge_test_{}_{}", label, ts);
+            let mut client = admin.connect(NoTls).unwrap();
+            client
+                .batch_execute(&format!("CREATE SCHEMA {}", name))
+                .unwrap();
+            Some(Self { admin, name })
+        }
+
+        fn config(&self) -> postgres::Config {
+            let mut config = self.admin.clone();
+            config.options(&format!("-c search_path={}", self.name));
+            config
+        }
+
+        fn writer(&self) -> PostgresStore {
+            PostgresStore::connect_writer_with(self.config()).unwrap().0
+        }
+    }
+
+    impl Drop for TestSchema {
+        fn drop(&mut self) {
+            if let Ok(mut client) = self.admin.connect(NoTls) {
+                let _ = client.batch_execute(&format!("DROP SCHEMA {} CASCADE", self.name));
+            }
+        }
+    }
+
+    fn lock(item_id: &str, tx_hash: &str) -> WorkPayload {
+        LockPayload {
+            tx_hash: tx_hash.to_string(),
+            ..LockPayload::for_test(item_id)
+        }
+        .into()
+    }
+
+    #[test]
+    fn a_lock_runs_through_the_pipeline_with_its_history() {
+        let Some(schema) = TestSchema::create("pipeline") else {
+            return;
+        };
+        let (store, report) = PostgresStore::connect_writer_with(schema.config()).unwrap();
+        assert_eq!(report.from_version, 0);
+        assert_eq!(report.steps.len(), SCHEMA.len());
+        let store: SharedStore = Arc::new(store);
+
+        store
+            .enqueue_detected("lock:1", "lock:1:key", &lock("lock:1", "0xaa"))
+            .unwrap();
+        store
+            .enqueue_detected("lock:1", "lock:1:key", &lock("lock:1", "0xaa"))
+            .unwrap();
+        assert!(store.move_detected_to_queued("lock:1:key").unwrap());
+        assert!(!store.move_detected_to_queued("lock:1:key").unwrap());
+
+        let rows = store
+            .list_pending_by_step("lock", WorkStep::New, 10)
+            .unwrap();
+        assert_eq!(rows.len(), 1, "the second enqueue is a no-op");
+        let id = rows[0].id;
+        assert!(matches!(rows[0].payload().unwrap(), WorkPayload::Lock(_)));
+
+        store.mark_in_flight(id).unwrap();
+        store.advance_to_cl_link_created(id, "uhCkkLink").unwrap();
+        store.advance_to_cl_rave_executed(id, None).unwrap();
+        store.advance_to_br_spend_created(id, "uhCkkSpend").unwrap();
+        store
+            .advance_to_br_rave_executed(id, Some("uhCkkRave"))
+            .unwrap();
+
+        let done = store
+            .list_work_items("lock", WorkState::Succeeded, 10)
+            .unwrap();
+        assert_eq!(done[0].step, WorkStep::BrRaveExecuted);
+        assert_eq!(done[0].cl_link_hash.as_deref(), Some("uhCkkLink"));
+        assert_eq!(done[0].br_rave_hash.as_deref(), Some("uhCkkRave"));
+
+        let steps: Vec<_> = store
+            .history("lock:1")
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.to_state, e.to_step))
+            .collect();
+        assert_eq!(steps.len(), 7);
+        assert_eq!(steps[0], ("detected".to_string(), "new".to_string()));
+        assert_eq!(
+            steps[6],
+            ("succeeded".to_string(), "br_rave_executed".to_string())
+        );
+
+        let filter = StateFilter {
+            flow: Some("lock".to_string()),
+            tx_hash: Some("0xAA".to_string()),
+            step: Some("br_rave_executed".to_string()),
+            limit: 10,
+            ..Default::default()
+        };
+        let status = store.status(filter.clone()).unwrap();
+        assert_eq!(status[0].direction.as_deref(), Some("transfer_in"));
+        let page = store
+            .status(StateFilter {
+                cursor: Some(filter.sort.cursor_after(&status[0])),
+                ..filter.clone()
+            })
+            .unwrap();
+        assert!(page.is_empty());
+        let summary = store.status_summary(&filter).unwrap();
+        assert_eq!(summary.rows, 1);
+        assert_eq!(summary.by_state["succeeded"].hot_total, "1");
+        let found = store
+            .lookup(
+                &Lookup::parse(&format!("0x{}", "01".repeat(32))).unwrap(),
+                10,
+            )
+            .unwrap();
+        assert_eq!(found[0].cl_link_hash.as_deref(), Some("uhCkkLink"));
+        assert_eq!(found[0].coupon, None);
+        let items = store.report_items(0, i64::MAX).unwrap();
+        assert_eq!(items.len(), 1);
+        assert!(items[0].completed_at.is_some());
+        assert_eq!(
+            items[0].amount_wei,
+            crate::signer::parse_amount("1").unwrap()
+        );
+
+        let stats = store.aggregate_stats().unwrap();
+        assert_eq!(stats.succeeded_total, 1);
+        assert_eq!(stats.succeeded_1h, 1);
+
+        store
+            .set_checkpoint_u64("lock.last_processed_block", 42)
+            .unwrap();
+        assert_eq!(
+            store
+                .get_checkpoint_u64("lock.last_processed_block")
+                .unwrap(),
+            Some(42)
+        );
+    }
+
+    #[test]
+    fn withdrawals_and_failures_match_the_sqlite_store() {
+        let Some(schema) = TestSchema::create("withdraw") else {
+            return;
+        };
+        let store = schema.writer();
+
+        let payload = WithdrawPayload::for_test("uhCkkW1", "2.5");
+        assert!(store.record_withdrawal_seen(&payload).unwrap());
+        assert!(!store.record_withdrawal_seen(&payload).unwrap());
+        let now = chrono::Utc::now().timestamp();
+        assert!(store.withdrawal_due("uhCkkW1", now).unwrap());
+        store
+            .mark_withdrawal_coupon_failed(
+                "uhCkkW1",
+                ErrorClass::Signer,
+                "boom",
+                &RetryPolicy {
+                    base_delay_s: 180,
+                    ..RetryPolicy::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            store.list_pending_withdrawal_links(10).unwrap(),
+            vec![("uhCkkW1".to_string(), WithdrawStep::Seen)]
+        );
+        assert!(!store.withdrawal_due("uhCkkW1", now).unwrap());
+        assert!(store.withdrawal_due("uhCkkW1", i64::MAX).unwrap());
+        // Only a signed coupon can have been delivered.
+        store
+            .advance_withdrawal_to_rave_executed("uhCkkW1", None)
+            .unwrap();
+        assert_eq!(store.list_pending_withdrawal_links(10).unwrap().len(), 1);
+        store
+            .mark_withdrawal_coupon_signed(
+                "uhCkkW1",
+                &WithdrawalFee {
+                    model: "none",
+                    fee_wei: U256::ZERO,
+                    net_wei: U256::from(1u8),
+                },
+            )
+            .unwrap();
+        store
+            .advance_withdrawal_to_rave_executed("uhCkkW1", None)
+            .unwrap();
+        assert!(store.list_pending_withdrawal_links(10).unwrap().is_empty());
+        let found = store
+            .lookup(&Lookup::parse(&payload.recipient).unwrap(), 10)
+            .unwrap();
+        assert_eq!(found[0].row.item_id, "withdraw:uhCkkW1");
+        assert_eq!(found[0].coupon, Some(CouponStatus::Delivered));
+
+        assert!(store
+            .record_withdrawal_rejection("uhCkkBad", "bad_amount", "x", &Value::Null)
+            .unwrap());
+        assert!(!store
+            .record_withdrawal_rejection("uhCkkBad", "bad_amount", "x", &Value::Null)
+            .unwrap());
+        assert_eq!(
+            store.aggregate_stats().unwrap().withdrawals_rejected_total,
+            1
+        );
+
+        store
+            .enqueue_detected("lock:2", "lock:2:key", &lock("lock:2", "0xbb"))
+            .unwrap();
+        store.move_detected_to_queued("lock:2:key").unwrap();
+        let id = store
+            .list_pending_by_step("lock", WorkStep::New, 1)
+            .unwrap()[0]
+            .id;
+        store.mark_in_flight(id).unwrap();
+        assert_eq!(
+            store
+                .reset_in_flight_to_queued(
+                    "lock",
+                    ErrorClass::Rpc,
+                    "cycle failed",
+                    &RetryPolicy::default()
+                )
+                .unwrap(),
+            1
+        );
+        let row = &store.list_work_items("lock", WorkState::Queued, 1).unwrap()[0];
+        assert_eq!(row.attempts, 1);
+        assert_eq!(row.error_class, Some(ErrorClass::Rpc));
+        assert!(row.next_retry_at.is_some(), "requeued behind a backoff");
+        store
+            .mark_failed_permanent(id, ErrorClass::TagOversize, "bad proof")
+            .unwrap();
+        let refundable: i64 = schema
+            .config()
+            .connect(NoTls)
+            .unwrap()
+            .query_one(
+                "SELECT count(*) FROM lock_refunds WHERE status = 'refundable'",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(refundable, 1, "an oversize proof is recorded for a refund");
+        let stats = store.aggregate_stats().unwrap();
+        assert_eq!(stats.failed_total, 1);
+        assert_eq!(stats.errors_by_class[&ErrorClass::TagOversize].failed, 1);
+        let status = store
+            .status(StateFilter {
+                error_class: Some(ErrorClass::TagOversize),
+                limit: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        let classes: Vec<_> = status[0].recent_errors.iter().map(|e| e.class).collect();
+        assert_eq!(classes, [ErrorClass::Rpc, ErrorClass::TagOversize]);
+
+        let archived = std::cell::RefCell::new(Vec::new());
+        let archive = |rows: &[ArchivedWorkItem]| {
+            archived.borrow_mut().extend_from_slice(rows);
+            Ok(())
+        };
+        let pruned = store
+            .prune_terminal_batch(0, 0, 100, Some(&archive))
+            .unwrap();
+        assert_eq!(pruned.total(), 0, "nothing is older than now yet");
+        let pruned = store
+            .prune_terminal_batch(u64::MAX, u64::MAX, 100, Some(&archive))
+            .unwrap();
+        assert_eq!((pruned.succeeded_deleted, pruned.failed_deleted), (1, 0));
+        assert_eq!(archived.borrow().len(), 1);
+        assert!(!archived.borrow()[0].events.is_empty());
+        assert!(
+            !store.history("lock:2").unwrap().is_empty(),
+            "a failed lock is a dead letter, never pruned"
+        );
+
+        let letters = store.dead_letters(10).unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].row.item_id, "lock:2");
+        assert_eq!(letters[0].replay_state, WorkState::Queued);
+        assert_eq!(letters[0].refund, Some(RefundStatus::Refundable));
+        let event = store
+            .replay_dead_letter("lock:2", "operator:alice")
+            .unwrap();
+        assert_eq!(
+            event.reason.as_deref(),
+            Some("dlq replay at new after 1 attempt(s)")
+        );
+        assert_eq!((event.attempt, event.actor.as_str()), (1, "operator:alice"));
+        assert!(store.dead_letters(10).unwrap().is_empty());
+        let row = &store.list_work_items("lock", WorkState::Queued, 1).unwrap()[0];
+        assert_eq!((row.attempts, row.error_class), (0, None));
+        assert!(store
+            .replay_dead_letter("lock:2", "operator:alice")
+            .is_err());
+    }
+
+    #[test]
+    fn a_refund_runs_from_approval_to_a_reported_claim() {
+        let Some(schema) = TestSchema::create("refunds") else {
+            return;
+        };
+        let store = schema.writer();
+        let payload = LockPayload {
+            sender: alloy::primitives::Address::repeat_byte(0x22).to_string(),
+            ..LockPayload::for_test("3")
+        };
+        store
+            .enqueue_detected("lock:3", "lock:3:key", &payload.into())
+            .unwrap();
+        store.move_detected_to_queued("lock:3:key").unwrap();
+        let id = store
+            .list_pending_by_step("lock", WorkStep::New, 1)
+            .unwrap()[0]
+            .id;
+        store
+            .mark_failed_permanent(id, ErrorClass::TagOversize, "proof exceeds")
+            .unwrap();
+        assert!(store.approve_refund("lock:9", "operator:alice").is_err());
+
+        let refunds = store.refunds(Some(RefundStatus::Refundable), 10).unwrap();
+        assert_eq!(refunds.len(), 1);
+        assert_eq!(refunds[0].amount.as_deref(), Some("1"));
+        let approved = store.approve_refund("lock:3", "operator:alice").unwrap();
+        assert_eq!(approved.status, RefundStatus::Approved);
+        assert_eq!(approved.approved_by.as_deref(), Some("operator:alice"));
+        store
+            .record_refund_error("lock:3", "signer offline", "operator:alice")
+            .unwrap();
+        let retried = store.approve_refund("lock:3", "operator:alice").unwrap();
+        assert_eq!(retried.last_error.as_deref(), Some("signer offline"));
+        let signed = store
+            .record_refund_coupon("lock:3", "0xsigner,0xsig,1", "operator:alice")
+            .unwrap();
+        assert_eq!(signed.status, RefundStatus::CouponSigned);
+        assert_eq!(signed.last_error, None);
+
+        let claim = format!("0x{}", "AB".repeat(32));
+        let reported = store
+            .complete_refund("lock:3", &claim, "operator:alice")
+            .unwrap();
+        assert_eq!(reported.status, RefundStatus::OperatorReported);
+        assert_eq!(reported.claim_tx_hash, Some(claim.to_lowercase()));
+        assert!(reported.reported_at.is_some());
+        assert!(store
+            .complete_refund("lock:3", &claim, "operator:alice")
+            .is_err());
+        let reasons: Vec<_> = store
+            .history("lock:3")
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.actor == "operator:alice")
+            .filter_map(|e| e.reason)
+            .collect();
+        assert_eq!(reasons.len(), 4, "{:?}", reasons);
+    }
+
+    #[test]
+    fn operator_commands_match_the_sqlite_store() {
+        let Some(schema) = TestSchema::create("operator") else {
+            return;
+        };
+        let store = schema.writer();
+        store
+            .record_withdrawal_rejection("0xlink", "invalid_payload", "no recipient", &json!({}))
+            .unwrap();
+        let rejections = store.list_withdrawal_rejections(10).unwrap();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].payload_json, json!({}));
+
+        for n in ["4", "5"] {
+            let key = format!("lock:{}:key", n);
+            store
+                .enqueue_detected(&format!("lock:{}", n), &key, &lock(n, &format!("0x{}", n)))
+                .unwrap();
+            store.move_detected_to_queued(&key).unwrap();
+        }
+        let failed = store
+            .force_fail_item("lock:4", "never bridgeable", false, "operator:alice")
+            .unwrap();
+        assert_eq!(failed.to_state, "failed");
+        assert!(store
+            .force_fail_item("lock:4", "again", false, "operator:alice")
+            .is_err());
+        let note = store
+            .annotate_item("lock:4", "ticket OPS-123", "operator:alice")
+            .unwrap();
+        assert_eq!(note.to_state, "failed");
+        assert_eq!(note.reason.as_deref(), Some("note: ticket OPS-123"));
+
+        let requeued = store
+            .requeue_item(
+                "lock:4",
+                &RequeueOptions {
+                    reset_attempts: true,
+                    step: Some(WorkStep::ClLinkCreated),
+                },
+                false,
+                "operator:alice",
+            )
+            .unwrap();
+        assert_eq!(requeued.to_state, "queued");
+        assert_eq!(requeued.to_step, "cl_link_created");
+        let pending = store
+            .list_pending_by_step("lock", WorkStep::ClLinkCreated, 10)
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].item_id, "lock:4");
+        assert_eq!(pending[0].cl_rave_hash, None);
+
+        store
+            .force_fail_item("lock:4", "never bridgeable", false, "operator:alice")
+            .unwrap();
+        let live = store.list_work_items("lock", WorkState::Queued, 1).unwrap()[0].id;
+        store.mark_in_flight(live).unwrap();
+        assert!(store
+            .force_fail_item("lock:5", "stuck", false, "operator:alice")
+            .is_err());
+        assert_eq!(store.clear_non_in_progress().unwrap(), 1);
+        assert!(store.history("lock:4").unwrap().is_empty());
+        assert_eq!(store.clear_all().unwrap(), 1);
+    }
+
+    #[test]
+    fn a_second_writer_is_refused_until_the_first_disconnects() {
+        let Some(schema) = TestSchema::create("writer") else {
+            return;
+        };
+        let first = schema.writer();
+        first.record_writer_lease(4242, "node-a").unwrap();
+
+        let err = PostgresStore::connect_writer_with(schema.config())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("pid 4242 on node-a"), "{}", err);
+        assert!(PostgresStore::try_connect_writer_with(schema.config())
+            .unwrap()
+            .is_none());
+
+        // A shared connection is not refused, and sees the writer's rows.
+        let shared = PostgresStore::connect_shared_with(schema.config()).unwrap();
+        first.set_checkpoint_u64("k", 1).unwrap();
+        assert_eq!(shared.get_checkpoint_u64("k").unwrap(), Some(1));
+
+        drop(first);
+        let (_second, report) = PostgresStore::connect_writer_with(schema.config()).unwrap();
+        assert!(report.steps.is_empty(), "already migrated");
+    }
+
+    #[test]
+    fn a_writer_that_stops_heartbeating_is_taken_over_while_still_connected() {
+        let Some(schema) = TestSchema::create("take_over") else {
+            return;
+        };
+        let hung = schema.writer();
+        hung.record_writer_lease(4242, "node-a").unwrap();
+        let stale_after = crate::lease::HEARTBEAT_EXPIRY;
+        let err = PostgresStore::take_over_writer_with(schema.config(), stale_after)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("pid 4242 on node-a"), "{}", err);
+
+        hung.session
+            .with_client(|client| {
+                client.execute(
+                    "UPDATE writer_lease SET heartbeat_at = heartbeat_at - 60",
+                    &[],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+        let (taker, _) =
+            PostgresStore::take_over_writer_with(schema.config(), stale_after).unwrap();
+        taker.record_writer_lease(4343, "node-b").unwrap();
+        // The row names the new holder, and the hung writer's session is gone.
+        assert!(!taker.refresh_writer_lease(4242, "node-a").unwrap());
+        assert!(taker.refresh_writer_lease(4343, "node-b").unwrap());
+        assert!(hung.refresh_writer_lease(4242, "node-a").is_err());
+    }
+
+    #[test]
+    fn a_shared_connection_refuses_a_schema_that_is_behind() {
+        let Some(schema) = TestSchema::create("behind") else {
+            return;
+        };
+        let err = PostgresStore::connect_shared_with(schema.config())
+            .err()
+            .expect("an unmigrated schema must be refused");
+        assert!(format!("{:#}", err).contains("db migrate"), "{:#}", err);
+        let mut client = schema.config().connect(NoTls).unwrap();
+        assert_eq!(plan(&mut client).unwrap().steps.len(), SCHEMA.len());
+        let created: bool = client
+            .query_one("SELECT to_regclass('schema_meta') IS NOT NULL", &[])
+            .unwrap()
+            .get(0);
+        assert!(!created, "planning must not create schema_meta");
+
+        drop(schema.writer());
+        assert!(plan(&mut client).unwrap().steps.is_empty());
+        PostgresStore::connect_shared_with(schema.config()).unwrap();
+    }
+
+    #[test]
+    fn the_leader_lease_behaves_like_the_sqlite_backend() {
+        let Some(schema) = TestSchema::create("leader") else {
+            return;
+        };
+        let backend = PostgresLeaseBackend::connect_with(schema.config()).unwrap();
+        let a = backend.try_acquire("a", 1_000, 500).unwrap();
+        assert_eq!((a.holder.as_str(), a.term), ("a", 1));
+        let held = backend.try_acquire("b", 1_200, 500).unwrap();
+        assert_eq!(held.holder, "a");
+        let b = backend.try_acquire("b", 1_600, 500).unwrap();
+        assert_eq!((b.holder.as_str(), b.term), ("b", 2));
+        backend.release("b").unwrap();
+        let a = backend.try_acquire("a", 1_700, 500).unwrap();
+        assert_eq!((a.holder.as_str(), a.term), ("a", 3));
+    }
+}
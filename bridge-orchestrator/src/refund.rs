@@ -0,0 +1,137 @@
+//! Refunds of locks that can never be bridged.
+//!
+//! A lock whose proof is larger than any link tag can hold, or whose
+//! `holochainAgent` is not an agent key, is failed by the cycle with a
+//! [refundable](crate::state::ErrorClass::is_refundable) class and entered
+//! in the `lock_refunds` ledger as `refundable`. Its HOT stays in the
+//! vault until an operator approves the refund with `refund approve`,
+//! which signs a claim coupon paying the lock's sender the full amount it
+//! locked. The coupon is handed to the sender out of band; once they have
+//! claimed it, `refund complete` records the claim transaction the operator
+//! reports, unverified, as `operator_reported`. Every step is recorded in
+//! the lock's history.
+
+use crate::signer::{generate_coupon, signer_context_from_env};
+use crate::state::LockRefund;
+use crate::store::StateStore;
+use anyhow::{Context, Result};
+use std::future::Future;
+
+/// Approve `item_id`'s refund as `actor` and sign its coupon. If signing
+/// fails the refund stays approved with the error recorded; running this
+/// again finds it approved with no coupon and signs it, without approving
+/// it twice.
+pub async fn approve(db: &dyn StateStore, item_id: &str, actor: &str) -> Result<LockRefund> {
+    approve_with(db, item_id, actor, |recipient, amount| async move {
+        let ctx = signer_context_from_env()?;
+        generate_coupon(&amount, &recipient, &ctx).await
+    })
+    .await
+}
+
+/// [`approve`] with the coupon signed by `sign(recipient, amount)`.
+async fn approve_with<F, Fut>(
+    db: &dyn StateStore,
+    item_id: &str,
+    actor: &str,
+    sign: F,
+) -> Result<LockRefund>
+where
+    F: FnOnce(String, String) -> Fut,
+    Fut: Future<Output = Result<String>>,
+{
+    let refund = db.approve_refund(item_id, actor)?;
+    let recipient = refund.recipient.context("refund has no recipient")?;
+    let amount = refund.amount.context("refund has no amount")?;
+    match sign(recipient, amount).await {
+        Ok(coupon) => db.record_refund_coupon(item_id, &coupon, actor),
+        Err(e) => {
+            db.record_refund_error(item_id, &format!("{:#}", e), actor)?;
+            Err(e.context(format!(
+                "{}'s refund is approved but its coupon was not signed; run refund approve again",
+                item_id
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::LockPayload;
+    use crate::state::{ErrorClass, RefundStatus, SqliteStore, WorkState};
+    use alloy::primitives::Address;
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn a_refund_whose_coupon_failed_to_sign_is_signed_on_the_next_run() {
+        let path = format!(
+            "/tmp/bridge-orchestrator-refund-resign-{}.db",
+            std::process::id()
+        );
+        let _ = std::fs::remove_file(&path);
+        let db = SqliteStore::open(&path).unwrap();
+        db.enqueue_queued(
+            "lock:7",
+            "lock:7:key",
+            &LockPayload {
+                sender: Address::repeat_byte(0x22).to_string(),
+                ..LockPayload::for_test("7")
+            }
+            .into(),
+        )
+        .unwrap();
+        let id = db.list_work_items("lock", WorkState::Queued, 1).unwrap()[0].id;
+        db.mark_failed_permanent(id, ErrorClass::TagOversize, "proof exceeds")
+            .unwrap();
+
+        let err = approve_with(&db, "lock:7", "operator:alice", |_, _| async {
+            Err(anyhow!("signer offline"))
+        })
+        .await
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("run refund approve again"),
+            "{:#}",
+            err
+        );
+        let pending = &db.refunds(None, 1).unwrap()[0];
+        assert_eq!(pending.status, RefundStatus::Approved);
+        assert_eq!(
+            (pending.coupon.as_deref(), pending.last_error.as_deref()),
+            (None, Some("signer offline"))
+        );
+
+        let signed = approve_with(
+            &db,
+            "lock:7",
+            "operator:bob",
+            |recipient, amount| async move { Ok(format!("0xsigner,{},{}", recipient, amount)) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(signed.status, RefundStatus::CouponSigned);
+        assert_eq!(signed.approved_by.as_deref(), Some("operator:alice"));
+        assert_eq!(
+            signed.coupon,
+            Some(format!("0xsigner,{},1", Address::repeat_byte(0x22)))
+        );
+        assert_eq!(signed.last_error, None);
+        let approvals = db
+            .history("lock:7")
+            .unwrap()
+            .into_iter()
+            .filter(|e| {
+                e.reason
+                    .as_deref()
+                    .is_some_and(|r| r.starts_with("refund approved"))
+            })
+            .count();
+        assert_eq!(approvals, 1, "approved once, signed on the second run");
+        assert!(approve_with(&db, "lock:7", "operator:bob", |_, _| async {
+            Ok(String::new())
+        })
+        .await
+        .is_err());
+    }
+}
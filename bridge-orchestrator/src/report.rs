@@ -0,0 +1,522 @@
+//! `report`: daily bridged volume for accounting.
+//!
+//! Every lock and withdrawal that was detected, completed or failed in the
+//! requested days is read from `work_items` and, when retention archives
+//! what it prunes, from the archive too, so a report over months that
+//! retention has since cleared still adds up. A row counts on the UTC day
+//! each thing happened to it:
+//!
+//! - a lock is *detected* on the day its row was created, *bridged* on the
+//!   day it reached `br_rave_executed` and *failed* on the day it was
+//!   failed;
+//! - a withdrawal is *seen* on the day its row was created, its coupon
+//!   *issued* on the day the bridging RAVE delivered it (`rave_executed`),
+//!   and *failed* likewise.
+//!
+//! Latencies are from detection to completion, in seconds, over the rows
+//! completed that day. Amounts are summed in wei and printed in HOT. A row
+//! archived twice, or archived and still live after a crash mid-prune,
+//! counts once.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use alloy::primitives::U256;
+use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::{ArchiveFormat, RetentionArchiveConfig};
+use crate::state::{extract_transfer_fields, ArchivedWorkItem, WorkState};
+use crate::store::StateStore;
+use crate::withdrawal::format_token_amount;
+
+const DAY_S: i64 = 86_400;
+
+/// Steps that complete a flow: the lock's last RAVE, the withdrawal's
+/// coupon delivery.
+const COMPLETING_STEPS: [&str; 2] = ["br_rave_executed", "rave_executed"];
+
+/// What the report needs of one work item.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportItem {
+    pub id: i64,
+    pub flow: String,
+    pub created_at: i64,
+    pub completed_at: Option<i64>,
+    pub failed_at: Option<i64>,
+    pub amount_wei: U256,
+    pub fee_wei: U256,
+}
+
+impl ReportItem {
+    /// `completed_at` is when the row reached a completing step, from its
+    /// history. A succeeded row from before history was kept falls back
+    /// to its `updated_at`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        id: i64,
+        flow: String,
+        task_type: &str,
+        state: &str,
+        payload: &Value,
+        created_at: i64,
+        updated_at: i64,
+        completed_at: Option<i64>,
+    ) -> Self {
+        let fields = extract_transfer_fields(&flow, task_type, payload);
+        let wei = |hot: Option<String>| {
+            hot.and_then(|a| crate::signer::parse_amount(&a).ok())
+                .unwrap_or_default()
+        };
+        let state: WorkState = state.parse().unwrap_or(WorkState::Failed);
+        Self {
+            id,
+            completed_at: completed_at
+                .or_else(|| (state == WorkState::Succeeded).then_some(updated_at)),
+            failed_at: (state == WorkState::Failed).then_some(updated_at),
+            amount_wei: wei(fields.amount_raw),
+            fee_wei: wei(fields.fee),
+            flow,
+            created_at,
+        }
+    }
+
+    fn archived(row: &ArchivedWorkItem) -> Self {
+        let item = &row.item;
+        let completed_at = row
+            .events
+            .iter()
+            .filter(|e| COMPLETING_STEPS.contains(&e.to_step.as_str()))
+            .map(|e| e.created_at)
+            .min();
+        Self::new(
+            item.id,
+            item.flow.clone(),
+            &item.task_type,
+            &item.state.to_string(),
+            &item.payload_json,
+            item.created_at,
+            item.updated_at,
+            completed_at,
+        )
+    }
+
+    /// Whether anything counted happened to the row in `[from, to)`.
+    fn touches(&self, from: i64, to: i64) -> bool {
+        [Some(self.created_at), self.completed_at, self.failed_at]
+            .into_iter()
+            .flatten()
+            .any(|t| (from..to).contains(&t))
+    }
+}
+
+/// The query behind [`StateStore::report_items`], shared by both backends:
+/// every row created before `to` and last updated at or after `from`,
+/// which covers everything that happened to a row in between. Binds `from`
+/// and `to` as the first and second parameters.
+pub(crate) fn report_query(placeholder: fn(usize) -> String) -> String {
+    format!(
+        "SELECT w.id, w.flow, w.task_type, w.state, w.payload_json, w.created_at, w.updated_at,
+                (SELECT MIN(e.created_at) FROM work_item_events e
+                 WHERE e.work_item_id = w.id AND e.to_step IN ('{}', '{}'))
+         FROM work_items w
+         WHERE w.created_at < {} AND w.updated_at >= {}",
+        COMPLETING_STEPS[0],
+        COMPLETING_STEPS[1],
+        placeholder(2),
+        placeholder(1)
+    )
+}
+
+/// One UTC day of bridge activity. Amounts are in HOT.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DailyReport {
+    pub date: String,
+    pub locks_detected: i64,
+    pub hot_locked: String,
+    pub locks_bridged: i64,
+    pub hot_bridged: String,
+    pub locks_failed: i64,
+    pub withdrawals_seen: i64,
+    pub coupons_issued: i64,
+    /// Gross amount of the issued coupons' withdrawals, fees included.
+    pub hot_withdrawn: String,
+    pub fees: String,
+    pub withdrawals_failed: i64,
+    pub lock_latency_p50_s: Option<i64>,
+    pub lock_latency_p90_s: Option<i64>,
+    pub lock_latency_p99_s: Option<i64>,
+    pub withdraw_latency_p50_s: Option<i64>,
+    pub withdraw_latency_p90_s: Option<i64>,
+    pub withdraw_latency_p99_s: Option<i64>,
+}
+
+#[derive(Default)]
+struct Day {
+    locks_detected: i64,
+    hot_locked: U256,
+    locks_bridged: i64,
+    hot_bridged: U256,
+    locks_failed: i64,
+    withdrawals_seen: i64,
+    coupons_issued: i64,
+    hot_withdrawn: U256,
+    fees: U256,
+    withdrawals_failed: i64,
+    lock_latencies: Vec<i64>,
+    withdraw_latencies: Vec<i64>,
+}
+
+/// The report for every UTC day from `from_day` to `to_day` inclusive
+/// (unix days), one entry per day even when nothing happened.
+pub fn daily_report(
+    db: &dyn StateStore,
+    archive: Option<&RetentionArchiveConfig>,
+    from_day: i64,
+    to_day: i64,
+) -> Result<Vec<DailyReport>> {
+    let (from, to) = (from_day * DAY_S, (to_day + 1) * DAY_S);
+    let mut seen = HashSet::new();
+    let mut items = Vec::new();
+    // Live rows first, so they win over a stale archived copy.
+    let archived = match archive {
+        Some(archive) => read_archive(archive, from, to)?,
+        None => Vec::new(),
+    };
+    for item in db.report_items(from, to)?.into_iter().chain(archived) {
+        if seen.insert((item.id, item.created_at)) {
+            items.push(item);
+        }
+    }
+    Ok(tally(&items, from_day, to_day))
+}
+
+fn tally(items: &[ReportItem], from_day: i64, to_day: i64) -> Vec<DailyReport> {
+    let mut days: BTreeMap<i64, Day> = (from_day..=to_day).map(|d| (d, Day::default())).collect();
+    for item in items {
+        let lock = match item.flow.as_str() {
+            "lock" => true,
+            "withdraw" => false,
+            _ => continue,
+        };
+        if let Some(day) = days.get_mut(&item.created_at.div_euclid(DAY_S)) {
+            if lock {
+                day.locks_detected += 1;
+                day.hot_locked = day.hot_locked.saturating_add(item.amount_wei);
+            } else {
+                day.withdrawals_seen += 1;
+            }
+        }
+        if let Some(done) = item.completed_at {
+            if let Some(day) = days.get_mut(&done.div_euclid(DAY_S)) {
+                let latency = done - item.created_at;
+                if lock {
+                    day.locks_bridged += 1;
+                    day.hot_bridged = day.hot_bridged.saturating_add(item.amount_wei);
+                    day.lock_latencies.push(latency);
+                } else {
+                    day.coupons_issued += 1;
+                    day.hot_withdrawn = day.hot_withdrawn.saturating_add(item.amount_wei);
+                    day.fees = day.fees.saturating_add(item.fee_wei);
+                    day.withdraw_latencies.push(latency);
+                }
+            }
+        }
+        if let Some(failed) = item.failed_at {
+            if let Some(day) = days.get_mut(&failed.div_euclid(DAY_S)) {
+                if lock {
+                    day.locks_failed += 1;
+                } else {
+                    day.withdrawals_failed += 1;
+                }
+            }
+        }
+    }
+    days.into_iter()
+        .map(|(d, mut day)| {
+            day.lock_latencies.sort_unstable();
+            day.withdraw_latencies.sort_unstable();
+            DailyReport {
+                date: chrono::DateTime::from_timestamp(d * DAY_S, 0)
+                    .map(|t| t.format("%Y-%m-%d").to_string())
+                    .unwrap_or_default(),
+                locks_detected: day.locks_detected,
+                hot_locked: format_token_amount(day.hot_locked),
+                locks_bridged: day.locks_bridged,
+                hot_bridged: format_token_amount(day.hot_bridged),
+                locks_failed: day.locks_failed,
+                withdrawals_seen: day.withdrawals_seen,
+                coupons_issued: day.coupons_issued,
+                hot_withdrawn: format_token_amount(day.hot_withdrawn),
+                fees: format_token_amount(day.fees),
+                withdrawals_failed: day.withdrawals_failed,
+                lock_latency_p50_s: percentile(&day.lock_latencies, 50),
+                lock_latency_p90_s: percentile(&day.lock_latencies, 90),
+                lock_latency_p99_s: percentile(&day.lock_latencies, 99),
+                withdraw_latency_p50_s: percentile(&day.withdraw_latencies, 50),
+                withdraw_latency_p90_s: percentile(&day.withdraw_latencies, 90),
+                withdraw_latency_p99_s: percentile(&day.withdraw_latencies, 99),
+            }
+        })
+        .collect()
+}
+
+/// Nearest-rank percentile of sorted `values`.
+pub(crate) fn percentile(sorted: &[i64], p: usize) -> Option<i64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+/// Archived rows for `[from, to)`. The JSONL archive has a file per day
+/// the rows were pruned on, always after they last changed, so files from
+/// before `from` are skipped.
+fn read_archive(archive: &RetentionArchiveConfig, from: i64, to: i64) -> Result<Vec<ReportItem>> {
+    let rows = match archive.format {
+        ArchiveFormat::JsonlGz => read_jsonl_gz(&archive.path, from)?,
+        ArchiveFormat::Sqlite => read_sqlite(&archive.path, from, to)?,
+    };
+    Ok(rows
+        .iter()
+        .map(ReportItem::archived)
+        .filter(|item| item.touches(from, to))
+        .collect())
+}
+
+fn read_jsonl_gz(dir: &Path, from: i64) -> Result<Vec<ArchivedWorkItem>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let first_day = chrono::DateTime::from_timestamp(from, 0)
+        .map(|t| t.format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    let mut rows = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("read archive {}", dir.display()))? {
+        let path = entry?.path();
+        let Some(date) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("work_items-"))
+            .and_then(|n| n.strip_suffix(".jsonl.gz"))
+        else {
+            continue;
+        };
+        if date < first_day.as_str() {
+            continue;
+        }
+        let file = fs::File::open(&path)?;
+        for (n, line) in BufReader::new(MultiGzDecoder::new(file))
+            .lines()
+            .enumerate()
+        {
+            let line = line.with_context(|| format!("read {}", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            rows.push(
+                serde_json::from_str(&line)
+                    .with_context(|| format!("{} line {}", path.display(), n + 1))?,
+            );
+        }
+    }
+    Ok(rows)
+}
+
+fn read_sqlite(path: &Path, from: i64, to: i64) -> Result<Vec<ArchivedWorkItem>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("open archive {}", path.display()))?;
+    let mut stmt = conn.prepare(
+        "SELECT record_json FROM archived_work_items WHERE created_at < ?1 AND updated_at >= ?2",
+    )?;
+    let rows = stmt
+        .query_map([to, from], |row| row.get::<_, String>(0))?
+        .map(|json| Ok(serde_json::from_str(&json?)?))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{LockPayload, WithdrawPayload};
+    use crate::state::SqliteStore;
+    use crate::state::{ErrorClass, WorkStep};
+    use crate::withdrawal::WithdrawalFee;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn test_path(name: &str) -> String {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        format!("/tmp/bridge-orchestrator-report-{}-{}", name, ts)
+    }
+
+    fn item(flow: &str, created_at: i64, completed_at: Option<i64>, hot: u64) -> ReportItem {
+        ReportItem {
+            id: created_at,
+            flow: flow.to_string(),
+            created_at,
+            completed_at,
+            failed_at: None,
+            amount_wei: U256::from(hot) * U256::from(10u64).pow(U256::from(18u64)),
+            fee_wei: U256::ZERO,
+        }
+    }
+
+    #[test]
+    fn each_event_counts_on_its_own_day() {
+        let day = 20_000;
+        let t = day * DAY_S;
+        let items = [
+            // Detected the day before, bridged on the day.
+            item("lock", t - 100, Some(t + 500), 2),
+            item("lock", t + 10, Some(t + 70), 3),
+            ReportItem {
+                failed_at: Some(t + DAY_S + 5),
+                ..item("lock", t + 20, None, 4)
+            },
+            ReportItem {
+                fee_wei: U256::from(5u64) * U256::from(10u64).pow(U256::from(17u64)),
+                ..item("withdraw", t + 30, Some(t + DAY_S + 30), 7)
+            },
+        ];
+
+        let days = tally(&items, day, day + 1);
+        assert_eq!(days.len(), 2);
+        let (first, second) = (&days[0], &days[1]);
+        assert_eq!(first.date, "2024-10-04");
+        assert_eq!((first.locks_detected, first.locks_bridged), (2, 2));
+        assert_eq!(first.hot_locked, "7");
+        assert_eq!(first.hot_bridged, "5");
+        assert_eq!(first.lock_latency_p50_s, Some(60));
+        assert_eq!(first.lock_latency_p99_s, Some(600));
+        assert_eq!(first.withdrawals_seen, 1);
+        assert_eq!(first.coupons_issued, 0);
+        assert_eq!(first.withdraw_latency_p50_s, None);
+        assert_eq!(second.locks_failed, 1);
+        assert_eq!(second.coupons_issued, 1);
+        assert_eq!(second.hot_withdrawn, "7");
+        assert_eq!(second.fees, "0.5");
+        assert_eq!(second.withdraw_latency_p90_s, Some(DAY_S));
+    }
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let values: Vec<i64> = (1..=10).collect();
+        assert_eq!(percentile(&values, 50), Some(5));
+        assert_eq!(percentile(&values, 90), Some(9));
+        assert_eq!(percentile(&values, 99), Some(10));
+        assert_eq!(percentile(&[42], 1), Some(42));
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[test]
+    fn live_rows_and_the_archive_are_counted_once() {
+        let path = test_path("live.db");
+        let store = SqliteStore::open(&path).unwrap();
+        store
+            .enqueue_queued("lock:1", "lock:1:key", &LockPayload::for_test("1").into())
+            .unwrap();
+        let id = store
+            .list_pending_by_step("lock", WorkStep::New, 1)
+            .unwrap()[0]
+            .id;
+        store.advance_to_cl_link_created(id, "uhCkkLink").unwrap();
+        store.advance_to_cl_rave_executed(id, None).unwrap();
+        store.advance_to_br_spend_created(id, "uhCkkSpend").unwrap();
+        store.advance_to_br_rave_executed(id, None).unwrap();
+        store
+            .record_withdrawal_seen(&WithdrawPayload::for_test("uhCkkW", "2.5"))
+            .unwrap();
+        let fee = WithdrawalFee {
+            model: "flat",
+            fee_wei: crate::signer::parse_amount("0.5").unwrap(),
+            net_wei: crate::signer::parse_amount("2").unwrap(),
+        };
+        store.mark_withdrawal_coupon_signed("uhCkkW", &fee).unwrap();
+        store
+            .advance_withdrawal_to_rave_executed("uhCkkW", None)
+            .unwrap();
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute("UPDATE work_items SET created_at = created_at - 600", [])
+            .unwrap();
+
+        // A failed lock from another database stands in for one retention
+        // has pruned; the bridged lock is archived too, as after a crash
+        // between the archive write and the prune's commit.
+        let other_path = test_path("other.db");
+        let other = SqliteStore::open(&other_path).unwrap();
+        other
+            .enqueue_queued("lock:2", "lock:2:key", &LockPayload::for_test("2").into())
+            .unwrap();
+        let other_id = other
+            .list_pending_by_step("lock", WorkStep::New, 1)
+            .unwrap()[0]
+            .id;
+        other
+            .mark_failed_permanent(other_id, ErrorClass::InvalidPayload, "bad proof")
+            .unwrap();
+        rusqlite::Connection::open(&other_path)
+            .unwrap()
+            .execute("UPDATE work_items SET created_at = created_at - 1000", [])
+            .unwrap();
+        let archived = |db: &SqliteStore, state| {
+            let item = db.list_work_items("lock", state, 1).unwrap().remove(0);
+            let events = db.history(&item.item_id).unwrap();
+            ArchivedWorkItem { item, events }
+        };
+        let rows = [
+            archived(&other, WorkState::Failed),
+            archived(&store, WorkState::Succeeded),
+        ];
+
+        let dir = PathBuf::from(test_path("archive"));
+        fs::create_dir_all(&dir).unwrap();
+        let today = chrono::Utc::now().format("%Y-%m-%d");
+        let mut gz = GzEncoder::new(
+            fs::File::create(dir.join(format!("work_items-{}.jsonl.gz", today))).unwrap(),
+            Compression::default(),
+        );
+        for row in &rows {
+            serde_json::to_writer(&mut gz, row).unwrap();
+            gz.write_all(b"\n").unwrap();
+        }
+        gz.finish().unwrap();
+        // Pruned long before the report's first day: never opened.
+        fs::write(dir.join("work_items-2000-01-01.jsonl.gz"), "not gzip").unwrap();
+
+        let archive = RetentionArchiveConfig {
+            format: ArchiveFormat::JsonlGz,
+            path: dir,
+        };
+        let today = chrono::Utc::now().timestamp().div_euclid(DAY_S);
+        let days = daily_report(&store, Some(&archive), today - 1, today).unwrap();
+        let sum = |f: fn(&DailyReport) -> i64| days.iter().map(f).sum::<i64>();
+        assert_eq!(sum(|d| d.locks_detected), 2);
+        assert_eq!(sum(|d| d.locks_bridged), 1);
+        assert_eq!(sum(|d| d.locks_failed), 1);
+        assert_eq!(sum(|d| d.withdrawals_seen), 1);
+        assert_eq!(sum(|d| d.coupons_issued), 1);
+        let done = days.iter().find(|d| d.coupons_issued == 1).unwrap();
+        assert_eq!(done.hot_withdrawn, "2.5");
+        assert_eq!(done.fees, "0.5");
+        let latency = done.withdraw_latency_p50_s.unwrap();
+        assert!((600..=602).contains(&latency), "{}", latency);
+    }
+}
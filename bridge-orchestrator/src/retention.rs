@@ -7,19 +7,40 @@
 //! per-state retention windows, and logs a single `info!` line if
 //! anything was pruned.
 //!
-//! The task uses the writer mutex (DELETE is a write), but the
-//! cadence is 1h and the SQL hits the `idx_work_items_state_created`
-//! index — so each tick holds the mutex for well under a millisecond
-//! in practice. If volume ever grows enough to matter we can switch
-//! to `DELETE ... WHERE id IN (SELECT id ... LIMIT n)` batches.
+//! The task uses the writer mutex (DELETE is a write), so it deletes
+//! in `DELETE ... WHERE id IN (SELECT id ... LIMIT n)` batches of
+//! `BRIDGE_RETENTION_BATCH_SIZE` rows, one transaction each, and
+//! pauses between them so the bridge cycle can take the mutex. A tick
+//! stops at `BRIDGE_RETENTION_MAX_ROWS_PER_TICK` rows or
+//! `BRIDGE_RETENTION_MAX_TICK_MS`, whichever comes first, and leaves
+//! the rest to the next tick: a first prune after months of
+//! accumulation drains over several ticks instead of holding the
+//! mutex for one long DELETE.
+//!
+//! With an archive configured, every row is written out, together with
+//! its `work_item_events`, before it is deleted, inside the same
+//! transaction: if the archive write fails nothing is pruned and the
+//! tick is retried. Delivery is at-least-once. A crash between the
+//! archive write and the commit archives the same rows again on the
+//! next tick; the SQLite archive absorbs that with an upsert, JSONL
+//! readers should de-duplicate on `id` + `created_at`.
 
-use std::time::Duration;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusqlite::{params, Connection};
 use tokio::task::JoinHandle;
 use tokio::time::{interval, MissedTickBehavior};
 
-use crate::config::RetentionConfig;
-use crate::state::StateStore;
+use crate::config::{ArchiveFormat, RetentionArchiveConfig, RetentionConfig};
+use crate::state::{ArchivedWorkItem, PruneStats};
+use crate::store::{SharedStore, StateStore};
 
 /// Spawn the retention loop. Returns the `JoinHandle` so callers can
 /// `drop(...)` it (matches the reporter wiring and keeps the
@@ -27,7 +48,7 @@ use crate::state::StateStore;
 ///
 /// When `cfg.enabled` is `false` the spawned task exits immediately,
 /// so callers don't have to special-case the disabled path.
-pub fn spawn(cfg: RetentionConfig, db: StateStore) -> JoinHandle<()> {
+pub fn spawn(cfg: RetentionConfig, db: SharedStore) -> JoinHandle<()> {
     tokio::spawn(async move {
         if !cfg.enabled {
             tracing::info!(
@@ -42,6 +63,7 @@ pub fn spawn(cfg: RetentionConfig, db: StateStore) -> JoinHandle<()> {
             tick_interval_ms = cfg.tick_interval_ms,
             succeeded_max_age_s = cfg.succeeded_max_age_s,
             failed_max_age_s = cfg.failed_max_age_s,
+            archive = cfg.archive.as_ref().map(|a| a.path.display().to_string()),
             "retention task started"
         );
 
@@ -58,13 +80,103 @@ pub fn spawn(cfg: RetentionConfig, db: StateStore) -> JoinHandle<()> {
     })
 }
 
-async fn run_tick(cfg: &RetentionConfig, db: &StateStore) {
-    let db_clone = db.clone();
+/// Pause between batches, so a waiting bridge cycle gets the writer
+/// mutex before the next batch takes it again.
+const BATCH_PAUSE: Duration = Duration::from_millis(10);
+
+/// How much one [`prune`] call may delete.
+#[derive(Debug, Clone, Copy)]
+pub struct PruneBudget {
+    pub batch_size: usize,
+    pub max_rows: Option<u64>,
+    pub max_duration: Option<Duration>,
+}
+
+impl PruneBudget {
+    /// One retention tick's budget.
+    pub fn per_tick(cfg: &RetentionConfig) -> Self {
+        Self {
+            batch_size: cfg.batch_size,
+            max_rows: cfg.max_rows_per_tick,
+            max_duration: cfg.max_tick_ms.map(Duration::from_millis),
+        }
+    }
+
+    /// Batched like a tick but run until nothing eligible is left; for
+    /// `clear`, which holds the writer lease with the daemon stopped.
+    pub fn until_done(cfg: &RetentionConfig) -> Self {
+        Self {
+            batch_size: cfg.batch_size,
+            max_rows: None,
+            max_duration: None,
+        }
+    }
+}
+
+/// Delete terminal rows past their windows in batches, archiving each
+/// batch first when `archive` is set, until none are left or `budget`
+/// is spent. Shared by the task and `clear --older-than-s`. An error
+/// stops the prune; batches already committed stay deleted.
+pub fn prune(
+    db: &dyn StateStore,
+    archive: Option<&RetentionArchiveConfig>,
+    succeeded_max_age_s: u64,
+    failed_max_age_s: u64,
+    budget: PruneBudget,
+) -> Result<PruneStats> {
+    let write = |rows: &[ArchivedWorkItem]| match archive {
+        Some(archive) => write_archive(archive, rows),
+        None => Ok(()),
+    };
+    let started = Instant::now();
+    let mut stats = PruneStats::default();
+    loop {
+        let room = match budget.max_rows {
+            Some(max) => (max.saturating_sub(stats.total() as u64) as usize).min(budget.batch_size),
+            None => budget.batch_size,
+        };
+        if room == 0 {
+            stats.budget_exhausted = true;
+            break;
+        }
+        let batch = db.prune_terminal_batch(
+            succeeded_max_age_s,
+            failed_max_age_s,
+            room,
+            archive.is_some().then_some(&write as _),
+        )?;
+        stats.succeeded_deleted += batch.succeeded_deleted;
+        stats.failed_deleted += batch.failed_deleted;
+        if batch.total() < room {
+            break;
+        }
+        if budget
+            .max_duration
+            .is_some_and(|max| started.elapsed() >= max)
+        {
+            stats.budget_exhausted = true;
+            break;
+        }
+        std::thread::sleep(BATCH_PAUSE);
+    }
+    Ok(stats)
+}
+
+async fn run_tick(cfg: &RetentionConfig, db: &SharedStore) {
+    let db_clone = Arc::clone(db);
+    let archive = cfg.archive.clone();
     let succeeded_max_age_s = cfg.succeeded_max_age_s;
     let failed_max_age_s = cfg.failed_max_age_s;
+    let budget = PruneBudget::per_tick(cfg);
 
     let join_result = tokio::task::spawn_blocking(move || {
-        db_clone.prune_terminal_older_than(succeeded_max_age_s, failed_max_age_s)
+        prune(
+            db_clone.as_ref(),
+            archive.as_ref(),
+            succeeded_max_age_s,
+            failed_max_age_s,
+            budget,
+        )
     })
     .await;
 
@@ -74,6 +186,7 @@ async fn run_tick(cfg: &RetentionConfig, db: &StateStore) {
                 event = "bridge_orchestrator.retention.pruned",
                 succeeded = stats.succeeded_deleted,
                 failed = stats.failed_deleted,
+                budget_exhausted = stats.budget_exhausted,
                 "pruned terminal work_items rows"
             );
         }
@@ -103,10 +216,85 @@ async fn run_tick(cfg: &RetentionConfig, db: &StateStore) {
     }
 }
 
+fn write_archive(archive: &RetentionArchiveConfig, rows: &[ArchivedWorkItem]) -> Result<()> {
+    match archive.format {
+        ArchiveFormat::JsonlGz => append_jsonl_gz(&archive.path, rows),
+        ArchiveFormat::Sqlite => upsert_sqlite(&archive.path, rows),
+    }
+    .with_context(|| format!("archive pruned rows to {}", archive.path.display()))
+}
+
+/// Append one JSON line per row to `<dir>/work_items-<UTC date>.jsonl.gz`.
+/// Each call adds a gzip member to the day's file; `zcat` reads them as one
+/// stream. Synced before returning, so the rows are on disk before the
+/// prune commits.
+fn append_jsonl_gz(dir: &Path, rows: &[ArchivedWorkItem]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "work_items-{}.jsonl.gz",
+        chrono::Utc::now().format("%Y-%m-%d")
+    ));
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let mut gz = GzEncoder::new(file, Compression::default());
+    for row in rows {
+        serde_json::to_writer(&mut gz, row)?;
+        gz.write_all(b"\n")?;
+    }
+    gz.finish()?.sync_all()?;
+    Ok(())
+}
+
+/// Upsert the rows into `archived_work_items` in a separate SQLite file,
+/// keyed on the original row's `id` + `created_at`.
+fn upsert_sqlite(path: &Path, rows: &[ArchivedWorkItem]) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS archived_work_items (
+            work_item_id INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            item_id TEXT NOT NULL,
+            flow TEXT NOT NULL,
+            state TEXT NOT NULL,
+            updated_at INTEGER NOT NULL,
+            archived_at INTEGER NOT NULL,
+            record_json TEXT NOT NULL,
+            PRIMARY KEY (work_item_id, created_at)
+        );
+        CREATE INDEX IF NOT EXISTS idx_archived_work_items_item_id
+            ON archived_work_items(item_id);",
+    )?;
+    let tx = conn.transaction()?;
+    let archived_at = chrono::Utc::now().timestamp();
+    for row in rows {
+        tx.execute(
+            "INSERT INTO archived_work_items
+               (work_item_id, created_at, item_id, flow, state, updated_at, archived_at, record_json)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT(work_item_id, created_at) DO UPDATE SET
+               state = excluded.state,
+               updated_at = excluded.updated_at,
+               archived_at = excluded.archived_at,
+               record_json = excluded.record_json",
+            params![
+                row.item.id,
+                row.item.created_at,
+                row.item.item_id,
+                row.item.flow,
+                row.item.state.to_string(),
+                row.item.updated_at,
+                archived_at,
+                serde_json::to_string(row)?,
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::state::WorkState;
+    use crate::state::{SqliteStore, WorkState};
     use std::time::{SystemTime, UNIX_EPOCH};
 
     fn test_db_path(name: &str) -> String {
@@ -118,11 +306,15 @@ mod tests {
     }
 
     fn insert_row(path: &str, item_id: &str, state: WorkState) {
+        insert_flow_row(path, "lock", item_id, state);
+    }
+
+    fn insert_flow_row(path: &str, flow: &str, item_id: &str, state: WorkState) {
         let conn = rusqlite::Connection::open(path).unwrap();
         conn.execute(
             "INSERT INTO work_items (flow, task_type, item_id, idempotency_key, payload_json, state, attempts, max_attempts, created_at, updated_at)
-             VALUES ('lock','create_parked_link',?1,?2,'{}',?3,0,8,strftime('%s','now'),strftime('%s','now'))",
-            rusqlite::params![item_id, format!("{}:key", item_id), state.to_string()],
+             VALUES (?4,'create_parked_link',?1,?2,'{}',?3,0,8,strftime('%s','now'),strftime('%s','now'))",
+            rusqlite::params![item_id, format!("{}:key", item_id), state.to_string(), flow],
         )
         .unwrap();
     }
@@ -130,12 +322,16 @@ mod tests {
     #[tokio::test]
     async fn disabled_config_short_circuits_immediately() {
         let path = test_db_path("disabled");
-        let db = StateStore::open(&path).unwrap();
+        let db: SharedStore = Arc::new(SqliteStore::open(&path).unwrap());
         let cfg = RetentionConfig {
             enabled: false,
             tick_interval_ms: 1,
             succeeded_max_age_s: 0,
             failed_max_age_s: 0,
+            batch_size: 500,
+            max_rows_per_tick: None,
+            max_tick_ms: None,
+            archive: None,
         };
         let handle = spawn(cfg, db);
         // A disabled task returns immediately; if we can await the
@@ -146,7 +342,7 @@ mod tests {
     #[tokio::test]
     async fn enabled_tick_deletes_stale_rows() {
         let path = test_db_path("enabled");
-        let db = StateStore::open(&path).unwrap();
+        let db: SharedStore = Arc::new(SqliteStore::open(&path).unwrap());
 
         insert_row(&path, "retain:young-succ", WorkState::Succeeded);
         insert_row(&path, "retain:old-succ", WorkState::Succeeded);
@@ -168,6 +364,10 @@ mod tests {
             tick_interval_ms: 5,
             succeeded_max_age_s: 3_600,
             failed_max_age_s: 3_600,
+            batch_size: 500,
+            max_rows_per_tick: None,
+            max_tick_ms: None,
+            archive: None,
         };
 
         let handle = spawn(cfg, db.clone());
@@ -181,4 +381,168 @@ mod tests {
         assert_eq!(stats.succeeded_total, 1);
         assert_eq!(stats.queued, 1);
     }
+
+    const UNBOUNDED: PruneBudget = PruneBudget {
+        batch_size: 500,
+        max_rows: None,
+        max_duration: None,
+    };
+
+    fn backdate(path: &str) {
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute(
+            "UPDATE work_items SET updated_at = CAST(strftime('%s','now') AS INTEGER) - 7200",
+            [],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn prune_works_through_batches_and_stops_at_the_row_budget() {
+        let path = test_db_path("batches");
+        let db = SqliteStore::open(&path).unwrap();
+        for i in 0..7 {
+            insert_row(&path, &format!("retain:s{}", i), WorkState::Succeeded);
+        }
+        for i in 0..3 {
+            insert_flow_row(
+                &path,
+                "withdraw",
+                &format!("retain:f{}", i),
+                WorkState::Failed,
+            );
+        }
+        insert_row(&path, "retain:dead-letter", WorkState::Failed);
+        backdate(&path);
+
+        let budget = PruneBudget {
+            batch_size: 3,
+            max_rows: Some(8),
+            max_duration: None,
+        };
+        let first = prune(&db, None, 3_600, 3_600, budget).unwrap();
+        assert_eq!((first.succeeded_deleted, first.failed_deleted), (7, 1));
+        assert!(first.budget_exhausted);
+
+        let second = prune(&db, None, 3_600, 3_600, budget).unwrap();
+        assert_eq!((second.succeeded_deleted, second.failed_deleted), (0, 2));
+        assert!(!second.budget_exhausted);
+        assert_eq!(
+            db.aggregate_stats().unwrap().failed_total,
+            1,
+            "the failed lock is a dead letter and stays"
+        );
+    }
+
+    #[test]
+    fn prune_stops_at_the_time_budget_after_a_batch() {
+        let path = test_db_path("time-budget");
+        let db = SqliteStore::open(&path).unwrap();
+        for i in 0..4 {
+            insert_row(&path, &format!("retain:t{}", i), WorkState::Succeeded);
+        }
+        backdate(&path);
+
+        let budget = PruneBudget {
+            batch_size: 1,
+            max_rows: None,
+            max_duration: Some(Duration::ZERO),
+        };
+        let stats = prune(&db, None, 3_600, 3_600, budget).unwrap();
+        assert_eq!(stats.succeeded_deleted, 1);
+        assert!(stats.budget_exhausted);
+    }
+
+    /// A succeeded row last updated two hours ago, with a note in its
+    /// history.
+    fn stale_row_with_history(path: &str, db: &SqliteStore, item_id: &str) {
+        insert_row(path, item_id, WorkState::Succeeded);
+        db.annotate_item(item_id, "settled", "operator:test")
+            .unwrap();
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute(
+            "UPDATE work_items SET updated_at = CAST(strftime('%s','now') AS INTEGER) - 7200
+             WHERE item_id = ?1",
+            [item_id],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn jsonl_archive_keeps_pruned_rows_and_their_history() {
+        let path = test_db_path("archive-jsonl");
+        let db = SqliteStore::open(&path).unwrap();
+        stale_row_with_history(&path, &db, "retain:archived");
+        let archive = RetentionArchiveConfig {
+            format: ArchiveFormat::JsonlGz,
+            path: format!("{}.archive", path).into(),
+        };
+
+        let stats = prune(&db, Some(&archive), 3_600, 3_600, UNBOUNDED).unwrap();
+        assert_eq!(stats.succeeded_deleted, 1);
+        assert!(db.history("retain:archived").unwrap().is_empty());
+
+        let file = fs::read_dir(&archive.path)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let mut lines = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::MultiGzDecoder::new(fs::File::open(file).unwrap()),
+            &mut lines,
+        )
+        .unwrap();
+        let rows: Vec<ArchivedWorkItem> = lines
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].item.item_id, "retain:archived");
+        assert_eq!(rows[0].events[0].reason.as_deref(), Some("note: settled"));
+    }
+
+    #[test]
+    fn sqlite_archive_is_idempotent_and_a_failed_archive_prunes_nothing() {
+        let path = test_db_path("archive-sqlite");
+        let db = SqliteStore::open(&path).unwrap();
+        stale_row_with_history(&path, &db, "retain:kept");
+
+        // The archive path is a directory, so the archive can't be opened.
+        let broken = RetentionArchiveConfig {
+            format: ArchiveFormat::Sqlite,
+            path: std::env::temp_dir(),
+        };
+        assert!(prune(&db, Some(&broken), 3_600, 3_600, UNBOUNDED).is_err());
+        assert_eq!(db.history("retain:kept").unwrap().len(), 1);
+
+        let archive = RetentionArchiveConfig {
+            format: ArchiveFormat::Sqlite,
+            path: format!("{}.archive.db", path).into(),
+        };
+        let item = db
+            .list_work_items("lock", WorkState::Succeeded, 1)
+            .unwrap()
+            .remove(0);
+        // A crash after the archive write re-archives the same rows.
+        let row = ArchivedWorkItem {
+            item,
+            events: Vec::new(),
+        };
+        write_archive(&archive, std::slice::from_ref(&row)).unwrap();
+
+        let stats = prune(&db, Some(&archive), 3_600, 3_600, UNBOUNDED).unwrap();
+        assert_eq!(stats.succeeded_deleted, 1);
+        let archived = rusqlite::Connection::open(&archive.path).unwrap();
+        let (count, record): (i64, String) = archived
+            .query_row(
+                "SELECT COUNT(*), MAX(record_json) FROM archived_work_items",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(record.contains("note: settled"), "{}", record);
+    }
 }
@@ -106,7 +106,7 @@ fn pad_address(addr: Address) -> U256 {
     })
 }
 
-fn parse_amount(amount_str: &str) -> Result<U256> {
+pub(crate) fn parse_amount(amount_str: &str) -> Result<U256> {
     if amount_str.contains('.') {
         let parts: Vec<&str> = amount_str.split('.').collect();
         if parts.len() != 2 {
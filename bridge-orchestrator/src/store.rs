@@ -0,0 +1,373 @@
+//! The storage the daemon runs on, as a trait.
+//!
+//! [`StateStore`] is everything the lock watcher, the bridge cycle, the
+//! retention task and the watchtower reporter need from the work queue:
+//! enqueueing detected work, listing and claiming it, advancing it through
+//! its steps, the lock checkpoint, stats, and the writer-lease row, and
+//! what the operator commands read and write. The daemon and the CLI only
+//! ever hold a [`SharedStore`], so they run unchanged on either backend:
+//!
+//! - [`SqliteStore`], the default: one file at `DB_PATH`, owned by one
+//!   host.
+//! - [`crate::postgres::PostgresStore`], behind the `postgres` feature and
+//!   `DB_BACKEND=postgres`: a database at `DATABASE_URL` that dashboards
+//!   and standby nodes can share.
+//!
+//! Backups, `db doctor` and WAL checkpoints work on the SQLite file itself
+//! and are reached through [`StateStore::as_sqlite`].
+//!
+//! Every method blocks. The daemon calls them from its async tasks the way
+//! it always has; a backend whose client needs a runtime of its own must
+//! cope with being called from one (see [`crate::postgres`]).
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::config::{Config, RetryPolicy, StorageBackend};
+use crate::migrations::{self, MigrationReport};
+use crate::payload::{WithdrawPayload, WorkPayload};
+use crate::report::ReportItem;
+use crate::state::{
+    ArchiveFn, BridgeAggregateStats, DeadLetter, ErrorClass, LockRefund, Lookup, LookupRow,
+    PruneStats, RefundStatus, RequeueOptions, SqliteStore, StateFilter, StatusRow, StatusSummary,
+    WithdrawStep, WithdrawalRejectionRow, WorkItem, WorkItemEvent, WorkState, WorkStep,
+};
+use crate::withdrawal::WithdrawalFee;
+
+/// A store shared between the daemon's tasks.
+pub type SharedStore = Arc<dyn StateStore>;
+
+pub trait StateStore: Send + Sync {
+    /// Record an on-chain event awaiting confirmations. The payload is
+    /// validated first, so a malformed one is refused here rather than
+    /// failing the cycle that would bridge it. A second call with the same
+    /// `idempotency_key` is a no-op.
+    fn enqueue_detected(
+        &self,
+        item_id: &str,
+        idempotency_key: &str,
+        payload: &WorkPayload,
+    ) -> Result<()>;
+
+    /// Move a `detected` row to `queued` once it has its confirmations.
+    /// Returns `false` when there was no such row.
+    fn move_detected_to_queued(&self, idempotency_key: &str) -> Result<bool>;
+
+    fn get_checkpoint_u64(&self, key: &str) -> Result<Option<u64>>;
+
+    fn set_checkpoint_u64(&self, key: &str, value: u64) -> Result<()>;
+
+    /// Rows of `flow` in `state`, oldest first.
+    fn list_work_items(&self, flow: &str, state: WorkState, limit: usize) -> Result<Vec<WorkItem>>;
+
+    /// All non-terminal rows (state IN `queued` or `in_flight`) for the
+    /// given flow at the given pipeline step, ordered oldest-first. This is
+    /// the core query used by the step-driven bridge cycle: each stage
+    /// (S1..S4) selects its input by `step` value rather than by a
+    /// dedup-derived decision.
+    fn list_pending_by_step(
+        &self,
+        flow: &str,
+        step: WorkStep,
+        limit: usize,
+    ) -> Result<Vec<WorkItem>>;
+
+    /// Claim a row for the zome call about to be made on its behalf.
+    fn mark_in_flight(&self, id: i64) -> Result<()>;
+
+    /// Advance a row to `step='cl_link_created'`, recording the ActionHash
+    /// returned by `create_parked_link`. `state` is reset to `queued` so the
+    /// row is eligible for the next stage.
+    fn advance_to_cl_link_created(&self, id: i64, cl_link_hash: &str) -> Result<()>;
+
+    /// Advance a row to `step='cl_rave_executed'`. `cl_rave_hash` is optional
+    /// — we record it when we observed the RAVE's returned ActionHash
+    /// directly, and leave it NULL when the reconciler inferred the advance
+    /// from the parked link no longer being live.
+    fn advance_to_cl_rave_executed(&self, id: i64, cl_rave_hash: Option<&str>) -> Result<()>;
+
+    /// Advance a row to `step='br_spend_created'`, recording the ActionHash
+    /// returned by `create_parked_spend`.
+    fn advance_to_br_spend_created(&self, id: i64, br_spend_hash: &str) -> Result<()>;
+
+    /// Advance a row to `step='br_rave_executed'` and simultaneously mark it
+    /// `state='succeeded'` — the bridging RAVE is the terminal stage of the
+    /// lock pipeline.
+    fn advance_to_br_rave_executed(&self, id: i64, br_rave_hash: Option<&str>) -> Result<()>;
+
+    /// Record a validated withdrawal the first time S4 sees it live.
+    /// Idempotent on the parked spend's ActionHash: later sightings are
+    /// no-ops, so re-running a cycle never duplicates a row. Returns `true`
+    /// when a row was created.
+    fn record_withdrawal_seen(&self, payload: &WithdrawPayload) -> Result<bool>;
+
+    /// Move a withdrawal to `step='coupon_signed'` / `state='in_flight'`
+    /// once its coupon is in the RAVE inputs. Clears any earlier coupon
+    /// failure so the row reflects the current attempt, and records the
+    /// fee the coupon was signed with: `fee_wei` for aggregation, and
+    /// `fee_model` / `fee` / `net_amount` in the payload for `status`. A
+    /// re-signed coupon overwrites the earlier quote. Only a pending row
+    /// (`seen` / `coupon_signed`, `queued` / `in_flight`) moves; returns
+    /// `false`, changing nothing, for any other, whose coupon must then be
+    /// left out of the RAVE.
+    fn mark_withdrawal_coupon_signed(&self, link_id: &str, fee: &WithdrawalFee) -> Result<bool>;
+
+    /// Record a per-link coupon failure of `class` on the withdrawal's row,
+    /// bumping its attempts like [`Self::reset_in_flight_to_queued`]: the
+    /// row goes back to `queued` at its current step behind the `retry`
+    /// backoff, or is failed once it has spent the class's budget. Only a
+    /// pending row moves.
+    fn mark_withdrawal_coupon_failed(
+        &self,
+        link_id: &str,
+        class: ErrorClass,
+        error: &str,
+        retry: &RetryPolicy,
+    ) -> Result<()>;
+
+    /// Whether S4 may sign a coupon for the withdrawal with parked spend
+    /// `link_id` now: its row is pending and not waiting out a backoff
+    /// (`next_retry_at` after `now`). A failed or delivered withdrawal's
+    /// spend is left out of the RAVE even while it is still live.
+    fn withdrawal_due(&self, link_id: &str, now: i64) -> Result<bool>;
+
+    /// Advance a withdrawal at `coupon_signed` to `step='rave_executed'`
+    /// and mark it `state='succeeded'`. `br_rave_hash` is NULL when the
+    /// reconciler inferred the advance from the spend no longer being live.
+    /// A row at any other step, or already terminal, is left alone: only a
+    /// RAVE carrying its coupon can have delivered it.
+    fn advance_withdrawal_to_rave_executed(
+        &self,
+        link_id: &str,
+        br_rave_hash: Option<&str>,
+    ) -> Result<()>;
+
+    /// Fail a withdrawal still at `seen` whose parked spend is no longer
+    /// live, as [`ErrorClass::SpendCancelled`]: no RAVE carrying a coupon
+    /// for it returned, so the spend was cancelled rather than bridged.
+    fn mark_withdrawal_cancelled(&self, link_id: &str) -> Result<()>;
+
+    /// Parked-spend ActionHashes, and steps, of every withdrawal the
+    /// bridging RAVE has not yet consumed (`step` in `seen` /
+    /// `coupon_signed`, non-terminal state). Input to the reconciler's
+    /// withdrawal rule.
+    fn list_pending_withdrawal_links(&self, limit: usize) -> Result<Vec<(String, WithdrawStep)>>;
+
+    /// Record a rejected withdrawal in the rejection ledger. Returns `true`
+    /// on the first sighting of `link_id` and `false` when it was already
+    /// quarantined, in which case only `seen_count` / `last_seen_at` (and
+    /// the latest reason, should validation rules have changed) move. The
+    /// cycle uses the return value to warn once per spend instead of once
+    /// per cycle.
+    fn record_withdrawal_rejection(
+        &self,
+        link_id: &str,
+        reason: &str,
+        detail: &str,
+        payload_json: &Value,
+    ) -> Result<bool>;
+
+    /// Terminally fail a single row with `class`. Used by the cycle for
+    /// per-lock failure modes that cannot possibly succeed on retry
+    /// (malformed payload, tag-size estimation bug, or a single proof that
+    /// is structurally larger than the link tag cap). A lock failed with a
+    /// [refundable](ErrorClass::is_refundable) class is also entered in
+    /// `lock_refunds`.
+    fn mark_failed_permanent(&self, id: i64, class: ErrorClass, error: &str) -> Result<()>;
+
+    /// Promote any `queued` rows that have already exhausted their retry
+    /// budget to `failed`, keeping the class of the error that spent it
+    /// (`unclassified` if they have none). Intended to be
+    /// called at the top of each cycle so a broken lock cannot loop
+    /// forever in a long-running session (the startup recovery only runs
+    /// when a writer opens the store).
+    fn fail_exhausted_queued(&self, flow: &str) -> Result<usize>;
+
+    /// Return every `in_flight` row of `flow` to `queued` after a cycle
+    /// failed with `class`, bumping its attempts. Each row waits out the
+    /// `retry` backoff (`next_retry_at`) and takes the class's attempt
+    /// budget as its `max_attempts`; one that has spent it is failed
+    /// instead. Either way the row's `error_class` is `class`. A withdrawal
+    /// at `coupon_signed` goes back to `seen`: the RAVE carrying its coupon
+    /// may never have run. Returns the rows taken out of `in_flight`.
+    fn reset_in_flight_to_queued(
+        &self,
+        flow: &str,
+        class: ErrorClass,
+        error: &str,
+        retry: &RetryPolicy,
+    ) -> Result<usize>;
+
+    /// One page of rows for `status`, in the filter's order.
+    fn status(&self, filter: StateFilter) -> Result<Vec<StatusRow>>;
+
+    /// Counts and HOT totals of every row `filter` matches, by flow, state
+    /// and step. The cursor, sort and limit are ignored.
+    fn status_summary(&self, filter: &StateFilter) -> Result<StatusSummary>;
+
+    /// Up to `limit` locks and withdrawals, newest first, that any form of
+    /// `lookup` matches: by sender, recipient, agent or transaction.
+    fn lookup(&self, lookup: &Lookup, limit: usize) -> Result<Vec<LookupRow>>;
+
+    /// Every row something happened to in `[from, to)` (unix seconds), for
+    /// [`crate::report`]: created before `to` and last updated at or after
+    /// `from`.
+    fn report_items(&self, from: i64, to: i64) -> Result<Vec<ReportItem>>;
+
+    /// Every recorded transition of the work item(s) with `item_id`
+    /// (e.g. `lock:42`, `withdraw:uhCkk...`), oldest first.
+    fn history(&self, item_id: &str) -> Result<Vec<WorkItemEvent>>;
+
+    /// Failed locks, most recently failed first. Retention never prunes
+    /// them, so this is every lock that needs an operator.
+    fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>>;
+
+    /// Take a failed lock out of the dead-letter queue: re-validate its
+    /// payload and re-enter it at the step it reached, with a fresh retry
+    /// budget. The attempts it used before are named in the audit event,
+    /// which is returned, and its history and `recent_errors` are kept.
+    /// Only called while holding the writer lease.
+    fn replay_dead_letter(&self, item_id: &str, actor: &str) -> Result<WorkItemEvent>;
+
+    /// The refund ledger, most recently updated first, optionally only the
+    /// refunds at `status`.
+    fn refunds(&self, status: Option<RefundStatus>, limit: usize) -> Result<Vec<LockRefund>>;
+
+    /// Approve the refund of a lock that can never be bridged, so its
+    /// coupon can be signed. An already approved refund is returned as it
+    /// is, so a coupon that failed to sign can be tried again.
+    fn approve_refund(&self, item_id: &str, actor: &str) -> Result<LockRefund>;
+
+    /// Record the coupon signed for an approved refund.
+    fn record_refund_coupon(&self, item_id: &str, coupon: &str, actor: &str) -> Result<LockRefund>;
+
+    /// Record why an approved refund's coupon could not be signed. It
+    /// stays approved.
+    fn record_refund_error(&self, item_id: &str, error: &str, actor: &str) -> Result<()>;
+
+    /// Record an operator's report that the coupon was claimed in
+    /// `claim_tx_hash`. The hash is only checked for shape, not looked up
+    /// on-chain, so the refund becomes `operator_reported`.
+    fn complete_refund(
+        &self,
+        item_id: &str,
+        claim_tx_hash: &str,
+        actor: &str,
+    ) -> Result<LockRefund>;
+
+    /// Rejection ledger, most recently seen first.
+    fn list_withdrawal_rejections(&self, limit: usize) -> Result<Vec<WithdrawalRejectionRow>>;
+
+    /// Delete every `succeeded` and `failed` row, except a lock whose refund
+    /// an operator has approved but not yet reported claimed: its coupon may
+    /// be out, and the ledger is the only record of it.
+    fn clear_non_in_progress(&self) -> Result<usize>;
+
+    /// Delete every row. Refused while a refund is outstanding, for the
+    /// reason [`Self::clear_non_in_progress`] keeps those.
+    fn clear_all(&self) -> Result<usize>;
+
+    /// Move a `failed` (or stuck `queued` / `in_flight`) row back to
+    /// `queued` so the next cycle picks it up, optionally with a fresh
+    /// retry budget and, for a lock, at an earlier or later step. Refuses
+    /// rows that already succeeded, and rows that would fail again straight
+    /// away because their retry budget is spent. Returns the audit event.
+    ///
+    /// A `claimed` / `in_flight` row is only touched when `holds_lease`:
+    /// the caller holds the [`crate::lease::WriterLease`], so the row is a
+    /// leftover of a crash, not a call a running daemon is waiting on.
+    fn requeue_item(
+        &self,
+        item_id: &str,
+        opts: &RequeueOptions,
+        holds_lease: bool,
+        actor: &str,
+    ) -> Result<WorkItemEvent>;
+
+    /// Terminally fail a row by hand with `error_class='operator'`, e.g.
+    /// a lock the operator knows can never be bridged. Returns the audit
+    /// event. Refuses a `claimed` / `in_flight` row unless `holds_lease`,
+    /// like [`Self::requeue_item`].
+    fn force_fail_item(
+        &self,
+        item_id: &str,
+        reason: &str,
+        holds_lease: bool,
+        actor: &str,
+    ) -> Result<WorkItemEvent>;
+
+    /// Attach a free-form note to a row's history without changing it.
+    /// Needs no lease, since it writes nothing the daemon reads.
+    fn annotate_item(&self, item_id: &str, note: &str, actor: &str) -> Result<WorkItemEvent>;
+
+    /// Aggregate snapshot for the watchtower reporter. Must not hold up
+    /// the bridge cycle for longer than a handful of milliseconds.
+    fn aggregate_stats(&self) -> Result<BridgeAggregateStats>;
+
+    /// Delete up to `max_rows` terminal (`succeeded` / `failed`) rows whose
+    /// `updated_at` is older than the supplied per-state age windows,
+    /// oldest `id` first and succeeded rows before failed ones, together
+    /// with their events. Failed locks are the dead-letter queue and are
+    /// never deleted here. One call is one transaction;
+    /// [`crate::retention::prune`] loops over batches.
+    ///
+    /// With `archive`, every row about to be deleted is handed to it
+    /// first, with its events, inside that transaction, so no row is
+    /// deleted without having been archived: an `archive` error rolls the
+    /// batch back. `archive` is not called when nothing is eligible.
+    fn prune_terminal_batch(
+        &self,
+        succeeded_max_age_s: u64,
+        failed_max_age_s: u64,
+        max_rows: usize,
+        archive: Option<&ArchiveFn<'_>>,
+    ) -> Result<PruneStats>;
+
+    /// Record `pid` on `host` as the writer-lease holder, or refresh its
+    /// heartbeat if it already is. `acquired_at` only moves when the holder
+    /// changes. Only called while holding the lease.
+    fn record_writer_lease(&self, pid: u32, host: &str) -> Result<()>;
+
+    /// Refresh the heartbeat of `pid` on `host`. `false` if the row names
+    /// another holder: the lease was taken over after this one's heartbeat
+    /// expired, and must not be reclaimed.
+    fn refresh_writer_lease(&self, pid: u32, host: &str) -> Result<bool>;
+
+    /// Clear the lease row if `pid` still holds it.
+    fn release_writer_lease(&self, pid: u32) -> Result<()>;
+
+    /// The SQLite store behind this one, if that is what it is.
+    fn as_sqlite(&self) -> Option<&SqliteStore> {
+        None
+    }
+}
+
+/// What taking the writer lease on the configured backend would migrate,
+/// without writing anything.
+pub fn schema_plan(cfg: &Config) -> Result<MigrationReport> {
+    match &cfg.storage {
+        StorageBackend::Sqlite => migrations::plan(Path::new(&cfg.db_path)),
+        #[cfg(feature = "postgres")]
+        StorageBackend::Postgres { url } => crate::postgres::PostgresStore::schema_plan(url),
+    }
+}
+
+/// Open the configured backend alongside a writer that may be mid-cycle,
+/// leaving `in_flight` rows alone. For the read-only commands and a
+/// leader-election standby. Refuses a schema that is behind rather than
+/// migrating it: only [`crate::lease::WriterLease::open`] migrates.
+pub fn open_shared(cfg: &Config) -> Result<SharedStore> {
+    match &cfg.storage {
+        StorageBackend::Sqlite => Ok(Arc::new(SqliteStore::open_shared(
+            &cfg.db_path,
+            &cfg.sqlite,
+        )?)),
+        #[cfg(feature = "postgres")]
+        StorageBackend::Postgres { url } => Ok(Arc::new(
+            crate::postgres::PostgresStore::connect_shared(url)?,
+        )),
+    }
+}
@@ -16,11 +16,13 @@
 //!   Worker can reuse its existing auth logic.
 
 use crate::config::WatchtowerReporterConfig;
-use crate::state::{BridgeAggregateStats, StateStore};
+use crate::state::{BridgeAggregateStats, ErrorClass, ErrorClassCount, FlowCounts, StageLatencies};
+use crate::store::SharedStore;
 use anyhow::{Context, Result};
 use hmac::{Hmac, Mac};
 use serde::Serialize;
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
@@ -57,6 +59,13 @@ pub struct ReporterHealth {
     pub unclassified_active: bool,
     pub unclassified_consecutive: u32,
     pub stage_ejections_total: u32,
+    /// Withdrawals S4 validated but could not sign a coupon for. Each
+    /// failure is isolated to its link, so this is the only cycle-level
+    /// trace of a broken signer configuration.
+    pub coupon_failures_total: u32,
+    /// Leader election only: this instance is standing by and runs no
+    /// bridge cycles, so a stale last cycle is expected, not stuck.
+    pub standby: bool,
     pub last_error: Option<String>,
     pub last_error_at_ms: Option<i64>,
 }
@@ -185,6 +194,8 @@ struct PayloadSelfHealth<'a> {
     unclassified_active: bool,
     unclassified_consecutive: u32,
     stage_ejections_total: u32,
+    coupon_failures_total: u32,
+    standby: bool,
     is_stuck: bool,
     last_error: Option<String>,
     last_error_at_iso: Option<String>,
@@ -209,6 +220,8 @@ impl PayloadSelfHealth<'static> {
             unclassified_active: health.unclassified_active,
             unclassified_consecutive: health.unclassified_consecutive,
             stage_ejections_total: health.stage_ejections_total,
+            coupon_failures_total: health.coupon_failures_total,
+            standby: health.standby,
             is_stuck,
             last_error: health.last_error.clone(),
             last_error_at_iso: health.last_error_at_ms.and_then(ms_to_rfc3339),
@@ -224,7 +237,13 @@ struct PayloadBacklog {
     in_flight: i64,
     succeeded_total: i64,
     failed_total: i64,
+    /// The same counts per flow, with each flow's terminal rows in the
+    /// last 24h.
+    by_flow: BTreeMap<String, FlowCounts>,
     oldest_queued_age_s: Option<i64>,
+    withdrawals_rejected_total: i64,
+    /// Retrying and failed rows by the class of their latest error.
+    errors_by_class: BTreeMap<ErrorClass, ErrorClassCount>,
 }
 
 #[derive(Debug, Serialize)]
@@ -234,6 +253,13 @@ struct PayloadThroughput {
     succeeded_24h: i64,
     failed_24h: i64,
     avg_time_to_succeed_s_24h: Option<f64>,
+    withdrawals_fee_charged_24h: i64,
+    /// Decimal HOT, so no precision is lost in the JSON number.
+    withdrawal_fees_24h: String,
+    /// p50/p90/p99 seconds per lock pipeline stage, by when the stage
+    /// ended.
+    stage_latencies_1h: StageLatencies,
+    stage_latencies_24h: StageLatencies,
 }
 
 /// Spawn the reporter in a detached tokio task. Returns immediately;
@@ -243,7 +269,7 @@ struct PayloadThroughput {
 pub fn spawn(
     cfg: WatchtowerReporterConfig,
     state: ReporterState,
-    db: StateStore,
+    db: SharedStore,
     stuck_threshold_ms: u64,
 ) -> tokio::task::JoinHandle<()> {
     tracing::info!(
@@ -314,22 +340,19 @@ fn build_client() -> Result<reqwest::Client> {
 async fn run_tick(
     cfg: &WatchtowerReporterConfig,
     state: &ReporterState,
-    db: &StateStore,
+    db: &SharedStore,
     client: &reqwest::Client,
     stuck_threshold_ms: u64,
 ) -> Result<()> {
-    // Run the SQL aggregate on a blocking thread. We deliberately open
-    // a dedicated read-only sqlite connection per tick rather than
-    // going through `StateStore::aggregate_stats`, which would take the
-    // writer mutex and briefly contend with the bridge cycle. Opening
-    // a fresh read-only connection is ~sub-millisecond against an
-    // already-present db file, which is rounded-to-nothing next to our
-    // 60s reporter period.
-    let db_clone = db.clone();
+    // Run the SQL aggregate on a blocking thread. Every backend reads it
+    // over a connection of its own rather than the writer's (on SQLite a
+    // fresh read-only connection per tick, ~sub-millisecond against an
+    // already-present db file), so it never contends with the bridge
+    // cycle; that is rounded-to-nothing next to our 60s reporter period.
+    let db_clone = Arc::clone(db);
     let stats: BridgeAggregateStats =
         tokio::task::spawn_blocking(move || -> Result<BridgeAggregateStats> {
-            let conn = db_clone.open_read_only_connection()?;
-            crate::state::compute_aggregate_stats(&conn)
+            db_clone.aggregate_stats()
         })
         .await
         .context("reporter: spawn_blocking join")?
@@ -339,10 +362,11 @@ async fn run_tick(
     let uptime_s = state.uptime_s();
     let now_ms = chrono::Utc::now().timestamp_millis();
 
-    let is_stuck = matches!(
-        health.last_cycle_finished_at_ms,
-        Some(t) if now_ms.saturating_sub(t) as u64 > stuck_threshold_ms
-    );
+    let is_stuck = !health.standby
+        && matches!(
+            health.last_cycle_finished_at_ms,
+            Some(t) if now_ms.saturating_sub(t) as u64 > stuck_threshold_ms
+        );
 
     let payload = BridgePayload {
         schema_version: cfg.schema_version,
@@ -357,7 +381,10 @@ async fn run_tick(
             in_flight: stats.in_flight,
             succeeded_total: stats.succeeded_total,
             failed_total: stats.failed_total,
+            by_flow: stats.by_flow,
             oldest_queued_age_s: stats.oldest_queued_age_s,
+            withdrawals_rejected_total: stats.withdrawals_rejected_total,
+            errors_by_class: stats.errors_by_class,
         },
         throughput: PayloadThroughput {
             succeeded_1h: stats.succeeded_1h,
@@ -365,6 +392,10 @@ async fn run_tick(
             succeeded_24h: stats.succeeded_24h,
             failed_24h: stats.failed_24h,
             avg_time_to_succeed_s_24h: stats.avg_time_to_succeed_s_24h,
+            withdrawals_fee_charged_24h: stats.withdrawals_fee_charged_24h,
+            withdrawal_fees_24h: stats.withdrawal_fees_24h,
+            stage_latencies_1h: stats.stage_latencies_1h,
+            stage_latencies_24h: stats.stage_latencies_24h,
         },
     };
 
@@ -563,4 +594,15 @@ mod tests {
         assert_eq!(json["pressure_active"], serde_json::json!(false));
         assert_eq!(json["pressure_consecutive"], serde_json::json!(0));
     }
+
+    #[test]
+    fn self_health_payload_carries_coupon_failures() {
+        let h = ReporterHealth {
+            coupon_failures_total: 3,
+            ..Default::default()
+        };
+        let json = serde_json::to_value(PayloadSelfHealth::from_health(&h, 0, false))
+            .expect("serialize self_health");
+        assert_eq!(json["coupon_failures_total"], serde_json::json!(3));
+    }
 }
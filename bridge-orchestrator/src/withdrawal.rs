@@ -0,0 +1,677 @@
+//! Typed schema for the parked spends S4 finds on the bridging EA.
+//!
+//! Every live parked spend on the bridging EA is either a deposit (S3 wrote
+//! it with a `proof_of_deposit` payload) or a withdrawal a user parked with a
+//! `withdraw_to_address` payload. Withdrawals are user-authored, so they are
+//! validated here — recipient address and amount — before the cycle signs a
+//! coupon for them. A withdrawal that fails validation is classified as
+//! rejected rather than surfacing an error: the cycle records it in the
+//! rejection ledger (`withdrawal_rejections`) and leaves it out of the RAVE,
+//! so one bad spend cannot fail every subsequent cycle.
+//!
+//! The configured withdrawal fee is also applied here: the coupon carries
+//! the parked-spend amount less the fee, and the fee is returned alongside
+//! it for the cycle to record on the withdrawal's row.
+
+use crate::config::{WithdrawalFeeConfig, WithdrawalFeeModel};
+use crate::payload::WithdrawPayload;
+use crate::signer::{generate_coupon, parse_amount, SignerContext};
+use crate::state::ErrorClass;
+use alloy::primitives::{Address, U256};
+use rave_engine::types::{Transaction, TransactionDetails};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Unit the withdrawal amount is read from. Coupons are denominated in the
+/// bridged token only, so this is the one unit a withdrawal may spend.
+pub const WITHDRAWAL_UNIT_INDEX: &str = "1";
+
+/// The `attached_payload` a withdrawal parked spend carries. Unknown keys
+/// are tolerated so the UI can attach metadata without a coordinated
+/// release; the fields named here are the contract.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WithdrawalPayload {
+    pub withdraw_to_address: String,
+}
+
+/// A withdrawal that passed validation and is ready for coupon signing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithdrawalRequest {
+    /// The parked spend's ActionHash, which also keys its coupon.
+    pub link_id: String,
+    pub recipient: Address,
+    /// Decimal amount in the bridged unit, as `generate_coupon` expects it.
+    pub amount: String,
+    /// Holochain agent that parked the spend.
+    pub spender: String,
+}
+
+impl WithdrawalRequest {
+    /// Payload stored on the withdrawal's `work_items` row. `status`
+    /// reads `amount` / `recipient` / `spender` back out of it.
+    pub fn payload(&self) -> WithdrawPayload {
+        WithdrawPayload {
+            link_id: self.link_id.clone(),
+            recipient: self.recipient.to_string(),
+            amount: self.amount.clone(),
+            spender: self.spender.clone(),
+            fee_model: None,
+            fee: None,
+            net_amount: None,
+        }
+    }
+}
+
+/// Why a withdrawal was rejected. Persisted as `as_str()` in the rejection
+/// ledger, so variants may be added but never renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionReason {
+    /// The payload names a withdrawal but does not deserialize into
+    /// [`WithdrawalPayload`] (e.g. `withdraw_to_address` is not a string).
+    MalformedPayload,
+    /// `withdraw_to_address` is not a `0x`-prefixed 20-byte hex address, or
+    /// is mixed-case with a bad EIP-55 checksum.
+    InvalidAddress,
+    /// `withdraw_to_address` is the zero address; a coupon for it would burn
+    /// the funds.
+    ZeroAddress,
+    /// The spend carries no amount in [`WITHDRAWAL_UNIT_INDEX`].
+    MissingAmount,
+    /// The amount does not convert to a positive 18-decimal token amount.
+    InvalidAmount,
+}
+
+impl RejectionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionReason::MalformedPayload => "malformed_payload",
+            RejectionReason::InvalidAddress => "invalid_address",
+            RejectionReason::ZeroAddress => "zero_address",
+            RejectionReason::MissingAmount => "missing_amount",
+            RejectionReason::InvalidAmount => "invalid_amount",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why S4 could not sign a coupon for a withdrawal that passed validation.
+/// Unlike a [`RejectionReason`] these can clear without the user doing
+/// anything — signer configuration, the signing call, or a fee that a later
+/// gas price brings back under the amount — so the withdrawal is skipped
+/// for this cycle only and retried on the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CouponFailureClass {
+    /// The signer context (`ORDER_HASH`, `ORDERBOOK_ADDRESS`, ...) could not
+    /// be read from the environment.
+    SignerConfig,
+    /// `generate_coupon` failed, e.g. a missing or malformed
+    /// `SIGNER_PRIVATE_KEY`.
+    Signing,
+    /// The `gas_indexed` fee model could not read the gas price.
+    FeeQuote,
+    /// The fee would consume the whole withdrawal amount.
+    FeeExceedsAmount,
+}
+
+impl CouponFailureClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CouponFailureClass::SignerConfig => "signer_config",
+            CouponFailureClass::Signing => "coupon_signing",
+            CouponFailureClass::FeeQuote => "fee_quote",
+            CouponFailureClass::FeeExceedsAmount => "fee_exceeds_amount",
+        }
+    }
+
+    /// The [`ErrorClass`] the withdrawal's row records this under.
+    pub fn error_class(&self) -> ErrorClass {
+        match self {
+            CouponFailureClass::SignerConfig | CouponFailureClass::Signing => ErrorClass::Signer,
+            CouponFailureClass::FeeQuote => ErrorClass::Rpc,
+            CouponFailureClass::FeeExceedsAmount => ErrorClass::PolicyRejected,
+        }
+    }
+}
+
+impl std::fmt::Display for CouponFailureClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The configured fee model with its live input already read, so every
+/// withdrawal in a cycle is charged against the same gas price.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeQuote {
+    pub model: WithdrawalFeeModel,
+    pub max_fee_wei: Option<U256>,
+    /// Gas price the `gas_indexed` model was quoted at; zero otherwise.
+    pub gas_price_wei: u128,
+}
+
+impl FeeQuote {
+    pub fn new(cfg: &WithdrawalFeeConfig, gas_price_wei: u128) -> Self {
+        Self {
+            model: cfg.model,
+            max_fee_wei: cfg.max_fee_wei,
+            gas_price_wei,
+        }
+    }
+
+    /// Fee for a withdrawal of `amount_wei`, capped at `max_fee_wei`.
+    pub fn fee_for(&self, amount_wei: U256) -> U256 {
+        let fee = match self.model {
+            WithdrawalFeeModel::None => U256::ZERO,
+            WithdrawalFeeModel::Flat { fee_wei } => fee_wei,
+            WithdrawalFeeModel::Percentage { bps } => {
+                amount_wei.saturating_mul(U256::from(bps)) / U256::from(10_000u32)
+            }
+            WithdrawalFeeModel::GasIndexed {
+                gas_units,
+                hot_per_eth_wei,
+            } => {
+                let gas_cost_wei =
+                    U256::from(gas_units).saturating_mul(U256::from(self.gas_price_wei));
+                gas_cost_wei.saturating_mul(hot_per_eth_wei) / wei_per_token()
+            }
+        };
+        match self.max_fee_wei {
+            Some(max) => fee.min(max),
+            None => fee,
+        }
+    }
+}
+
+/// The fee charged on one withdrawal and what its coupon pays out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithdrawalFee {
+    pub model: &'static str,
+    pub fee_wei: U256,
+    pub net_wei: U256,
+}
+
+impl WithdrawalFee {
+    /// Decimal fee in the bridged unit, as recorded in the row's payload.
+    pub fn fee_amount(&self) -> String {
+        format_token_amount(self.fee_wei)
+    }
+
+    /// Decimal amount the coupon carries.
+    pub fn net_amount(&self) -> String {
+        format_token_amount(self.net_wei)
+    }
+}
+
+/// Apply `quote` to a validated withdrawal. A fee that would leave nothing
+/// to pay out is refused rather than signed as a zero coupon.
+pub fn withdrawal_fee(
+    quote: &FeeQuote,
+    request: &WithdrawalRequest,
+) -> Result<WithdrawalFee, (CouponFailureClass, String)> {
+    // `validate_withdrawal` already parsed this amount, so a failure here
+    // means the request was built some other way; treat it as a signing
+    // fault rather than panicking.
+    let amount_wei = parse_amount(&request.amount)
+        .map_err(|e| (CouponFailureClass::Signing, format!("{:#}", e)))?;
+    let fee_wei = quote.fee_for(amount_wei);
+    if fee_wei >= amount_wei {
+        return Err((
+            CouponFailureClass::FeeExceedsAmount,
+            format!(
+                "{} fee {} leaves nothing of amount {}",
+                quote.model.as_str(),
+                format_token_amount(fee_wei),
+                request.amount
+            ),
+        ));
+    }
+    Ok(WithdrawalFee {
+        model: quote.model.as_str(),
+        fee_wei,
+        net_wei: amount_wei - fee_wei,
+    })
+}
+
+/// Sign the coupon for one validated withdrawal, for its amount less the
+/// fee. The signer context and fee quote are read once per cycle by the
+/// caller and passed in as `Result`s, so a bad signer configuration or an
+/// unreachable gas oracle fails each withdrawal individually instead of the
+/// cycle — deposits in the same RAVE are unaffected.
+pub async fn sign_withdrawal_coupon(
+    signer_ctx: &Result<SignerContext, String>,
+    fee_quote: &Result<FeeQuote, String>,
+    request: &WithdrawalRequest,
+) -> Result<(String, WithdrawalFee), (CouponFailureClass, String)> {
+    let ctx = signer_ctx
+        .as_ref()
+        .map_err(|e| (CouponFailureClass::SignerConfig, e.clone()))?;
+    let quote = fee_quote
+        .as_ref()
+        .map_err(|e| (CouponFailureClass::FeeQuote, e.clone()))?;
+    let fee = withdrawal_fee(quote, request)?;
+    let coupon = generate_coupon(&fee.net_amount(), &request.recipient.to_string(), ctx)
+        .await
+        .map_err(|e| (CouponFailureClass::Signing, format!("{:#}", e)))?;
+    Ok((coupon, fee))
+}
+
+fn wei_per_token() -> U256 {
+    U256::from(10u64).pow(U256::from(18u64))
+}
+
+/// Exact decimal form of an 18-decimal amount, trailing zeros trimmed, so
+/// `parse_amount` reads back the same value.
+pub fn format_token_amount(amount_wei: U256) -> String {
+    let whole = amount_wei / wei_per_token();
+    let frac = (amount_wei % wei_per_token()).to_string();
+    if frac == "0" {
+        return whole.to_string();
+    }
+    let frac = format!("{:0>18}", frac);
+    format!("{}.{}", whole, frac.trim_end_matches('0'))
+}
+
+/// A withdrawal that failed validation, with enough context for the
+/// rejection ledger and the operator reading it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithdrawalRejection {
+    pub link_id: String,
+    pub reason: RejectionReason,
+    pub detail: String,
+    pub payload: Value,
+}
+
+/// What a parked spend on the bridging EA is, as far as S4 is concerned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BridgingLink {
+    /// A deposit spend written by S3.
+    Deposit,
+    /// A well-formed withdrawal.
+    Withdrawal(WithdrawalRequest),
+    /// A withdrawal that failed validation; quarantined, never signed.
+    Rejected(WithdrawalRejection),
+    /// Neither: not a parked spend, or a payload naming no bridge action.
+    Unrelated,
+}
+
+/// Classify one live bridging link. Pure, so every rejection path is
+/// unit-testable without a conductor.
+pub fn classify_bridging_link(tx: &Transaction) -> BridgingLink {
+    let TransactionDetails::ParkedSpend {
+        attached_payload,
+        spender,
+        ..
+    } = &tx.details
+    else {
+        return BridgingLink::Unrelated;
+    };
+
+    if attached_payload.get("proof_of_deposit").is_some() {
+        return BridgingLink::Deposit;
+    }
+    if attached_payload.get("withdraw_to_address").is_none() {
+        return BridgingLink::Unrelated;
+    }
+
+    let link_id = tx.id.to_string();
+    let amount = tx.amount.get(WITHDRAWAL_UNIT_INDEX).map(|v| v.to_string());
+    match validate_withdrawal(attached_payload, amount.as_deref()) {
+        Ok((recipient, amount)) => BridgingLink::Withdrawal(WithdrawalRequest {
+            link_id,
+            recipient,
+            amount,
+            spender: spender.to_string(),
+        }),
+        Err((reason, detail)) => BridgingLink::Rejected(WithdrawalRejection {
+            link_id,
+            reason,
+            detail,
+            payload: attached_payload.clone(),
+        }),
+    }
+}
+
+/// Validate a withdrawal payload and amount, returning the parsed
+/// recipient and the amount to sign for.
+fn validate_withdrawal(
+    payload: &Value,
+    amount: Option<&str>,
+) -> Result<(Address, String), (RejectionReason, String)> {
+    let parsed: WithdrawalPayload = serde_json::from_value(payload.clone())
+        .map_err(|e| (RejectionReason::MalformedPayload, e.to_string()))?;
+    let recipient = parse_recipient(&parsed.withdraw_to_address)?;
+
+    let amount = amount.ok_or_else(|| {
+        (
+            RejectionReason::MissingAmount,
+            format!("no amount in unit {}", WITHDRAWAL_UNIT_INDEX),
+        )
+    })?;
+    match parse_amount(amount) {
+        Ok(v) if !v.is_zero() => {}
+        Ok(_) => {
+            return Err((
+                RejectionReason::InvalidAmount,
+                format!("amount {} is zero", amount),
+            ))
+        }
+        Err(e) => {
+            return Err((
+                RejectionReason::InvalidAmount,
+                format!("amount {}: {}", amount, e),
+            ))
+        }
+    }
+
+    Ok((recipient, amount.to_string()))
+}
+
+fn parse_recipient(raw: &str) -> Result<Address, (RejectionReason, String)> {
+    let invalid = |why: &str| {
+        (
+            RejectionReason::InvalidAddress,
+            format!("{:?}: {}", raw, why),
+        )
+    };
+    let hex = raw
+        .strip_prefix("0x")
+        .ok_or_else(|| invalid("missing 0x prefix"))?;
+    if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid("not 20 bytes of hex"));
+    }
+    let mixed_case =
+        hex.chars().any(|c| c.is_ascii_lowercase()) && hex.chars().any(|c| c.is_ascii_uppercase());
+    let address = if mixed_case {
+        Address::parse_checksummed(raw, None).map_err(|_| invalid("bad EIP-55 checksum"))?
+    } else {
+        raw.parse::<Address>()
+            .map_err(|e| invalid(&e.to_string()))?
+    };
+    if address.is_zero() {
+        return Err((RejectionReason::ZeroAddress, format!("{:?}", raw)));
+    }
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use holo_hash::{ActionHash, AgentPubKey, AgentPubKeyB64};
+    use holochain_zome_types::timestamp::Timestamp;
+    use rave_engine::types::{TransactionType, UnitMap};
+    use serde_json::json;
+
+    const RECIPIENT: &str = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+
+    fn spend(payload: Value, amount: UnitMap) -> Transaction {
+        let agent: AgentPubKeyB64 = AgentPubKey::from_raw_32(vec![3u8; 32]).into();
+        let hash = |seed: u8| ActionHash::from_raw_32(vec![seed; 32]).into();
+        Transaction {
+            id: hash(0x11),
+            tx_type: TransactionType::ParkedSpend,
+            amount,
+            counterparty: vec![],
+            history: vec![],
+            timestamp: Timestamp(0),
+            creator: agent.clone(),
+            details: TransactionDetails::ParkedSpend {
+                is_parked_spend_credit: false,
+                ea_id: hash(0xEB),
+                smart_agreement_title: "test".to_string(),
+                spender: agent.clone(),
+                executor: agent,
+                ct_role_id: "role".to_string(),
+                role_display_name: "Role".to_string(),
+                global_definition: hash(0xAA),
+                lane_definitions: vec![],
+                new_balance: UnitMap::new(),
+                fees_owed: UnitMap::new(),
+                proposed_balance: UnitMap::new(),
+                attached_payload: payload,
+            },
+        }
+    }
+
+    fn withdrawal(address: Value, amount: &str) -> Transaction {
+        spend(
+            json!({ "withdraw_to_address": address }),
+            UnitMap::from(vec![(1_u32, amount)]),
+        )
+    }
+
+    fn rejection(tx: &Transaction) -> RejectionReason {
+        match classify_bridging_link(tx) {
+            BridgingLink::Rejected(r) => r.reason,
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_deposit_proof_classifies_as_deposit() {
+        let tx = spend(
+            json!({ "proof_of_deposit": [{ "tx_hash": "0xabc" }] }),
+            UnitMap::new(),
+        );
+        assert_eq!(classify_bridging_link(&tx), BridgingLink::Deposit);
+    }
+
+    #[test]
+    fn a_payload_naming_no_bridge_action_is_unrelated() {
+        let tx = spend(json!({ "note": "hello" }), UnitMap::new());
+        assert_eq!(classify_bridging_link(&tx), BridgingLink::Unrelated);
+    }
+
+    #[test]
+    fn a_well_formed_withdrawal_carries_its_recipient_and_amount() {
+        let tx = withdrawal(json!(RECIPIENT), "12.5");
+        match classify_bridging_link(&tx) {
+            BridgingLink::Withdrawal(req) => {
+                assert_eq!(req.link_id, tx.id.to_string());
+                assert_eq!(req.recipient, RECIPIENT.parse::<Address>().unwrap());
+                assert_eq!(req.amount, tx.amount.get("1").unwrap().to_string());
+            }
+            other => panic!("expected a withdrawal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn single_case_addresses_skip_the_checksum() {
+        let lower = withdrawal(json!(RECIPIENT.to_lowercase()), "1");
+        assert!(matches!(
+            classify_bridging_link(&lower),
+            BridgingLink::Withdrawal(_)
+        ));
+    }
+
+    #[test]
+    fn a_non_string_address_is_a_malformed_payload() {
+        assert_eq!(
+            rejection(&withdrawal(json!(42), "1")),
+            RejectionReason::MalformedPayload
+        );
+    }
+
+    #[test]
+    fn unparseable_addresses_are_rejected_not_propagated() {
+        for bad in [
+            "not-an-address",
+            "5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0x1234",
+            "0xzzzeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            // Valid hex, one letter's case flipped: checksum must fail.
+            "0x5aaeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+        ] {
+            assert_eq!(
+                rejection(&withdrawal(json!(bad), "1")),
+                RejectionReason::InvalidAddress,
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn the_zero_address_is_rejected() {
+        let zero = format!("0x{}", "0".repeat(40));
+        assert_eq!(
+            rejection(&withdrawal(json!(zero), "1")),
+            RejectionReason::ZeroAddress
+        );
+    }
+
+    #[test]
+    fn a_withdrawal_without_a_unit_one_amount_is_rejected() {
+        let tx = spend(
+            json!({ "withdraw_to_address": RECIPIENT }),
+            UnitMap::from(vec![(0_u32, "5")]),
+        );
+        assert_eq!(rejection(&tx), RejectionReason::MissingAmount);
+    }
+
+    #[test]
+    fn a_zero_amount_is_rejected() {
+        assert_eq!(
+            rejection(&withdrawal(json!(RECIPIENT), "0")),
+            RejectionReason::InvalidAmount
+        );
+    }
+
+    #[tokio::test]
+    async fn a_missing_signer_context_fails_the_withdrawal_not_the_caller() {
+        let request = WithdrawalRequest {
+            link_id: "uhCkkW1".to_string(),
+            recipient: RECIPIENT.parse().unwrap(),
+            amount: "1".to_string(),
+            spender: "uhCAkSpender".to_string(),
+        };
+        let ctx = Err("ORDER_HASH not set".to_string());
+        let (class, detail) = sign_withdrawal_coupon(&ctx, &Ok(no_fee()), &request)
+            .await
+            .expect_err("no signer context, no coupon");
+        assert_eq!(class, CouponFailureClass::SignerConfig);
+        assert_eq!(detail, "ORDER_HASH not set");
+    }
+
+    fn no_fee() -> FeeQuote {
+        FeeQuote {
+            model: WithdrawalFeeModel::None,
+            max_fee_wei: None,
+            gas_price_wei: 0,
+        }
+    }
+
+    fn request_for(amount: &str) -> WithdrawalRequest {
+        WithdrawalRequest {
+            link_id: "uhCkkW1".to_string(),
+            recipient: RECIPIENT.parse().unwrap(),
+            amount: amount.to_string(),
+            spender: "uhCAkSpender".to_string(),
+        }
+    }
+
+    fn hot(amount: &str) -> U256 {
+        parse_amount(amount).unwrap()
+    }
+
+    #[test]
+    fn each_fee_model_deducts_from_the_coupon_amount() {
+        let request = request_for("100");
+        let fee = |model, max_fee_wei, gas_price_wei| {
+            withdrawal_fee(
+                &FeeQuote {
+                    model,
+                    max_fee_wei,
+                    gas_price_wei,
+                },
+                &request,
+            )
+            .unwrap()
+        };
+
+        let none = fee(WithdrawalFeeModel::None, None, 0);
+        assert_eq!(
+            (none.fee_amount(), none.net_amount()),
+            ("0".into(), "100".into())
+        );
+
+        let flat = fee(
+            WithdrawalFeeModel::Flat {
+                fee_wei: hot("1.5"),
+            },
+            None,
+            0,
+        );
+        assert_eq!(flat.net_amount(), "98.5");
+        assert_eq!(flat.model, "flat");
+
+        let pct = fee(WithdrawalFeeModel::Percentage { bps: 25 }, None, 0);
+        assert_eq!(pct.fee_amount(), "0.25");
+
+        // 100k gas at 20 gwei is 0.002 ETH; at 5000 HOT/ETH that is 10 HOT.
+        let gas = WithdrawalFeeModel::GasIndexed {
+            gas_units: 100_000,
+            hot_per_eth_wei: hot("5000"),
+        };
+        assert_eq!(fee(gas, None, 20_000_000_000).fee_amount(), "10");
+        assert_eq!(
+            fee(gas, Some(hot("4")), 20_000_000_000).fee_amount(),
+            "4",
+            "WITHDRAWAL_FEE_MAX caps a gas spike"
+        );
+    }
+
+    #[tokio::test]
+    async fn fee_failures_skip_the_withdrawal_before_signing() {
+        let quote = FeeQuote {
+            model: WithdrawalFeeModel::Flat { fee_wei: hot("2") },
+            max_fee_wei: None,
+            gas_price_wei: 0,
+        };
+        let (class, detail) = withdrawal_fee(&quote, &request_for("2")).unwrap_err();
+        assert_eq!(class, CouponFailureClass::FeeExceedsAmount);
+        assert!(detail.contains("flat"), "{}", detail);
+
+        let signer = Ok(SignerContext {
+            order_hash: String::new(),
+            order_owner: String::new(),
+            orderbook: String::new(),
+            token: String::new(),
+            vault_id: String::new(),
+            expiry_seconds: 0,
+        });
+        let (class, detail) = sign_withdrawal_coupon(
+            &signer,
+            &Err("eth_gasPrice failed".to_string()),
+            &request_for("2"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(class, CouponFailureClass::FeeQuote);
+        assert_eq!(detail, "eth_gasPrice failed");
+    }
+
+    #[test]
+    fn format_token_amount_round_trips_through_parse_amount() {
+        for amount in ["0", "7", "0.000000000000000001", "12.5", "98.123456789"] {
+            assert_eq!(format_token_amount(hot(amount)), amount);
+        }
+    }
+
+    #[test]
+    fn a_rejection_keeps_the_payload_for_the_ledger() {
+        let tx = withdrawal(json!("bogus"), "1");
+        match classify_bridging_link(&tx) {
+            BridgingLink::Rejected(r) => {
+                assert_eq!(r.link_id, tx.id.to_string());
+                assert_eq!(r.payload, json!({ "withdraw_to_address": "bogus" }));
+                assert!(r.detail.contains("bogus"), "{}", r.detail);
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+    }
+}